@@ -0,0 +1,215 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::mem::ManuallyDrop;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+use crate::chain::from_hex;
+
+// hashes of files seen to keep, they're forgotten all at once past this
+const SEEN_LEN: usize = 10000;
+
+fn invalid(at: &Path, msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("{}: {}", at.display(), msg))
+}
+
+/// Add the hashes in a manifest of `sha256sum` lines, or only hashes, to
+/// `hashes`.
+fn parse_manifest(at: &Path, s: &str, hashes: &mut HashSet<String>) -> io::Result<()> {
+    for (i, line) in s.lines().enumerate() {
+        let hash = match line.split_whitespace().next() {
+            None => continue,
+            Some(w) if w.starts_with('#') => continue,
+            Some(w) => w.to_ascii_lowercase(),
+        };
+        if hash.len() != 64 || from_hex(&hash).is_none() {
+            return Err(invalid(
+                at,
+                format!("line {}: {}: expected a sha256 in hex", i + 1, hash),
+            ));
+        }
+        hashes.insert(hash);
+    }
+    Ok(())
+}
+
+/// Whether `sig`, raw or in hex, is `key`'s signature of `manifest`.
+fn signed(key: &[u8], manifest: &[u8], sig: &[u8]) -> bool {
+    let hex = std::str::from_utf8(sig)
+        .ok()
+        .and_then(|s| from_hex(s.trim()));
+    let sig = hex.as_deref().unwrap_or(sig);
+    UnparsedPublicKey::new(&ED25519, key)
+        .verify(manifest, sig)
+        .is_ok()
+}
+
+/// The file open at `fd` by dev, ino and ctime, which changes with any
+/// write.
+fn key(fd: RawFd) -> io::Result<(u64, u64, i64, i64)> {
+    // borrowed, the caller closes it
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    let m = file.metadata()?;
+    Ok((m.dev(), m.ino(), m.ctime(), m.ctime_nsec()))
+}
+
+/// The SHA-256 hashes of the executables allowed to run, from a manifest,
+/// or a directory of them, each signed with Ed25519 in a .sig next to it
+/// if there's a key.
+pub struct Allowlist {
+    hashes: HashSet<String>,
+    /// the hashes of the files checked, by dev, ino and ctime, which
+    /// changes with any write
+    seen: HashMap<(u64, u64, i64, i64), String>,
+}
+
+impl Allowlist {
+    pub fn load(path: &Path, key: Option<&Path>) -> io::Result<Allowlist> {
+        let key = match key {
+            Some(file) => {
+                let hex = fs::read_to_string(file)?;
+                match from_hex(hex.trim()) {
+                    Some(key) if key.len() == 32 => Some(key),
+                    _ => {
+                        return Err(invalid(
+                            file,
+                            "expected an Ed25519 public key in hex".into(),
+                        ))
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let single = !fs::metadata(path)?.is_dir();
+        let mut manifests = Vec::new();
+        if single {
+            manifests.push(PathBuf::from(path));
+        } else {
+            for e in fs::read_dir(path)? {
+                let path = e?.path();
+                if path.is_file() && path.extension() != Some(OsStr::new("sig")) {
+                    manifests.push(path);
+                }
+            }
+            manifests.sort();
+        }
+
+        let mut hashes = HashSet::new();
+        for manifest in manifests {
+            let s = fs::read(&manifest)?;
+            if let Some(key) = &key {
+                let mut sig = manifest.clone().into_os_string();
+                sig.push(".sig");
+                let bad = match fs::read(&sig) {
+                    Ok(sig) if signed(key, &s, &sig) => None,
+                    Ok(_) => Some("bad signature".to_string()),
+                    Err(e) => Some(format!("no signature: {}", e)),
+                };
+                if let Some(bad) = bad {
+                    // all there is, so nothing could run
+                    if single {
+                        return Err(invalid(&manifest, bad));
+                    }
+                    error!("{}: {}, skipping it", manifest.display(), bad);
+                    continue;
+                }
+            }
+            let s = String::from_utf8(s).map_err(|e| invalid(&manifest, e.to_string()))?;
+            parse_manifest(&manifest, &s, &mut hashes)?;
+        }
+        info!("{}: {} executables allowed", path.display(), hashes.len());
+        Ok(Allowlist {
+            hashes,
+            seen: HashMap::new(),
+        })
+    }
+
+    /// The hash of the file open at `fd`, if it was checked before and
+    /// hasn't changed since, so it needn't be hashed again.
+    pub fn hashed(&self, fd: RawFd) -> Option<String> {
+        self.seen.get(&key(fd).ok()?).cloned()
+    }
+
+    /// Whether `hash`, of the file open at `fd`, is allowed.
+    pub fn check(&mut self, fd: RawFd, hash: &str) -> bool {
+        if let Ok(key) = key(fd) {
+            if self.seen.len() >= SEEN_LEN {
+                self.seen.clear();
+            }
+            self.seen.insert(key, hash.into());
+        }
+        self.hashes.contains(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::to_hex;
+    use crate::enrich::sha256;
+    use crate::TempDir;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn allowlist_manifests() -> io::Result<()> {
+        let dir = TempDir::new("allowlist")?;
+        let write = |name: &str, data: &[u8]| fs::write(dir.join(name), data);
+
+        write("exe", b"#!/bin/sh\n")?;
+        write("other", b"#!/bin/true\n")?;
+        let exe = File::open(dir.join("exe"))?;
+        let other = File::open(dir.join("other"))?;
+        let hash = sha256(&exe)?;
+
+        let rng = SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        write("key", to_hex(pair.public_key().as_ref()).as_bytes())?;
+
+        fs::create_dir_all(dir.join("d"))?;
+        let manifest = format!("# allowed\n{}  /bin/exe\n", hash.to_uppercase());
+        write("d/a.sha256", manifest.as_bytes())?;
+        write(
+            "d/a.sha256.sig",
+            to_hex(pair.sign(manifest.as_bytes()).as_ref()).as_bytes(),
+        )?;
+        // signed by someone else
+        let other_hash = sha256(&other)?;
+        write("d/b.sha256", other_hash.as_bytes())?;
+        write("d/b.sha256.sig", pair.sign(b"something else").as_ref())?;
+
+        let key = dir.join("key");
+        let mut allowlist = Allowlist::load(&dir.join("d"), Some(&key))?;
+        assert_eq!(allowlist.hashed(exe.as_raw_fd()), None);
+        assert!(allowlist.check(exe.as_raw_fd(), &hash));
+        assert_eq!(allowlist.hashed(exe.as_raw_fd()), Some(hash.clone()));
+        assert!(allowlist.check(exe.as_raw_fd(), &hash));
+        assert_eq!(allowlist.seen.len(), 1);
+        assert!(!allowlist.check(other.as_raw_fd(), &other_hash));
+
+        // without a key nothing is checked
+        let mut allowlist = Allowlist::load(&dir.join("d"), None)?;
+        assert!(allowlist.check(other.as_raw_fd(), &other_hash));
+        let mut allowlist = Allowlist::load(&dir.join("d/b.sha256"), None)?;
+        assert!(allowlist.check(other.as_raw_fd(), &other_hash));
+        // and a file on its own is refused without its signature
+        let mut allowlist = Allowlist::load(&dir.join("d/a.sha256"), Some(&key))?;
+        assert!(allowlist.check(exe.as_raw_fd(), &hash));
+        assert!(Allowlist::load(&dir.join("d/b.sha256"), Some(&key)).is_err());
+        write("e.sha256", hash.as_bytes())?;
+        assert!(Allowlist::load(&dir.join("e.sha256"), Some(&key)).is_err());
+
+        write("d/c.sha256", b"abc  /bin/x\n")?;
+        assert!(Allowlist::load(&dir.join("d"), None).is_err());
+        assert!(Allowlist::load(&dir.join("d"), Some(&dir.join("exe"))).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{write_path, EventEntry};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// how far apart our event and the audit record may be and still match
+const JOIN_WINDOW: Duration = Duration::from_secs(2);
+const MAX_SEEN: usize = 4096;
+
+/// One line of the audit log, like
+/// type=SYSCALL msg=audit(1586000000.123:456): syscall=257 pid=1 exe="/bin/cat"
+#[derive(Debug)]
+struct Record {
+    kind: String,
+    time: SystemTime,
+    serial: u64,
+    fields: HashMap<String, String>,
+}
+
+fn parse_line(line: &str) -> Option<Record> {
+    // drop the interpreted fields auditd appends in enriched format
+    let line = line.split('\x1d').next()?;
+    let (kind, rest) = line.strip_prefix("type=")?.split_once(' ')?;
+    let (stamp, rest) = rest.strip_prefix("msg=audit(")?.split_once("):")?;
+    let (time, serial) = stamp.split_once(':')?;
+    let (secs, millis) = time.split_once('.')?;
+
+    Some(Record {
+        kind: kind.into(),
+        time: UNIX_EPOCH
+            + Duration::from_secs(secs.parse().ok()?)
+            + Duration::from_millis(millis.parse().ok()?),
+        serial: serial.parse().ok()?,
+        fields: rest
+            .split_whitespace()
+            .filter_map(|kv| kv.split_once('='))
+            .map(|(k, v)| (k.into(), v.trim_matches('"').into()))
+            .collect(),
+    })
+}
+
+/// A kernel FANOTIFY audit record with the syscall that triggered it,
+/// joined with the event we saw for it if any.
+#[derive(Debug, PartialEq)]
+pub struct Audited {
+    pub pid: Option<u32>,
+    pub resp: String,
+    pub syscall: String,
+    pub auid: String,
+    pub exe: String,
+    pub path: Option<PathBuf>,
+}
+
+impl Audited {
+    pub fn write_to(&self, w: &mut dyn Write, raw_paths: bool) -> io::Result<()> {
+        let resp = match self.resp.as_str() {
+            "1" => "allow",
+            "2" => "deny",
+            r => r,
+        };
+        w.write_fmt(format_args!(
+            "AUDIT\t{}\t{}\t{}\t{}\t{}\t",
+            self.pid
+                .map(|p| p.to_string())
+                .unwrap_or_else(|| "-".into()),
+            resp,
+            self.syscall,
+            self.auid,
+            self.exe,
+        ))?;
+        match &self.path {
+            Some(path) => write_path(w, path, raw_paths),
+            None => w.write_all(b"-"),
+        }
+    }
+}
+
+struct Seen {
+    at: SystemTime,
+    pid: u32,
+    path: Option<PathBuf>,
+}
+
+/// Follows the audit log and matches FANOTIFY records against the
+/// permission events we handled, by pid and time.
+pub struct AuditLog {
+    path: PathBuf,
+    file: Option<File>,
+    ino: u64,
+    partial: Vec<u8>,
+    next: Instant,
+    group: Vec<Record>,
+    seen: VecDeque<Seen>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> io::Result<AuditLog> {
+        let mut log = AuditLog {
+            path: path.into(),
+            file: None,
+            ino: 0,
+            partial: Vec::new(),
+            next: Instant::now(),
+            group: Vec::new(),
+            seen: VecDeque::new(),
+        };
+
+        // only what's logged from now on
+        let mut f = File::open(path)?;
+        f.seek(SeekFrom::End(0))?;
+        log.ino = f.metadata()?.ino();
+        log.file = Some(f);
+        Ok(log)
+    }
+
+    pub fn record(&mut self, entry: &EventEntry) {
+        if let Some(pid) = entry.pid {
+            self.seen.push_back(Seen {
+                at: SystemTime::now(),
+                pid,
+                path: entry.path.clone(),
+            });
+            if self.seen.len() > MAX_SEEN {
+                self.seen.pop_front();
+            }
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.next
+    }
+
+    fn read_new(&mut self) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        if let Some(f) = &mut self.file {
+            if f.metadata()?.len() < f.stream_position()? {
+                // truncated under us
+                f.seek(SeekFrom::Start(0))?;
+            }
+            f.read_to_end(&mut buf)?;
+        }
+
+        // rotated, finish the old file and start on the new one
+        match fs::metadata(&self.path) {
+            Ok(m) if m.ino() != self.ino => {
+                let mut f = File::open(&self.path)?;
+                f.read_to_end(&mut buf)?;
+                self.ino = m.ino();
+                self.file = Some(f);
+            }
+            Ok(_) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        Ok(buf)
+    }
+
+    /// Read what's new in the log, returns the FANOTIFY records found.
+    pub fn poll(&mut self) -> io::Result<Vec<Audited>> {
+        self.next = Instant::now() + POLL_INTERVAL;
+        let buf = self.read_new()?;
+        self.partial.extend_from_slice(&buf);
+
+        let mut out = Vec::new();
+        while let Some(nl) = self.partial.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=nl).collect();
+            let record = match parse_line(String::from_utf8_lossy(&line).trim_end()) {
+                Some(r) => r,
+                None => continue,
+            };
+            out.extend(self.push(record));
+        }
+        Ok(out)
+    }
+
+    fn push(&mut self, record: Record) -> Option<Audited> {
+        let mut done = None;
+        if self
+            .group
+            .first()
+            .is_some_and(|r| r.serial != record.serial)
+        {
+            done = self.finish();
+        }
+        if record.kind == "EOE" {
+            return self.finish().or(done);
+        }
+        self.group.push(record);
+        done
+    }
+
+    /// Done with all the records of one audit event.
+    fn finish(&mut self) -> Option<Audited> {
+        let group: Vec<Record> = self.group.drain(..).collect();
+        let fan = group.iter().find(|r| r.kind == "FANOTIFY")?;
+        let syscall = group.iter().find(|r| r.kind == "SYSCALL");
+        let field = |name: &str| {
+            syscall
+                .and_then(|r| r.fields.get(name))
+                .cloned()
+                .unwrap_or_else(|| "-".into())
+        };
+        let pid = syscall
+            .and_then(|r| r.fields.get("pid"))
+            .and_then(|p| p.parse().ok());
+
+        let path = pid.and_then(|pid| self.join(pid, fan.time)).or_else(|| {
+            group
+                .iter()
+                .find(|r| r.kind == "PATH")
+                .and_then(|r| r.fields.get("name"))
+                .map(PathBuf::from)
+        });
+
+        Some(Audited {
+            pid,
+            resp: fan.fields.get("resp").cloned().unwrap_or_default(),
+            syscall: field("syscall"),
+            auid: field("auid"),
+            exe: field("exe"),
+            path,
+        })
+    }
+
+    /// The path of our closest event from `pid` around `at`.
+    fn join(&mut self, pid: u32, at: SystemTime) -> Option<PathBuf> {
+        let apart = |t: SystemTime| {
+            at.duration_since(t)
+                .or_else(|_| t.duration_since(at))
+                .unwrap_or_default()
+        };
+        let (i, _) = self
+            .seen
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.pid == pid && apart(s.at) <= JOIN_WINDOW)
+            .min_by_key(|(_, s)| apart(s.at))?;
+        self.seen.remove(i)?.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+
+    #[test]
+    fn audit_parse() {
+        let r = parse_line(
+            "type=SYSCALL msg=audit(1586000000.123:456): arch=c000003e syscall=257 pid=42 \
+             exe=\"/bin/cat\" key=(null)\x1dARCH=x86_64",
+        )
+        .unwrap();
+        assert_eq!(r.kind, "SYSCALL");
+        assert_eq!(r.serial, 456);
+        assert_eq!(
+            r.time,
+            UNIX_EPOCH + Duration::from_secs(1586000000) + Duration::from_millis(123)
+        );
+        assert_eq!(r.fields["exe"], "/bin/cat");
+        assert_eq!(r.fields["key"], "(null)");
+        assert!(!r.fields.contains_key("ARCH"));
+        assert!(parse_line("garbage").is_none());
+    }
+
+    #[test]
+    fn audit_join() -> io::Result<()> {
+        let dir = TempDir::new("audit")?;
+        let path = dir.join("audit.log");
+        File::create(&path)?.write_all(b"type=FANOTIFY msg=audit(1.000:1): resp=2\n")?;
+
+        let mut log = AuditLog::open(&path)?;
+        log.record(&EventEntry {
+            pid: Some(42),
+            ..EventEntry::test(libc::FAN_OPEN_PERM, "/a")
+        });
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_fmt(format_args!(
+                "type=FANOTIFY msg=audit({0}.000:2): resp=2\n\
+                 type=SYSCALL msg=audit({0}.000:2): syscall=257 pid=42 auid=1000 exe=\"/bin/cat\"\n\
+                 type=EOE msg=audit({0}.000:2): \n\
+                 type=FANOTIFY msg=audit({0}.000:3): resp=1\n\
+                 type=SYSCALL msg=audit({0}.000:3): syscall=257 pid=43 auid=0 exe=\"/bin/ls\"\n\
+                 type=PATH msg=audit({0}.000:3): item=0 name=\"/b\"\n\
+                 type=EOE msg=audit({0}.000:3): \n",
+                now
+            ))?;
+
+        let found = log.poll()?;
+        assert_eq!(
+            found,
+            vec![
+                Audited {
+                    pid: Some(42),
+                    resp: "2".into(),
+                    syscall: "257".into(),
+                    auid: "1000".into(),
+                    exe: "/bin/cat".into(),
+                    path: Some("/a".into()),
+                },
+                Audited {
+                    pid: Some(43),
+                    resp: "1".into(),
+                    syscall: "257".into(),
+                    auid: "0".into(),
+                    exe: "/bin/ls".into(),
+                    path: Some("/b".into()),
+                },
+            ]
+        );
+        assert!(log.seen.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use crate::enrich::sha256;
+use crate::write_path;
+
+/// Walks a tree and writes an EXISTS record for everything in it but
+/// directories, so the events that follow have something to apply to.
+pub struct Baseline {
+    pub recursive: bool,
+    /// don't cross into other filesystems
+    pub one_dev: bool,
+    pub hash: bool,
+    /// write the paths as they are, like --raw-paths
+    pub raw_paths: bool,
+}
+
+impl Baseline {
+    fn record(&self, path: &Path, m: &fs::Metadata) -> io::Result<Vec<u8>> {
+        let mut record = Vec::new();
+        record.write_fmt(format_args!("EXISTS\t{}\t{}\t", m.len(), m.mtime()))?;
+        write_path(&mut record, path, self.raw_paths)?;
+        if self.hash && m.is_file() {
+            record.write_fmt(format_args!("\tsha256={}", sha256(&File::open(path)?)?))?;
+        }
+        record.push(b'\n');
+        Ok(record)
+    }
+
+    /// Scan under `root`, returns how many records were emitted.
+    pub fn scan(
+        &self,
+        root: &Path,
+        emit: &mut dyn FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<u64> {
+        let dev = fs::metadata(root)?.dev();
+        let mut n = 0;
+        let mut dirs: Vec<PathBuf> = vec![root.into()];
+
+        while let Some(dir) = dirs.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    warn!("{:?}: {}", dir, e);
+                    continue;
+                }
+            };
+
+            for entry in entries {
+                let path = match entry {
+                    Ok(entry) => entry.path(),
+                    Err(e) => {
+                        warn!("{:?}: {}", dir, e);
+                        continue;
+                    }
+                };
+                // doesn't follow symlinks
+                let m = match fs::symlink_metadata(&path) {
+                    Ok(m) => m,
+                    // gone already
+                    Err(ref e) if e.kind() == ErrorKind::NotFound => continue,
+                    Err(e) => {
+                        warn!("{:?}: {}", path, e);
+                        continue;
+                    }
+                };
+
+                if m.is_dir() {
+                    if self.recursive && (!self.one_dev || m.dev() == dev) {
+                        dirs.push(path);
+                    }
+                    continue;
+                }
+
+                match self.record(&path, &m) {
+                    Ok(record) => {
+                        emit(&record)?;
+                        n += 1;
+                    }
+                    Err(e) => warn!("{:?}: {}", path, e),
+                }
+            }
+        }
+        Ok(n)
+    }
+
+    /// Scan under each of `roots` on a thread of its own, so the events
+    /// that come in meanwhile, its own opens included, are still answered.
+    pub fn spawn(self, roots: Vec<PathBuf>) -> io::Result<Scan> {
+        let (tx, records) = mpsc::channel();
+        let (wake, mut wake_tx) = UnixStream::pair()?;
+        wake.set_nonblocking(true)?;
+        wake_tx.set_nonblocking(true)?;
+        thread::Builder::new()
+            .name("baseline".into())
+            .spawn(move || {
+                for root in roots {
+                    let res = self.scan(&root, &mut |record| {
+                        tx.send(record.to_vec())
+                            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))?;
+                        // it's nonblocking, and a full buffer already wakes it
+                        let _ = wake_tx.write(&[0]);
+                        Ok(())
+                    });
+                    match res {
+                        Ok(n) => info!("baseline of {:?}: {} entries", root, n),
+                        Err(e) => warn!("baseline of {:?}: {}", root, e),
+                    }
+                }
+            })?;
+        Ok(Scan { records, wake })
+    }
+}
+
+/// A baseline scan running on its own thread.
+pub struct Scan {
+    records: Receiver<Vec<u8>>,
+    wake: UnixStream,
+}
+
+impl Scan {
+    /// The records scanned since the last call, and whether that's all.
+    pub fn read(&mut self) -> (Vec<Vec<u8>>, bool) {
+        let mut buf = [0; 4096];
+        while let Ok(n) = (&self.wake).read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+
+        let mut records = Vec::new();
+        loop {
+            match self.records.try_recv() {
+                Ok(record) => records.push(record),
+                Err(TryRecvError::Empty) => return (records, false),
+                Err(TryRecvError::Disconnected) => return (records, true),
+            }
+        }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.wake.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+
+    #[test]
+    fn baseline_scan() -> io::Result<()> {
+        let dir = TempDir::new("baseline")?;
+        fs::create_dir_all(dir.join("sub"))?;
+        fs::write(dir.join("a"), "hello")?;
+        fs::write(dir.join("sub/b"), "")?;
+
+        let scan = |recursive| -> io::Result<Vec<String>> {
+            let mut out = Vec::new();
+            Baseline {
+                recursive,
+                one_dev: true,
+                hash: true,
+                raw_paths: false,
+            }
+            .scan(&dir, &mut |r| {
+                out.push(String::from_utf8(r.to_vec()).unwrap());
+                Ok(())
+            })?;
+            out.sort();
+            Ok(out)
+        };
+
+        let mtime = fs::metadata(dir.join("a"))?.mtime();
+        let a = format!(
+            "EXISTS\t5\t{}\t{}/a\tsha256=\
+             2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824\n",
+            mtime,
+            dir.display()
+        );
+        assert_eq!(scan(false)?, vec![a.clone()]);
+        assert_eq!(scan(true)?.len(), 2);
+
+        let mut scan = Baseline {
+            recursive: true,
+            one_dev: true,
+            hash: false,
+            raw_paths: false,
+        }
+        .spawn(vec![dir.to_path_buf(), dir.join("sub")])?;
+        let mut records = Vec::new();
+        loop {
+            let mut pfd = libc::pollfd {
+                fd: scan.fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            assert_eq!(unsafe { libc::poll(&mut pfd, 1, 10000) }, 1);
+            let (got, done) = scan.read();
+            records.extend(got);
+            if done {
+                break;
+            }
+        }
+        assert_eq!(records.len(), 3);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use libc::c_int;
+
+use crate::stats::Stats;
+use crate::webhook::Webhook;
+use crate::{fanotify_mark, ignore, policy, write_escaped, EventEntry};
+
+/// Paths nothing should ever touch, like a decoy ~/.aws/credentials, whose
+/// every event is an alert.
+pub struct Canaries {
+    paths: Vec<CString>,
+    webhook: Option<Webhook>,
+}
+
+impl Canaries {
+    pub fn new(paths: Vec<CString>, webhook: Option<&str>) -> io::Result<Canaries> {
+        Ok(Canaries {
+            paths,
+            webhook: match webhook {
+                // one at a time, as soon as they happen
+                Some(url) => Some(Webhook::new(url, Vec::new(), 1, 0)?),
+                None => None,
+            },
+        })
+    }
+
+    /// Watch each of them for `mask`, and what's in them, whatever else is
+    /// watched.
+    pub fn mark(&self, notify_fd: c_int, dirfd: c_int, mask: u64) -> io::Result<()> {
+        for path in &self.paths {
+            fanotify_mark(
+                notify_fd,
+                libc::FAN_MARK_ADD,
+                mask | libc::FAN_EVENT_ON_CHILD,
+                dirfd,
+                path.as_ptr(),
+            )
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("--canary {}: {}", path.to_string_lossy(), e),
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Whether `path` is one of them, or under one.
+    pub fn hit(&self, path: &Path) -> bool {
+        ignore::ignored(&self.paths, path)
+    }
+
+    /// Raise the alarm about `entry`.
+    pub fn alert(&mut self, entry: &EventEntry, stats: &mut Stats) -> io::Result<()> {
+        stats.canary_hits += 1;
+        let mut path = Vec::new();
+        if let Some(p) = &entry.path {
+            write_escaped(&mut path, p.as_os_str().as_bytes())?;
+        }
+        error!(
+            "canary {} touched: {} by {} (pid {})",
+            String::from_utf8_lossy(&path),
+            entry.mask_names(),
+            policy::comm(entry).as_deref().unwrap_or("?"),
+            EventEntry::display_field(&entry.pid)
+        );
+        if let Some(webhook) = &mut self.webhook {
+            webhook.record(entry, stats);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canary_hits() -> io::Result<()> {
+        let mut canaries = Canaries::new(
+            vec![
+                CString::new("/home/a/.aws/credentials").unwrap(),
+                CString::new("/srv/decoy").unwrap(),
+            ],
+            None,
+        )?;
+        assert!(canaries.hit(Path::new("/home/a/.aws/credentials")));
+        assert!(canaries.hit(Path::new("/srv/decoy/invoices/2024.xlsx")));
+        assert!(!canaries.hit(Path::new("/home/a/.aws/config")));
+        assert!(!canaries.hit(Path::new("/srv/decoys")));
+
+        let mut stats = Stats::default();
+        let entry = EventEntry {
+            pid: Some(42),
+            fields: vec![("comm", "cat".into())],
+            ..EventEntry::test(libc::FAN_OPEN, "/srv/decoy/x")
+        };
+        canaries.alert(&entry, &mut stats)?;
+        assert_eq!(stats.canary_hits, 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::color::{self, Class};
+use crate::{EventEntry, EventFormat};
+
+const VENDOR: &str = "fanotify-cli";
+const PRODUCT: &str = "fanotify-cli";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// --enrich fields with a CEF key of their own, the rest go in the custom
+/// string fields.
+const CEF_KEYS: &[(&str, &str)] = &[
+    ("comm", "sproc"),
+    ("size", "fsize"),
+    ("ino", "fileId"),
+    ("mode", "filePermission"),
+    ("sha256", "fileHash"),
+];
+// cs1 to cs6
+const CEF_CUSTOM_STRINGS: usize = 6;
+
+fn severity(mask: u64) -> u8 {
+    match color::class(mask) {
+        Some(Class::Perm) => 5,
+        Some(Class::Write) => 3,
+        Some(Class::Read) | None => 1,
+    }
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+fn escape(s: &str, special: &[char]) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c == '\\' || special.contains(&c) => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn header(s: &str) -> String {
+    escape(s, &['|'])
+}
+
+fn cef_value(s: &str) -> String {
+    escape(s, &['='])
+}
+
+/// Writes events as ArcSight CEF, the mask names as the signature.
+pub struct Cef;
+
+impl EventFormat for Cef {
+    fn write_event(&self, entry: &EventEntry, w: &mut dyn Write) -> io::Result<()> {
+        let names = entry.mask_names();
+        w.write_fmt(format_args!(
+            "CEF:0|{}|{}|{}|{}|{}|{}|rt={}",
+            header(VENDOR),
+            header(PRODUCT),
+            header(VERSION),
+            header(&names),
+            header(&names),
+            severity(entry.mask),
+            now_ms()
+        ))?;
+        if let Some(pid) = entry.pid {
+            w.write_fmt(format_args!(" spid={}", pid))?;
+        }
+        if let Some(path) = &entry.path {
+            w.write_fmt(format_args!(
+                " filePath={}",
+                cef_value(&path.to_string_lossy())
+            ))?;
+            if let Some(name) = path.file_name() {
+                w.write_fmt(format_args!(
+                    " fname={}",
+                    cef_value(&name.to_string_lossy())
+                ))?;
+            }
+        }
+
+        let mut custom = 0;
+        for (k, v) in &entry.fields {
+            match CEF_KEYS.iter().find(|(name, _)| name == k) {
+                Some((_, key)) => w.write_fmt(format_args!(" {}={}", key, cef_value(v)))?,
+                None if custom < CEF_CUSTOM_STRINGS => {
+                    custom += 1;
+                    w.write_fmt(format_args!(
+                        " cs{}={} cs{}Label={}",
+                        custom,
+                        cef_value(v),
+                        custom,
+                        k
+                    ))?;
+                }
+                None => debug!("no CEF field left for {}", k),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes events as IBM QRadar LEEF 1.0, the mask names as the event id
+/// and every field under its own name.
+pub struct Leef;
+
+impl EventFormat for Leef {
+    fn write_event(&self, entry: &EventEntry, w: &mut dyn Write) -> io::Result<()> {
+        let names = entry.mask_names();
+        w.write_fmt(format_args!(
+            "LEEF:1.0|{}|{}|{}|{}|devTime={}\tcat={}\tsev={}",
+            header(VENDOR),
+            header(PRODUCT),
+            header(VERSION),
+            header(&names),
+            now_ms(),
+            escape(&names, &[]),
+            severity(entry.mask)
+        ))?;
+        if let Some(pid) = entry.pid {
+            w.write_fmt(format_args!("\tpid={}", pid))?;
+        }
+        if let Some(path) = &entry.path {
+            w.write_fmt(format_args!(
+                "\tfilePath={}",
+                escape(&path.to_string_lossy(), &[])
+            ))?;
+        }
+        for (k, v) in &entry.fields {
+            w.write_fmt(format_args!("\t{}={}", k, escape(v, &[])))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> EventEntry {
+        EventEntry {
+            fd: Some(3),
+            pid: Some(42),
+            fields: vec![("comm", "vi".into()), ("exe", "/usr/bin/vi".into())],
+            ..EventEntry::test(libc::FAN_CLOSE_WRITE, "/etc/a=b|c\\d")
+        }
+    }
+
+    #[test]
+    fn cef_escaping() {
+        let mut buf = Vec::new();
+        Cef.write_event(&entry(), &mut buf).unwrap();
+        let line = String::from_utf8(buf).unwrap();
+        let (head, ext) = line.split_at(line.find("rt=").unwrap());
+        assert_eq!(
+            head,
+            format!(
+                "CEF:0|fanotify-cli|fanotify-cli|{}|FAN_CLOSE_WRITE|FAN_CLOSE_WRITE|3|",
+                VERSION
+            )
+        );
+        assert!(ext.ends_with(
+            " spid=42 filePath=/etc/a\\=b|c\\\\d fname=a\\=b|c\\\\d sproc=vi \
+             cs1=/usr/bin/vi cs1Label=exe"
+        ));
+    }
+
+    #[test]
+    fn leef_escaping() {
+        let mut buf = Vec::new();
+        Leef.write_event(&entry(), &mut buf).unwrap();
+        let line = String::from_utf8(buf).unwrap();
+        assert!(line.starts_with(&format!(
+            "LEEF:1.0|fanotify-cli|fanotify-cli|{}|FAN_CLOSE_WRITE|devTime=",
+            VERSION
+        )));
+        assert!(line.ends_with(
+            "\tcat=FAN_CLOSE_WRITE\tsev=3\tpid=42\tfilePath=/etc/a=b|c\\\\d\
+             \tcomm=vi\texe=/usr/bin/vi"
+        ));
+    }
+}
@@ -0,0 +1,383 @@
+use std::ffi::OsStr;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Instant;
+
+use sha2::{Digest, Sha256};
+
+use crate::sink::Sink;
+use crate::stats::Stats;
+
+type Hash = [u8; 32];
+
+/// The record a chain starts with when it can't carry on from the one
+/// already in the file, and from which verify starts over.
+const CHAIN_START: &[u8] = b"CHAIN_START";
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        write!(s, "{:02x}", b).unwrap();
+    }
+    s
+}
+
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn chain_hash(prev: &Hash, body: &[u8]) -> Hash {
+    let mut h = Sha256::new();
+    h.update(prev);
+    h.update(body);
+    h.finalize().into()
+}
+
+/// Append `body` to `out` chained from `prev`, returning its hash.
+fn append(out: &mut Vec<u8>, prev: &Hash, body: &[u8]) -> Hash {
+    let hash = chain_hash(prev, body);
+    out.extend_from_slice(body);
+    out.push(b'\t');
+    out.extend_from_slice(to_hex(&hash).as_bytes());
+    out.push(b'\n');
+    hash
+}
+
+/// The hash the file at `path` ends with, if it's a chain, to carry on from.
+fn last_hash(path: &Path) -> io::Result<Option<Hash>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let len = file.metadata()?.len();
+    // a tab, the hash and the newline
+    let mut tail = [0; 66];
+    if len < tail.len() as u64 {
+        return Ok(None);
+    }
+    file.read_exact_at(&mut tail, len - tail.len() as u64)?;
+    match (tail[0], tail[65], std::str::from_utf8(&tail[1..65])) {
+        (b'\t', b'\n', Ok(hex)) => Ok(from_hex(hex).map(|h| {
+            let mut hash = [0; 32];
+            hash.copy_from_slice(&h);
+            hash
+        })),
+        _ => Ok(None),
+    }
+}
+
+/// The hash the file `inner` appends to ends with, to carry on from, or
+/// None to start a new chain.
+fn carry_on(inner: &dyn Sink) -> Option<Hash> {
+    let path = inner.path()?;
+    match last_hash(path) {
+        Ok(Some(prev)) => Some(prev),
+        Ok(None) => {
+            info!("--hash-chain: starting a new chain in {:?}", path);
+            None
+        }
+        Err(e) => {
+            warn!("--hash-chain: {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Appends to each record a hash over the record and the previous hash, so
+/// removing or editing a record breaks every hash after it. Every
+/// `checkpoint_every` records, `checkpoint_cmd` is given the current hash on
+/// stdin and whatever it prints (a signature, presumably) is logged as a
+/// CHECKPOINT record. Across restarts it carries on from the hash the file
+/// ends with, elsewhere, and in each new file it's rotated to, it starts
+/// over with CHAIN_START.
+pub struct ChainSink {
+    inner: Box<dyn Sink>,
+    prev: Hash,
+    /// not carrying on from the file, so CHAIN_START goes first
+    start: bool,
+    count: u64,
+    checkpoint_cmd: Option<String>,
+    checkpoint_every: u64,
+}
+
+impl ChainSink {
+    pub fn new(
+        inner: Box<dyn Sink>,
+        checkpoint_cmd: Option<String>,
+        checkpoint_every: u64,
+    ) -> ChainSink {
+        let prev = carry_on(&*inner);
+        ChainSink {
+            inner,
+            start: prev.is_none(),
+            prev: prev.unwrap_or([0; 32]),
+            count: 0,
+            checkpoint_cmd,
+            checkpoint_every,
+        }
+    }
+
+    /// Send `body` chained, after a CHAIN_START if it's the first in the
+    /// file.
+    fn send_chained(&mut self, body: &[u8], stats: &mut Stats) -> io::Result<()> {
+        // a tab, the hash and the newline after each
+        let mut len = body.len() + 66;
+        if self.start {
+            len += CHAIN_START.len() + 66;
+        }
+        if self.inner.roll(len as u64)? {
+            match carry_on(&*self.inner) {
+                Some(prev) => {
+                    self.prev = prev;
+                    self.start = false;
+                }
+                None => self.start = true,
+            }
+        }
+
+        let mut record = Vec::with_capacity(len + CHAIN_START.len() + 66);
+        let mut prev = self.prev;
+        if self.start {
+            prev = append(&mut record, &[0; 32], CHAIN_START);
+        }
+        prev = append(&mut record, &prev, body);
+        // together, so a rotation can't come between them
+        self.inner.send(&record, stats)?;
+        self.prev = prev;
+        self.start = false;
+        Ok(())
+    }
+
+    fn checkpoint(&mut self, stats: &mut Stats) -> io::Result<()> {
+        let cmd = match &self.checkpoint_cmd {
+            Some(cmd) => cmd,
+            None => return Ok(()),
+        };
+
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(format!("{}\n", to_hex(&self.prev)).as_bytes())?;
+        let out = child.wait_with_output()?;
+        if !out.status.success() {
+            return Err(io::Error::other(format!(
+                "checkpoint command failed: {}",
+                out.status
+            )));
+        }
+
+        let sig = String::from_utf8_lossy(&out.stdout);
+        let body = format!("CHECKPOINT\t{}\t{}", self.count, sig.trim());
+        self.send_chained(body.as_bytes(), stats)
+    }
+}
+
+impl Sink for ChainSink {
+    fn send(&mut self, record: &[u8], stats: &mut Stats) -> io::Result<()> {
+        let body = record.strip_suffix(b"\n").unwrap_or(record);
+        self.send_chained(body, stats)?;
+
+        self.count += 1;
+        if self.checkpoint_every != 0 && self.count.is_multiple_of(self.checkpoint_every) {
+            if let Err(e) = self.checkpoint(stats) {
+                error!("checkpoint: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.inner.deadline()
+    }
+
+    fn tick(&mut self, stats: &mut Stats) -> io::Result<()> {
+        self.inner.tick(stats)
+    }
+
+    fn fd(&self) -> Option<RawFd> {
+        self.inner.fd()
+    }
+
+    fn path(&self) -> Option<&Path> {
+        self.inner.path()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync()
+    }
+}
+
+/// Whether `verify_cmd` takes `sig` for a signature of `hash`, given the
+/// hash on stdin as --checkpoint-cmd was and the signature in
+/// $FANOTIFY_SIGNATURE.
+fn verify_checkpoint(verify_cmd: &str, hash: &Hash, sig: &[u8]) -> io::Result<bool> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(verify_cmd)
+        .env("FANOTIFY_SIGNATURE", OsStr::from_bytes(sig))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(format!("{}\n", to_hex(hash)).as_bytes())?;
+    Ok(child.wait()?.success())
+}
+
+/// Check the hash chain of a log written with --hash-chain, and with
+/// `verify_cmd` the signature of each CHECKPOINT, returning the number of
+/// records verified.
+pub fn verify(path: &Path, verify_cmd: Option<&str>) -> io::Result<u64> {
+    let mut prev = [0; 32];
+    let mut n = 0;
+
+    for line in BufReader::new(File::open(path)?).split(b'\n') {
+        let line = line?;
+        n += 1;
+
+        let bad = |what: &str| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{:?}:{}: {}", path, n, what),
+            )
+        };
+        let tab = line
+            .iter()
+            .rposition(|b| *b == b'\t')
+            .ok_or_else(|| bad("missing hash"))?;
+        let (body, hash) = (&line[..tab], &line[tab + 1..]);
+
+        if body == CHAIN_START {
+            // only ever the first, records before it could be anything
+            if n != 1 {
+                return Err(bad("chain started over"));
+            }
+            prev = [0; 32];
+        }
+        if let (Some(cmd), Some(rest)) = (verify_cmd, body.strip_prefix(b"CHECKPOINT\t")) {
+            // the count, then the signature of the hash before it
+            let sig = rest.splitn(2, |b| *b == b'\t').nth(1).unwrap_or_default();
+            if !verify_checkpoint(cmd, &prev, sig)? {
+                return Err(bad("bad checkpoint signature"));
+            }
+        }
+        prev = chain_hash(&prev, body);
+        if to_hex(&prev).as_bytes() != hash {
+            return Err(bad("hash mismatch"));
+        }
+    }
+
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logfile::{BySize, FileSink};
+    use crate::TempDir;
+    use std::cell::RefCell;
+    use std::fs;
+    use std::rc::Rc;
+
+    struct VecSink(Rc<RefCell<Vec<u8>>>);
+
+    impl Sink for VecSink {
+        fn send(&mut self, record: &[u8], _stats: &mut Stats) -> io::Result<()> {
+            self.0.borrow_mut().extend_from_slice(record);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn chain_verify() -> io::Result<()> {
+        let dir = TempDir::new("chain")?;
+        let path = dir.join("log");
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut stats = Stats::default();
+        let mut chain = ChainSink::new(Box::new(VecSink(log.clone())), Some("cat".into()), 2);
+        chain.send(b"FAN_OPEN\t3\t1\t/a\n", &mut stats)?;
+        chain.send(b"FAN_CLOSE_NOWRITE\t3\t1\t/a\n", &mut stats)?;
+        chain.send(b"FAN_OPEN\t3\t1\t/b\n", &mut stats)?;
+
+        let log = String::from_utf8(log.borrow().clone()).unwrap();
+        assert_eq!(log.lines().count(), 5);
+        assert!(log.starts_with("CHAIN_START\t"));
+        assert!(log.lines().nth(3).unwrap().starts_with("CHECKPOINT\t2\t"));
+        assert!(log.ends_with(&format!("\t{}\n", to_hex(&chain.prev))));
+
+        fs::write(&path, &log)?;
+        assert_eq!(verify(&path, None)?, 5);
+        // cat signed it with the hash itself
+        let same = r#"[ "$FANOTIFY_SIGNATURE" = "$(cat)" ]"#;
+        assert_eq!(verify(&path, Some(same))?, 5);
+        assert!(verify(&path, Some("false")).is_err());
+
+        fs::write(&path, log.replace("/b", "/c"))?;
+        assert!(verify(&path, None).is_err());
+        fs::remove_file(&path)?;
+
+        // carries on from what's in the file after a restart
+        for _ in 0..2 {
+            let file = FileSink::new(path.clone(), None, None)?;
+            let mut chain = ChainSink::new(Box::new(file), None, 0);
+            chain.send(b"FAN_OPEN\t3\t1\t/a\n", &mut stats)?;
+        }
+        let log = fs::read_to_string(&path)?;
+        assert_eq!(log.matches("CHAIN_START").count(), 1);
+        assert_eq!(verify(&path, None)?, 3);
+
+        // a chain started over partway through hides what came before
+        fs::write(&path, log.clone() + &log)?;
+        assert!(verify(&path, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn chain_rotated() -> io::Result<()> {
+        let dir = TempDir::new("chain-rotated")?;
+        let path = dir.join("log");
+        let by_size = BySize {
+            size: 250,
+            keep: 2,
+            compress: false,
+        };
+        let file = FileSink::new(path.clone(), None, Some(by_size))?;
+        let mut chain = ChainSink::new(Box::new(file), None, 0);
+        let mut stats = Stats::default();
+        // a CHAIN_START and two records fill the first file
+        for p in &["/a", "/b", "/c"] {
+            chain.send(format!("FAN_OPEN\t3\t1\t{}\n", p).as_bytes(), &mut stats)?;
+        }
+
+        let rotated = dir.join("log.1");
+        assert_eq!(verify(&rotated, None)?, 3);
+        assert!(fs::read_to_string(&rotated)?.starts_with("CHAIN_START\t"));
+        let log = fs::read_to_string(&path)?;
+        assert!(log.starts_with("CHAIN_START\t"));
+        assert!(log.contains("\t/c\t"));
+        assert_eq!(verify(&path, None)?, 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+use std::ffi::{CStr, CString};
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const TIME_FORMATS: &[&str] = &[
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%d %H:%M",
+];
+
+/// Parse a local time like 2020-04-01T13:00[:00], or @SECONDS since the
+/// epoch.
+pub fn parse_time(s: &str) -> io::Result<SystemTime> {
+    let invalid = || {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{}: expected YYYY-MM-DDTHH:MM[:SS] or @EPOCH", s),
+        )
+    };
+
+    if let Some(secs) = s.strip_prefix('@') {
+        let secs = secs.parse::<u64>().map_err(|_| invalid())?;
+        return Ok(UNIX_EPOCH + Duration::from_secs(secs));
+    }
+
+    let cs = CString::new(s).map_err(|_| invalid())?;
+    for fmt in TIME_FORMATS {
+        let cfmt = CString::new(*fmt).unwrap();
+        let mut tm: libc::tm = unsafe { mem::zeroed() };
+        let end = unsafe { libc::strptime(cs.as_ptr(), cfmt.as_ptr(), &mut tm) };
+        if end.is_null() || unsafe { *end } != 0 {
+            continue;
+        }
+
+        // let mktime figure out daylight saving
+        tm.tm_isdst = -1;
+        let t = unsafe { libc::mktime(&mut tm) };
+        if t < 0 {
+            return Err(invalid());
+        }
+        return Ok(UNIX_EPOCH + Duration::from_secs(t as u64));
+    }
+
+    Err(invalid())
+}
+
+/// Parse a duration like 90, 90s, 10m, 2h or 1d.
+pub fn parse_duration(s: &str) -> io::Result<Duration> {
+    let invalid = || {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{}: expected a number of seconds, or minutes, hours or days like 10m, 2h or 1d",
+                s
+            ),
+        )
+    };
+
+    let (n, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    n.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(secs))
+        .map(Duration::from_secs)
+        .ok_or_else(invalid)
+}
+
+/// Format `t` as local time with strftime(3).
+pub fn strftime(t: SystemTime, fmt: &str) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    unsafe { libc::localtime_r(&secs, &mut tm) };
+
+    let cfmt = CString::new(fmt).unwrap_or_default();
+    let mut buf = [0 as libc::c_char; 128];
+    let n = unsafe { libc::strftime(buf.as_mut_ptr(), buf.len(), cfmt.as_ptr(), &tm) };
+    if n == 0 {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr(buf.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// How --timestamp prints the time an event was read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timestamp {
+    Iso8601,
+    Epoch,
+    EpochMs,
+    Monotonic,
+}
+
+impl FromStr for Timestamp {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "iso8601" => Ok(Timestamp::Iso8601),
+            "epoch" => Ok(Timestamp::Epoch),
+            "epoch-ms" => Ok(Timestamp::EpochMs),
+            "monotonic" => Ok(Timestamp::Monotonic),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "invalid value: {}, options: iso8601, epoch, epoch-ms, monotonic",
+                    s
+                ),
+            )),
+        }
+    }
+}
+
+fn monotonic() -> Duration {
+    let mut ts: libc::timespec = unsafe { mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+impl Timestamp {
+    /// The time now.
+    pub fn now(self) -> String {
+        self.format(SystemTime::now(), monotonic())
+    }
+
+    pub fn format(self, t: SystemTime, mono: Duration) -> String {
+        let epoch = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+        match self {
+            Timestamp::Iso8601 => format!(
+                "{}.{:03}{}",
+                strftime(t, "%Y-%m-%dT%H:%M:%S"),
+                epoch.subsec_millis(),
+                strftime(t, "%z")
+            ),
+            Timestamp::Epoch => format!("{}.{:06}", epoch.as_secs(), epoch.subsec_micros()),
+            Timestamp::EpochMs => epoch.as_millis().to_string(),
+            // same clock as CLOCK_MONOTONIC in other logs, like the kernel's
+            Timestamp::Monotonic => format!("{}.{:06}", mono.as_secs(), mono.subsec_micros()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_epoch() {
+        assert_eq!(
+            parse_time("@100").unwrap(),
+            UNIX_EPOCH + Duration::from_secs(100)
+        );
+        assert!(parse_time("@x").is_err());
+    }
+
+    #[test]
+    fn parse_local() {
+        let t = parse_time("2020-04-01T13:05:06").unwrap();
+        assert_eq!(strftime(t, "%Y-%m-%dT%H:%M:%S"), "2020-04-01T13:05:06");
+        let t = parse_time("2020-04-01 13:05").unwrap();
+        assert_eq!(strftime(t, "%Y-%m-%dT%H:%M:%S"), "2020-04-01T13:05:00");
+        assert!(parse_time("2020-04-01T13:05:06 junk").is_err());
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("10m").unwrap(), Duration::from_secs(600));
+        assert_eq!(parse_duration("1d").unwrap(), Duration::from_secs(86400));
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("10w").is_err());
+        assert!(parse_duration("-1s").is_err());
+    }
+
+    #[test]
+    fn timestamp_formats() {
+        let t = UNIX_EPOCH + Duration::from_millis(1_500_000_000_250);
+        let mono = Duration::from_micros(12_000_034);
+        assert_eq!(Timestamp::Epoch.format(t, mono), "1500000000.250000");
+        assert_eq!(Timestamp::EpochMs.format(t, mono), "1500000000250");
+        assert_eq!(Timestamp::Monotonic.format(t, mono), "12.000034");
+        let iso = Timestamp::Iso8601.format(t, mono);
+        assert!(iso.contains(".250"), "{}", iso);
+        assert_eq!(
+            parse_time(&iso[..19]).unwrap(),
+            UNIX_EPOCH + Duration::from_secs(1_500_000_000)
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use std::io::{self, ErrorKind, Write};
+use std::str::FromStr;
+
+use crate::{EventEntry, EventFormat};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for Color {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Color::Auto),
+            "always" => Ok(Color::Always),
+            "never" => Ok(Color::Never),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid value: {}, options: auto, always, never", s),
+            )),
+        }
+    }
+}
+
+impl Color {
+    /// Whether to color events that go to stdout, or elsewhere if not
+    /// `stdout`.
+    pub fn enabled(self, stdout: bool) -> bool {
+        match self {
+            Color::Always => true,
+            Color::Never => false,
+            Color::Auto => {
+                stdout
+                    && std::env::var_os("NO_COLOR").is_none()
+                    && unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1
+            }
+        }
+    }
+}
+
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const BOLD_YELLOW: &str = "\x1b[1;33m";
+
+const PERM_EVENTS: u64 = libc::FAN_OPEN_PERM | libc::FAN_ACCESS_PERM | libc::FAN_OPEN_EXEC_PERM;
+const WRITE_EVENTS: u64 = libc::FAN_MODIFY
+    | libc::FAN_CLOSE_WRITE
+    | libc::FAN_ATTRIB
+    | libc::FAN_CREATE
+    | libc::FAN_DELETE
+    | libc::FAN_DELETE_SELF
+    | libc::FAN_MOVED_FROM
+    | libc::FAN_MOVED_TO
+    | libc::FAN_MOVE_SELF
+    | libc::FAN_RENAME;
+const READ_EVENTS: u64 =
+    libc::FAN_ACCESS | libc::FAN_OPEN | libc::FAN_OPEN_EXEC | libc::FAN_CLOSE_NOWRITE;
+
+/// What kind of access an event is about.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Class {
+    Perm,
+    Write,
+    Read,
+}
+
+/// The most interesting class of event in `mask`.
+pub fn class(mask: u64) -> Option<Class> {
+    if mask & PERM_EVENTS != 0 {
+        Some(Class::Perm)
+    } else if mask & WRITE_EVENTS != 0 {
+        Some(Class::Write)
+    } else if mask & READ_EVENTS != 0 {
+        Some(Class::Read)
+    } else {
+        None
+    }
+}
+
+fn mask_color(mask: u64) -> Option<&'static str> {
+    class(mask).map(|c| match c {
+        Class::Perm => BOLD_YELLOW,
+        Class::Write => RED,
+        Class::Read => GREEN,
+    })
+}
+
+/// Writes events like the default output, with ANSI colors.
+pub struct Colored {
+    pub raw_paths: bool,
+}
+
+impl EventFormat for Colored {
+    fn write_event(&self, entry: &EventEntry, w: &mut dyn Write) -> io::Result<()> {
+        match mask_color(entry.mask) {
+            Some(c) => w.write_fmt(format_args!("{}{}{}\t", c, entry.mask_names(), RESET))?,
+            None => w.write_fmt(format_args!("{}\t", entry.mask_names()))?,
+        }
+        w.write_fmt(format_args!(
+            "{}{}\t{}{}\t",
+            DIM,
+            EventEntry::display_field(&entry.fd),
+            EventEntry::display_field(&entry.pid),
+            RESET,
+        ))?;
+
+        entry.write_path(w, self.raw_paths)?;
+        entry.write_fields(w, self.raw_paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colored_event() {
+        let entry = EventEntry {
+            fd: Some(3),
+            pid: Some(42),
+            fields: vec![("comm", "cat".into())],
+            ..EventEntry::test(libc::FAN_OPEN_PERM | libc::FAN_OPEN, "/a")
+        };
+        let mut buf = Vec::new();
+        Colored { raw_paths: false }
+            .write_event(&entry, &mut buf)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "\x1b[1;33mFAN_OPEN|FAN_OPEN_PERM\x1b[0m\t\x1b[2m3\t42\x1b[0m\t/a\tcomm=cat"
+        );
+
+        assert_eq!(mask_color(libc::FAN_CLOSE_WRITE), Some(RED));
+        assert_eq!(mask_color(libc::FAN_ACCESS), Some(GREEN));
+        assert_eq!(mask_color(libc::FAN_Q_OVERFLOW), None);
+    }
+}
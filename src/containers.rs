@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+// containers to remember, they're forgotten all at once past this
+const CONTAINERS_LEN: usize = 1000;
+const TIMEOUT: Duration = Duration::from_secs(1);
+// how long to go without asking about one that couldn't be looked up
+const RETRY: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+struct Inspect {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "Config")]
+    config: Config,
+}
+
+#[derive(Deserialize)]
+struct Config {
+    #[serde(rename = "Image")]
+    image: String,
+}
+
+/// The name and image of a container.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Container {
+    pub name: String,
+    pub image: String,
+}
+
+enum Lookup {
+    /// waiting on the worker
+    Asked,
+    /// None for the ones the daemon doesn't know
+    Found(Option<Container>),
+    /// couldn't be looked up, not to be asked about again until then
+    Failed(Instant),
+}
+
+/// Looks up containers by ID with the Docker API on a unix socket, which
+/// Podman has too, remembering them. The lookups are done by a worker, so
+/// an event only has its container once it's been looked up.
+pub struct Containers {
+    socket: PathBuf,
+    known: HashMap<String, Lookup>,
+    tx: Sender<String>,
+    replies: Receiver<(String, io::Result<Option<Container>>)>,
+}
+
+/// GET /containers/ID/json, or None if there's no such container.
+fn inspect(socket: &Path, id: &str) -> io::Result<Option<Container>> {
+    let mut conn = UnixStream::connect(socket)?;
+    conn.set_read_timeout(Some(TIMEOUT))?;
+    conn.set_write_timeout(Some(TIMEOUT))?;
+    // 1.0 so the response is neither chunked nor kept alive
+    conn.write_fmt(format_args!(
+        "GET /containers/{}/json HTTP/1.0\r\n\
+         Host: localhost\r\n\
+         User-Agent: fanotify-cli\r\n\r\n",
+        id
+    ))?;
+    let mut resp = Vec::new();
+    conn.read_to_end(&mut resp)?;
+
+    let bad =
+        |what: &str| io::Error::new(ErrorKind::InvalidData, format!("bad response: {}", what));
+    let split = resp
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| bad("no end of headers"))?;
+    let head = String::from_utf8_lossy(&resp[..split]);
+    let status = head.split_whitespace().nth(1).ok_or_else(|| bad(&head))?;
+    match status {
+        "200" => {
+            let inspect: Inspect = serde_json::from_slice(&resp[split + 4..])
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            Ok(Some(Container {
+                name: inspect.name.trim_start_matches('/').into(),
+                image: inspect.config.image,
+            }))
+        }
+        "404" => Ok(None),
+        _ => Err(bad(head.lines().next().unwrap_or_default())),
+    }
+}
+
+impl Containers {
+    pub fn new(socket: &Path) -> io::Result<Containers> {
+        let (tx, rx) = mpsc::channel::<String>();
+        let (replies_tx, replies) = mpsc::channel();
+        let from = socket.to_path_buf();
+        thread::Builder::new()
+            .name("containers".into())
+            .spawn(move || {
+                for id in rx {
+                    let container = inspect(&from, &id);
+                    if replies_tx.send((id, container)).is_err() {
+                        break;
+                    }
+                }
+            })?;
+        Ok(Containers {
+            socket: socket.into(),
+            known: HashMap::new(),
+            tx,
+            replies,
+        })
+    }
+
+    /// The container with the full `id`, if it's been looked up already,
+    /// or else it's asked about for the next time.
+    pub fn lookup(&mut self, id: &str) -> Option<&Container> {
+        let now = Instant::now();
+        while let Ok((id, container)) = self.replies.try_recv() {
+            let lookup = match container {
+                Ok(container) => Lookup::Found(container),
+                Err(e) => {
+                    // maybe the daemon is restarting, try again in a while
+                    debug!("{}: {}: {}", self.socket.display(), id, e);
+                    Lookup::Failed(now + RETRY)
+                }
+            };
+            self.known.insert(id, lookup);
+        }
+
+        let ask = match self.known.get(id) {
+            None => true,
+            Some(Lookup::Failed(until)) => now >= *until,
+            Some(_) => false,
+        };
+        if ask {
+            if self.known.len() >= CONTAINERS_LEN {
+                self.known.clear();
+            }
+            if self.tx.send(id.into()).is_ok() {
+                self.known.insert(id.into(), Lookup::Asked);
+            }
+        }
+        match self.known.get(id) {
+            Some(Lookup::Found(container)) => container.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+    use std::os::unix::net::UnixListener;
+    use std::{fs, thread};
+
+    /// What the lookup of `id` comes to once the worker's done with it.
+    fn settled(containers: &mut Containers, id: &str) -> Option<Container> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let container = containers.lookup(id).cloned();
+            if !matches!(containers.known.get(id), Some(Lookup::Asked)) {
+                return container;
+            }
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn container_lookup() -> io::Result<()> {
+        let dir = TempDir::new("containers")?;
+        let socket = dir.join("docker.sock");
+        let listener = UnixListener::bind(&socket)?;
+        let server = thread::spawn(move || -> io::Result<Vec<String>> {
+            let mut reqs = Vec::new();
+            // the second lookup of each is remembered
+            for _ in 0..2 {
+                let (mut conn, _) = listener.accept()?;
+                let mut req = String::new();
+                while !req.ends_with("\r\n\r\n") {
+                    let mut buf = [0; 1024];
+                    let n = conn.read(&mut buf)?;
+                    if n == 0 {
+                        break;
+                    }
+                    req.push_str(&String::from_utf8_lossy(&buf[..n]));
+                }
+                if req.starts_with("GET /containers/abc/json ") {
+                    conn.write_all(
+                        b"HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n\
+                          {\"Id\":\"abc\",\"Name\":\"/web\",\"Config\":{\"Image\":\"nginx:1.25\"}}",
+                    )?;
+                } else {
+                    conn.write_all(
+                        b"HTTP/1.0 404 Not Found\r\n\r\n{\"message\":\"no such container\"}",
+                    )?;
+                }
+                reqs.push(req);
+            }
+            Ok(reqs)
+        });
+
+        let mut containers = Containers::new(&socket)?;
+        let web = Container {
+            name: "web".into(),
+            image: "nginx:1.25".into(),
+        };
+        assert_eq!(settled(&mut containers, "abc"), Some(web.clone()));
+        assert_eq!(containers.lookup("abc"), Some(&web));
+        assert_eq!(settled(&mut containers, "def"), None);
+        assert_eq!(containers.lookup("def"), None);
+        let reqs = server.join().unwrap()?;
+        assert_eq!(reqs.len(), 2);
+        assert!(reqs[1].starts_with("GET /containers/def/json HTTP/1.0\r\n"));
+
+        // nothing listening is only a miss, and not asked about again
+        // for a while
+        fs::remove_file(&socket)?;
+        assert_eq!(settled(&mut containers, "ghi"), None);
+        assert_eq!(containers.lookup("ghi"), None);
+        assert!(matches!(
+            containers.known.get("ghi"),
+            Some(Lookup::Failed(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::str::FromStr;
+
+use crate::cef::{Cef, Leef};
+use crate::json::Json;
+use crate::{EventEntry, EventFormat};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Tab,
+    Csv,
+    Json,
+    Cef,
+    Leef,
+}
+
+impl FromStr for Format {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tab" => Ok(Format::Tab),
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            "cef" => Ok(Format::Cef),
+            "leef" => Ok(Format::Leef),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid value: {}, options: tab, csv, json, cef, leef", s),
+            )),
+        }
+    }
+}
+
+impl Format {
+    /// How to write events in this format, none for the default one.
+    pub fn event_format(self, fields: &[&'static str]) -> Option<Box<dyn EventFormat>> {
+        match self {
+            Format::Tab => None,
+            Format::Csv => Some(Box::new(Csv::new(fields.to_vec()))),
+            Format::Json => Some(Box::new(Json)),
+            Format::Cef => Some(Box::new(Cef)),
+            Format::Leef => Some(Box::new(Leef)),
+        }
+    }
+}
+
+const COLUMNS: &[&str] = &["mask", "fd", "pid", "path"];
+
+/// Quote `field` if it has to be, RFC 4180 style.
+fn write_field(w: &mut dyn Write, field: &[u8]) -> io::Result<()> {
+    if !field.iter().any(|b| b",\"\r\n".contains(b)) {
+        return w.write_all(field);
+    }
+    w.write_all(b"\"")?;
+    for &b in field {
+        if b == b'"' {
+            w.write_all(b"\"\"")?;
+        } else {
+            w.write_all(&[b])?;
+        }
+    }
+    w.write_all(b"\"")
+}
+
+/// Writes events as CSV, with a column for each field they may have.
+pub struct Csv {
+    fields: Vec<&'static str>,
+}
+
+impl Csv {
+    pub fn new(fields: Vec<&'static str>) -> Csv {
+        Csv { fields }
+    }
+
+    pub fn write_header(&self, w: &mut dyn Write) -> io::Result<()> {
+        let names: Vec<&str> = COLUMNS.iter().chain(&self.fields).cloned().collect();
+        w.write_all(names.join(",").as_bytes())
+    }
+}
+
+impl EventFormat for Csv {
+    /// Empty fields for what the event doesn't have.
+    fn write_event(&self, entry: &EventEntry, w: &mut dyn Write) -> io::Result<()> {
+        fn opt<T: ToString>(v: Option<T>) -> String {
+            v.map(|v| v.to_string()).unwrap_or_default()
+        }
+
+        write_field(w, entry.mask_names().as_bytes())?;
+        w.write_fmt(format_args!(",{},{},", opt(entry.fd), opt(entry.pid)))?;
+        if let Some(path) = &entry.path {
+            write_field(w, path.as_os_str().as_bytes())?;
+        }
+        for name in &self.fields {
+            w.write_all(b",")?;
+            if let Some((_, v)) = entry.fields.iter().find(|(k, _)| k == name) {
+                write_field(w, v.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn separator(&self) -> u8 {
+        b','
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_quoting() -> io::Result<()> {
+        let csv = Csv::new(vec!["comm", "exe"]);
+        let mut buf = vec![];
+        csv.write_header(&mut buf)?;
+        assert_eq!(buf, b"mask,fd,pid,path,comm,exe");
+
+        let mut buf = vec![];
+        csv.write_event(
+            &EventEntry {
+                fd: Some(5),
+                pid: None,
+                fields: vec![("exe", "/bin/cat".into())],
+                ..EventEntry::test(libc::FAN_OPEN | libc::FAN_CLOSE_NOWRITE, "/a,b/\"c\"")
+            },
+            &mut buf,
+        )?;
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "FAN_CLOSE_NOWRITE|FAN_OPEN,5,,\"/a,b/\"\"c\"\"\",,/bin/cat"
+        );
+        Ok(())
+    }
+}
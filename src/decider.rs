@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::listen;
+use crate::policy::{self, Action};
+use crate::{EventEntry, PermId};
+
+/// What --on-decider-failure does when --decider disconnects, or stdin
+/// closes without one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnFailure {
+    /// the permission events it had and would have had
+    Answer(Action),
+    /// after answering the pending ones with --perm-default
+    Exit,
+}
+
+impl FromStr for OnFailure {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(OnFailure::Answer(Action::Allow)),
+            "deny" => Ok(OnFailure::Answer(Action::Deny)),
+            "exit" => Ok(OnFailure::Exit),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid value: {}, options: allow, deny, exit", s),
+            )),
+        }
+    }
+}
+
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize)]
+struct Request<'a> {
+    id: u64,
+    mask: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comm: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct Reply {
+    id: u64,
+    action: Action,
+}
+
+/// Asks a daemon on a unix socket to decide permission events, a line of
+/// JSON each way: `{"id":1,"mask":"FAN_OPEN_PERM","path":"/a","pid":2,
+/// "comm":"cat"}` and `{"id":1,"action":"allow"}`. The ids stand in for
+/// the event fds, which the daemon doesn't need to know about.
+pub struct Decider {
+    path: PathBuf,
+    conn: Option<UnixStream>,
+    /// a reply we've only read part of
+    buf: Vec<u8>,
+    next_id: u64,
+    /// the events waiting for a reply, by id
+    asked: HashMap<u64, PermId>,
+    /// events that couldn't be asked about or answered
+    lost: Vec<PermId>,
+    retry_at: Instant,
+}
+
+impl Decider {
+    pub fn new(url: &str) -> io::Result<Decider> {
+        let path = listen::parse_addr(url)?;
+        let conn = UnixStream::connect(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", url, e)))?;
+        conn.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        Ok(Decider {
+            path,
+            conn: Some(conn),
+            buf: Vec::new(),
+            next_id: 1,
+            asked: HashMap::new(),
+            lost: Vec::new(),
+            retry_at: Instant::now(),
+        })
+    }
+
+    fn disconnect(&mut self, e: io::Error) {
+        if self.conn.take().is_some() {
+            error!("decider {:?}: {}", self.path, e);
+        }
+        self.buf.clear();
+        self.lost.extend(self.asked.drain().map(|(_, perm)| perm));
+        self.retry_at = Instant::now() + RECONNECT_INTERVAL;
+    }
+
+    /// Ask about the permission event `perm`.
+    pub fn ask(&mut self, perm: PermId, entry: &EventEntry) {
+        // the fd was reused, so the last event with it was answered
+        self.asked.retain(|_, (fd, _)| *fd != perm.0);
+
+        let comm = policy::comm(entry);
+        let request = Request {
+            id: self.next_id,
+            mask: entry.mask_names(),
+            path: entry
+                .path
+                .as_ref()
+                .map(|p| p.to_string_lossy().into_owned()),
+            pid: entry.pid,
+            comm: comm.as_deref(),
+        };
+        let mut line = serde_json::to_vec(&request).unwrap();
+        line.push(b'\n');
+
+        match &mut self.conn {
+            Some(conn) => match conn.write_all(&line) {
+                Ok(()) => {
+                    self.asked.insert(self.next_id, perm);
+                    self.next_id += 1;
+                }
+                Err(e) => {
+                    self.lost.push(perm);
+                    self.disconnect(e);
+                }
+            },
+            None => self.lost.push(perm),
+        }
+    }
+
+    /// Read what replies there are, returning the events they decide.
+    pub fn read(&mut self) -> Vec<(PermId, Action)> {
+        let mut decided = Vec::new();
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => return decided,
+        };
+        let mut buf = [0; 65536];
+        let n = match conn.read(&mut buf) {
+            Ok(0) => {
+                self.disconnect(io::Error::new(ErrorKind::UnexpectedEof, "disconnected"));
+                return decided;
+            }
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => return decided,
+            Err(e) => {
+                self.disconnect(e);
+                return decided;
+            }
+        };
+        self.buf.extend_from_slice(&buf[..n]);
+
+        while let Some(i) = self.buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=i).collect();
+            match serde_json::from_slice::<Reply>(&line) {
+                Ok(reply) => match self.asked.remove(&reply.id) {
+                    Some(perm) => decided.push((perm, reply.action)),
+                    None => debug!("decider: no event {}", reply.id),
+                },
+                Err(e) => warn!(
+                    "decider: {}: {}",
+                    String::from_utf8_lossy(&line).trim_end(),
+                    e
+                ),
+            }
+        }
+        decided
+    }
+
+    /// The events to answer without it, because it was unreachable.
+    pub fn take_lost(&mut self) -> Vec<PermId> {
+        std::mem::take(&mut self.lost)
+    }
+
+    /// Forget the events that were answered some other way.
+    pub fn answered(&mut self, pending: &dyn Fn(PermId) -> bool) {
+        self.asked.retain(|_, perm| pending(*perm));
+    }
+
+    pub fn fd(&self) -> Option<RawFd> {
+        self.conn.as_ref().map(|c| c.as_raw_fd())
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.conn {
+            Some(_) => None,
+            None => Some(self.retry_at),
+        }
+    }
+
+    pub fn tick(&mut self) {
+        if self.conn.is_some() || Instant::now() < self.retry_at {
+            return;
+        }
+        match UnixStream::connect(&self.path)
+            .and_then(|c| c.set_write_timeout(Some(WRITE_TIMEOUT)).map(|_| c))
+        {
+            Ok(conn) => {
+                info!("decider {:?}: reconnected", self.path);
+                self.conn = Some(conn);
+            }
+            Err(e) => {
+                debug!("decider {:?}: {}", self.path, e);
+                self.retry_at = Instant::now() + RECONNECT_INTERVAL;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn decider_protocol() -> io::Result<()> {
+        let dir = TempDir::new("decider")?;
+        let path = dir.join("sock");
+        let listener = UnixListener::bind(&path)?;
+        let mut decider = Decider::new(&format!("unix:{}", path.display()))?;
+        let (daemon, _) = listener.accept()?;
+
+        let entry = |path: &str| EventEntry {
+            fd: Some(7),
+            pid: Some(42),
+            fields: vec![("comm", "cat".into())],
+            ..EventEntry::test(libc::FAN_OPEN_PERM, path)
+        };
+        decider.ask((7, 1), &entry("/etc/shadow"));
+        decider.ask((8, 2), &entry("/etc/passwd"));
+
+        let mut lines = BufReader::new(&daemon).lines();
+        assert_eq!(
+            lines.next().unwrap()?,
+            "{\"id\":1,\"mask\":\"FAN_OPEN_PERM\",\"path\":\"/etc/shadow\",\"pid\":42,\
+             \"comm\":\"cat\"}"
+        );
+        assert!(lines.next().unwrap()?.starts_with("{\"id\":2,"));
+
+        // out of order, in pieces, and with one the decider didn't ask
+        (&daemon).write_all(b"{\"id\":2,\"action\":\"allow\"}\n{\"id\":9,\"act")?;
+        assert_eq!(decider.read(), vec![((8, 2), Action::Allow)]);
+        (&daemon).write_all(b"ion\":\"deny\"}\n{\"id\":1,\"action\":\"deny\"}\n")?;
+        assert_eq!(decider.read(), vec![((7, 1), Action::Deny)]);
+
+        // answered by stdin, so a late reply is for nothing
+        decider.ask((7, 3), &entry("/tmp/w"));
+        decider.answered(&|_| false);
+        (&daemon).write_all(b"{\"id\":3,\"action\":\"deny\"}\n")?;
+        assert!(decider.read().is_empty());
+
+        decider.ask((9, 4), &entry("/tmp/x"));
+        drop(lines);
+        drop(daemon);
+        assert!(decider.read().is_empty());
+        assert!(decider.fd().is_none());
+        assert_eq!(decider.take_lost(), vec![(9, 4)]);
+        decider.ask((10, 5), &entry("/tmp/y"));
+        assert_eq!(decider.take_lost(), vec![(10, 5)]);
+
+        assert_eq!(
+            "deny".parse::<OnFailure>()?,
+            OnFailure::Answer(Action::Deny)
+        );
+        assert_eq!("exit".parse::<OnFailure>()?, OnFailure::Exit);
+        assert!("retry".parse::<OnFailure>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,389 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection};
+
+use crate::enrich::sha256;
+use crate::policy::Action;
+use crate::EventEntry;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS decisions (
+        -- fnmatch pattern of the paths it's for, as bytes
+        path BLOB NOT NULL,
+        -- sha256 of the executable it's for, empty for any
+        exe_sha256 TEXT NOT NULL DEFAULT '',
+        action TEXT NOT NULL CHECK (action IN ('allow', 'deny')),
+        -- milliseconds since the epoch
+        time INTEGER NOT NULL,
+        PRIMARY KEY (path, exe_sha256)
+    );
+";
+
+fn db_err(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// `path` as a pattern matching only itself.
+fn escape_glob(path: &Path) -> Vec<u8> {
+    let mut glob = Vec::new();
+    for &b in path.as_os_str().as_bytes() {
+        if b"*?[\\".contains(&b) {
+            glob.push(b'\\');
+        }
+        glob.push(b);
+    }
+    glob
+}
+
+/// An executable by dev, ino and mtime.
+type ExeKey = (u64, u64, i64);
+
+/// The sha256 of executables, by `ExeKey`, or None if they couldn't be
+/// read. Those being hashed aren't in yet.
+type Hashes = Arc<Mutex<HashMap<ExeKey, Option<String>>>>;
+
+/// What `pid` runs, opened with O_PATH so it doesn't give us a
+/// FAN_OPEN_PERM to answer while we wait for it.
+fn open_exe(pid: u32) -> io::Result<(ExeKey, File)> {
+    let exe = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH)
+        .open(format!("/proc/{}/exe", pid))?;
+    let m = exe.metadata()?;
+    Ok(((m.dev(), m.ino(), m.mtime()), exe))
+}
+
+/// Hash what's sent in the background, so the open it takes to read them is
+/// answered by the main loop like anyone else's.
+fn spawn_hasher(hashes: Hashes) -> io::Result<Sender<(ExeKey, File)>> {
+    let (tx, rx) = mpsc::channel::<(ExeKey, File)>();
+    thread::Builder::new()
+        .name("decisions-hash".into())
+        .spawn(move || {
+            for (key, exe) in rx {
+                if hashes.lock().unwrap().contains_key(&key) {
+                    continue;
+                }
+                let hash = File::open(format!("/proc/self/fd/{}", exe.as_raw_fd()))
+                    .and_then(|f| sha256(&f));
+                if let Err(e) = &hash {
+                    debug!("--decisions: {}", e);
+                }
+                hashes.lock().unwrap().insert(key, hash.ok());
+            }
+        })?;
+    Ok(tx)
+}
+
+/// What's known of the executable of a process.
+#[derive(Debug, PartialEq)]
+enum Exe {
+    /// it couldn't be hashed, or is gone
+    Unknown,
+    /// still being hashed
+    Hashing(ExeKey),
+    Hashed(String),
+}
+
+/// The sha256 of executables, hashed by a worker.
+struct ExeHashes {
+    hashes: Hashes,
+    hasher: Sender<(ExeKey, File)>,
+    /// sent to `hasher`, so they're only sent once
+    sent: HashSet<ExeKey>,
+}
+
+impl ExeHashes {
+    fn new() -> io::Result<ExeHashes> {
+        let hashes = Hashes::default();
+        Ok(ExeHashes {
+            hasher: spawn_hasher(hashes.clone())?,
+            hashes,
+            sent: HashSet::new(),
+        })
+    }
+
+    /// What `pid` runs, starting to hash it if it hasn't been yet.
+    fn get(&mut self, pid: u32) -> Exe {
+        let (key, exe) = match open_exe(pid) {
+            Ok(exe) => exe,
+            Err(_) => return Exe::Unknown,
+        };
+        match self.hashes.lock().unwrap().get(&key) {
+            Some(Some(hash)) => return Exe::Hashed(hash.clone()),
+            Some(None) => return Exe::Unknown,
+            None => (),
+        }
+        if self.sent.insert(key) && self.hasher.send((key, exe)).is_err() {
+            return Exe::Unknown;
+        }
+        Exe::Hashing(key)
+    }
+
+    /// The hash of `key` if it's done, None for any if it failed.
+    fn done(&self, key: &ExeKey) -> Option<Option<String>> {
+        self.hashes.lock().unwrap().get(key).cloned()
+    }
+}
+
+struct Remembered {
+    glob: CString,
+    /// for any executable if none
+    exe: Option<String>,
+    action: Action,
+}
+
+impl Remembered {
+    fn matches_path(&self, path: &Path) -> bool {
+        match CString::new(path.as_os_str().as_bytes()) {
+            Ok(path) => unsafe { libc::fnmatch(self.glob.as_ptr(), path.as_ptr(), 0) == 0 },
+            Err(_) => false,
+        }
+    }
+}
+
+/// An always answer for an executable that's still being hashed.
+struct Unsaved {
+    glob: Vec<u8>,
+    exe: ExeKey,
+    action: Action,
+}
+
+/// The always answers given to permission events, kept in a SQLite
+/// database so they outlive us, for a path pattern and either one
+/// executable, by its sha256, or any.
+pub struct Decisions {
+    conn: Connection,
+    /// those for one executable first, then the latest first
+    remembered: Vec<Remembered>,
+    exes: ExeHashes,
+    /// in the order they were given
+    unsaved: Vec<Unsaved>,
+}
+
+impl Decisions {
+    pub fn open(path: &Path) -> io::Result<Decisions> {
+        let conn = Connection::open(path).map_err(db_err)?;
+        conn.execute_batch(SCHEMA).map_err(db_err)?;
+        let remembered = conn
+            .prepare(
+                "SELECT path, exe_sha256, action FROM decisions
+                 ORDER BY exe_sha256 = '', time DESC",
+            )
+            .and_then(|mut select| {
+                select
+                    .query_map([], |row| {
+                        // TEXT from before globs were saved as they are
+                        let glob = match row.get_ref(0)? {
+                            ValueRef::Text(glob) | ValueRef::Blob(glob) => Some(glob.to_vec()),
+                            _ => None,
+                        };
+                        Ok((glob, row.get::<_, String>(1)?, row.get::<_, String>(2)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(db_err)?
+            .into_iter()
+            .filter_map(|(glob, exe, action)| {
+                Some(Remembered {
+                    glob: CString::new(glob?).ok()?,
+                    exe: Some(exe).filter(|e| !e.is_empty()),
+                    action: action.parse().ok()?,
+                })
+            })
+            .collect::<Vec<_>>();
+        info!(
+            "{}: {} remembered decisions",
+            path.display(),
+            remembered.len()
+        );
+        Ok(Decisions {
+            conn,
+            remembered,
+            exes: ExeHashes::new()?,
+            unsaved: Vec::new(),
+        })
+    }
+
+    /// How to answer `entry`, if it was answered always before. Not if that
+    /// depends on an executable that's still being hashed.
+    pub fn decide(&mut self, entry: &EventEntry) -> Option<Action> {
+        if let Err(e) = self.save_hashed() {
+            error!("--decisions: {}", e);
+        }
+        let path = entry.path.as_ref()?;
+        let exes = &mut self.exes;
+        let mut exe = None;
+        for r in &self.remembered {
+            if !r.matches_path(path) {
+                continue;
+            }
+            if let Some(want) = &r.exe {
+                let exe = exe.get_or_insert_with(|| match entry.pid {
+                    Some(pid) => exes.get(pid),
+                    None => Exe::Unknown,
+                });
+                match exe {
+                    Exe::Hashed(hash) if hash == want => (),
+                    // rather than the answer for any executable
+                    Exe::Hashing(_) => return None,
+                    _ => continue,
+                }
+            }
+            return Some(r.action);
+        }
+        None
+    }
+
+    /// Answer `path` with `action` from now on, when `pid`'s executable
+    /// asks, or any if it's gone. Saved once that's hashed.
+    pub fn remember(&mut self, path: &Path, pid: Option<u32>, action: Action) -> io::Result<()> {
+        self.save_hashed()?;
+        let glob = escape_glob(path);
+        match pid.map_or(Exe::Unknown, |pid| self.exes.get(pid)) {
+            Exe::Hashing(exe) => {
+                self.unsaved.push(Unsaved { glob, exe, action });
+                Ok(())
+            }
+            Exe::Hashed(exe) => self.save(glob, Some(exe), action),
+            Exe::Unknown => self.save(glob, None, action),
+        }
+    }
+
+    /// Save what was waiting on an executable that's been hashed since.
+    fn save_hashed(&mut self) -> io::Result<()> {
+        let mut i = 0;
+        while i < self.unsaved.len() {
+            match self.exes.done(&self.unsaved[i].exe) {
+                Some(exe) => {
+                    let u = self.unsaved.remove(i);
+                    self.save(u.glob, exe, u.action)?;
+                }
+                None => i += 1,
+            }
+        }
+        Ok(())
+    }
+
+    fn save(&mut self, glob: Vec<u8>, exe: Option<String>, action: Action) -> io::Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        // one saved as TEXT before is a different key
+        self.conn
+            .execute(
+                "DELETE FROM decisions WHERE CAST(path AS BLOB) = ?1 AND exe_sha256 = ?2",
+                params![glob, exe.as_deref().unwrap_or("")],
+            )
+            .map_err(db_err)?;
+        self.conn
+            .execute(
+                "INSERT INTO decisions (path, exe_sha256, action, time)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![glob, exe.as_deref().unwrap_or(""), action.name(), time],
+            )
+            .map_err(db_err)?;
+
+        let glob = CString::new(glob).map_err(io::Error::other)?;
+        self.remembered
+            .retain(|r| !(r.glob == glob && r.exe == exe));
+        let at = if exe.is_some() {
+            0
+        } else {
+            self.remembered
+                .iter()
+                .position(|r| r.exe.is_none())
+                .unwrap_or(self.remembered.len())
+        };
+        self.remembered.insert(at, Remembered { glob, exe, action });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+    use std::ffi::OsStr;
+    use std::process;
+    use std::time::{Duration, Instant};
+
+    /// Wait for the worker to hash what `pid` runs.
+    fn hashed(decisions: &mut Decisions, pid: u32) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while let Exe::Hashing(_) = decisions.exes.get(pid) {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn decisions_remembered() -> io::Result<()> {
+        let dir = TempDir::new("decisions")?;
+        let db = dir.join("decisions.db");
+        let entry = |path: &str, pid| EventEntry {
+            fd: Some(3),
+            pid: Some(pid),
+            ..EventEntry::test(libc::FAN_OPEN_PERM, path)
+        };
+        // something small to hash
+        let mut sleep = process::Command::new("sleep").arg("60").spawn()?;
+        let pid = sleep.id();
+        let mut decisions = Decisions::open(&db)?;
+        assert_eq!(decisions.decide(&entry("/etc/shadow", pid)), None);
+        // saved once it's hashed
+        decisions.remember(Path::new("/etc/sha*ow"), Some(pid), Action::Deny)?;
+        assert_eq!(decisions.unsaved.len(), 1);
+        hashed(&mut decisions, pid);
+        decisions.remember(Path::new("/etc/sha*ow"), None, Action::Allow)?;
+        assert!(decisions.unsaved.is_empty());
+        // not valid UTF-8
+        decisions.remember(
+            Path::new(OsStr::from_bytes(b"/tmp/\xff")),
+            None,
+            Action::Deny,
+        )?;
+        drop(decisions);
+
+        let mut decisions = Decisions::open(&db)?;
+        assert_eq!(decisions.remembered.len(), 3);
+        assert_eq!(decisions.decide(&entry("/etc/shadow", pid)), None);
+        // not until it's hashed again
+        assert_eq!(decisions.decide(&entry("/etc/sha*ow", pid)), None);
+        hashed(&mut decisions, pid);
+        assert_eq!(
+            decisions.decide(&entry("/etc/sha*ow", pid)),
+            Some(Action::Deny)
+        );
+        assert_eq!(
+            decisions.decide(&entry("/etc/sha*ow", 0)),
+            Some(Action::Allow)
+        );
+        let mut invalid = entry("", 0);
+        invalid.path = Some(Path::new(OsStr::from_bytes(b"/tmp/\xff")).into());
+        assert_eq!(decisions.decide(&invalid), Some(Action::Deny));
+        // what we run changed its mind
+        decisions.remember(Path::new("/etc/sha*ow"), Some(pid), Action::Allow)?;
+        assert_eq!(decisions.remembered.len(), 3);
+        assert_eq!(
+            decisions.decide(&entry("/etc/sha*ow", pid)),
+            Some(Action::Allow)
+        );
+        sleep.kill()?;
+        sleep.wait()?;
+        Ok(())
+    }
+}
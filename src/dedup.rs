@@ -0,0 +1,125 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::EventEntry;
+
+/// The same mask by the same pid on the same path.
+type Key = (u64, Option<u32>, Option<PathBuf>);
+
+struct Seen {
+    at: Instant,
+    repeated: u64,
+}
+
+/// Drops repeats of an event, the same mask by the same pid on the same
+/// path, within a window of it, whatever came between them. How many there
+/// were goes on the next one after the window, or on one of its own once
+/// the window is over without one, as syslog does with "last message
+/// repeated".
+pub struct Dedup {
+    window: Duration,
+    seen: HashMap<Key, Seen>,
+    /// in the order the windows started, which is the order they end
+    queue: VecDeque<(Instant, Key)>,
+}
+
+impl Dedup {
+    pub fn new(window: Duration) -> Dedup {
+        Dedup {
+            window,
+            seen: HashMap::new(),
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// None if `entry` repeats an event within its window, or how many
+    /// times it was repeated in the window before.
+    pub fn record(&mut self, entry: &EventEntry, now: Instant) -> Option<u64> {
+        let key = (entry.mask, entry.pid, entry.path.clone());
+        let repeated = match self.seen.get_mut(&key) {
+            Some(seen) if now.saturating_duration_since(seen.at) < self.window => {
+                seen.repeated += 1;
+                return None;
+            }
+            Some(seen) => std::mem::take(&mut seen.repeated),
+            None => 0,
+        };
+        self.seen.insert(
+            key.clone(),
+            Seen {
+                at: now,
+                repeated: 0,
+            },
+        );
+        self.queue.push_back((now, key));
+        Some(repeated)
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.queue.front().map(|(at, _)| *at + self.window)
+    }
+
+    /// Forget the events whose window is over by `now`, with one for each
+    /// that was repeated in it, saying how many times.
+    pub fn expire(&mut self, now: Instant) -> Vec<EventEntry> {
+        let mut repeats = Vec::new();
+        while let Some((at, _)) = self.queue.front() {
+            if *at + self.window > now {
+                break;
+            }
+            let (at, key) = self.queue.pop_front().unwrap();
+            // unless a later window started
+            if self.seen.get(&key).is_some_and(|seen| seen.at == at) {
+                let seen = self.seen.remove(&key).unwrap();
+                if seen.repeated != 0 {
+                    let (mask, pid, path) = key;
+                    repeats.push(EventEntry {
+                        mask,
+                        fd: None,
+                        pid,
+                        path,
+                        fields: vec![("repeated", seen.repeated.to_string())],
+                    });
+                }
+            }
+        }
+        repeats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats() {
+        let entry = EventEntry::test;
+        let mut d = Dedup::new(Duration::from_millis(100));
+        let t = Instant::now();
+
+        assert_eq!(d.record(&entry(libc::FAN_MODIFY, "/a"), t), Some(0));
+        assert_eq!(d.record(&entry(libc::FAN_MODIFY, "/a"), t), None);
+        assert_eq!(d.record(&entry(libc::FAN_MODIFY, "/b"), t), Some(0));
+        // whatever came between
+        assert_eq!(d.record(&entry(libc::FAN_MODIFY, "/a"), t), None);
+        assert_eq!(d.record(&entry(libc::FAN_CLOSE_WRITE, "/b"), t), Some(0));
+        assert!(d.expire(t).is_empty());
+        assert_eq!(d.deadline(), Some(t + Duration::from_millis(100)));
+
+        // past the window, on the next one of the same
+        let later = t + Duration::from_millis(100);
+        assert_eq!(d.record(&entry(libc::FAN_MODIFY, "/a"), later), Some(2));
+        assert_eq!(d.record(&entry(libc::FAN_MODIFY, "/a"), later), None);
+        assert!(d.expire(later).is_empty());
+
+        // or on one of its own
+        let repeats = d.expire(later + Duration::from_millis(100));
+        assert_eq!(repeats.len(), 1);
+        assert_eq!(repeats[0].mask, libc::FAN_MODIFY);
+        assert_eq!(repeats[0].path, Some("/a".into()));
+        assert_eq!(repeats[0].fields, vec![("repeated", "1".to_string())]);
+        assert!(d.seen.is_empty());
+        assert_eq!(d.deadline(), None);
+    }
+}
@@ -0,0 +1,1129 @@
+use std::collections::HashMap;
+use std::ffi::{CStr, OsStr};
+use std::fs::{self, File, FileType};
+use std::io;
+use std::mem::{self, ManuallyDrop};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::str::FromStr;
+use std::time::Instant;
+
+use sha2::{Digest, Sha256};
+
+use crate::chain::to_hex;
+use crate::mountinfo;
+use crate::stats::Stats;
+use crate::write_escaped;
+
+// processes to remember, they're forgotten all at once past this
+const PROCS_LEN: usize = 10000;
+// of a command line, for a bare --cmdline
+pub const CMDLINE_LEN: usize = 1024;
+const DELETED_SUFFIX: &[u8] = b" (deleted)";
+
+const STEPS: &[(&str, Step)] = &[
+    ("path", Step::Path),
+    ("proc", Step::Proc),
+    ("stat", Step::Stat),
+    ("hash", Step::Hash),
+    ("container", Step::Container),
+    ("mount", Step::Mount),
+    ("inode", Step::Inode),
+    ("user", Step::User),
+    ("magic", Step::Magic),
+    ("io", Step::Io),
+    ("tty", Step::Tty),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Step {
+    /// the path of the file, and whether it was deleted
+    Path,
+    /// comm and exe of the process, or what they were before it exited
+    Proc,
+    /// size, inode, owner, mode and mtime of the file, as it is when the
+    /// event is read
+    Stat,
+    /// sha256 of the content
+    Hash,
+    /// the container the process runs in, from its cgroup
+    Container,
+    /// the mount the file is on and the type of its filesystem
+    Mount,
+    /// device and inode number, to follow a file across renames and tell
+    /// hard links apart
+    Inode,
+    /// effective uid and gid of the process, and their names
+    User,
+    /// what kind of file it is and its MIME type, from its first bytes
+    Magic,
+    /// how many bytes the process read from and wrote to storage since its
+    /// last event on the file, like between its FAN_OPEN and
+    /// FAN_CLOSE_WRITE
+    Io,
+    /// the controlling terminal and session of the process, to tell
+    /// someone at a shell from a daemon
+    Tty,
+}
+
+impl Step {
+    fn name(self) -> &'static str {
+        STEPS.iter().find(|(_, s)| *s == self).unwrap().0
+    }
+
+    /// The fields this step may add.
+    fn fields(self) -> &'static [&'static str] {
+        match self {
+            Step::Path => &["type", "deleted"],
+            Step::Proc => &["comm", "exe"],
+            // gid and mtime last, so the --output-format csv columns before
+            // them stay where they were
+            Step::Stat => &["size", "ino", "uid", "mode", "gid", "mtime"],
+            Step::Hash => &["sha256"],
+            Step::Container => &["container", "container_id", "container_runtime"],
+            Step::Mount => &["mnt_id", "mnt", "fstype"],
+            Step::Inode => &["dev", "ino"],
+            Step::User => &["uid", "user", "gid", "group"],
+            Step::Magic => &["kind", "mime"],
+            Step::Io => &["read_bytes", "write_bytes"],
+            Step::Tty => &["tty", "session"],
+        }
+    }
+}
+
+/// The enrichment steps to run on each event, in order.
+#[derive(Debug, PartialEq)]
+pub struct Pipeline(Vec<Step>);
+
+impl FromStr for Pipeline {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Pipeline, String> {
+        let pipeline = s
+            .split(',')
+            .filter(|s| !s.is_empty())
+            .map(|name| {
+                STEPS
+                    .iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, s)| *s)
+                    .ok_or_else(|| {
+                        let all: Vec<&str> = STEPS.iter().map(|(n, _)| *n).collect();
+                        format!("{}: expected one of {}", name, all.join(","))
+                    })
+            })
+            .collect::<Result<_, _>>()
+            .map(Pipeline)?;
+        if pipeline.contains(Step::Stat) && pipeline.contains(Step::User) {
+            // the owner of the file and the user of the process
+            return Err("stat and user would both add uid and gid".into());
+        }
+        if pipeline.contains(Step::Stat) && pipeline.contains(Step::Inode) {
+            return Err("stat and inode would both add ino".into());
+        }
+        Ok(pipeline)
+    }
+}
+
+/// What the pipeline found out about an event.
+#[derive(Default)]
+pub struct Enriched {
+    pub path: Option<PathBuf>,
+    pub fields: Vec<(&'static str, String)>,
+}
+
+/// What the fd is if it's not something with a path worth resolving,
+/// /proc would only tell us pipe:[123] and the like.
+fn special_type(t: FileType) -> Option<&'static str> {
+    if t.is_fifo() {
+        Some("fifo")
+    } else if t.is_socket() {
+        Some("socket")
+    } else if t.is_char_device() {
+        Some("chardev")
+    } else if t.is_block_device() {
+        Some("blockdev")
+    } else {
+        None
+    }
+}
+
+/// The full ID of the container in `cgroup`, and the runtime that started
+/// it if that shows.
+fn container_id(cgroup: &str) -> Option<(&str, Option<&'static str>)> {
+    // docker-<id>.scope, cri-containerd-<id>.scope, /docker/<id> and so on
+    let mut parent = "";
+    for dir in cgroup.split(['/', ':', '\n']) {
+        if let Some(id) = dir
+            .split(['-', '.'])
+            .find(|s| s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit()))
+        {
+            let prefix = &dir[..dir.find(id).unwrap_or(0)];
+            let runtime = match (prefix, parent) {
+                ("docker-", _) | ("", "docker") => Some("docker"),
+                ("cri-containerd-", _) | ("", "containerd") => Some("containerd"),
+                ("crio-", _) | ("crio-conmon-", _) => Some("crio"),
+                ("libpod-", _) | ("libpod-conmon-", _) => Some("podman"),
+                _ => None,
+            };
+            return Some((id, runtime));
+        }
+        parent = dir;
+    }
+    None
+}
+
+/// The comm and start time, in clock ticks since boot, in a
+/// /proc/PID/stat.
+fn parse_stat(stat: &str) -> Option<(&str, u64)> {
+    // the comm is in parentheses, and may have both and spaces in it
+    let (head, tail) = stat.rsplit_once(')')?;
+    let comm = head.split_once('(')?.1;
+    // from the 3rd field, state, to the 22nd, starttime
+    let start = tail.split_whitespace().nth(19)?.parse().ok()?;
+    Some((comm, start))
+}
+
+/// The session id and controlling terminal, if any, in a /proc/PID/stat.
+fn parse_session(stat: &str) -> Option<(u32, Option<String>)> {
+    let mut fields = stat.rsplit_once(')')?.1.split_whitespace();
+    // from the 3rd field, state, to the 7th, tty_nr
+    let session = fields.nth(3)?.parse().ok()?;
+    let tty: u32 = fields.next()?.parse::<i32>().ok()? as u32;
+    Some((session, tty_name(tty)))
+}
+
+/// The name of the terminal with this tty_nr, as ps shows it.
+fn tty_name(tty: u32) -> Option<String> {
+    if tty == 0 {
+        return None;
+    }
+    let major = (tty >> 8) & 0xfff;
+    let minor = (tty & 0xff) | ((tty >> 12) & 0xfff00);
+    Some(match major {
+        // the Unix98 ptys
+        136..=143 => format!("pts/{}", (major - 136) * 256 + minor),
+        4 if minor < 64 => format!("tty{}", minor),
+        4 => format!("ttyS{}", minor - 64),
+        _ => format!("{}:{}", major, minor),
+    })
+}
+
+/// The read_bytes and write_bytes in a /proc/PID/io.
+fn parse_io(io: &str) -> Option<(u64, u64)> {
+    let bytes = |key| {
+        io.lines()
+            .find_map(|l| l.strip_prefix(key))?
+            .trim()
+            .parse()
+            .ok()
+    };
+    Some((bytes("read_bytes:")?, bytes("write_bytes:")?))
+}
+
+/// The effective uid and gid in a /proc/PID/status.
+pub fn parse_status(status: &str) -> Option<(u32, u32)> {
+    let id = |key| {
+        status
+            .lines()
+            .find_map(|l| l.strip_prefix(key))?
+            .split_whitespace()
+            .nth(1)?
+            .parse()
+            .ok()
+    };
+    Some((id("Uid:")?, id("Gid:")?))
+}
+
+/// The name of user `uid`, or of group `gid` with `group`, from NSS.
+fn id_name(id: u32, group: bool) -> Option<String> {
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        let ret = unsafe {
+            if group {
+                let mut gr: libc::group = mem::zeroed();
+                let mut result = ptr::null_mut();
+                let ret = libc::getgrgid_r(id, &mut gr, buf.as_mut_ptr(), buf.len(), &mut result);
+                if ret == 0 && !result.is_null() {
+                    return Some(CStr::from_ptr(gr.gr_name).to_string_lossy().into_owned());
+                }
+                ret
+            } else {
+                let mut pw: libc::passwd = mem::zeroed();
+                let mut result = ptr::null_mut();
+                let ret = libc::getpwuid_r(id, &mut pw, buf.as_mut_ptr(), buf.len(), &mut result);
+                if ret == 0 && !result.is_null() {
+                    return Some(CStr::from_ptr(pw.pw_name).to_string_lossy().into_owned());
+                }
+                ret
+            }
+        };
+        // a huge group, or the like
+        if ret != libc::ERANGE || buf.len() >= 1 << 20 {
+            return None;
+        }
+        buf.resize(buf.len() * 2, 0);
+    }
+}
+
+/// The argv in a /proc/PID/cmdline, cut to `max` bytes, joined by spaces
+/// and escaped like write_escaped, with the spaces in them as \x20.
+fn format_cmdline(cmdline: &[u8], max: usize) -> Option<String> {
+    let cmdline = cmdline.strip_suffix(b"\0").unwrap_or(cmdline);
+    if cmdline.is_empty() {
+        return None;
+    }
+    let cut = cmdline.len() > max;
+    let mut out = Vec::new();
+    for (i, arg) in cmdline[..cmdline.len().min(max)]
+        .split(|b| *b == 0)
+        .enumerate()
+    {
+        if i != 0 {
+            out.push(b' ');
+        }
+        for (j, word) in arg.split(|b| *b == b' ').enumerate() {
+            if j != 0 {
+                out.extend_from_slice(b"\\x20");
+            }
+            // to a Vec, which can't fail
+            let _ = write_escaped(&mut out, word);
+        }
+    }
+    if cut {
+        out.extend_from_slice(b"...");
+    }
+    Some(String::from_utf8_lossy(&out).into_owned())
+}
+
+/// What Step::Proc found about each process, by pid, for its events
+/// after it exits, and to not look up its exe again while its comm stays
+/// the same, as it would after an exec.
+#[derive(Default)]
+pub struct Procs {
+    procs: HashMap<u32, Proc>,
+    /// how much of the command lines to read, if any
+    cmdline: Option<usize>,
+    /// the names of users and groups, by whether it's a group and id, as
+    /// NSS may well go over the network for them
+    names: HashMap<(bool, u32), Option<String>>,
+    /// what Step::Io last read of each process, by pid and the dev and ino
+    /// of the file of the event
+    io: HashMap<(u32, u64, u64), (u64, u64)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Proc {
+    start: u64,
+    comm: String,
+    exe: String,
+    cmdline: Option<String>,
+}
+
+impl Procs {
+    pub fn new(cmdline: Option<usize>) -> Procs {
+        Procs {
+            procs: HashMap::new(),
+            cmdline,
+            names: HashMap::new(),
+            io: HashMap::new(),
+        }
+    }
+
+    /// How much more `now` is than the io read for `key` before, if it
+    /// was, remembering it for the next event.
+    fn io_since(&mut self, key: (u32, u64, u64), now: (u64, u64)) -> Option<(u64, u64)> {
+        if self.io.len() >= PROCS_LEN && !self.io.contains_key(&key) {
+            self.io.clear();
+        }
+        // a reused pid would go backwards
+        self.io
+            .insert(key, now)
+            .map(|(read, write)| (now.0.saturating_sub(read), now.1.saturating_sub(write)))
+    }
+
+    fn name(&mut self, id: u32, group: bool) -> Option<String> {
+        if self.names.len() >= PROCS_LEN {
+            self.names.clear();
+        }
+        self.names
+            .entry((group, id))
+            .or_insert_with(|| id_name(id, group))
+            .clone()
+    }
+
+    /// The comm and exe of `pid`, as Step::Proc would add them.
+    pub fn comm_exe(&mut self, pid: u32) -> io::Result<(String, String)> {
+        self.lookup(pid).map(|p| (p.comm, p.exe))
+    }
+
+    /// What there is to know about `pid`.
+    fn lookup(&mut self, pid: u32) -> io::Result<Proc> {
+        let stat = match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => stat,
+            // gone, but a pid is only reused once it is
+            Err(e) => return self.procs.get(&pid).cloned().ok_or(e),
+        };
+        let (comm, start) = parse_stat(&stat).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("/proc/{}/stat: {}", pid, stat),
+            )
+        })?;
+        if let Some(p) = self.procs.get(&pid) {
+            if p.start == start && p.comm == comm {
+                return Ok(p.clone());
+            }
+        }
+
+        let exe = fs::read_link(format!("/proc/{}/exe", pid))?
+            .to_string_lossy()
+            .into_owned();
+        let cmdline = match self.cmdline {
+            Some(max) => format_cmdline(&fs::read(format!("/proc/{}/cmdline", pid))?, max),
+            None => None,
+        };
+        let p = Proc {
+            start,
+            comm: comm.into(),
+            exe,
+            cmdline,
+        };
+        if self.procs.len() >= PROCS_LEN {
+            self.procs.clear();
+        }
+        self.procs.insert(pid, p.clone());
+        Ok(p)
+    }
+}
+
+/// A path from readlink of an fd without the " (deleted)" it ends in once
+/// the file is unlinked, and whether it did.
+pub fn strip_deleted(path: PathBuf) -> (PathBuf, bool) {
+    match path.as_os_str().as_bytes().strip_suffix(DELETED_SUFFIX) {
+        Some(p) => (PathBuf::from(OsStr::from_bytes(p)), true),
+        None => (path, false),
+    }
+}
+
+/// The dev and ino fields, dev as major:minor.
+pub fn inode_fields(dev: u64, ino: u64) -> [(&'static str, String); 2] {
+    [
+        ("dev", format!("{}:{}", libc::major(dev), libc::minor(dev))),
+        ("ino", ino.to_string()),
+    ]
+}
+
+/// The mnt and fstype fields of the filesystem on `dev`, for the events
+/// without a mount id.
+pub fn dev_mount_fields(dev: u64) -> Vec<(&'static str, String)> {
+    match mountinfo::dev_mount_point(dev) {
+        Ok(Some((point, fstype))) => vec![
+            ("mnt", point.to_string_lossy().into_owned()),
+            ("fstype", fstype),
+        ],
+        Ok(None) => Vec::new(),
+        Err(e) => {
+            debug!("enrich mount: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+pub fn sha256(f: &File) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 65536];
+    let mut off = 0;
+    loop {
+        let n = f.read_at(&mut buf, off)?;
+        if n == 0 {
+            return Ok(to_hex(&hasher.finalize()));
+        }
+        hasher.update(&buf[..n]);
+        off += n as u64;
+    }
+}
+
+/// The kind and MIME type of a file that starts with `head`.
+fn sniff(head: &[u8]) -> Option<(&'static str, &'static str)> {
+    const MAGIC: &[(&[u8], &str, &str)] = &[
+        (b"\x7fELF", "elf", "application/x-executable"),
+        (b"%PDF-", "document", "application/pdf"),
+        (
+            b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1",
+            "office",
+            "application/x-ole-storage",
+        ),
+        (b"\x1f\x8b", "archive", "application/gzip"),
+        (b"\xfd7zXZ\0", "archive", "application/x-xz"),
+        (b"BZh", "archive", "application/x-bzip2"),
+        (b"\x28\xb5\x2f\xfd", "archive", "application/zstd"),
+        (
+            b"7z\xbc\xaf\x27\x1c",
+            "archive",
+            "application/x-7z-compressed",
+        ),
+        (b"\x89PNG\r\n\x1a\n", "image", "image/png"),
+        (b"\xff\xd8\xff", "image", "image/jpeg"),
+        (b"GIF8", "image", "image/gif"),
+    ];
+
+    if head.is_empty() {
+        return None;
+    }
+    if let Some(line) = head.strip_prefix(b"#!") {
+        let line = line.split(|b| *b == b'\n').next().unwrap_or_default();
+        let mut words = line
+            .split(|b| *b == b' ' || *b == b'\t')
+            .filter(|w| !w.is_empty());
+        let mut interp = words.next().and_then(|w| w.rsplit(|b| *b == b'/').next());
+        // #!/usr/bin/env python3
+        if interp == Some(&b"env"[..]) {
+            interp = words.find(|w| !w.starts_with(b"-"));
+        }
+        let mime = match interp {
+            Some(i) if i.ends_with(b"sh") => "text/x-shellscript",
+            Some(i) if i.starts_with(b"python") => "text/x-python",
+            Some(i) if i.starts_with(b"perl") => "text/x-perl",
+            _ => "text/plain",
+        };
+        return Some(("script", mime));
+    }
+    if let Some((_, kind, mime)) = MAGIC.iter().find(|(m, _, _)| head.starts_with(m)) {
+        return Some((*kind, *mime));
+    }
+    if head.starts_with(b"PK\x03\x04") {
+        return Some(sniff_zip(head));
+    }
+    if head.get(257..262) == Some(&b"ustar"[..]) {
+        return Some(("archive", "application/x-tar"));
+    }
+    if !head.contains(&0) && std::str::from_utf8(head).is_ok() {
+        Some(("text", "text/plain"))
+    } else {
+        Some(("data", "application/octet-stream"))
+    }
+}
+
+/// Whether the zip that starts with `head` is an office document, by the
+/// name of its first entry.
+fn sniff_zip(head: &[u8]) -> (&'static str, &'static str) {
+    const ODF: &[&str] = &[
+        "application/vnd.oasis.opendocument.text",
+        "application/vnd.oasis.opendocument.spreadsheet",
+        "application/vnd.oasis.opendocument.presentation",
+    ];
+    let u16_at = |i: usize| {
+        head.get(i..i + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)
+    };
+    let name = u16_at(26).and_then(|len| head.get(30..30 + len));
+    match name {
+        // OpenDocument, with the type stored right after
+        Some(b"mimetype") => {
+            let data = 30 + 8 + u16_at(28).unwrap_or(0);
+            let odf = head.get(data..).unwrap_or_default();
+            match ODF.iter().find(|m| odf.starts_with(m.as_bytes())) {
+                Some(mime) => ("office", *mime),
+                None => ("archive", "application/zip"),
+            }
+        }
+        Some(n) if n.starts_with(b"word/") => (
+            "office",
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        ),
+        Some(n) if n.starts_with(b"xl/") => (
+            "office",
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        ),
+        Some(n) if n.starts_with(b"ppt/") => (
+            "office",
+            "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        ),
+        _ => ("archive", "application/zip"),
+    }
+}
+
+fn mnt_id(fd: RawFd) -> io::Result<Option<u32>> {
+    Ok(fs::read_to_string(format!("/proc/self/fdinfo/{}", fd))?
+        .lines()
+        .find_map(|l| l.strip_prefix("mnt_id:"))
+        .and_then(|id| id.trim().parse().ok()))
+}
+
+/// Whether the process behind `pidfd` is still around, and so its pid
+/// hasn't been handed to another.
+fn alive(pidfd: RawFd) -> bool {
+    let null = std::ptr::null::<libc::siginfo_t>();
+    unsafe { libc::syscall(libc::SYS_pidfd_send_signal, pidfd, 0, null, 0) == 0 }
+}
+
+impl Pipeline {
+    pub fn contains(&self, step: Step) -> bool {
+        self.0.contains(&step)
+    }
+
+    /// The fields the steps may add, in the order they would.
+    pub fn fields(&self) -> Vec<&'static str> {
+        self.0.iter().flat_map(|s| s.fields()).cloned().collect()
+    }
+
+    fn step(
+        step: Step,
+        fd: RawFd,
+        pid: Option<u32>,
+        procs: &mut Procs,
+        out: &mut Enriched,
+    ) -> io::Result<()> {
+        // borrowed, the caller closes it
+        let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+
+        match step {
+            Step::Path => match special_type(file.metadata()?.file_type()) {
+                Some(t) => out.fields.push(("type", t.into())),
+                None => {
+                    let (path, deleted) =
+                        strip_deleted(fs::read_link(format!("/proc/self/fd/{}", fd))?);
+                    out.path = Some(path);
+                    if deleted {
+                        out.fields.push(("deleted", "true".into()));
+                    }
+                }
+            },
+            Step::Proc => {
+                if let Some(pid) = pid {
+                    let p = procs.lookup(pid)?;
+                    out.fields.push(("comm", p.comm));
+                    out.fields.push(("exe", p.exe));
+                    if let Some(cmdline) = p.cmdline {
+                        out.fields.push(("cmdline", cmdline));
+                    }
+                }
+            }
+            Step::Stat => {
+                let m = file.metadata()?;
+                out.fields.push(("size", m.len().to_string()));
+                out.fields.push(("ino", m.ino().to_string()));
+                out.fields.push(("uid", m.uid().to_string()));
+                out.fields.push(("mode", format!("{:o}", m.mode())));
+                out.fields.push(("gid", m.gid().to_string()));
+                out.fields
+                    .push(("mtime", format!("{}.{:09}", m.mtime(), m.mtime_nsec())));
+            }
+            Step::Hash => {
+                if file.metadata()?.is_file() {
+                    out.fields.push(("sha256", sha256(&file)?));
+                }
+            }
+            Step::Container => {
+                if let Some(pid) = pid {
+                    let cgroup = fs::read_to_string(format!("/proc/{}/cgroup", pid))?;
+                    if let Some((id, runtime)) = container_id(&cgroup) {
+                        // as docker ps shows it
+                        out.fields.push(("container", id[..12].into()));
+                        out.fields.push(("container_id", id.into()));
+                        if let Some(runtime) = runtime {
+                            out.fields.push(("container_runtime", runtime.into()));
+                        }
+                    }
+                }
+            }
+            Step::Mount => match mnt_id(fd)? {
+                Some(id) => {
+                    out.fields.push(("mnt_id", id.to_string()));
+                    if let Some((point, fstype)) = mountinfo::mount_point(id)? {
+                        out.fields
+                            .push(("mnt", point.to_string_lossy().into_owned()));
+                        out.fields.push(("fstype", fstype));
+                    }
+                }
+                // before linux 3.15
+                None => out.fields.extend(dev_mount_fields(file.metadata()?.dev())),
+            },
+            Step::Inode => {
+                let m = file.metadata()?;
+                out.fields.extend(inode_fields(m.dev(), m.ino()));
+            }
+            Step::User => {
+                if let Some(pid) = pid {
+                    let status = fs::read_to_string(format!("/proc/{}/status", pid))?;
+                    if let Some((uid, gid)) = parse_status(&status) {
+                        out.fields.push(("uid", uid.to_string()));
+                        if let Some(user) = procs.name(uid, false) {
+                            out.fields.push(("user", user));
+                        }
+                        out.fields.push(("gid", gid.to_string()));
+                        if let Some(group) = procs.name(gid, true) {
+                            out.fields.push(("group", group));
+                        }
+                    }
+                }
+            }
+            Step::Magic => {
+                if file.metadata()?.is_file() {
+                    // enough for the tar header
+                    let mut head = [0; 512];
+                    let n = file.read_at(&mut head, 0)?;
+                    if let Some((kind, mime)) = sniff(&head[..n]) {
+                        out.fields.push(("kind", kind.into()));
+                        out.fields.push(("mime", mime.into()));
+                    }
+                }
+            }
+            Step::Io => {
+                if let Some(pid) = pid {
+                    let counters = fs::read_to_string(format!("/proc/{}/io", pid))?;
+                    let now = parse_io(&counters).ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("/proc/{}/io: {}", pid, counters),
+                        )
+                    })?;
+                    let m = file.metadata()?;
+                    if let Some((read, write)) = procs.io_since((pid, m.dev(), m.ino()), now) {
+                        out.fields.push(("read_bytes", read.to_string()));
+                        out.fields.push(("write_bytes", write.to_string()));
+                    }
+                }
+            }
+            Step::Tty => {
+                if let Some(pid) = pid {
+                    let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+                    if let Some((session, tty)) = parse_session(&stat) {
+                        if let Some(tty) = tty {
+                            out.fields.push(("tty", tty));
+                        }
+                        out.fields.push(("session", session.to_string()));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Run each step on the event's `fd`, timing them into `stats`. A step
+    /// that fails only loses its own fields, the process may well be gone.
+    /// With a `pidfd`, what was read from /proc is dropped unless the
+    /// process outlived the reading.
+    pub fn run(
+        &self,
+        fd: RawFd,
+        pid: Option<u32>,
+        pidfd: Option<RawFd>,
+        procs: &mut Procs,
+        stats: &mut Stats,
+    ) -> Enriched {
+        let path = self.path(fd, stats);
+        self.rest(fd, pid, pidfd, procs, stats, path)
+    }
+
+    /// Only the path step, if it's one of them, for what can be filtered
+    /// out or decided before the rest.
+    pub fn path(&self, fd: RawFd, stats: &mut Stats) -> Enriched {
+        let mut out = Enriched::default();
+        if self.contains(Step::Path) {
+            let mut procs = Procs::default();
+            Pipeline::timed(Step::Path, fd, None, None, &mut procs, stats, &mut out);
+        }
+        out
+    }
+
+    /// The steps other than the path, whose findings stay where it was
+    /// asked for among them.
+    pub fn rest(
+        &self,
+        fd: RawFd,
+        pid: Option<u32>,
+        pidfd: Option<RawFd>,
+        procs: &mut Procs,
+        stats: &mut Stats,
+        mut path: Enriched,
+    ) -> Enriched {
+        let mut out = Enriched::default();
+        for &step in &self.0 {
+            if step == Step::Path {
+                out.path = path.path.take();
+                out.fields.append(&mut path.fields);
+            } else {
+                Pipeline::timed(step, fd, pid, pidfd, procs, stats, &mut out);
+            }
+        }
+        out
+    }
+
+    fn timed(
+        step: Step,
+        fd: RawFd,
+        pid: Option<u32>,
+        pidfd: Option<RawFd>,
+        procs: &mut Procs,
+        stats: &mut Stats,
+        out: &mut Enriched,
+    ) {
+        let start = Instant::now();
+        let before = out.fields.len();
+        if let Err(e) = Pipeline::step(step, fd, pid, procs, out) {
+            debug!("enrich {}: {}", step.name(), e);
+        }
+        if let (Step::Proc | Step::Container | Step::User | Step::Io | Step::Tty, Some(pidfd)) =
+            (step, pidfd)
+        {
+            if !alive(pidfd) {
+                debug!("enrich {}: {:?} exited", step.name(), pid);
+                out.fields.truncate(before);
+            }
+        }
+        stats.time_enrich(step.name(), start.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn pipeline_parse() {
+        assert_eq!(
+            "hash,path".parse::<Pipeline>(),
+            Ok(Pipeline(vec![Step::Hash, Step::Path]))
+        );
+        assert_eq!("".parse::<Pipeline>(), Ok(Pipeline(vec![])));
+        assert!("path,bogus".parse::<Pipeline>().is_err());
+        assert!("stat,user".parse::<Pipeline>().is_err());
+        assert!("inode,stat".parse::<Pipeline>().is_err());
+    }
+
+    #[test]
+    fn special_files() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut stats = Stats::default();
+        let mut procs = Procs::default();
+        let enriched = Pipeline(vec![Step::Path]).run(fds[0], None, None, &mut procs, &mut stats);
+        assert_eq!(enriched.path, None);
+        assert_eq!(enriched.fields, vec![("type", "fifo".to_string())]);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+
+        let null = File::open("/dev/null").unwrap();
+        let enriched =
+            Pipeline(vec![Step::Path]).run(null.as_raw_fd(), None, None, &mut procs, &mut stats);
+        assert_eq!(enriched.fields, vec![("type", "chardev".to_string())]);
+    }
+
+    #[test]
+    fn deleted_path() -> io::Result<()> {
+        assert_eq!(
+            strip_deleted("/tmp/x (deleted)".into()),
+            (PathBuf::from("/tmp/x"), true)
+        );
+        assert_eq!(
+            strip_deleted("/tmp/x".into()),
+            (PathBuf::from("/tmp/x"), false)
+        );
+
+        let dir = TempDir::new("deleted")?;
+        let path = dir.join("file");
+        let f = File::create(&path)?;
+        fs::remove_file(&path)?;
+        let mut stats = Stats::default();
+        let enriched = Pipeline(vec![Step::Path]).run(
+            f.as_raw_fd(),
+            None,
+            None,
+            &mut Procs::default(),
+            &mut stats,
+        );
+        assert_eq!(enriched.path, Some(path));
+        assert_eq!(enriched.fields, vec![("deleted", "true".to_string())]);
+        Ok(())
+    }
+
+    #[test]
+    fn path_first() -> io::Result<()> {
+        let dir = TempDir::new("unwanted")?;
+        let path = dir.join("file");
+        let f = File::create(&path)?;
+        let pipeline = Pipeline(vec![Step::Stat, Step::Path, Step::Magic]);
+        let mut stats = Stats::default();
+        let mut procs = Procs::default();
+        let enriched = pipeline.path(f.as_raw_fd(), &mut stats);
+        assert_eq!(enriched.path.as_ref(), Some(&path));
+        // found first, so stat didn't run
+        assert!(enriched.fields.is_empty());
+
+        let enriched = pipeline.run(f.as_raw_fd(), None, None, &mut procs, &mut stats);
+        assert_eq!(enriched.path.as_ref(), Some(&path));
+        assert_eq!(
+            enriched.fields.len(),
+            Step::Stat.fields().len() + Step::Magic.fields().len()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stat_step() {
+        let null = File::open("/dev/null").unwrap();
+        let m = null.metadata().unwrap();
+        let mut stats = Stats::default();
+        let enriched = Pipeline(vec![Step::Stat]).run(
+            null.as_raw_fd(),
+            None,
+            None,
+            &mut Procs::default(),
+            &mut stats,
+        );
+        let keys: Vec<_> = enriched.fields.iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, Step::Stat.fields());
+        assert_eq!(enriched.fields[4], ("gid", m.gid().to_string()));
+        assert_eq!(
+            enriched.fields[5],
+            ("mtime", format!("{}.{:09}", m.mtime(), m.mtime_nsec()))
+        );
+    }
+
+    #[test]
+    fn inode_step() {
+        let null = File::open("/dev/null").unwrap();
+        let m = null.metadata().unwrap();
+        let mut stats = Stats::default();
+        let enriched = Pipeline(vec![Step::Inode]).run(
+            null.as_raw_fd(),
+            None,
+            None,
+            &mut Procs::default(),
+            &mut stats,
+        );
+        assert_eq!(
+            enriched.fields,
+            vec![
+                (
+                    "dev",
+                    format!("{}:{}", libc::major(m.dev()), libc::minor(m.dev()))
+                ),
+                ("ino", m.ino().to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn proc_cache() -> io::Result<()> {
+        assert_eq!(
+            parse_stat("42 (a) b (c)) S 1 42 42 0 -1 4194560 1 0 0 0 0 0 0 0 20 0 1 0 777 0"),
+            Some(("a) b (c)", 777))
+        );
+        assert_eq!(parse_stat("42 (sh) S 1"), None);
+
+        assert_eq!(
+            format_cmdline(b"sh\0-c\0echo a\tb\0", 100),
+            Some("sh -c echo\\x20a\\tb".into())
+        );
+        assert_eq!(
+            format_cmdline(b"sh\0-c\0echo a\0", 5),
+            Some("sh -c...".into())
+        );
+        assert_eq!(format_cmdline(b"", 100), None);
+
+        let mut procs = Procs::new(Some(4096));
+        let me = std::process::id();
+        let p = procs.lookup(me)?;
+        assert_eq!(p.comm, fs::read_to_string("/proc/self/comm")?.trim_end());
+        assert_eq!(p.exe, fs::read_link("/proc/self/exe")?.to_string_lossy());
+        assert!(p.cmdline.is_some());
+        assert_eq!(procs.lookup(me)?, p);
+
+        // past the largest pid there can be, as if it exited
+        let gone = 1 << 22 | 1;
+        assert!(procs.lookup(gone).is_err());
+        procs.procs.insert(gone, p.clone());
+        assert_eq!(procs.lookup(gone)?, p);
+        Ok(())
+    }
+
+    #[test]
+    fn user_step() {
+        assert_eq!(
+            parse_status("Name:\tcat\nUid:\t1000\t0\t0\t0\nGid:\t100\t5\t5\t5\n"),
+            Some((0, 5))
+        );
+        assert_eq!(parse_status("Name:\tcat\n"), None);
+        assert_eq!(id_name(0, false).as_deref(), Some("root"));
+        assert_eq!(id_name(0, true).as_deref(), Some("root"));
+
+        let me = std::process::id();
+        let mut procs = Procs::default();
+        let mut out = Enriched::default();
+        let null = File::open("/dev/null").unwrap();
+        Pipeline::step(Step::User, null.as_raw_fd(), Some(me), &mut procs, &mut out).unwrap();
+        let euid = unsafe { libc::geteuid() };
+        assert_eq!(out.fields[0], ("uid", euid.to_string()));
+        assert_eq!(out.fields.iter().filter(|(k, _)| *k == "gid").count(), 1);
+    }
+
+    #[test]
+    fn magic_step() {
+        assert_eq!(sniff(b""), None);
+        assert_eq!(
+            sniff(b"\x7fELF\x02\x01"),
+            Some(("elf", "application/x-executable"))
+        );
+        assert_eq!(
+            sniff(b"#!/bin/sh\necho hi\n"),
+            Some(("script", "text/x-shellscript"))
+        );
+        assert_eq!(
+            sniff(b"#!/usr/bin/env python3\n"),
+            Some(("script", "text/x-python"))
+        );
+        assert_eq!(
+            sniff(b"\x1f\x8b\x08\0"),
+            Some(("archive", "application/gzip"))
+        );
+        assert_eq!(sniff(b"hello\n"), Some(("text", "text/plain")));
+        assert_eq!(
+            sniff(b"\0\x01\x02"),
+            Some(("data", "application/octet-stream"))
+        );
+
+        let mut tar = vec![0; 512];
+        tar[257..263].copy_from_slice(b"ustar\0");
+        assert_eq!(sniff(&tar), Some(("archive", "application/x-tar")));
+
+        let zip = |name: &[u8], data: &[u8]| {
+            let mut z = b"PK\x03\x04".to_vec();
+            z.resize(26, 0);
+            z.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            z.extend_from_slice(&[0, 0]);
+            z.extend_from_slice(name);
+            z.extend_from_slice(data);
+            z
+        };
+        assert_eq!(
+            sniff(&zip(b"a.txt", b"")),
+            Some(("archive", "application/zip"))
+        );
+        assert_eq!(sniff(&zip(b"word/document.xml", b"")).unwrap().0, "office");
+        assert_eq!(
+            sniff(&zip(
+                b"mimetype",
+                b"application/vnd.oasis.opendocument.text"
+            )),
+            Some(("office", "application/vnd.oasis.opendocument.text"))
+        );
+    }
+
+    #[test]
+    fn io_step() -> io::Result<()> {
+        assert_eq!(
+            parse_io("rchar: 10\nwchar: 20\nread_bytes: 4096\nwrite_bytes: 8192\n"),
+            Some((4096, 8192))
+        );
+        assert_eq!(parse_io("rchar: 10\n"), None);
+
+        let mut procs = Procs::default();
+        assert_eq!(procs.io_since((42, 1, 2), (100, 200)), None);
+        assert_eq!(procs.io_since((42, 1, 2), (150, 1200)), Some((50, 1000)));
+        assert_eq!(procs.io_since((42, 1, 3), (150, 1200)), None);
+        assert_eq!(procs.io_since((42, 1, 2), (0, 0)), Some((0, 0)));
+
+        let me = std::process::id();
+        let null = File::open("/dev/null")?;
+        let mut out = Enriched::default();
+        Pipeline::step(Step::Io, null.as_raw_fd(), Some(me), &mut procs, &mut out)?;
+        assert!(out.fields.is_empty());
+        Pipeline::step(Step::Io, null.as_raw_fd(), Some(me), &mut procs, &mut out)?;
+        let keys: Vec<_> = out.fields.iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, Step::Io.fields());
+        Ok(())
+    }
+
+    #[test]
+    fn tty_step() {
+        assert_eq!(
+            parse_session("42 (bash) S 1 42 42 34816 42 4194560 1 0"),
+            Some((42, Some("pts/0".into())))
+        );
+        assert_eq!(
+            parse_session("7 (cron) S 1 7 7 0 -1 4194560 1 0"),
+            Some((7, None))
+        );
+        assert_eq!(parse_session("7 (cron) S 1"), None);
+        assert_eq!(tty_name(0x8803).as_deref(), Some("pts/3"));
+        assert_eq!(tty_name(0x401).as_deref(), Some("tty1"));
+        assert_eq!(tty_name(0x440).as_deref(), Some("ttyS0"));
+
+        let me = std::process::id();
+        let null = File::open("/dev/null").unwrap();
+        let mut out = Enriched::default();
+        Pipeline::step(
+            Step::Tty,
+            null.as_raw_fd(),
+            Some(me),
+            &mut Procs::default(),
+            &mut out,
+        )
+        .unwrap();
+        let sid = unsafe { libc::getsid(0) };
+        assert_eq!(out.fields.last(), Some(&("session", sid.to_string())));
+    }
+
+    #[test]
+    fn pidfd_alive() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, child.id(), 0) } as RawFd;
+        assert!(pidfd >= 0);
+        let pidfd = unsafe { File::from_raw_fd(pidfd) };
+        assert!(alive(pidfd.as_raw_fd()));
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(!alive(pidfd.as_raw_fd()));
+    }
+
+    #[test]
+    fn cgroup_container() {
+        let id = "0123456789abcdef".repeat(4);
+        let found = |runtime| Some((id.as_str(), runtime));
+        assert_eq!(
+            container_id(&format!("0::/system.slice/docker-{}.scope\n", id)),
+            found(Some("docker"))
+        );
+        assert_eq!(
+            container_id(&format!("12:pids:/docker/{}\n", id)),
+            found(Some("docker"))
+        );
+        assert_eq!(
+            container_id(&format!(
+                "0::/kubepods.slice/kubepods-besteffort.slice/\
+                 kubepods-besteffort-pod1234.slice/cri-containerd-{}.scope\n",
+                id
+            )),
+            found(Some("containerd"))
+        );
+        assert_eq!(
+            container_id(&format!("0::/kubepods/besteffort/pod1234/crio-{}\n", id)),
+            found(Some("crio"))
+        );
+        assert_eq!(
+            container_id(&format!(
+                "0::/user.slice/user-1000.slice/user@1000.service/user.slice/libpod-{}.scope/container\n",
+                id
+            )),
+            found(Some("podman"))
+        );
+        assert_eq!(
+            container_id(&format!("0::/kubepods/besteffort/pod1234/{}\n", id)),
+            found(None)
+        );
+        assert_eq!(container_id("0::/user.slice\n"), None);
+    }
+}
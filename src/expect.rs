@@ -0,0 +1,193 @@
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus};
+use std::time::{Duration, Instant};
+
+use crate::{parse_mask, EventEntry};
+
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// `GLOB [MASK]`, matching events on paths that fnmatch GLOB and, if given,
+/// having any of the bits in MASK.
+pub struct Rule {
+    glob: CString,
+    /// 0 for any event
+    mask: u64,
+}
+
+impl Rule {
+    pub fn parse(s: &str) -> io::Result<Rule> {
+        let (glob, mask) = match s.rsplit_once(char::is_whitespace) {
+            Some((glob, mask)) if mask.starts_with("FAN_") => {
+                (glob.trim_end(), parse_mask(&mask.replace('|', ","))?)
+            }
+            _ => (s, 0),
+        };
+        Ok(Rule {
+            glob: CString::new(glob).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?,
+            mask,
+        })
+    }
+
+    pub fn matches(&self, entry: &EventEntry) -> bool {
+        let path = match &entry.path {
+            Some(p) => p,
+            None => return false,
+        };
+        let path = match CString::new(path.as_os_str().as_bytes()) {
+            Ok(p) => p,
+            Err(_) => return false,
+        };
+        (self.mask == 0 || entry.mask & self.mask != 0)
+            && unsafe { libc::fnmatch(self.glob.as_ptr(), path.as_ptr(), 0) } == 0
+    }
+}
+
+/// A line of the --expect file: `GLOB [MASK]` must be seen, `!GLOB [MASK]`
+/// must not be.
+struct Expectation {
+    line: String,
+    rule: Rule,
+    forbid: bool,
+    seen: u64,
+}
+
+impl Expectation {
+    fn matches(&self, entry: &EventEntry) -> bool {
+        self.rule.matches(entry)
+    }
+
+    fn met(&self) -> bool {
+        if self.forbid {
+            self.seen == 0
+        } else if self.rule.mask == 0 {
+            self.seen != 0
+        } else {
+            self.seen & self.rule.mask == self.rule.mask
+        }
+    }
+}
+
+fn parse(s: &str) -> io::Result<Vec<Expectation>> {
+    let mut list = Vec::new();
+    for line in s.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (forbid, rest) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        list.push(Expectation {
+            line: line.into(),
+            rule: Rule::parse(rest)?,
+            forbid,
+            seen: 0,
+        });
+    }
+    Ok(list)
+}
+
+/// Runs a command and checks the events it causes against a list of
+/// expectations.
+pub struct Expect {
+    list: Vec<Expectation>,
+    child: Child,
+    status: Option<ExitStatus>,
+    next_check: Instant,
+}
+
+impl Expect {
+    pub fn new(file: &Path, cmd: &str) -> io::Result<Expect> {
+        Ok(Expect {
+            list: parse(&fs::read_to_string(file)?)?,
+            child: Command::new("sh").arg("-c").arg(cmd).spawn()?,
+            status: None,
+            next_check: Instant::now() + CHECK_INTERVAL,
+        })
+    }
+
+    pub fn record(&mut self, entry: &EventEntry) {
+        for e in &mut self.list {
+            if e.matches(entry) {
+                e.seen |= entry.mask;
+            }
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.next_check
+    }
+
+    /// Returns true once the command has exited.
+    pub fn check_exit(&mut self) -> io::Result<bool> {
+        self.next_check = Instant::now() + CHECK_INTERVAL;
+        if self.status.is_none() {
+            self.status = self.child.try_wait()?;
+        }
+        Ok(self.status.is_some())
+    }
+
+    /// List what didn't turn out as expected, returns true if nothing.
+    pub fn write_report(&self, w: &mut dyn Write) -> io::Result<bool> {
+        let mut ok = true;
+        for e in self.list.iter().filter(|e| !e.met()) {
+            let what = if e.forbid { "unexpected" } else { "unmet" };
+            w.write_fmt(format_args!("{}\t{}\n", what, e.line))?;
+            ok = false;
+        }
+        if let Some(status) = self.status.filter(|s| !s.success()) {
+            w.write_fmt(format_args!("command failed\t{}\n", status))?;
+            ok = false;
+        }
+        Ok(ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(mask: u64, path: &str) -> EventEntry {
+        EventEntry {
+            pid: None,
+            ..EventEntry::test(mask, path)
+        }
+    }
+
+    #[test]
+    fn expect_match() -> io::Result<()> {
+        let mut list = parse(
+            "# comment\n\
+             /src/*.c FAN_OPEN|FAN_CLOSE_NOWRITE\n\
+             /out/*\n\
+             !/etc/* FAN_MODIFY\n\
+             /my dir/f\n",
+        )?;
+        assert_eq!(list.len(), 4);
+        assert_eq!(list[3].rule.glob.as_bytes(), b"/my dir/f");
+
+        for e in &[
+            entry(libc::FAN_OPEN, "/src/a/b.c"),
+            entry(libc::FAN_CLOSE_NOWRITE, "/src/a.c"),
+            entry(libc::FAN_ACCESS, "/etc/passwd"),
+        ] {
+            for x in &mut list {
+                if x.matches(e) {
+                    x.seen |= e.mask;
+                }
+            }
+        }
+        let met: Vec<bool> = list.iter().map(|e| e.met()).collect();
+        assert_eq!(met, vec![true, false, true, false]);
+
+        assert!(list[2].matches(&entry(libc::FAN_MODIFY, "/etc/x")));
+
+        assert!(parse("/a FAN_BOGUS").is_err());
+        Ok(())
+    }
+}
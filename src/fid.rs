@@ -0,0 +1,326 @@
+use std::convert::TryInto;
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::PathBuf;
+use std::slice;
+
+// fanotify_event_info_header, then the fsid
+const INFO_HEADER_LEN: usize = 4;
+const FSID_LEN: usize = 8;
+// file_handle without f_handle
+const HANDLE_HEADER_LEN: usize = 8;
+const MAX_HANDLE_SZ: usize = 128;
+
+fn fsid_of(fd: RawFd) -> io::Result<[u8; FSID_LEN]> {
+    let mut st: libc::statfs = unsafe { mem::zeroed() };
+    if unsafe { libc::fstatfs(fd, &mut st) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { mem::transmute::<libc::fsid_t, [u8; FSID_LEN]>(st.f_fsid) })
+}
+
+/// The handle of `fd` as fanotify reports it.
+fn handle_of(fd: RawFd) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u64; (HANDLE_HEADER_LEN + MAX_HANDLE_SZ) / 8];
+    let fh = buf.as_mut_ptr() as *mut libc::file_handle;
+    unsafe { (*fh).handle_bytes = MAX_HANDLE_SZ as u32 };
+    let mut mount_id = 0;
+    let empty = CString::default();
+    if unsafe {
+        libc::name_to_handle_at(fd, empty.as_ptr(), fh, &mut mount_id, libc::AT_EMPTY_PATH)
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    let len = HANDLE_HEADER_LEN + unsafe { (*fh).handle_bytes } as usize;
+    Ok(unsafe { slice::from_raw_parts(buf.as_ptr() as *const u8, len) }.to_vec())
+}
+
+/// An info record following an event's metadata.
+#[derive(Debug, PartialEq)]
+struct Fid<'a> {
+    info_type: u8,
+    fsid: &'a [u8],
+    /// a struct file_handle
+    handle: &'a [u8],
+    /// the entry in the directory for the DFID_NAME types
+    name: Option<&'a [u8]>,
+}
+
+/// The type and body of each info record in `info`.
+fn records(mut info: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    std::iter::from_fn(move || {
+        if info.len() < INFO_HEADER_LEN {
+            return None;
+        }
+        let info_type = info[0];
+        let len = u16::from_ne_bytes([info[2], info[3]]) as usize;
+        if len < INFO_HEADER_LEN || len > info.len() {
+            return None;
+        }
+        let record = &info[INFO_HEADER_LEN..len];
+        info = &info[len..];
+        Some((info_type, record))
+    })
+}
+
+/// The pidfd of the process behind the event, from FAN_REPORT_PIDFD. The
+/// caller owns it.
+pub fn pidfd(info: &[u8]) -> Option<RawFd> {
+    let (_, record) = records(info).find(|(t, _)| *t == libc::FAN_EVENT_INFO_TYPE_PIDFD)?;
+    let fd = RawFd::from_ne_bytes(record.get(..4)?.try_into().ok()?);
+    // FAN_NOPIDFD or FAN_EPIDFD if the process is gone or it failed
+    if fd < 0 {
+        None
+    } else {
+        Some(fd)
+    }
+}
+
+/// The FID, DFID and the various DFID_NAME records in `info`.
+fn parse_info(info: &[u8]) -> Vec<Fid<'_>> {
+    let mut fids = Vec::new();
+    for (info_type, record) in records(info) {
+        if ![
+            libc::FAN_EVENT_INFO_TYPE_FID,
+            libc::FAN_EVENT_INFO_TYPE_DFID,
+            libc::FAN_EVENT_INFO_TYPE_DFID_NAME,
+            libc::FAN_EVENT_INFO_TYPE_OLD_DFID_NAME,
+            libc::FAN_EVENT_INFO_TYPE_NEW_DFID_NAME,
+        ]
+        .contains(&info_type)
+        {
+            continue;
+        }
+        let parse = || {
+            let fsid = record.get(..FSID_LEN)?;
+            let rest = record.get(FSID_LEN..)?;
+            let bytes = u32::from_ne_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+            let handle = rest.get(..HANDLE_HEADER_LEN + bytes)?;
+            let name = if info_type != libc::FAN_EVENT_INFO_TYPE_FID
+                && info_type != libc::FAN_EVENT_INFO_TYPE_DFID
+            {
+                // nul terminated, then padding
+                let name = &rest[handle.len()..];
+                Some(&name[..name.iter().position(|&b| b == 0)?])
+            } else {
+                None
+            };
+            Some(Fid {
+                info_type,
+                fsid,
+                handle,
+                name,
+            })
+        };
+        fids.extend(parse());
+    }
+    fids
+}
+
+/// Turns the file handles of FAN_REPORT_FID events back into paths, using
+/// an fd on each filesystem we watch.
+pub struct FidResolver {
+    mounts: Vec<([u8; FSID_LEN], File)>,
+    /// the handles of the marked paths, which we can resolve even when
+    /// open_by_handle_at isn't allowed
+    known: Vec<([u8; FSID_LEN], Vec<u8>, PathBuf)>,
+}
+
+impl FidResolver {
+    pub fn new(paths: &[CString], dirfd: RawFd) -> io::Result<FidResolver> {
+        let mut mounts = Vec::new();
+        let mut known = Vec::new();
+        for path in paths {
+            let fd =
+                unsafe { libc::openat(dirfd, path.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let f = unsafe { File::from_raw_fd(fd) };
+            let fsid = fsid_of(f.as_raw_fd())?;
+            match handle_of(f.as_raw_fd()) {
+                Ok(handle) => {
+                    let path = fs::read_link(format!("/proc/self/fd/{}", f.as_raw_fd()))?;
+                    known.push((fsid, handle, path));
+                }
+                Err(e) => debug!("name_to_handle_at {:?}: {}", path, e),
+            }
+            mounts.push((fsid, f));
+        }
+        Ok(FidResolver { mounts, known })
+    }
+
+    fn open(&self, fid: &Fid) -> Option<PathBuf> {
+        if let Some((_, _, path)) = self
+            .known
+            .iter()
+            .find(|(id, handle, _)| id[..] == *fid.fsid && handle[..] == *fid.handle)
+        {
+            return Some(path.clone());
+        }
+        let (_, mount) = self.mounts.iter().find(|(id, _)| id[..] == *fid.fsid)?;
+        let f = self.open_handle(mount, fid)?;
+        fs::read_link(format!("/proc/self/fd/{}", f.as_raw_fd())).ok()
+    }
+
+    fn open_handle(&self, mount: &File, fid: &Fid) -> Option<File> {
+        // copy it out for alignment
+        let mut buf = vec![0u64; fid.handle.len().div_ceil(8)];
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                fid.handle.as_ptr(),
+                buf.as_mut_ptr() as *mut u8,
+                fid.handle.len(),
+            )
+        };
+        let fd = unsafe {
+            libc::open_by_handle_at(
+                mount.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::file_handle,
+                libc::O_PATH | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            debug!("open_by_handle_at: {}", io::Error::last_os_error());
+            return None;
+        }
+        Some(unsafe { File::from_raw_fd(fd) })
+    }
+
+    fn dir_and_name(&self, fid: &Fid) -> Option<PathBuf> {
+        let dir = self.open(fid)?;
+        match fid.name {
+            Some(name) if name != b"." => Some(dir.join(OsStr::from_bytes(name))),
+            _ => Some(dir),
+        }
+    }
+
+    /// The path of the object in the event, None if it's already gone or
+    /// on a filesystem we don't know. For FAN_RENAME that's the old path.
+    pub fn resolve(&self, info: &[u8]) -> Option<PathBuf> {
+        let fids = parse_info(info);
+
+        // the directory and name say what the event was about, the object
+        // itself may have been moved or deleted since
+        if let Some(fid) = fids.iter().find(|f| {
+            f.info_type != libc::FAN_EVENT_INFO_TYPE_FID
+                && f.info_type != libc::FAN_EVENT_INFO_TYPE_NEW_DFID_NAME
+        }) {
+            if let Some(path) = self.dir_and_name(fid) {
+                return Some(path);
+            }
+        }
+        if fids
+            .iter()
+            .any(|f| f.info_type == libc::FAN_EVENT_INFO_TYPE_NEW_DFID_NAME)
+        {
+            // renamed from a directory we don't watch, the object's own
+            // handle would only tell us where it is now
+            return None;
+        }
+        // or a directory we can't open, the object may be a marked file
+        fids.iter()
+            .find(|f| f.info_type == libc::FAN_EVENT_INFO_TYPE_FID)
+            .and_then(|fid| self.open(fid))
+    }
+
+    /// The device and inode number of the object in the event. Once it's
+    /// gone, only handles that are just the inode and generation can tell.
+    pub fn inode(&self, info: &[u8]) -> Option<(u64, u64)> {
+        let fids = parse_info(info);
+        if let Some(fid) = fids
+            .iter()
+            .find(|f| f.info_type == libc::FAN_EVENT_INFO_TYPE_FID)
+        {
+            let (_, mount) = self.mounts.iter().find(|(id, _)| id[..] == *fid.fsid)?;
+            if let Some(f) = self.open_handle(mount, fid) {
+                let m = f.metadata().ok()?;
+                return Some((m.dev(), m.ino()));
+            }
+            // FILEID_INO32_GEN, used by ext4 and others
+            let handle_type = i32::from_ne_bytes(fid.handle.get(4..8)?.try_into().ok()?);
+            if handle_type == 1 && fid.handle.len() == HANDLE_HEADER_LEN + 8 {
+                let ino = u32::from_ne_bytes(fid.handle[8..12].try_into().ok()?);
+                return Some((mount.metadata().ok()?.dev(), ino.into()));
+            }
+            return None;
+        }
+
+        // only the directory and name, the entry has to still be there
+        let fid = fids.iter().find(|f| {
+            f.info_type != libc::FAN_EVENT_INFO_TYPE_FID
+                && f.info_type != libc::FAN_EVENT_INFO_TYPE_NEW_DFID_NAME
+        })?;
+        let (_, mount) = self.mounts.iter().find(|(id, _)| id[..] == *fid.fsid)?;
+        let dir = self.open_handle(mount, fid)?;
+        let name = CString::new(fid.name.unwrap_or(b".")).ok()?;
+        let mut st: libc::stat = unsafe { mem::zeroed() };
+        let flags = libc::AT_SYMLINK_NOFOLLOW | libc::AT_EMPTY_PATH;
+        if unsafe { libc::fstatat(dir.as_raw_fd(), name.as_ptr(), &mut st, flags) } < 0 {
+            return None;
+        }
+        Some((st.st_dev, st.st_ino))
+    }
+
+    /// Where FAN_RENAME moved the object to.
+    pub fn resolve_target(&self, info: &[u8]) -> Option<PathBuf> {
+        parse_info(info)
+            .iter()
+            .find(|f| f.info_type == libc::FAN_EVENT_INFO_TYPE_NEW_DFID_NAME)
+            .and_then(|fid| self.dir_and_name(fid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(info_type: u8, fsid: u8, handle: &[u8], name: &[u8]) -> Vec<u8> {
+        let mut r = vec![info_type, 0, 0, 0];
+        r.extend_from_slice(&[fsid; 8]);
+        r.extend_from_slice(&(handle.len() as u32).to_ne_bytes());
+        r.extend_from_slice(&1i32.to_ne_bytes());
+        r.extend_from_slice(handle);
+        r.extend_from_slice(name);
+        let len = r.len() as u16;
+        r[2..4].copy_from_slice(&len.to_ne_bytes());
+        r
+    }
+
+    #[test]
+    fn fid_parse() {
+        let mut info = vec![];
+        // some other record first
+        info.extend_from_slice(&[libc::FAN_EVENT_INFO_TYPE_PIDFD, 0, 8, 0, 1, 2, 3, 4]);
+        info.extend(record(
+            libc::FAN_EVENT_INFO_TYPE_DFID_NAME,
+            9,
+            &[7; 4],
+            b"new\0\0\0",
+        ));
+        info.extend(record(libc::FAN_EVENT_INFO_TYPE_FID, 8, &[6; 4], b""));
+
+        let fids = parse_info(&info);
+        assert_eq!(fids.len(), 2);
+        assert_eq!(fids[0].fsid, &[9; 8]);
+        assert_eq!(&fids[0].handle[8..], &[7; 4]);
+        assert_eq!(fids[0].name, Some(&b"new"[..]));
+        assert_eq!(fids[1].info_type, libc::FAN_EVENT_INFO_TYPE_FID);
+        assert_eq!(fids[1].name, None);
+
+        assert!(parse_info(&info[..10]).is_empty());
+        assert_eq!(pidfd(&info), Some(i32::from_ne_bytes([1, 2, 3, 4])));
+        assert_eq!(pidfd(&info[8..]), None);
+
+        let gone = [libc::FAN_EVENT_INFO_TYPE_PIDFD, 0, 8, 0];
+        let mut info = gone.to_vec();
+        info.extend_from_slice(&libc::FAN_NOPIDFD.to_ne_bytes());
+        assert_eq!(pidfd(&info), None);
+    }
+}
@@ -0,0 +1,554 @@
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, OsStr};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
+
+use crate::enrich::{self, Procs};
+use crate::flags::Opt;
+
+// processes to remember the lineage of, they're forgotten all at once past
+// this
+const TREE_LEN: usize = 10000;
+
+fn fnmatch(glob: &CStr, path: &CStr) -> bool {
+    unsafe { libc::fnmatch(glob.as_ptr(), path.as_ptr(), 0) == 0 }
+}
+
+/// A POSIX extended regular expression, found anywhere in the path unless
+/// anchored.
+struct Regex(Box<libc::regex_t>);
+
+impl Regex {
+    fn new(re: &str) -> io::Result<Regex> {
+        let invalid =
+            |msg: String| io::Error::new(ErrorKind::InvalidInput, format!("{}: {}", re, msg));
+        let c = CString::new(re).map_err(|e| invalid(e.to_string()))?;
+        let mut regex = Box::new(unsafe { mem::zeroed::<libc::regex_t>() });
+        let ret = unsafe {
+            libc::regcomp(
+                &mut *regex,
+                c.as_ptr(),
+                libc::REG_EXTENDED | libc::REG_NOSUB,
+            )
+        };
+        if ret != 0 {
+            let mut buf = [0 as libc::c_char; 256];
+            unsafe { libc::regerror(ret, &*regex, buf.as_mut_ptr(), buf.len()) };
+            let msg = unsafe { CStr::from_ptr(buf.as_ptr()) };
+            return Err(invalid(msg.to_string_lossy().into_owned()));
+        }
+        Ok(Regex(regex))
+    }
+
+    fn is_match(&self, s: &CStr) -> bool {
+        unsafe { libc::regexec(&*self.0, s.as_ptr(), 0, ptr::null_mut(), 0) == 0 }
+    }
+}
+
+impl Drop for Regex {
+    fn drop(&mut self) {
+        unsafe { libc::regfree(&mut *self.0) };
+    }
+}
+
+/// The uid of user `name`, or gid of group `name` with `group`, or the
+/// number itself.
+fn id(name: &str, group: bool) -> io::Result<u32> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let c = CString::new(name).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+    // before any other threads look up names
+    let id = unsafe {
+        if group {
+            libc::getgrnam(c.as_ptr()).as_ref().map(|gr| gr.gr_gid)
+        } else {
+            libc::getpwnam(c.as_ptr()).as_ref().map(|pw| pw.pw_uid)
+        }
+    };
+    id.ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::NotFound,
+            format!("no {} {}", if group { "group" } else { "user" }, name),
+        )
+    })
+}
+
+/// The parent and start time, in clock ticks since boot, of `pid`.
+fn parent(pid: u32) -> Option<(u32, u64)> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // past the comm, from the 3rd field, state, to the 22nd, starttime
+    let mut fields = stat.rsplit_once(')')?.1.split_whitespace();
+    let ppid = fields.nth(1)?.parse().ok()?;
+    let start = fields.nth(17)?.parse().ok()?;
+    Some((ppid, start))
+}
+
+/// A process and those under it, found by walking up the parents of each,
+/// and remembered by start time as they lose their ancestry once their
+/// parent exits and they're reparented.
+struct Tree {
+    root: u32,
+    /// the pids of events are thread ids
+    tid: bool,
+    known: HashMap<(u32, u64), bool>,
+}
+
+impl Tree {
+    fn contains(&mut self, pid: u32) -> bool {
+        if pid == self.root {
+            return true;
+        }
+        // its parent is the root's
+        if self.tid && Path::new(&format!("/proc/{}/task/{}", self.root, pid)).exists() {
+            return true;
+        }
+        let mut walked = Vec::new();
+        let mut pid = pid;
+        let found = loop {
+            let (ppid, start) = match parent(pid) {
+                Some(p) => p,
+                None => break false,
+            };
+            if let Some(&known) = self.known.get(&(pid, start)) {
+                break known;
+            }
+            walked.push((pid, start));
+            if ppid == self.root {
+                break true;
+            }
+            if ppid <= 1 {
+                break false;
+            }
+            pid = ppid;
+        };
+        if self.known.len() + walked.len() > TREE_LEN {
+            self.known.clear();
+        }
+        for key in walked {
+            self.known.insert(key, found);
+        }
+        found
+    }
+}
+
+/// Which events to report by their path, for what the marks can't tell
+/// apart.
+#[derive(Default)]
+pub struct Filter {
+    /// the paths of -r, a mount or filesystem mark reaches beyond them
+    under: Vec<PathBuf>,
+    include: Vec<CString>,
+    exclude: Vec<CString>,
+    include_re: Vec<Regex>,
+    exclude_re: Vec<Regex>,
+    exts: Vec<String>,
+    exclude_exts: Vec<String>,
+    pids: Vec<u32>,
+    ignore_pids: Vec<u32>,
+    follow: Option<Tree>,
+    uids: Vec<u32>,
+    ignore_uids: Vec<u32>,
+    gids: Vec<u32>,
+    ignore_gids: Vec<u32>,
+    comms: Vec<CString>,
+    exes: Vec<CString>,
+    /// the S_IFMT of each --type
+    types: Vec<libc::mode_t>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    /// our own pid, if our events are to be dropped
+    me: Option<u32>,
+    /// whether we lead our process group, so it's what we run and pipe into
+    leader: bool,
+    /// the pids of events are thread ids
+    tid: bool,
+}
+
+impl Filter {
+    pub fn new(opt: &Opt) -> io::Result<Filter> {
+        let compile = |res: &[String]| -> io::Result<Vec<Regex>> {
+            res.iter().map(|re| Regex::new(re)).collect()
+        };
+        let exts = |lists: &[String]| -> Vec<String> {
+            lists
+                .iter()
+                .flat_map(|l| l.split(','))
+                .map(|e| e.trim_start_matches('.').to_string())
+                .collect()
+        };
+        let ids = |names: &[String], group| -> io::Result<Vec<u32>> {
+            names.iter().map(|name| id(name, group)).collect()
+        };
+        Ok(Filter {
+            // the paths are theirs, not ours, in another namespace
+            under: if opt.recursive && opt.namespace.is_none() {
+                opt.paths
+                    .iter()
+                    .map(|p| OsStr::from_bytes(p.as_bytes()).into())
+                    .collect()
+            } else {
+                Vec::new()
+            },
+            include: opt.include.clone(),
+            exclude: opt.exclude.clone(),
+            include_re: compile(&opt.include_re)?,
+            exclude_re: compile(&opt.exclude_re)?,
+            exts: exts(&opt.ext),
+            exclude_exts: exts(&opt.exclude_ext),
+            pids: opt.pids.clone(),
+            ignore_pids: opt.ignore_pid.clone(),
+            follow: opt.follow_pid.map(|root| Tree {
+                root,
+                tid: opt.tid,
+                known: HashMap::new(),
+            }),
+            uids: ids(&opt.uid, false)?,
+            ignore_uids: ids(&opt.ignore_uid, false)?,
+            gids: ids(&opt.gid, true)?,
+            ignore_gids: ids(&opt.ignore_gid, true)?,
+            comms: opt.comm.clone(),
+            exes: opt.exe.clone(),
+            types: opt
+                .types
+                .iter()
+                .map(|t| match t {
+                    'd' => libc::S_IFDIR,
+                    'l' => libc::S_IFLNK,
+                    _ => libc::S_IFREG,
+                })
+                .collect(),
+            min_size: opt.min_size,
+            max_size: opt.max_size,
+            me: if opt.no_ignore_self {
+                None
+            } else {
+                Some(process::id())
+            },
+            // what --run runs is what's watched
+            leader: opt.run.is_none() && unsafe { libc::getpgrp() } as u32 == process::id(),
+            tid: opt.tid,
+        })
+    }
+
+    /// Whether `pid` is us, or in the process group we lead, and our
+    /// events are to be dropped.
+    pub fn ours(&self, pid: u32) -> bool {
+        let me = match self.me {
+            Some(me) => me,
+            None => return false,
+        };
+        pid == me
+            || (self.tid && Path::new(&format!("/proc/self/task/{}", pid)).exists())
+            || (self.leader && unsafe { libc::getpgid(pid as libc::pid_t) } == me as libc::pid_t)
+    }
+
+    /// Whether `pid` runs one of the commands or executables, if any, with
+    /// what it's found to be left in `procs` for Step::Proc.
+    fn command(&self, pid: u32, procs: &mut Procs) -> bool {
+        if self.comms.is_empty() && self.exes.is_empty() {
+            return true;
+        }
+        let (comm, exe) = match procs.comm_exe(pid) {
+            Ok(p) => p,
+            Err(_) => return false,
+        };
+        let matches = |globs: &[CString], s: String| {
+            globs.is_empty() || CString::new(s).is_ok_and(|s| globs.iter().any(|g| fnmatch(g, &s)))
+        };
+        matches(&self.comms, comm) && matches(&self.exes, exe)
+    }
+
+    /// Whether to report the events of `pid`, before looking any further,
+    /// other than our own.
+    pub fn pid(&mut self, pid: u32, procs: &mut Procs) -> bool {
+        if !self.pids.is_empty() && !self.pids.contains(&pid) {
+            return false;
+        }
+        if self.ignore_pids.contains(&pid) {
+            return false;
+        }
+        if let Some(tree) = &mut self.follow {
+            if !tree.contains(pid) {
+                return false;
+            }
+        }
+        if !self.command(pid, procs) {
+            return false;
+        }
+        if self.uids.is_empty()
+            && self.ignore_uids.is_empty()
+            && self.gids.is_empty()
+            && self.ignore_gids.is_empty()
+        {
+            return true;
+        }
+        // gone, so it matches none of them
+        let (uid, gid) = fs::read_to_string(format!("/proc/{}/status", pid))
+            .ok()
+            .and_then(|status| enrich::parse_status(&status))
+            .map_or((None, None), |(uid, gid)| (Some(uid), Some(gid)));
+        let included =
+            |ids: &[u32], id: Option<u32>| ids.is_empty() || id.is_some_and(|id| ids.contains(&id));
+        let excluded = |ids: &[u32], id: Option<u32>| id.is_some_and(|id| ids.contains(&id));
+        included(&self.uids, uid)
+            && included(&self.gids, gid)
+            && !excluded(&self.ignore_uids, uid)
+            && !excluded(&self.ignore_gids, gid)
+    }
+
+    /// Whether to report an event of `mask` on what `fd` is open on, by
+    /// its type and size, or without an fd, by whether it's on a directory
+    /// alone.
+    pub fn file(&self, fd: RawFd, mask: u64) -> bool {
+        if (self.types.is_empty() && self.min_size.is_none() && self.max_size.is_none())
+            || mask & libc::FAN_Q_OVERFLOW != 0
+        {
+            return true;
+        }
+        if fd >= 0 {
+            let mut st = unsafe { mem::zeroed::<libc::stat>() };
+            if unsafe { libc::fstat(fd, &mut st) } < 0 {
+                // can't tell, so don't hide it
+                return true;
+            }
+            let size = st.st_size as u64;
+            (self.types.is_empty() || self.types.contains(&(st.st_mode & libc::S_IFMT)))
+                && self.min_size.map_or(true, |min| size >= min)
+                && self.max_size.map_or(true, |max| size <= max)
+        } else if self.types.is_empty() {
+            true
+        } else if mask & libc::FAN_ONDIR != 0 {
+            self.types.contains(&libc::S_IFDIR)
+        } else {
+            self.types.iter().any(|&t| t != libc::S_IFDIR)
+        }
+    }
+
+    /// Whether to report the events on `path`.
+    pub fn path(&self, path: &Path) -> bool {
+        if !self.under.is_empty() && !self.under.iter().any(|p| path.starts_with(p)) {
+            return false;
+        }
+        if !self.exts.is_empty() || !self.exclude_exts.is_empty() {
+            // of the basename, so .bashrc has none
+            let ext = path.extension().map(OsStr::as_bytes);
+            let any = |exts: &[String]| ext.is_some_and(|e| exts.iter().any(|x| x.as_bytes() == e));
+            if (!self.exts.is_empty() && !any(&self.exts)) || any(&self.exclude_exts) {
+                return false;
+            }
+        }
+        if self.include.is_empty()
+            && self.exclude.is_empty()
+            && self.include_re.is_empty()
+            && self.exclude_re.is_empty()
+        {
+            return true;
+        }
+        let path = match CString::new(path.as_os_str().as_bytes()) {
+            Ok(path) => path,
+            Err(_) => return false,
+        };
+        (self.include.is_empty() || self.include.iter().any(|g| fnmatch(g, &path)))
+            && !self.exclude.iter().any(|g| fnmatch(g, &path))
+            && (self.include_re.is_empty() || self.include_re.iter().any(|r| r.is_match(&path)))
+            && !self.exclude_re.iter().any(|r| r.is_match(&path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn path_globs() {
+        let glob = |s: &str| CString::new(s).unwrap();
+        let mut filter = Filter::default();
+        assert!(filter.path(Path::new("/etc/passwd")));
+
+        filter.under = vec!["/etc".into(), "/home".into()];
+        assert!(filter.path(Path::new("/etc/passwd")));
+        assert!(filter.path(Path::new("/home/a/.bashrc")));
+        assert!(!filter.path(Path::new("/etcetera")));
+
+        filter.include = vec![glob("/home/*"), glob("*.conf")];
+        filter.exclude = vec![glob("*/.cache/*")];
+        assert!(filter.path(Path::new("/home/a/.bashrc")));
+        assert!(filter.path(Path::new("/etc/ld.so.conf")));
+        assert!(!filter.path(Path::new("/etc/passwd")));
+        assert!(!filter.path(Path::new("/home/a/.cache/x")));
+    }
+
+    #[test]
+    fn pids() {
+        let me = process::id();
+        let mut procs = Procs::default();
+        let mut filter = Filter::default();
+        assert!(filter.pid(me, &mut procs));
+        assert!(!filter.ours(me));
+
+        filter.me = Some(me);
+        assert!(filter.ours(me));
+        assert!(!filter.ours(1));
+        assert!(filter.pid(1, &mut procs));
+
+        filter.ignore_pids = vec![1];
+        assert!(!filter.pid(1, &mut procs));
+        filter.pids = vec![1, 2];
+        assert!(!filter.pid(3, &mut procs));
+        assert!(filter.pid(2, &mut procs));
+    }
+
+    #[test]
+    fn uids() -> io::Result<()> {
+        assert_eq!(id("0", false)?, 0);
+        assert_eq!(id("root", false)?, 0);
+        assert_eq!(id("root", true)?, 0);
+        assert!(id("no such user", false).is_err());
+
+        let me = process::id();
+        let euid = unsafe { libc::geteuid() };
+        let mut procs = Procs::default();
+        let mut filter = Filter::default();
+        filter.uids = vec![euid];
+        assert!(filter.pid(me, &mut procs));
+        filter.ignore_uids = vec![euid];
+        assert!(!filter.pid(me, &mut procs));
+        filter.ignore_uids.clear();
+        filter.gids = vec![unsafe { libc::getegid() } + 1];
+        assert!(!filter.pid(me, &mut procs));
+        // past the largest pid there can be, as if it exited
+        filter.gids.clear();
+        assert!(!filter.pid(1 << 22 | 1, &mut procs));
+        Ok(())
+    }
+
+    #[test]
+    fn follow_tree() {
+        let me = process::id();
+        let (ppid, start) = parent(me).unwrap();
+        assert_eq!(ppid, unsafe { libc::getppid() } as u32);
+        assert!(start > 0);
+
+        let mut child = process::Command::new("sleep").arg("10").spawn().unwrap();
+        let mut tree = Tree {
+            root: me,
+            tid: false,
+            known: HashMap::new(),
+        };
+        assert!(tree.contains(me));
+        assert!(tree.contains(child.id()));
+        assert!(!tree.contains(ppid));
+        assert!(!tree.contains(1));
+        let (_, child_start) = parent(child.id()).unwrap();
+        assert_eq!(tree.known.get(&(child.id(), child_start)), Some(&true));
+        let (_, parent_start) = parent(ppid).unwrap();
+        assert_eq!(tree.known.get(&(ppid, parent_start)), Some(&false));
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn commands() {
+        let glob = |s: &str| CString::new(s).unwrap();
+        let me = process::id();
+        let mut procs = Procs::default();
+        let (comm, _) = procs.comm_exe(me).unwrap();
+        let mut filter = Filter::default();
+        filter.comms = vec![glob("nothing-runs-this"), glob(&comm)];
+        assert!(filter.pid(me, &mut procs));
+        filter.exes = vec![glob("/nowhere/*")];
+        assert!(!filter.pid(me, &mut procs));
+        filter.comms.clear();
+        filter.exes = vec![glob("/*")];
+        assert!(filter.pid(me, &mut procs));
+        assert!(!filter.pid(1 << 22 | 1, &mut procs));
+    }
+
+    #[test]
+    fn types() -> io::Result<()> {
+        let tmp = TempDir::new("types")?;
+        let path = tmp.join("file");
+        let file = fs::File::create(&path)?;
+        fs::remove_file(&path)?;
+        let dir = fs::File::open(&*tmp)?;
+        let mut filter = Filter::default();
+        assert!(filter.file(dir.as_raw_fd(), libc::FAN_OPEN));
+
+        filter.types = vec![libc::S_IFREG];
+        assert!(filter.file(file.as_raw_fd(), libc::FAN_OPEN));
+        assert!(!filter.file(dir.as_raw_fd(), libc::FAN_OPEN | libc::FAN_ONDIR));
+        assert!(!filter.file(-1, libc::FAN_CREATE | libc::FAN_ONDIR));
+        assert!(filter.file(-1, libc::FAN_CREATE));
+        assert!(filter.file(-1, libc::FAN_Q_OVERFLOW));
+
+        filter.types = vec![libc::S_IFDIR];
+        assert!(!filter.file(file.as_raw_fd(), libc::FAN_OPEN));
+        assert!(filter.file(dir.as_raw_fd(), libc::FAN_OPEN));
+        assert!(filter.file(-1, libc::FAN_CREATE | libc::FAN_ONDIR));
+        assert!(!filter.file(-1, libc::FAN_CREATE));
+        Ok(())
+    }
+
+    #[test]
+    fn sizes() -> io::Result<()> {
+        let dir = TempDir::new("sizes")?;
+        let path = dir.join("file");
+        let mut file = fs::File::create(&path)?;
+        fs::remove_file(&path)?;
+        file.write_all(b"0123456789")?;
+        let mut filter = Filter::default();
+        filter.min_size = Some(10);
+        assert!(filter.file(file.as_raw_fd(), libc::FAN_MODIFY));
+        filter.min_size = Some(11);
+        assert!(!filter.file(file.as_raw_fd(), libc::FAN_MODIFY));
+        // can't tell without an fd
+        assert!(filter.file(-1, libc::FAN_MODIFY));
+
+        filter.min_size = None;
+        filter.max_size = Some(9);
+        assert!(!filter.file(file.as_raw_fd(), libc::FAN_MODIFY));
+        filter.max_size = Some(10);
+        assert!(filter.file(file.as_raw_fd(), libc::FAN_MODIFY));
+        Ok(())
+    }
+
+    #[test]
+    fn extensions() {
+        let mut filter = Filter::default();
+        filter.exts = vec!["log".into(), "gz".into()];
+        assert!(filter.path(Path::new("/var/log/syslog.log")));
+        assert!(filter.path(Path::new("/var/log/syslog.2.gz")));
+        assert!(!filter.path(Path::new("/var/log/syslog")));
+        assert!(!filter.path(Path::new("/home/a/.log")));
+        assert!(!filter.path(Path::new("/var/log.d/syslog")));
+
+        filter.exts.clear();
+        filter.exclude_exts = vec!["swp".into()];
+        assert!(filter.path(Path::new("/home/a/notes")));
+        assert!(!filter.path(Path::new("/home/a/.notes.swp")));
+    }
+
+    #[test]
+    fn path_regexes() -> io::Result<()> {
+        assert!(Regex::new("(").is_err());
+
+        let mut filter = Filter::default();
+        filter.include_re = vec![Regex::new(r"\.(key|pem)$")?];
+        filter.exclude_re = vec![Regex::new("^/usr/")?];
+        assert!(filter.path(Path::new("/etc/ssl/private/a.key")));
+        assert!(filter.path(Path::new("/home/a/b.pem")));
+        assert!(!filter.path(Path::new("/home/a/b.pem.bak")));
+        assert!(!filter.path(Path::new("/usr/share/a.pem")));
+        Ok(())
+    }
+}
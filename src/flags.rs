@@ -1,20 +1,176 @@
+use std::env;
 use std::ffi::{CString, OsStr, OsString};
 use std::fmt::Debug;
 use std::fs;
 use std::io::{self, ErrorKind};
 use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
 use structopt::StructOpt;
 
+use crate::clock::{self, Timestamp};
+use crate::color::Color;
+use crate::csv::Format;
+use crate::decider::OnFailure;
+use crate::enrich::{Pipeline, Step};
+use crate::hasher::HashSpec;
+use crate::inotifywait;
+use crate::logfile::Rotate;
+use crate::policy::Action;
+use crate::ratelimit::OverLimit;
+use crate::sink::Output;
+use crate::throttle::{OverRate, RateBy};
+use crate::{parse_mask, FanResponse, DIRENT_EVENTS, PERM_EVENTS};
+
 fn cstring_from_os_str(src: &OsStr) -> Result<CString, OsString> {
     return CString::new(src.to_os_string().into_vec())
         .map_err(|e| OsString::from(format!("unexpected \\0 at pos {}", e.nul_position())));
 }
 
 #[derive(Debug, StructOpt)]
-#[structopt(about)]
+pub enum Command {
+    /// write a systemd unit that runs fanotify-cli with the flags after --
+    InstallService {
+        /// name of the unit and its config file
+        #[structopt(long, default_value = "fanotify-cli")]
+        name: String,
+
+        #[structopt(long, default_value = "/etc/systemd/system", parse(from_os_str))]
+        unit_dir: PathBuf,
+
+        #[structopt(long, default_value = "/etc/fanotify-cli", parse(from_os_str))]
+        config_dir: PathBuf,
+
+        /// also enable and start the unit
+        #[structopt(long)]
+        enable: bool,
+
+        #[structopt(parse(from_os_str))]
+        args: Vec<OsString>,
+    },
+
+    /// check that the events in -e come through for a scratch directory
+    Selftest,
+
+    /// print the events stored with --output sqlite:DB, oldest first
+    Query {
+        #[structopt(parse(from_os_str))]
+        db: PathBuf,
+
+        /// only events on paths matching this glob
+        #[structopt(long)]
+        path: Option<String>,
+
+        #[structopt(long)]
+        pid: Option<u32>,
+
+        /// only events by this command, needs --enrich proc when stored
+        #[structopt(long)]
+        comm: Option<String>,
+
+        /// only events with any of these, like FAN_MODIFY,FAN_CLOSE_WRITE
+        #[structopt(long)]
+        events: Option<String>,
+
+        /// only events since this time, as YYYY-MM-DDTHH:MM[:SS] or @EPOCH
+        #[structopt(long, parse(try_from_str = clock::parse_time))]
+        since: Option<SystemTime>,
+
+        /// only events before this time
+        #[structopt(long, parse(try_from_str = clock::parse_time))]
+        until: Option<SystemTime>,
+
+        /// only the latest this many events
+        #[structopt(long)]
+        limit: Option<u64>,
+    },
+
+    /// thaw the cgroups a freeze rule of --policy froze, like
+    /// /system.slice/nginx.service
+    Unfreeze {
+        #[structopt(parse(from_os_str), required = true)]
+        cgroups: Vec<PathBuf>,
+    },
+
+    /// check --policy without watching anything
+    Policy {
+        #[structopt(subcommand)]
+        cmd: PolicyCommand,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum PolicyCommand {
+    /// print the rule of --policy an event would match, and its verdict
+    Test {
+        #[structopt(long, parse(from_os_str))]
+        path: Option<PathBuf>,
+
+        #[structopt(long)]
+        pid: Option<u32>,
+
+        /// the effective uid of the process
+        #[structopt(long)]
+        uid: Option<u32>,
+
+        #[structopt(long)]
+        comm: Option<String>,
+
+        #[structopt(long, parse(from_os_str))]
+        exe: Option<PathBuf>,
+
+        /// like FAN_OPEN_EXEC_PERM
+        #[structopt(long, default_value = "FAN_OPEN_PERM")]
+        event: String,
+    },
+}
+
+/// Which fanotify class to initialize the group with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Class {
+    Notif,
+    Content,
+    PreContent,
+}
+
+impl FromStr for Class {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "notif" => Ok(Class::Notif),
+            "content" => Ok(Class::Content),
+            "pre-content" => Ok(Class::PreContent),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid value: {}, options: notif, content, pre-content", s),
+            )),
+        }
+    }
+}
+
+impl Class {
+    pub fn flag(self) -> libc::c_uint {
+        match self {
+            Class::Notif => libc::FAN_CLASS_NOTIF,
+            Class::Content => libc::FAN_CLASS_CONTENT,
+            Class::PreContent => libc::FAN_CLASS_PRE_CONTENT,
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    about,
+    after_help = "Run as inotifywait, or with --inotifywait first, to take inotifywait's \
+                  -m, -r, -q, -e, -t, -o, --format and --timefmt instead."
+)]
 pub struct Opt {
-    /// default: FAN_ACCESS,FAN_MODIFY,FAN_CLOSE_WRITE,FAN_CLOSE_NOWRITE,FAN_OPEN,FAN_ONDIR,FAN_EVENT_ON_CHILD
+    /// default: FAN_ACCESS,FAN_MODIFY,FAN_CLOSE_WRITE,FAN_CLOSE_NOWRITE,FAN_OPEN,FAN_ONDIR,FAN_EVENT_ON_CHILD,
+    /// also FAN_CLOSE, FAN_MOVE and FAN_PERM, or lowercase without FAN_ like open,close, or
+    /// raw bits like 0x20000
     #[structopt(short, long)]
     pub events: Option<String>,
 
@@ -22,6 +178,10 @@ pub struct Opt {
     #[structopt(short = "p", long = "process")]
     pub namespace: Option<u32>,
 
+    /// with -p, print paths as seen from here rather than from that process
+    #[structopt(long, requires = "namespace")]
+    pub host_paths: bool,
+
     /// recursively monitor everything under paths, implies -m unless -f is used
     #[structopt(short, long)]
     pub recursive: bool,
@@ -34,18 +194,831 @@ pub struct Opt {
     #[structopt(short, long)]
     pub filesystem: bool,
 
+    /// open event fds with these, O_RDWR gives content scanners fds they
+    /// can fix files through
+    #[structopt(long, default_value = "O_RDONLY")]
+    pub event_fflags: String,
+
+    /// open event fds with O_NOATIME, so reading them leaves atime alone
+    #[structopt(long)]
+    pub noatime: bool,
+
+    /// watch without CAP_SYS_ADMIN, as --fid with only the paths themselves
+    /// and their direct children, since 5.13
+    #[structopt(long)]
+    pub unprivileged: bool,
+
+    /// fail unless the paths are directories
+    #[structopt(long)]
+    pub onlydir: bool,
+
+    /// mark symlinks among the paths rather than what they point to
+    #[structopt(long)]
+    pub dont_follow: bool,
+
+    /// let the kernel evict the inodes we mark and the marks with them,
+    /// for marking many files without pinning them in memory
+    #[structopt(long)]
+    pub evictable: bool,
+
+    /// don't report events for this file or directory, or anything under
+    /// it, even after it's modified, can be repeated
+    #[structopt(long, number_of_values = 1, parse(try_from_os_str = cstring_from_os_str))]
+    pub ignore: Vec<CString>,
+
+    /// alert on any event for this path, or anything under it, which nothing
+    /// should touch, watching it whatever else is, can be repeated
+    #[structopt(long, number_of_values = 1, parse(try_from_os_str = cstring_from_os_str))]
+    pub canary: Vec<CString>,
+
+    /// only report events on paths that fnmatch this glob, where * matches
+    /// / too, so /home/* is everything under /home, can be repeated; the
+    /// permission events this and the filters below leave out are still
+    /// decided, only not reported
+    #[structopt(long, number_of_values = 1, parse(try_from_os_str = cstring_from_os_str))]
+    pub include: Vec<CString>,
+
+    /// don't report events on paths that fnmatch this glob, like *.swp,
+    /// can be repeated
+    #[structopt(long, number_of_values = 1, parse(try_from_os_str = cstring_from_os_str))]
+    pub exclude: Vec<CString>,
+
+    /// only report events on paths this POSIX extended regular expression
+    /// is found in, like "\.(key|pem)$", can be repeated
+    #[structopt(long, number_of_values = 1)]
+    pub include_re: Vec<String>,
+
+    /// don't report events on paths this POSIX extended regular expression
+    /// is found in, can be repeated
+    #[structopt(long, number_of_values = 1)]
+    pub exclude_re: Vec<String>,
+
+    /// only report events on files with one of these extensions, like
+    /// log,tmp, after the last . of the name, can be repeated
+    #[structopt(long, number_of_values = 1)]
+    pub ext: Vec<String>,
+
+    /// don't report events on files with one of these extensions, like
+    /// swp,part, can be repeated
+    #[structopt(long, number_of_values = 1)]
+    pub exclude_ext: Vec<String>,
+
+    /// only report events of this process, or thread with --tid, can be
+    /// repeated
+    #[structopt(long = "pid", number_of_values = 1)]
+    pub pids: Vec<u32>,
+
+    /// don't report events of this process, or thread with --tid, can be
+    /// repeated
+    #[structopt(long, number_of_values = 1)]
+    pub ignore_pid: Vec<u32>,
+
+    /// only report events of this process and those it starts, and those
+    /// they start, by their parents
+    #[structopt(long)]
+    pub follow_pid: Option<u32>,
+
+    /// only report events of processes with this effective user, by name
+    /// or number, can be repeated
+    #[structopt(long, number_of_values = 1)]
+    pub uid: Vec<String>,
+
+    /// don't report events of processes with this effective user, like
+    /// root, can be repeated
+    #[structopt(long, number_of_values = 1)]
+    pub ignore_uid: Vec<String>,
+
+    /// only report events of processes with this effective group, by name
+    /// or number, can be repeated
+    #[structopt(long, number_of_values = 1)]
+    pub gid: Vec<String>,
+
+    /// don't report events of processes with this effective group, can be
+    /// repeated
+    #[structopt(long, number_of_values = 1)]
+    pub ignore_gid: Vec<String>,
+
+    /// only report events of processes whose comm fnmatches this glob,
+    /// like nginx, can be repeated
+    #[structopt(long, number_of_values = 1, parse(try_from_os_str = cstring_from_os_str))]
+    pub comm: Vec<CString>,
+
+    /// only report events of processes whose executable fnmatches this
+    /// glob, like /usr/sbin/*, can be repeated
+    #[structopt(long, number_of_values = 1, parse(try_from_os_str = cstring_from_os_str))]
+    pub exe: Vec<CString>,
+
+    /// only report events on regular files, f, directories, d, or
+    /// symlinks, l, by fstat of the event fd, or by FAN_ONDIR without one,
+    /// which can't tell a file from a symlink, can be repeated
+    #[structopt(long = "type", number_of_values = 1, possible_values = &["f", "d", "l"])]
+    pub types: Vec<char>,
+
+    /// only report events on files of at least this many bytes, by fstat
+    /// of the event fd, so not without one
+    #[structopt(long)]
+    pub min_size: Option<u64>,
+
+    /// only report events on files of at most this many bytes
+    #[structopt(long)]
+    pub max_size: Option<u64>,
+
+    /// drop repeats of an event, the same events by the same process on the
+    /// same path, for this many milliseconds after it, and count them as
+    /// repeated=N on the next one after that, or on their own if none comes
+    #[structopt(long)]
+    pub dedup: Option<u64>,
+
+    /// report at most this many events a second, the rest dropped or
+    /// sampled by --rate-limit-action, permission events are still
+    /// answered, 0 is unlimited
+    #[structopt(long)]
+    pub max_events_per_sec: Option<u32>,
+
+    /// report at most N events a second of those on paths that fnmatch
+    /// GLOB and, if given, have any of MASK, the first of these an event
+    /// matches, like "100 /var/log/* FAN_MODIFY", can be repeated
+    #[structopt(long, number_of_values = 1, value_name = "N GLOB [MASK]")]
+    pub rate_limit: Vec<String>,
+
+    /// what to do with the events over --max-events-per-sec or
+    /// --rate-limit: drop the rest of each second, counted as dropped=N on
+    /// the next one, or sample them evenly, as sample_rate=1/N
+    #[structopt(long, default_value = "drop")]
+    pub rate_limit_action: OverLimit,
+
+    /// report events of fanotify-cli itself too, and of the process group
+    /// it leads, like the commands it runs other than --run and what its
+    /// output is piped into, which are dropped so as not to report on
+    /// ourselves in a loop
+    #[structopt(long)]
+    pub no_ignore_self: bool,
+
+    /// deny the permission events for --canary paths before anything else
+    /// answers them
+    #[structopt(long, requires = "canary")]
+    pub canary_deny: bool,
+
+    /// exit with an error after the first --canary event
+    #[structopt(long, requires = "canary")]
+    pub canary_exit: bool,
+
+    /// POST each --canary event to this http:// or https:// URL as JSON
+    /// as it happens
+    #[structopt(long, requires = "canary")]
+    pub canary_webhook: Option<String>,
+
+    /// ask the daemon listening on this unix:PATH to decide the permission
+    /// events --policy doesn't, sending lines of {"id", "mask", "path",
+    /// "pid", "comm"} JSON that it answers with {"id", "action":
+    /// "allow"|"deny"}, --overload-response while it's unreachable
+    #[structopt(long, conflicts_with = "grpc")]
+    pub decider: Option<String>,
+
+    /// when --decider disconnects, or stdin closes without one: allow or
+    /// deny the permission events it had and would have had, or exit after
+    /// answering the pending ones with --perm-default
+    #[structopt(long)]
+    pub on_decider_failure: Option<OnFailure>,
+
+    /// ask the gRPC service at this http:// URL to decide the permission
+    /// events --policy doesn't, with fanotify.Decider/Decide(Event{mask,
+    /// path, pid, comm}) returning Verdict{action: ALLOW|DENY}
+    #[structopt(long)]
+    pub grpc: Option<String>,
+
+    /// how long --grpc has to decide an event before it fails
+    #[structopt(long, default_value = "1000")]
+    pub grpc_timeout_ms: u64,
+
+    /// connections to --grpc to spread the calls over
+    #[structopt(long, default_value = "4")]
+    pub grpc_connections: usize,
+
+    /// deny the events --grpc fails to decide rather than allow them
+    #[structopt(long, requires = "grpc")]
+    pub grpc_fail_closed: bool,
+
+    /// pass each event as JSON to this WebAssembly module's on_event, to
+    /// drop it or add fields, and permission events to its decide, to
+    /// answer them before --policy; the permission events it drops are
+    /// still decided, only not reported
+    #[structopt(long, parse(from_os_str))]
+    pub plugin: Option<PathBuf>,
+
+    /// run this Lua file's on_event(event) on each event, which can return
+    /// "allow" or "deny" to answer permission events, or "log", and a
+    /// table of fields to add; a call that runs too long answers
+    /// --perm-default
+    #[structopt(long, parse(from_os_str))]
+    pub script: Option<PathBuf>,
+
+    /// answer permission events by the first rule they match in this file,
+    /// lines like "deny path=/etc/shadow comm=cat exe=/usr/* [log]" and
+    /// "default allow", with kill or stop for deny and SIGKILL or SIGSTOP
+    /// the process, freeze for deny and freeze its whole cgroup, or
+    /// quarantine for deny and copy the file into --quarantine, or TOML if
+    /// it ends in .toml, leaving the rest to stdin without a default,
+    /// reloaded on SIGHUP
+    #[structopt(long, parse(from_os_str))]
+    pub policy: Option<PathBuf>,
+
+    /// allow executing only the files whose sha256 is in this manifest of
+    /// sha256sum lines, or directory of them, answering FAN_OPEN_EXEC_PERM
+    /// before anything else, reloaded on SIGHUP
+    #[structopt(long, parse(from_os_str))]
+    pub exec_allowlist: Option<PathBuf>,
+
+    /// only take the manifests of --exec-allowlist with a .sig next to
+    /// them signed by this Ed25519 public key, in hex, skipping the others
+    /// in a directory and refusing a single one
+    #[structopt(long, parse(from_os_str), requires = "exec-allowlist")]
+    pub exec_allowlist_key: Option<PathBuf>,
+
+    /// only log the executables --exec-allowlist would deny, leaving
+    /// everything to be answered as without it
+    #[structopt(long, requires = "exec-allowlist")]
+    pub exec_allowlist_report: bool,
+
+    /// answer FAN_OPEN_PERM events by running this shell command with the
+    /// file open as its stdin and its path in $FANOTIFY_PATH, like
+    /// "clamscan --no-summary -": allowing it if it exits 0 and denying it
+    /// if 1, --perm-default otherwise, remembered until the file changes
+    #[structopt(long, conflicts_with_all = &["decider", "grpc", "interactive", "notify"])]
+    pub scan_cmd: Option<String>,
+
+    /// keep the always answers of --interactive in this SQLite database,
+    /// for the path and the sha256 of the executable that asked, and answer
+    /// with them after --policy, across restarts
+    #[structopt(long, parse(from_os_str))]
+    pub decisions: Option<PathBuf>,
+
+    /// raise RLIMIT_NOFILE to this, each pending permission event holds an fd
+    #[structopt(long)]
+    pub nofile: Option<u64>,
+
+    /// answer permission events with --overload-response once more than
+    /// this many are pending, until half as many are, default RLIMIT_NOFILE
+    /// less room for a read of events and our own fds
+    #[structopt(long)]
+    pub max_pending: Option<usize>,
+
+    #[structopt(long, default_value = "FAN_ALLOW")]
+    pub overload_response: FanResponse,
+
+    /// answer permission events still pending after this many seconds
+    /// with --perm-default
+    #[structopt(long)]
+    pub perm_timeout: Option<u64>,
+
+    /// how to answer permission events after --perm-timeout, allow or deny
+    #[structopt(long, default_value = "allow")]
+    pub perm_default: Action,
+
+    /// let each process have at most this many permission events a
+    /// second, the rest held back or denied by --perm-rate-action
+    #[structopt(long)]
+    pub perm_rate: Option<u32>,
+
+    /// count --perm-rate by pid, or by comm for all the processes of a
+    /// command
+    #[structopt(long, default_value = "pid")]
+    pub perm_rate_by: RateBy,
+
+    /// what to do with the permission events over --perm-rate: delay them
+    /// until there's budget, or deny them
+    #[structopt(long, default_value = "delay")]
+    pub perm_rate_action: OverRate,
+
+    /// enrich at most this many events per second, 0 is unlimited, the
+    /// rest are reported with only their path, which is still found to
+    /// filter on. Events the filters drop don't count
+    #[structopt(long, default_value = "0")]
+    pub enrich_rate: u32,
+
+    /// what to look up for each event, in order: any of
+    /// path,proc,stat,hash,container,mount,inode,user,magic,io,tty, but not
+    /// both stat and user or stat and inode
+    #[structopt(long, default_value = "path")]
+    pub enrich: Pipeline,
+
+    /// with --enrich container, add the name and image of the container
+    /// from the Docker API on this socket, like /var/run/docker.sock or
+    /// /run/podman/podman.sock, to the events after it's been looked up
+    #[structopt(long, parse(from_os_str))]
+    pub container_api: Option<PathBuf>,
+
+    /// with --enrich proc, add the command line of the process too,
+    /// escaped and cut to this many bytes, 1024 if not given
+    #[structopt(long, require_equals = true)]
+    pub cmdline: Option<Option<usize>>,
+
+    /// add the sha256 of regular files, of only those up to MAXSIZE bytes
+    /// with sha256:MAXSIZE, hashed on worker threads while their events
+    /// and those after them wait
+    #[structopt(long)]
+    pub hash: Option<HashSpec>,
+
+    /// join this cgroup before marking, to cap our own cpu and memory
+    #[structopt(long, parse(from_os_str))]
+    pub cgroup: Option<PathBuf>,
+
+    /// print event and loss counters to stderr on exit, also done on SIGUSR1
+    #[structopt(long)]
+    pub stats: bool,
+
+    /// send events to tcp://HOST:PORT or tls://HOST:PORT instead of stdout
+    #[structopt(long)]
+    pub forward: Option<String>,
+
+    /// with tls:// --forward, trust the CA certificates in this PEM file
+    /// instead of the usual ones
+    #[structopt(long, parse(from_os_str), requires = "forward")]
+    pub forward_ca: Option<PathBuf>,
+
+    /// spill events here while --forward or --webhook is disconnected or
+    /// behind, replayed once it catches up
+    #[structopt(long, parse(from_os_str))]
+    pub spill_dir: Option<PathBuf>,
+
+    /// maximum size of the spill queue in bytes
+    #[structopt(long, default_value = "104857600")]
+    pub spill_max: u64,
+
+    /// write events to this file instead of stdout
+    #[structopt(long, parse(from_os_str), conflicts_with = "forward")]
+    pub output_file: Option<PathBuf>,
+
+    /// send events to journald, with FAN_MASK, FAN_PID, FAN_PATH and a
+    /// FAN_ field for each --enrich field, or to an indexed SQLite database
+    /// with sqlite:PATH, see the query subcommand
+    #[structopt(long, conflicts_with_all = &["forward", "output-file"])]
+    pub output: Option<Output>,
+
+    /// stream events as json lines to every client of this socket, as
+    /// unix:PATH
+    #[structopt(long, conflicts_with_all = &["forward", "output-file", "output"])]
+    pub listen: Option<String>,
+
+    /// disconnect --listen clients that fall this many bytes behind
+    #[structopt(long, default_value = "1048576")]
+    pub listen_buffer: usize,
+
+    /// tab, csv, json, cef or leef, csv has a column for every field the
+    /// events can have, json is an object per line and cef and leef are
+    /// for SIEMs
+    #[structopt(
+        long,
+        possible_values = &["tab", "csv", "json", "cef", "leef"],
+        default_value = "tab"
+    )]
+    pub output_format: Format,
+
+    /// color the event masks by class when printing to a terminal: auto,
+    /// always or never
+    #[structopt(long, possible_values = &["auto", "always", "never"], default_value = "auto")]
+    pub color: Color,
+
+    /// print events like "{time} {comm} {mask} {path}", with \t and \n
+    /// escapes, {mask}, {fd}, {pid}, {path}, {time}, {dir}, {name},
+    /// {events} as inotifywait prints them and the fields from --enrich can
+    /// be used
+    #[structopt(long)]
+    pub format: Option<String>,
+
+    /// start each event with when it was read, as iso8601, epoch, epoch-ms
+    /// or monotonic
+    #[structopt(long, conflicts_with = "format")]
+    pub timestamp: Option<Timestamp>,
+
+    /// number the events printed, counting from 1, after --timestamp
+    #[structopt(long, conflicts_with = "format")]
+    pub seq: bool,
+
+    /// print paths as they are, instead of with \\, tabs, newlines and bytes
+    /// that aren't UTF-8 escaped
+    #[structopt(long, conflicts_with_all = &["format", "output"])]
+    pub raw_paths: bool,
+
+    /// strftime format of {time} in --format
+    #[structopt(long, default_value = "%Y-%m-%dT%H:%M:%S")]
+    pub timefmt: String,
+
+    /// exit after printing this many events
+    #[structopt(long)]
+    pub max_events: Option<u64>,
+
+    /// also write the events matching GLOB [MASK], or all of them, as tab,
+    /// csv, json, cef or leef to DEST: - for stdout, a file, journald, or
+    /// tcp:// or tls:// to forward to, like "json:/var/log/etc.json /etc/*",
+    /// can be repeated
+    #[structopt(long, number_of_values = 1, value_name = "FORMAT:DEST [GLOB [MASK]]")]
+    pub tee: Vec<String>,
+
+    /// start csv output with a line of column names
+    #[structopt(long)]
+    pub csv_header: bool,
+
+    /// start a new --output-file every hour or day, named after the time
+    #[structopt(long, possible_values = &["hourly", "daily"], requires = "output-file")]
+    pub rotate: Option<Rotate>,
+
+    /// rotate --output-file once it's this many bytes, to .1, .2 and so on
+    #[structopt(long, requires = "output-file")]
+    pub rotate_size: Option<u64>,
+
+    /// how many files rotated by --rotate-size to keep
+    #[structopt(long, default_value = "5")]
+    pub rotate_keep: usize,
+
+    /// gzip files rotated by --rotate-size
+    #[structopt(long, requires = "rotate-size")]
+    pub rotate_compress: bool,
+
+    /// wait until this time to start watching, as YYYY-MM-DDTHH:MM[:SS] or @EPOCH
+    #[structopt(long, parse(try_from_str = clock::parse_time))]
+    pub start_at: Option<SystemTime>,
+
+    /// stop watching and exit at this time
+    #[structopt(long, parse(try_from_str = clock::parse_time))]
+    pub stop_at: Option<SystemTime>,
+
+    /// instead of printing events, print a summary of changed files on exit
+    /// and on SIGUSR1
+    #[structopt(long)]
+    pub report: bool,
+
+    /// run this shell command once nothing changed for --quiet-secs after a change
+    #[structopt(long)]
+    pub on_quiesce: Option<String>,
+
+    #[structopt(long, default_value = "2")]
+    pub quiet_secs: u64,
+
+    /// copy every file closed after writing that the filters keep into
+    /// this directory, timestamped, on a worker thread
+    #[structopt(long, parse(from_os_str))]
+    pub snapshot_dir: Option<PathBuf>,
+
+    /// copy the files quarantine rules of --policy deny into this
+    /// directory, by sha256, each event with them in HASH@TIME.json
+    #[structopt(long, parse(from_os_str), requires = "policy")]
+    pub quarantine: Option<PathBuf>,
+
+    /// notif, content or pre-content, default content, or notif with --fid
+    #[structopt(long)]
+    pub class: Option<Class>,
+
+    /// report the id of the thread rather than the process
+    #[structopt(long)]
+    pub tid: bool,
+
+    /// get a pidfd with each event so the process can't be mistaken for
+    /// another that reused its pid, printed as pidfd= and open until the
+    /// event is answered
+    #[structopt(long, conflicts_with = "tid")]
+    pub pidfd: bool,
+
+    /// identify files by handle rather than an open fd, cheaper but
+    /// can't be used with permission events or --enrich steps other than
+    /// path, implied by FAN_ATTRIB, FAN_CREATE, FAN_DELETE, FAN_MOVED_FROM,
+    /// FAN_MOVED_TO and FAN_RENAME
+    #[structopt(long)]
+    pub fid: bool,
+
+    /// before any events, print EXISTS size mtime path for every file
+    /// under paths
+    #[structopt(long, conflicts_with = "tui")]
+    pub baseline: bool,
+
+    /// with --baseline, add the sha256 of each file
+    #[structopt(long, requires = "baseline")]
+    pub baseline_hash: bool,
+
+    /// have the kernel audit log FAN_DENY answers, with a rule number
+    /// when answered as FAN_DENY FD RULE [SUBJ_TRUST OBJ_TRUST]
+    #[structopt(long)]
+    pub audit: bool,
+
+    /// follow this audit log and print an AUDIT record with the syscall
+    /// context for each FANOTIFY entry, joined with our event by pid and time
+    #[structopt(long, parse(from_os_str))]
+    pub audit_log: Option<PathBuf>,
+
+    /// instead of events, periodically print HEAT lines with event counts of
+    /// the busiest directories, rolled up to this many levels below /
+    #[structopt(long)]
+    pub heatmap: Option<usize>,
+
+    /// number of directories per --heatmap report
+    #[structopt(long, default_value = "10")]
+    pub heatmap_top: usize,
+
+    /// seconds between --heatmap reports
+    #[structopt(long = "heatmap-interval", default_value = "10")]
+    heatmap_interval_secs: u64,
+
+    /// instead of printing events, record which executables open what for
+    /// this long, like 10m or 2h, then print a --policy allowing only that
+    /// and exit
+    #[structopt(long, parse(try_from_str = clock::parse_duration), conflicts_with_all = &["report", "tui", "heatmap", "sessions"])]
+    pub learn: Option<Duration>,
+
+    /// print one SESSION line per open to close of a file, with the pid,
+    /// seconds it was open and whether it was written, instead of events,
+    /// paired by dev and inode with --enrich inode, so across renames, or
+    /// else by path
+    #[structopt(long)]
+    pub sessions: bool,
+
+    /// ask on the terminal about each permission event instead of reading
+    /// responses from stdin, answered with y, n, a to allow the path from
+    /// then on or d to deny it, which are printed as --policy rules on exit
+    #[structopt(long, conflicts_with_all = &["tui", "decider", "grpc"])]
+    pub interactive: bool,
+
+    /// ask about each permission event with a desktop notification, with
+    /// allow and deny buttons, over D-Bus
+    #[structopt(long, conflicts_with_all = &["decider", "grpc"])]
+    pub notify: bool,
+
+    /// also show a desktop notification for the events matching this
+    /// GLOB [MASK], like "/home/*/.ssh/* FAN_OPEN", can be repeated
+    #[structopt(long, number_of_values = 1)]
+    pub notify_match: Vec<String>,
+
+    /// show a live view of the busiest files and processes instead of
+    /// printing events, permission events can be answered with a/d
+    #[structopt(long, conflicts_with = "report")]
+    pub tui: bool,
+
+    /// append to each event a hash chained over all previous events
+    #[structopt(long)]
+    pub hash_chain: bool,
+
+    /// with --hash-chain, pipe the current hash to this command and log its output
+    #[structopt(long)]
+    pub checkpoint_cmd: Option<String>,
+
+    /// run --checkpoint-cmd every this many events
+    #[structopt(long, default_value = "1000")]
+    pub checkpoint_every: u64,
+
+    /// verify the hash chain of a log written with --hash-chain and exit
+    #[structopt(long, parse(from_os_str))]
+    pub verify_chain: Option<PathBuf>,
+
+    /// with --verify-chain, pipe the hash each CHECKPOINT signed to this
+    /// command, with the signature in $FANOTIFY_SIGNATURE, and fail unless
+    /// it exits 0
+    #[structopt(long, requires = "verify-chain")]
+    pub verify_checkpoint_cmd: Option<String>,
+
+    /// on SIGUSR2, re-exec this binary with the same flags, handing over
+    /// the fanotify fd and pending permission events
+    #[structopt(long, parse(from_os_str))]
+    pub upgrade_exec: Option<PathBuf>,
+
+    /// check the events caused by --run against this file of GLOB [MASK]
+    /// lines, a line starting with ! must not match, exit 1 on any mismatch
+    #[structopt(long, parse(from_os_str), requires = "run")]
+    pub expect: Option<PathBuf>,
+
+    /// with --expect, the shell command to run, we exit when it does
+    #[structopt(long, requires = "expect")]
+    pub run: Option<String>,
+
+    /// also POST events as JSON to this http:// or https:// URL, in
+    /// batches, for alerting
+    #[structopt(long)]
+    pub webhook: Option<String>,
+
+    /// only send events matching this GLOB [MASK] to --webhook, like
+    /// "/etc/* FAN_MODIFY|FAN_CLOSE_WRITE", can be repeated
+    #[structopt(long, number_of_values = 1, requires = "webhook")]
+    pub webhook_match: Vec<String>,
+
+    /// most events per --webhook POST
+    #[structopt(long, default_value = "100")]
+    pub webhook_batch: usize,
+
+    /// most --webhook POSTs per minute, 0 is unlimited
+    #[structopt(long, default_value = "60")]
+    pub webhook_rate: u32,
+
+    /// also export events as OpenTelemetry logs, and how long permission
+    /// events took to answer as spans, to this OTLP/HTTP endpoint like
+    /// http://localhost:4318
+    #[structopt(long)]
+    pub otlp: Option<String>,
+
+    /// read more flags from this file, one per line
+    #[structopt(long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
     #[structopt(parse(try_from_os_str = cstring_from_os_str))]
     pub paths: Vec<CString>,
+
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
 }
 
 const DEFAULT_EVENTS: &str =
     "FAN_ACCESS,FAN_MODIFY,FAN_CLOSE_WRITE,FAN_CLOSE_NOWRITE,FAN_OPEN,FAN_ONDIR,FAN_EVENT_ON_CHILD";
 
+// the kernel headers have FAN_CLOSE and FAN_MOVE too, but not as bits of
+// their own
+const EVENT_ALIASES: &[(&str, &str)] = &[
+    ("FAN_CLOSE", "FAN_CLOSE_WRITE,FAN_CLOSE_NOWRITE"),
+    ("FAN_MOVE", "FAN_MOVED_FROM,FAN_MOVED_TO"),
+    ("FAN_PERM", "FAN_OPEN_PERM,FAN_ACCESS_PERM"),
+];
+
+/// Spell out -e with the full names, so `close,move` becomes
+/// FAN_CLOSE_WRITE,FAN_CLOSE_NOWRITE,FAN_MOVED_FROM,FAN_MOVED_TO.
+fn expand_events(events: &str) -> String {
+    events
+        .split(',')
+        .map(|e| {
+            let e = if e.starts_with("FAN_") || e.starts_with("0x") {
+                e.to_string()
+            } else {
+                format!("FAN_{}", e.to_uppercase())
+            };
+            match EVENT_ALIASES.iter().find(|(alias, _)| *alias == e) {
+                Some((_, expanded)) => expanded.to_string(),
+                None => e,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
 impl Opt {
     pub fn from_args_with_default() -> io::Result<Opt> {
-        let mut opt = Opt::from_args();
+        let args: Vec<OsString> = env::args_os().collect();
+        let mut opt = if inotifywait::wanted(&args) {
+            Opt::from_iter(inotifywait::translate(&args)?)
+        } else {
+            Opt::from_iter(args)
+        };
+
+        if let Some(config) = &opt.config {
+            let mut args: Vec<OsString> = env::args_os().take(1).collect();
+            args.extend(read_config(config)?);
+            args.extend(env::args_os().skip(1));
+            opt = Opt::from_iter(args);
+        }
+
+        opt.with_default()
+    }
+
+    pub fn heatmap_interval(&self) -> Duration {
+        Duration::from_secs(self.heatmap_interval_secs)
+    }
+
+    pub fn with_default(self) -> io::Result<Opt> {
+        let mut opt = self;
 
-        opt.events.get_or_insert(DEFAULT_EVENTS.into());
+        opt.events = Some(expand_events(
+            opt.events.as_deref().unwrap_or(DEFAULT_EVENTS),
+        ));
+        // by the bits, which raw ones have too
+        let mask = parse_mask(opt.events.as_ref().unwrap())?;
+        if opt.spill_dir.is_some() && opt.forward.is_none() && opt.webhook.is_none() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "--spill-dir requires --forward or --webhook",
+            ));
+        }
+        if opt.listen.is_some() {
+            if opt.output_format != Format::Tab && opt.output_format != Format::Json {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "--listen sends json, not other --output-format",
+                ));
+            }
+            opt.output_format = Format::Json;
+        }
+        if opt.output_format != Format::Tab && opt.format.is_some() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "--format can't be used with --output-format",
+            ));
+        }
+        if opt.output_format != Format::Tab
+            && (opt.hash_chain || opt.sessions || opt.heatmap.is_some() || opt.baseline)
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "--output-format other than tab is only for events, not --hash-chain, \
+                 --sessions, --heatmap or --baseline",
+            ));
+        }
+        if let (Some(min), Some(max)) = (opt.min_size, opt.max_size) {
+            if min > max {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "--min-size can't be more than --max-size",
+                ));
+            }
+        }
+        if opt.output_format != Format::Tab && opt.raw_paths {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "--raw-paths is only for --output-format tab",
+            ));
+        }
+        if opt.output_format == Format::Json && (opt.timestamp.is_some() || opt.seq) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "--timestamp and --seq can't be used with --output-format json",
+            ));
+        }
+        if opt.output.is_some()
+            && (opt.output_format != Format::Tab
+                || opt.format.is_some()
+                || opt.timestamp.is_some()
+                || opt.seq
+                || opt.hash_chain
+                || opt.sessions
+                || opt.heatmap.is_some()
+                || opt.baseline)
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "--output has its own format, so no --output-format, --format, \
+                 --timestamp, --seq, --hash-chain, --sessions, --heatmap or --baseline",
+            ));
+        }
+        if opt.cmdline.is_some() && !opt.enrich.contains(Step::Proc) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "--cmdline requires --enrich proc",
+            ));
+        }
+        if opt.container_api.is_some() && !opt.enrich.contains(Step::Container) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "--container-api requires --enrich container",
+            ));
+        }
+        if opt.hash.is_some() && opt.enrich.contains(Step::Hash) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "--hash and --enrich hash both add sha256, use one",
+            ));
+        }
+        if opt.checkpoint_cmd.is_some() && !opt.hash_chain {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "--checkpoint-cmd requires --hash-chain",
+            ));
+        }
+        if opt.unprivileged {
+            for (used, what) in &[
+                (
+                    opt.recursive || opt.mount || opt.filesystem,
+                    "-r, -m and -f",
+                ),
+                (mask & PERM_EVENTS != 0, "permission events"),
+                (opt.class.is_some_and(|c| c != Class::Notif), "--class"),
+                (opt.namespace.is_some(), "-p"),
+                (opt.tid, "--tid"),
+                (opt.pidfd, "--pidfd"),
+                (opt.audit, "--audit"),
+            ] {
+                if *used {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("{} need privileges that --unprivileged lacks", what),
+                    ));
+                }
+            }
+            opt.fid = true;
+        }
+        // these only come with file handles
+        if mask & (DIRENT_EVENTS | libc::FAN_ATTRIB | libc::FAN_RENAME) != 0 {
+            opt.fid = true;
+        }
+        if opt.fid && mask & PERM_EVENTS != 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "--fid can't be used with permission events",
+            ));
+        }
+        if opt.fid && opt.class.is_some_and(|c| c != Class::Notif) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "--fid only works with --class notif",
+            ));
+        }
+        if opt.class == Some(Class::Notif) && mask & PERM_EVENTS != 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "permission events need --class content or pre-content",
+            ));
+        }
         if opt.filesystem {
             opt.recursive = true;
         } else if opt.mount {
@@ -54,25 +1027,108 @@ impl Opt {
             opt.mount = true;
         }
 
+        if opt.evictable && (opt.mount || opt.filesystem) && opt.ignore.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "--evictable only applies to inode marks, without -r, -m and -f or with --ignore",
+            ));
+        }
+
         if opt.namespace.is_none() {
-            opt.paths = opt
-                .paths
-                .into_iter()
-                .map(|p| {
-                    // convert relative paths to absolute paths
-                    fs::canonicalize(OsStr::from_bytes(&p.as_bytes()))
-                        .map_err(|e| {
-                            io::Error::new(ErrorKind::InvalidInput, format!("{:?}: {}", p, e))
-                        })
-                        // should be safe to unwrap here since the path should not contain
-                        // internal nul bytes
-                        .map(|p| CString::new(p.as_os_str().as_bytes().to_vec()).unwrap())
-                })
-                .collect::<Vec<io::Result<_>>>()
-                .into_iter()
-                .collect::<io::Result<Vec<_>>>()?;
+            opt.paths = absolute(opt.paths, !opt.dont_follow)?;
+            opt.ignore = absolute(opt.ignore, true)?;
+            opt.canary = absolute(opt.canary, true)?;
         }
 
         Ok(opt)
     }
 }
+
+/// Convert relative paths to absolute paths, resolving symlinks unless
+/// it's the last component and not `follow`.
+fn absolute(paths: Vec<CString>, follow: bool) -> io::Result<Vec<CString>> {
+    paths
+        .into_iter()
+        .map(|p| {
+            let path = Path::new(OsStr::from_bytes(p.as_bytes()));
+            let abs = match (follow, path.parent(), path.file_name()) {
+                (false, Some(dir), Some(name)) => {
+                    let dir = if dir.as_os_str().is_empty() {
+                        Path::new(".")
+                    } else {
+                        dir
+                    };
+                    fs::canonicalize(dir).map(|dir| dir.join(name))
+                }
+                _ => fs::canonicalize(path),
+            };
+            abs.map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("{:?}: {}", p, e)))
+                // should be safe to unwrap here since the path should not contain
+                // internal nul bytes
+                .map(|p| CString::new(p.as_os_str().as_bytes().to_vec()).unwrap())
+        })
+        .collect()
+}
+
+/// Read a config file written by `write_config`, ignoring blank lines and
+/// comments.
+pub fn read_config(path: &Path) -> io::Result<Vec<OsString>> {
+    let buf = fs::read(path)?;
+    Ok(buf
+        .split(|b| *b == b'\n')
+        .filter(|l| !l.is_empty() && !l.starts_with(b"#"))
+        .map(|l| OsStr::from_bytes(l).to_os_string())
+        .collect())
+}
+
+pub fn write_config(path: &Path, args: &[OsString]) -> io::Result<()> {
+    let mut buf = b"# fanotify-cli flags, one per line\n".to_vec();
+    for a in args {
+        if a.as_bytes().contains(&b'\n') {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{:?}: newlines are not supported", a),
+            ));
+        }
+        buf.extend_from_slice(a.as_bytes());
+        buf.push(b'\n');
+    }
+    fs::write(path, buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_aliases() {
+        assert_eq!(
+            expand_events("open,FAN_CLOSE,move"),
+            "FAN_OPEN,FAN_CLOSE_WRITE,FAN_CLOSE_NOWRITE,FAN_MOVED_FROM,FAN_MOVED_TO"
+        );
+        assert_eq!(expand_events("perm"), "FAN_OPEN_PERM,FAN_ACCESS_PERM");
+        assert_eq!(expand_events(DEFAULT_EVENTS), DEFAULT_EVENTS);
+        assert_eq!(expand_events("open,0x20000"), "FAN_OPEN,0x20000");
+    }
+
+    #[test]
+    fn events_bits() {
+        let opt = |events: &str, flag: &str| {
+            Opt::from_iter_safe(&["fanotify-cli", "-e", events, flag, "/"])
+                .unwrap()
+                .with_default()
+        };
+        // FAN_CREATE as it is and as bits
+        assert!(opt("create", "--tid").unwrap().fid);
+        assert!(opt("open,0x100", "--tid").unwrap().fid);
+        assert!(!opt("open,0x20", "--tid").unwrap().fid);
+        // FAN_OPEN_PERM
+        for events in &["open_perm", "0x10000"] {
+            assert!(opt(events, "--unprivileged").is_err());
+            assert!(opt(events, "--fid").is_err());
+            assert!(opt(events, "--class=notif").is_err());
+            assert!(opt(events, "--tid").is_ok());
+        }
+        assert!(opt("0xzz", "--tid").is_err());
+    }
+}
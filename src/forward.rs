@@ -0,0 +1,556 @@
+use std::cmp;
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc::c_int;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+use crate::sink::Sink;
+use crate::spool::Spool;
+use crate::stats::Stats;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// how soon to retry writing what the collector hasn't taken yet
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+// how far behind the collector can fall before events go to the spool
+const MAX_BUFFER: usize = 1 << 20;
+
+/// A connection to a collector, with or without TLS.
+pub enum Conn {
+    Tcp(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Conn::Tcp(s) => s.read(buf),
+            Conn::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Conn::Tcp(s) => s.write(buf),
+            Conn::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Conn::Tcp(s) => s.flush(),
+            Conn::Tls(s) => s.flush(),
+        }
+    }
+}
+
+/// Streams events to a remote collector, reconnecting with exponential
+/// backoff. Nothing blocks: what the collector hasn't taken yet is buffered,
+/// and past that, or while disconnected, events go to the spool if there is
+/// one.
+pub struct TcpSink {
+    addr: String,
+    tls: Option<Arc<ClientConfig>>,
+    /// what `addr` resolved to that's still to be tried
+    addrs: Vec<SocketAddr>,
+    link: Option<Link>,
+    /// what's been sent but not yet written to `link`
+    buf: Vec<u8>,
+    /// whether `buf` starts part way through a record
+    cut: bool,
+    /// records lost since the last send or tick
+    lost: u64,
+    spool: Option<Spool>,
+    retry_at: Instant,
+    backoff: Duration,
+}
+
+/// A nonblocking connection to a collector.
+struct Link {
+    sock: TcpStream,
+    tls: Option<Box<ClientConnection>>,
+    /// when connecting started, until that and the handshake are done
+    since: Option<Instant>,
+}
+
+/// The address in `url` and whether it wants TLS.
+pub fn parse_addr(url: &str) -> io::Result<(&str, bool)> {
+    if let Some(addr) = url.strip_prefix("tcp://") {
+        Ok((addr, false))
+    } else if let Some(addr) = url.strip_prefix("tls://") {
+        Ok((addr, true))
+    } else {
+        Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{}: only tcp://HOST:PORT and tls://HOST:PORT are supported",
+                url
+            ),
+        ))
+    }
+}
+
+/// The host to check the certificate against, without the port.
+fn server_name(addr: &str) -> io::Result<ServerName<'static>> {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host.to_string())
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("{}: {}", addr, e)))
+}
+
+/// Trust the CA certificates in `ca`, or the usual web roots.
+pub fn tls_config(ca: Option<&Path>) -> io::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            let invalid = |e| io::Error::new(ErrorKind::InvalidData, format!("{:?}: {}", ca, e));
+            for cert in CertificateDer::pem_file_iter(ca).map_err(invalid)? {
+                roots
+                    .add(cert.map_err(invalid)?)
+                    .map_err(io::Error::other)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+pub fn connect(addr: &str) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(ErrorKind::NotFound, format!("{}: no address", addr));
+    for a in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&a, CONNECT_TIMEOUT) {
+            Ok(conn) => {
+                conn.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                return Ok(conn);
+            }
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+pub fn handshake(sock: TcpStream, config: &Arc<ClientConfig>, addr: &str) -> io::Result<Conn> {
+    let mut sock = sock;
+    let mut conn =
+        ClientConnection::new(config.clone(), server_name(addr)?).map_err(io::Error::other)?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut sock)?;
+    }
+    Ok(Conn::Tls(Box::new(StreamOwned::new(conn, sock))))
+}
+
+/// The sockaddr for `addr`, and its length.
+fn sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(a) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr.s_addr = u32::from_ne_bytes(a.ip().octets());
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(a) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = a.port().to_be();
+            sin6.sin6_flowinfo = a.flowinfo();
+            sin6.sin6_addr.s6_addr = a.ip().octets();
+            sin6.sin6_scope_id = a.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+/// Start connecting to `addr` without waiting for it to finish.
+fn connect_nonblocking(addr: &SocketAddr) -> io::Result<TcpStream> {
+    let (storage, len) = sockaddr(addr);
+    let fd = unsafe {
+        libc::socket(
+            storage.ss_family as c_int,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let sock = unsafe { TcpStream::from_raw_fd(fd) };
+    if unsafe { libc::connect(fd, &storage as *const _ as *const libc::sockaddr, len) } < 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(e);
+        }
+    }
+    Ok(sock)
+}
+
+impl Link {
+    fn start(addr: &SocketAddr, host: &str, tls: Option<&Arc<ClientConfig>>) -> io::Result<Link> {
+        let tls = match tls {
+            Some(config) => Some(Box::new(
+                ClientConnection::new(config.clone(), server_name(host)?)
+                    .map_err(io::Error::other)?,
+            )),
+            None => None,
+        };
+        Ok(Link {
+            sock: connect_nonblocking(addr)?,
+            tls,
+            since: Some(Instant::now()),
+        })
+    }
+
+    /// Whether the connection is up and can take more, after pushing out
+    /// what TLS has buffered.
+    fn ready(&mut self) -> io::Result<bool> {
+        if let Some(since) = self.since {
+            if since.elapsed() >= CONNECT_TIMEOUT {
+                return Err(io::Error::from(ErrorKind::TimedOut));
+            }
+            let mut pfd = libc::pollfd {
+                fd: self.sock.as_raw_fd(),
+                events: libc::POLLOUT,
+                revents: 0,
+            };
+            match unsafe { libc::poll(&mut pfd, 1, 0) } {
+                -1 => return Err(io::Error::last_os_error()),
+                0 => return Ok(false),
+                _ => (),
+            }
+            if let Some(e) = self.sock.take_error()? {
+                return Err(e);
+            }
+        }
+
+        if let Some(tls) = &mut self.tls {
+            loop {
+                while tls.wants_write() {
+                    match tls.write_tls(&mut self.sock) {
+                        Ok(_) => (),
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                        Err(e) => return Err(e),
+                    }
+                }
+                if !tls.is_handshaking() {
+                    break;
+                }
+                match tls.read_tls(&mut self.sock) {
+                    Ok(0) => return Err(io::Error::from(ErrorKind::UnexpectedEof)),
+                    Ok(_) => {
+                        tls.process_new_packets().map_err(io::Error::other)?;
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        self.since = None;
+        Ok(true)
+    }
+
+    /// Write what can be written of `buf` without blocking.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let res = match &mut self.tls {
+            Some(tls) => tls.writer().write(buf),
+            None => self.sock.write(buf),
+        };
+        match res {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(0),
+            res => res,
+        }
+    }
+
+    /// Whether there's nothing left to do but wait for more to write.
+    fn idle(&self) -> bool {
+        self.since.is_none() && !self.tls.as_ref().is_some_and(|tls| tls.wants_write())
+    }
+}
+
+impl TcpSink {
+    pub fn new(url: &str, ca: Option<&Path>, spool: Option<Spool>) -> io::Result<TcpSink> {
+        let (addr, tls) = parse_addr(url)?;
+        let tls = if tls {
+            server_name(addr)?;
+            Some(tls_config(ca)?)
+        } else {
+            None
+        };
+        let mut sink = TcpSink {
+            addr: addr.into(),
+            tls,
+            addrs: Vec::new(),
+            link: None,
+            buf: Vec::new(),
+            cut: false,
+            lost: 0,
+            spool,
+            retry_at: Instant::now(),
+            backoff: MIN_BACKOFF,
+        };
+        sink.reconnect();
+        Ok(sink)
+    }
+
+    /// Start connecting to the next address, resolving `addr` again once
+    /// they've all been tried.
+    fn reconnect(&mut self) {
+        if self.addrs.is_empty() {
+            match self.addr.to_socket_addrs() {
+                Ok(addrs) => self.addrs = addrs.rev().collect(),
+                Err(e) => return self.retry(e),
+            }
+        }
+        let link = match self.addrs.pop() {
+            Some(a) => Link::start(&a, &self.addr, self.tls.as_ref()),
+            None => Err(io::Error::new(ErrorKind::NotFound, "no address")),
+        };
+        match link {
+            Ok(link) => self.link = Some(link),
+            Err(e) => self.retry(e),
+        }
+    }
+
+    /// Try the next address, or all of them again after a backoff.
+    fn retry(&mut self, e: io::Error) {
+        if self.addrs.is_empty() {
+            warn!("{}: {}, retrying in {:?}", self.addr, e, self.backoff);
+            self.retry_at = Instant::now() + self.backoff;
+            self.backoff = cmp::min(self.backoff * 2, MAX_BACKOFF);
+        } else {
+            warn!("{}: {}", self.addr, e);
+            self.retry_at = Instant::now();
+        }
+    }
+
+    fn disconnect(&mut self, e: io::Error) {
+        self.link = None;
+        if mem::take(&mut self.cut) {
+            // the collector has the start of this one, so resending it
+            // would duplicate it and the rest alone is garbage
+            let end = self
+                .buf
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(self.buf.len(), |i| i + 1);
+            self.buf.drain(..end);
+            self.lost += 1;
+        }
+        self.retry(e);
+    }
+
+    /// Write as much as the collector takes without blocking, topping up
+    /// from the spool.
+    fn flush(&mut self) {
+        while let Some(link) = &mut self.link {
+            let connecting = link.since.is_some();
+            match link.ready() {
+                Ok(true) => (),
+                Ok(false) => return,
+                Err(e) => return self.disconnect(e),
+            }
+            if connecting {
+                info!("connected to {}", self.addr);
+                self.addrs.clear();
+                self.backoff = MIN_BACKOFF;
+            }
+
+            if self.buf.is_empty() {
+                if let Some(spool) = &mut self.spool {
+                    let buf = &mut self.buf;
+                    let res = spool.replay(&mut |r| {
+                        if buf.len() >= MAX_BUFFER {
+                            return false;
+                        }
+                        buf.extend_from_slice(r);
+                        true
+                    });
+                    if let Err(e) = res {
+                        warn!("spool: {}", e);
+                    }
+                }
+                if self.buf.is_empty() {
+                    return;
+                }
+            }
+
+            match link.write(&self.buf) {
+                Ok(0) => return,
+                Ok(n) => {
+                    self.cut = self.buf[n - 1] != b'\n';
+                    self.buf.drain(..n);
+                }
+                Err(e) => return self.disconnect(e),
+            }
+        }
+    }
+
+    /// Whether there's anything left to write.
+    fn pending(&self) -> bool {
+        !self.buf.is_empty()
+            || self.spool.as_ref().is_some_and(|s| !s.is_empty())
+            || self.link.as_ref().is_some_and(|l| !l.idle())
+    }
+}
+
+impl Sink for TcpSink {
+    fn send(&mut self, record: &[u8], stats: &mut Stats) -> io::Result<()> {
+        // behind the spooled ones if there are any, to keep them in order
+        if self.buf.len() < MAX_BUFFER && self.spool.as_ref().is_none_or(|s| s.is_empty()) {
+            self.buf.extend_from_slice(record);
+        } else {
+            match &mut self.spool {
+                Some(spool) => {
+                    if spool.push(record)? {
+                        stats.spilled += 1;
+                    } else {
+                        stats.lost_spool_full += 1;
+                    }
+                }
+                None => {
+                    return Err(io::Error::other(format!(
+                        "{}: {} bytes behind",
+                        self.addr,
+                        self.buf.len()
+                    )))
+                }
+            }
+        }
+        self.tick(stats)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        if !self.pending() {
+            None
+        } else if self.link.is_some() {
+            Some(Instant::now() + RETRY_INTERVAL)
+        } else {
+            Some(self.retry_at)
+        }
+    }
+
+    fn tick(&mut self, stats: &mut Stats) -> io::Result<()> {
+        if self.link.is_none() && Instant::now() >= self.retry_at {
+            self.reconnect();
+        }
+        self.flush();
+        stats.lost_sink_error += mem::take(&mut self.lost);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        // give what's left a little while to go out
+        let deadline = Instant::now() + WRITE_TIMEOUT;
+        while self.link.is_some() && self.pending() && Instant::now() < deadline {
+            self.flush();
+            thread::sleep(RETRY_INTERVAL / 10);
+        }
+        // the rest of a record that's been half sent can only go on this
+        // connection
+        let start = if self.cut {
+            let end = self.buf.iter().position(|&b| b == b'\n');
+            end.map_or(self.buf.len(), |i| i + 1)
+        } else {
+            0
+        };
+        let spool = match &mut self.spool {
+            Some(spool) => spool,
+            None if start < self.buf.len() => {
+                warn!("{}: {} bytes unsent", self.addr, self.buf.len() - start);
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        // for the next run, even if out of order
+        let mut end = start;
+        let mut res = Ok(());
+        for record in self.buf[start..].split_inclusive(|&b| b == b'\n') {
+            match spool.push(record) {
+                Ok(true) => end += record.len(),
+                Ok(false) => {
+                    warn!("{}: spool full, events lost", self.addr);
+                    break;
+                }
+                Err(e) => {
+                    res = Err(e);
+                    break;
+                }
+            }
+        }
+        self.buf.drain(start..end);
+        res.and_then(|()| spool.sync())
+    }
+}
+
+impl Drop for TcpSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            warn!("{}: {}", self.addr, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn forward_addr() {
+        assert_eq!(parse_addr("tcp://a:1").unwrap(), ("a:1", false));
+        assert_eq!(parse_addr("tls://a:1").unwrap(), ("a:1", true));
+        assert!(parse_addr("udp://a:1").is_err());
+        assert_eq!(
+            server_name("example.com:6514").unwrap(),
+            ServerName::try_from("example.com").unwrap()
+        );
+        assert_eq!(
+            server_name("[::1]:6514").unwrap(),
+            ServerName::try_from("::1").unwrap()
+        );
+    }
+
+    #[test]
+    fn forward_nonblocking() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("tcp://{}", listener.local_addr()?);
+        let mut stats = Stats::default();
+        let mut sink = TcpSink::new(&url, None, None)?;
+        sink.send(b"a\n", &mut stats)?;
+        sink.send(b"b\n", &mut stats)?;
+
+        let (mut conn, _) = listener.accept()?;
+        conn.set_read_timeout(Some(Duration::from_secs(10)))?;
+        let mut got = Vec::new();
+        while got.len() < 4 {
+            sink.tick(&mut stats)?;
+            let mut buf = [0; 16];
+            let n = conn.read(&mut buf)?;
+            got.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(got, b"a\nb\n");
+        assert_eq!(stats.lost(), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::mountinfo;
+
+// where cgroup2 usually is, but for hybrid setups
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+fn parse_cgroup(s: &str) -> Option<PathBuf> {
+    s.lines()
+        .find_map(|l| l.strip_prefix("0::"))
+        .map(PathBuf::from)
+}
+
+/// The cgroup (v2) `pid` is in, like /system.slice/nginx.service.
+fn cgroup_of(pid: u32) -> io::Result<PathBuf> {
+    let s = fs::read_to_string(format!("/proc/{}/cgroup", pid))?;
+    parse_cgroup(&s).ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::NotFound,
+            format!("pid {}: not in a cgroup v2", pid),
+        )
+    })
+}
+
+/// The directory of `cgroup` under `root`, where cgroup2 is mounted,
+/// unless it's one already.
+fn dir(root: &Path, cgroup: &Path) -> PathBuf {
+    if cgroup.starts_with(root) {
+        cgroup.into()
+    } else {
+        root.join(cgroup.strip_prefix("/").unwrap_or(cgroup))
+    }
+}
+
+/// Freeze or thaw every process in `cgroup`, and those under it.
+pub fn set_frozen(cgroup: &Path, frozen: bool) -> io::Result<()> {
+    let root = mountinfo::find_fstype("cgroup2")?.unwrap_or_else(|| CGROUP_ROOT.into());
+    fs::write(
+        dir(&root, cgroup).join("cgroup.freeze"),
+        if frozen { "1" } else { "0" },
+    )
+    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", cgroup.display(), e)))
+}
+
+/// Freeze the cgroup `pid` is in, unless we're in it too and couldn't
+/// thaw it, returning it.
+pub fn freeze_pid(pid: u32) -> io::Result<PathBuf> {
+    let cgroup = cgroup_of(pid)?;
+    if cgroup_of(process::id())?.starts_with(&cgroup) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{}: would freeze us too", cgroup.display()),
+        ));
+    }
+    set_frozen(&cgroup, true)?;
+    Ok(cgroup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freeze_cgroup() {
+        assert_eq!(
+            parse_cgroup("1:name=systemd:/x\n0::/system.slice/nginx.service\n"),
+            Some("/system.slice/nginx.service".into())
+        );
+        assert_eq!(parse_cgroup("1:cpu:/x\n"), None);
+        let root = Path::new(CGROUP_ROOT);
+        assert_eq!(
+            dir(root, Path::new("/system.slice/nginx.service")),
+            Path::new("/sys/fs/cgroup/system.slice/nginx.service")
+        );
+        assert_eq!(
+            dir(root, Path::new("/sys/fs/cgroup/user.slice")),
+            Path::new("/sys/fs/cgroup/user.slice")
+        );
+        // never ourselves
+        assert!(freeze_pid(process::id()).is_err());
+    }
+}
@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tonic::client::Grpc as Client;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::transport::{Channel, Endpoint};
+use tonic::Request;
+use tonic_prost::ProstCodec;
+
+use crate::policy::{self, Action};
+use crate::stats::Stats;
+use crate::{EventEntry, PermId};
+
+const DECIDE: &str = "/fanotify.Decider/Decide";
+
+/// The messages of
+///
+/// ```proto
+/// syntax = "proto3";
+/// package fanotify;
+///
+/// service Decider {
+///   rpc Decide(Event) returns (Verdict);
+/// }
+///
+/// message Event {
+///   string mask = 1;
+///   string path = 2;
+///   uint32 pid = 3;
+///   string comm = 4;
+/// }
+///
+/// message Verdict {
+///   enum Action {
+///     UNSPECIFIED = 0;
+///     ALLOW = 1;
+///     DENY = 2;
+///   }
+///   Action action = 1;
+/// }
+/// ```
+#[derive(Clone, PartialEq, prost::Message)]
+struct Event {
+    #[prost(string, tag = "1")]
+    mask: String,
+    #[prost(string, tag = "2")]
+    path: String,
+    #[prost(uint32, tag = "3")]
+    pid: u32,
+    #[prost(string, tag = "4")]
+    comm: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Verdict {
+    #[prost(int32, tag = "1")]
+    action: i32,
+}
+
+impl Verdict {
+    fn action(&self) -> Option<Action> {
+        match self.action {
+            1 => Some(Action::Allow),
+            2 => Some(Action::Deny),
+            _ => None,
+        }
+    }
+}
+
+async fn decide(channel: Channel, event: Event, timeout: Duration) -> Result<Action, String> {
+    let mut client = Client::new(channel);
+    let call = async {
+        client.ready().await.map_err(|e| e.to_string())?;
+        let mut request = Request::new(event);
+        request.set_timeout(timeout);
+        let verdict: Verdict = client
+            .unary(
+                request,
+                PathAndQuery::from_static(DECIDE),
+                ProstCodec::default(),
+            )
+            .await
+            .map_err(|s| format!("{:?}: {}", s.code(), s.message()))?
+            .into_inner();
+        verdict
+            .action()
+            .ok_or_else(|| format!("no action in the verdict: {}", verdict.action))
+    };
+    match tokio::time::timeout(timeout, call).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("no verdict in {:?}", timeout)),
+    }
+}
+
+/// Runs the calls on a runtime of its own, sending back the verdicts by
+/// id and writing to `wake` for each so the main loop polls for them.
+fn run(
+    mut rx: UnboundedReceiver<(u64, Event)>,
+    endpoint: Endpoint,
+    connections: usize,
+    timeout: Duration,
+    verdicts: mpsc::Sender<(u64, Result<Action, String>)>,
+    wake: UnixStream,
+) -> io::Result<()> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    // written to by every call
+    let wake = Arc::new(wake);
+    rt.block_on(async {
+        // connected on their first call
+        let channels: Vec<Channel> = (0..connections).map(|_| endpoint.connect_lazy()).collect();
+        for i in 0.. {
+            let (id, event) = match rx.recv().await {
+                Some(ask) => ask,
+                None => break,
+            };
+            let channel = channels[i % channels.len()].clone();
+            let verdicts = verdicts.clone();
+            let wake = Arc::clone(&wake);
+            tokio::spawn(async move {
+                let verdict = decide(channel, event, timeout).await;
+                if verdicts.send((id, verdict)).is_ok() {
+                    // it's nonblocking, and a full buffer already wakes it
+                    let _ = (&*wake).write(&[0]);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Asks a gRPC service to decide permission events with a
+/// `fanotify.Decider/Decide` call each, over a few connections used in
+/// turn. Those it doesn't decide in time, or at all, get `fail`.
+pub struct Grpc {
+    tx: Option<UnboundedSender<(u64, Event)>>,
+    verdicts: Receiver<(u64, Result<Action, String>)>,
+    wake: UnixStream,
+    worker: Option<JoinHandle<io::Result<()>>>,
+    next_id: u64,
+    /// the events waiting for a verdict, by id
+    asked: HashMap<u64, PermId>,
+    fail: Action,
+    /// whether the last call failed, to log only when that changes
+    failing: bool,
+}
+
+impl Grpc {
+    pub fn new(url: &str, connections: usize, timeout: Duration, fail: Action) -> io::Result<Grpc> {
+        if !url.starts_with("http://") {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{}: only http:// is supported", url),
+            ));
+        }
+        let endpoint = Endpoint::from_shared(url.to_string())
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("{}: {}", url, e)))?
+            .connect_timeout(timeout);
+        let connections = connections.max(1);
+
+        let (tx, rx) = unbounded_channel();
+        let (verdicts_tx, verdicts) = mpsc::channel();
+        let (wake, wake_tx) = UnixStream::pair()?;
+        wake.set_nonblocking(true)?;
+        wake_tx.set_nonblocking(true)?;
+        let worker = thread::Builder::new()
+            .name("grpc".into())
+            .spawn(move || run(rx, endpoint, connections, timeout, verdicts_tx, wake_tx))?;
+
+        Ok(Grpc {
+            tx: Some(tx),
+            verdicts,
+            wake,
+            worker: Some(worker),
+            next_id: 1,
+            asked: HashMap::new(),
+            fail,
+            failing: false,
+        })
+    }
+
+    /// Ask about the permission event `perm`, or answer `fail` right away
+    /// if there's no worker left to ask.
+    pub fn ask(&mut self, perm: PermId, entry: &EventEntry, stats: &mut Stats) -> Option<Action> {
+        // the fd was reused, so the last event with it was answered
+        self.asked.retain(|_, (fd, _)| *fd != perm.0);
+
+        let event = Event {
+            mask: entry.mask_names(),
+            path: entry
+                .path
+                .as_ref()
+                .map(|p| p.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            pid: entry.pid.unwrap_or_default(),
+            comm: policy::comm(entry).unwrap_or_default(),
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+        match &self.tx {
+            Some(tx) if tx.send((id, event)).is_ok() => {
+                self.asked.insert(id, perm);
+                None
+            }
+            _ => {
+                if !self.failing {
+                    error!("grpc: worker stopped, answering {}", self.fail.name());
+                    self.failing = true;
+                }
+                stats.grpc_failed += 1;
+                Some(self.fail)
+            }
+        }
+    }
+
+    /// The events decided since the last time, with `fail` for the calls
+    /// that failed.
+    pub fn read(&mut self, stats: &mut Stats) -> Vec<(PermId, Action)> {
+        let mut buf = [0; 4096];
+        while let Ok(n) = (&self.wake).read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+
+        let mut decided = Vec::new();
+        while let Ok((id, verdict)) = self.verdicts.try_recv() {
+            let action = match verdict {
+                Ok(action) => {
+                    if self.failing {
+                        info!("grpc: deciding again");
+                        self.failing = false;
+                    }
+                    action
+                }
+                Err(e) => {
+                    if !self.failing {
+                        warn!("grpc: {}, answering {} until it works", e, self.fail.name());
+                        self.failing = true;
+                    } else {
+                        debug!("grpc: {}", e);
+                    }
+                    stats.grpc_failed += 1;
+                    self.fail
+                }
+            };
+            if let Some(perm) = self.asked.remove(&id) {
+                decided.push((perm, action));
+            }
+        }
+        decided
+    }
+
+    /// Forget the events that were answered some other way.
+    pub fn answered(&mut self, pending: &dyn Fn(PermId) -> bool) {
+        self.asked.retain(|_, perm| pending(*perm));
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.wake.as_raw_fd()
+    }
+}
+
+impl Drop for Grpc {
+    fn drop(&mut self) {
+        self.tx = None;
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::future::{self, Ready};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Instant;
+    use tonic::body::Body;
+    use tonic::codegen::{http, BoxFuture, Context, Poll, Service};
+    use tonic::server::{NamedService, UnaryService};
+    use tonic::transport::Server;
+    use tonic::{Response, Status};
+
+    /// Denies what's under /etc and allows the rest.
+    #[derive(Clone)]
+    struct Decider;
+
+    impl UnaryService<Event> for Decider {
+        type Response = Verdict;
+        type Future = Ready<Result<Response<Verdict>, Status>>;
+
+        fn call(&mut self, request: Request<Event>) -> Self::Future {
+            let action = if request.get_ref().path.starts_with("/etc/") {
+                2
+            } else {
+                1
+            };
+            future::ready(Ok(Response::new(Verdict { action })))
+        }
+    }
+
+    impl NamedService for Decider {
+        const NAME: &'static str = "fanotify.Decider";
+    }
+
+    impl Service<http::Request<Body>> for Decider {
+        type Response = http::Response<Body>;
+        type Error = Infallible;
+        type Future = BoxFuture<Self::Response, Infallible>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+            Box::pin(async move {
+                let mut grpc = tonic::server::Grpc::new(ProstCodec::default());
+                Ok(grpc.unary(Decider, request).await)
+            })
+        }
+    }
+
+    fn wait(grpc: &mut Grpc, stats: &mut Stats, n: usize) -> Vec<(PermId, Action)> {
+        let mut decided = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while decided.len() < n && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+            decided.extend(grpc.read(stats));
+        }
+        decided.sort_by_key(|(perm, _)| *perm);
+        decided
+    }
+
+    #[test]
+    fn grpc_decide() -> io::Result<()> {
+        let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        thread::spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            rt.block_on(Server::builder().add_service(Decider).serve(addr))
+        });
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while TcpStream::connect(addr).is_err() {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let url = format!("http://{}", addr);
+        let mut stats = Stats::default();
+        let mut grpc = Grpc::new(&url, 2, Duration::from_secs(5), Action::Allow)?;
+        let shadow = EventEntry::test(libc::FAN_OPEN_PERM, "/etc/shadow");
+        let tmp = EventEntry::test(libc::FAN_OPEN_PERM, "/tmp/a");
+        assert_eq!(grpc.ask((7, 1), &shadow, &mut stats), None);
+        assert_eq!(grpc.ask((8, 2), &tmp, &mut stats), None);
+        assert_eq!(
+            wait(&mut grpc, &mut stats, 2),
+            vec![((7, 1), Action::Deny), ((8, 2), Action::Allow)]
+        );
+        assert_eq!(stats.grpc_failed, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn grpc_fail_mode() -> io::Result<()> {
+        assert!(Grpc::new("unix:/x", 1, Duration::from_secs(1), Action::Allow).is_err());
+
+        // a port nothing listens on
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let url = format!("http://127.0.0.1:{}", port);
+        let entry = EventEntry {
+            fd: Some(7),
+            pid: Some(42),
+            fields: vec![("comm", "cat".into())],
+            ..EventEntry::test(libc::FAN_OPEN_PERM, "/etc/shadow")
+        };
+        let mut stats = Stats::default();
+        for &fail in &[Action::Deny, Action::Allow] {
+            let mut grpc = Grpc::new(&url, 2, Duration::from_millis(200), fail)?;
+            assert_eq!(grpc.ask((7, 1), &entry, &mut stats), None);
+            assert_eq!(grpc.ask((8, 2), &entry, &mut stats), None);
+            assert_eq!(
+                wait(&mut grpc, &mut stats, 2),
+                vec![((7, 1), fail), ((8, 2), fail)]
+            );
+
+            // with the worker gone it's answered right away
+            grpc.tx = None;
+            assert_eq!(grpc.ask((9, 3), &entry, &mut stats), Some(fail));
+        }
+        assert_eq!(stats.grpc_failed, 6);
+        Ok(())
+    }
+}
@@ -0,0 +1,360 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem::ManuallyDrop;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use sha2::{Digest, Sha256};
+
+use crate::chain::to_hex;
+use crate::stats::Stats;
+use crate::throttle::Held;
+use crate::PERM_EVENTS;
+
+/// How many events may be held back at once, past which the oldest are let
+/// go without their hash, rather than pile up behind a slow one.
+const MAX_QUEUED: usize = 4096;
+
+/// What --hash computes, and of files up to how many bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HashSpec {
+    pub max: Option<u64>,
+}
+
+impl FromStr for HashSpec {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid value: {}, options: sha256, sha256:MAXSIZE", s),
+            )
+        };
+        match s.split_once(':') {
+            None if s == "sha256" => Ok(HashSpec { max: None }),
+            Some(("sha256", max)) => Ok(HashSpec {
+                max: Some(max.parse().map_err(|_| invalid())?),
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// The sha256 of `file`, unless it's more than `max` bytes by now.
+fn sha256(file: &File, max: Option<u64>) -> io::Result<Option<String>> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 65536];
+    let mut off = 0;
+    loop {
+        let n = file.read_at(&mut buf, off)?;
+        if n == 0 {
+            return Ok(Some(to_hex(&hasher.finalize())));
+        }
+        hasher.update(&buf[..n]);
+        off += n as u64;
+        if max.is_some_and(|max| off > max) {
+            return Ok(None);
+        }
+    }
+}
+
+struct Job {
+    seq: u64,
+    file: File,
+    max: Option<u64>,
+}
+
+fn run(
+    jobs: Arc<Mutex<Receiver<Job>>>,
+    replies: Sender<(u64, Option<String>)>,
+    mut wake: UnixStream,
+) {
+    loop {
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        let hash = match sha256(&job.file, job.max) {
+            Ok(hash) => hash,
+            Err(e) => {
+                debug!("--hash: {}", e);
+                None
+            }
+        };
+        if replies.send((job.seq, hash)).is_err() {
+            return;
+        }
+        // it's nonblocking, and a full buffer already wakes it
+        let _ = wake.write(&[0]);
+    }
+}
+
+struct Queued {
+    seq: u64,
+    held: Held,
+    /// until it's hashed, or for good if it isn't
+    done: bool,
+    hash: Option<String>,
+}
+
+/// Hashes the files of events on worker threads, so a big one doesn't hold
+/// up the main loop, holding back the events until theirs is done, and
+/// those after them to keep them in order.
+pub struct Hasher {
+    /// for --hash, None if only for --exec-allowlist
+    spec: Option<HashSpec>,
+    /// whether executables are hashed whatever their size, for
+    /// --exec-allowlist
+    exec: bool,
+    tx: Sender<Job>,
+    replies: Receiver<(u64, Option<String>)>,
+    wake: UnixStream,
+    seq: u64,
+    queue: VecDeque<Queued>,
+}
+
+impl Hasher {
+    pub fn new(spec: Option<HashSpec>, exec: bool) -> io::Result<Hasher> {
+        let (tx, jobs) = mpsc::channel();
+        let (replies_tx, replies) = mpsc::channel();
+        let (wake, wake_tx) = UnixStream::pair()?;
+        wake.set_nonblocking(true)?;
+        wake_tx.set_nonblocking(true)?;
+        let jobs = Arc::new(Mutex::new(jobs));
+        let workers = thread::available_parallelism().map_or(1, |n| n.get());
+        for i in 0..workers {
+            let (jobs, replies_tx, wake_tx) =
+                (jobs.clone(), replies_tx.clone(), wake_tx.try_clone()?);
+            thread::Builder::new()
+                .name(format!("hash-{}", i))
+                .spawn(move || run(jobs, replies_tx, wake_tx))?;
+        }
+        Ok(Hasher {
+            spec,
+            exec,
+            tx,
+            replies,
+            wake,
+            seq: 0,
+            queue: VecDeque::new(),
+        })
+    }
+
+    /// Whether to hash the file of an event with `mask`, and up to how
+    /// many bytes.
+    fn max(&self, mask: u64) -> Option<Option<u64>> {
+        if self.exec && mask & libc::FAN_OPEN_EXEC_PERM != 0 {
+            Some(None)
+        } else {
+            self.spec.map(|spec| spec.max)
+        }
+    }
+
+    /// A file of its own for the `fd` of an event with `mask`, if it's a
+    /// regular file small enough to hash.
+    pub fn open(&self, fd: RawFd, mask: u64, stats: &mut Stats) -> Option<File> {
+        let max = self.max(mask)?;
+        // borrowed, the caller closes it
+        let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+        let m = file.metadata().ok()?;
+        if !m.is_file() {
+            return None;
+        }
+        if max.is_some_and(|max| m.len() > max) {
+            stats.hash_skipped += 1;
+            return None;
+        }
+        file.try_clone().ok()
+    }
+
+    /// Whether events are held back, so the next has to wait too.
+    pub fn busy(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    /// Hold back `held` until `file`, if any, is hashed. Returns the events
+    /// let go to make room for it.
+    pub fn queue(&mut self, mut held: Held, file: Option<File>, stats: &mut Stats) -> Vec<Held> {
+        // the fd was reused, so the events with it before were answered
+        for q in &mut self.queue {
+            if q.held.fd == held.fd {
+                q.held.fd = -1;
+            }
+        }
+        if held.entry.mask & PERM_EVENTS == 0 {
+            // closed already, the number may be another's by the time
+            // it's decided
+            held.fd = -1;
+        }
+        self.seq += 1;
+        let done = match file {
+            Some(file) => {
+                let job = Job {
+                    seq: self.seq,
+                    file,
+                    max: self.max(held.entry.mask).flatten(),
+                };
+                // the workers only stop when we do
+                let _ = self.tx.send(job);
+                false
+            }
+            None => true,
+        };
+        self.queue.push_back(Queued {
+            seq: self.seq,
+            held,
+            done,
+            hash: None,
+        });
+
+        let over = self.queue.len().saturating_sub(MAX_QUEUED);
+        for q in self.queue.iter_mut().take(over).filter(|q| !q.done) {
+            // its hash is ignored if it ever comes
+            q.done = true;
+            stats.hash_skipped += 1;
+        }
+        self.release()
+    }
+
+    /// The events done with, in the order they were read.
+    pub fn read(&mut self, stats: &mut Stats) -> Vec<Held> {
+        let mut buf = [0; 4096];
+        while let Ok(n) = (&self.wake).read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+
+        while let Ok((seq, hash)) = self.replies.try_recv() {
+            // in order, so the first left is the oldest
+            let first = self.queue.front().map_or(seq, |q| q.seq);
+            match self.queue.get_mut(seq.wrapping_sub(first) as usize) {
+                Some(q) if !q.done => {
+                    if hash.is_none() {
+                        stats.hash_skipped += 1;
+                    }
+                    q.done = true;
+                    q.hash = hash;
+                }
+                // let go without it already
+                _ => (),
+            }
+        }
+        self.release()
+    }
+
+    fn release(&mut self) -> Vec<Held> {
+        let mut released = Vec::new();
+        while self.queue.front().is_some_and(|q| q.done) {
+            let mut q = self.queue.pop_front().unwrap();
+            if let Some(hash) = q.hash {
+                q.held.entry.fields.push(("sha256", hash));
+            }
+            released.push(q.held);
+        }
+        released
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.wake.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EventEntry, TempDir};
+    use std::fs;
+
+    #[test]
+    fn hash_in_order() -> io::Result<()> {
+        assert_eq!("sha256".parse::<HashSpec>()?, HashSpec { max: None });
+        assert_eq!(
+            "sha256:100".parse::<HashSpec>()?,
+            HashSpec { max: Some(100) }
+        );
+        assert!("md5".parse::<HashSpec>().is_err());
+        assert!("sha256:big".parse::<HashSpec>().is_err());
+
+        let dir = TempDir::new("hasher")?;
+        fs::write(dir.join("small"), "hello\n")?;
+        fs::write(dir.join("big"), vec![0; 200])?;
+        let small = File::open(dir.join("small"))?;
+        let big = File::open(dir.join("big"))?;
+
+        let mut hasher = Hasher::new(Some("sha256:100".parse()?), true)?;
+        let mut stats = Stats::default();
+        let held = |fd, mask| Held {
+            fd,
+            entry: EventEntry {
+                fd: Some(fd),
+                pid: Some(42),
+                path: None,
+                ..EventEntry::test(mask, "")
+            },
+            pidfd: None,
+            timestamp: None,
+            report: true,
+        };
+        assert!(!hasher.busy());
+        let file = hasher.open(small.as_raw_fd(), libc::FAN_OPEN_PERM, &mut stats);
+        assert!(file.is_some());
+        assert!(hasher
+            .queue(
+                held(small.as_raw_fd(), libc::FAN_OPEN_PERM),
+                file,
+                &mut stats
+            )
+            .is_empty());
+        assert!(hasher.busy());
+        assert!(hasher
+            .open(big.as_raw_fd(), libc::FAN_OPEN, &mut stats)
+            .is_none());
+        assert_eq!(stats.hash_skipped, 1);
+        // executables whatever their size, for --exec-allowlist
+        assert!(hasher
+            .open(big.as_raw_fd(), libc::FAN_OPEN_EXEC_PERM, &mut stats)
+            .is_some());
+        let only_exec = Hasher::new(None, true)?;
+        assert!(only_exec
+            .open(small.as_raw_fd(), libc::FAN_OPEN_PERM, &mut stats)
+            .is_none());
+        assert!(only_exec
+            .open(big.as_raw_fd(), libc::FAN_OPEN_EXEC_PERM, &mut stats)
+            .is_some());
+        assert!(hasher
+            .queue(held(big.as_raw_fd(), libc::FAN_OPEN), None, &mut stats)
+            .is_empty());
+
+        let mut released = Vec::new();
+        while released.len() < 2 {
+            let mut pfd = libc::pollfd {
+                fd: hasher.fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            assert_eq!(unsafe { libc::poll(&mut pfd, 1, 10000) }, 1);
+            released.extend(hasher.read(&mut stats));
+        }
+        assert!(!hasher.busy());
+        assert_eq!(released[0].fd, small.as_raw_fd());
+        assert_eq!(
+            released[0].entry.fields,
+            vec![(
+                "sha256",
+                "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03".into()
+            )]
+        );
+        // not a permission event, so not answered by its fd
+        assert_eq!(released[1].fd, -1);
+        assert!(released[1].entry.fields.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::write_path;
+
+/// How many events there were under a directory.
+#[derive(Debug, PartialEq)]
+pub struct Heat {
+    pub dir: PathBuf,
+    pub count: u64,
+}
+
+impl Heat {
+    pub fn write_to(&self, w: &mut dyn Write, raw_paths: bool) -> io::Result<()> {
+        w.write_fmt(format_args!("HEAT\t{}\t", self.count))?;
+        write_path(w, &self.dir, raw_paths)
+    }
+}
+
+/// Counts events per directory, rolled up to `depth` levels below /, and
+/// reports the busiest ones every `interval`.
+pub struct HeatMap {
+    depth: usize,
+    top: usize,
+    interval: Duration,
+    next: Instant,
+    counts: HashMap<PathBuf, u64>,
+}
+
+fn rollup(path: &Path, depth: usize) -> PathBuf {
+    // +1 for the root
+    path.parent()
+        .unwrap_or(path)
+        .components()
+        .take(depth + 1)
+        .collect()
+}
+
+impl HeatMap {
+    pub fn new(depth: usize, top: usize, interval: Duration) -> HeatMap {
+        HeatMap {
+            depth,
+            top,
+            interval,
+            next: Instant::now() + interval,
+            counts: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, path: &Path) {
+        *self.counts.entry(rollup(path, self.depth)).or_insert(0) += 1;
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.next
+    }
+
+    /// The busiest directories since the last call, busiest first, then
+    /// start counting afresh.
+    pub fn take(&mut self) -> Vec<Heat> {
+        self.next = Instant::now() + self.interval;
+        let mut hot: Vec<(PathBuf, u64)> = self.counts.drain().collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        hot.truncate(self.top);
+        hot.into_iter()
+            .map(|(dir, count)| Heat { dir, count })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heat_rollup() {
+        assert_eq!(rollup(Path::new("/a/b/c/d"), 2), Path::new("/a/b"));
+        assert_eq!(rollup(Path::new("/a/f"), 2), Path::new("/a"));
+        assert_eq!(rollup(Path::new("/f"), 2), Path::new("/"));
+    }
+
+    #[test]
+    fn heat_top() -> io::Result<()> {
+        let mut h = HeatMap::new(1, 2, Duration::from_secs(1));
+        for p in &["/a/x", "/a/b/y", "/b/z", "/b/z", "/b/z", "/c/z"] {
+            h.record(Path::new(p));
+        }
+
+        let hot = h.take();
+        assert_eq!(hot.len(), 2);
+        let mut buf = vec![];
+        hot[0].write_to(&mut buf, false)?;
+        assert_eq!(String::from_utf8(buf).unwrap(), "HEAT\t3\t/b");
+        assert_eq!(
+            hot[1],
+            Heat {
+                dir: "/a".into(),
+                count: 2
+            }
+        );
+        assert!(h.counts.is_empty());
+        Ok(())
+    }
+}
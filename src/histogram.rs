@@ -0,0 +1,102 @@
+use std::fmt;
+
+// sub-buckets per power of two, as in HdrHistogram: values are kept to
+// within 1/8th
+const SUB_BITS: u32 = 3;
+const SUB: u64 = 1 << SUB_BITS;
+
+fn bucket(v: u64) -> usize {
+    if v < SUB {
+        return v as usize;
+    }
+    let shift = 63 - v.leading_zeros() - SUB_BITS;
+    ((shift as u64 + 1) * SUB + (v >> shift) - SUB) as usize
+}
+
+/// The largest value that goes in bucket `b`.
+fn highest(b: usize) -> u64 {
+    let b = b as u64;
+    if b < SUB {
+        return b;
+    }
+    let shift = b / SUB - 1;
+    // wraps to u64::MAX for the last bucket
+    ((b % SUB + SUB + 1) << shift).wrapping_sub(1)
+}
+
+/// A log-linear histogram, cheap to record into and small no matter the
+/// range of values.
+#[derive(Default, Debug)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, v: u64) {
+        let b = bucket(v);
+        if b >= self.counts.len() {
+            self.counts.resize(b + 1, 0);
+        }
+        self.counts[b] += 1;
+        self.total += 1;
+        self.max = self.max.max(v);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total == 0
+    }
+
+    /// The value that `q` of the recorded values are at or below.
+    pub fn quantile(&self, q: f64) -> u64 {
+        let want = ((q * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (b, n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= want {
+                return highest(b).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "n={} p50={} p90={} p99={} max={}",
+            self.total,
+            self.quantile(0.5),
+            self.quantile(0.9),
+            self.quantile(0.99),
+            self.max
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets() {
+        for v in [0, 7, 8, 15, 16, 17, 31, 32, 1000, u64::MAX] {
+            let b = bucket(v);
+            assert!(highest(b) >= v, "{}", v);
+            assert!(b == 0 || highest(b - 1) < v, "{}", v);
+        }
+        assert_eq!(bucket(16), bucket(17));
+        assert_ne!(bucket(17), bucket(18));
+    }
+
+    #[test]
+    fn histogram_quantile() {
+        let mut h = Histogram::default();
+        for v in 1..=100 {
+            h.record(v);
+        }
+        assert_eq!(h.to_string(), "n=100 p50=51 p90=95 p99=100 max=100");
+    }
+}
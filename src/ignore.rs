@@ -0,0 +1,108 @@
+use std::ffi::{CString, OsStr};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use libc::{c_int, c_uint};
+
+use crate::fanotify_mark;
+
+fn stat_at(dirfd: c_int, path: &CString) -> io::Result<libc::stat> {
+    let mut st: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstatat(dirfd, path.as_ptr(), &mut st, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(st)
+}
+
+/// Whether `path` is the root of a mount, bind mounts of a directory on
+/// the same filesystem aside.
+fn is_mount_root(dirfd: c_int, path: &CString) -> io::Result<bool> {
+    let mut parent = path.as_bytes().to_vec();
+    parent.extend_from_slice(b"/..");
+    let st = stat_at(dirfd, path)?;
+    let up = stat_at(dirfd, &CString::new(parent).unwrap())?;
+    Ok(st.st_dev != up.st_dev || st.st_ino == up.st_ino)
+}
+
+/// Add an ignore mark for `mask` on `path`. A mount root is ignored
+/// entirely, a directory only for itself and its direct children, the
+/// rest of the subtree is up to `Ignore::contains`.
+pub fn mark(
+    notify_fd: c_int,
+    dirfd: c_int,
+    path: &CString,
+    mask: u64,
+    evictable: bool,
+) -> io::Result<()> {
+    let events = mask & !(libc::FAN_ONDIR | libc::FAN_EVENT_ON_CHILD);
+    let (mark_type, mask) = if is_mount_root(dirfd, path)? {
+        (libc::FAN_MARK_MOUNT, events | libc::FAN_ONDIR)
+    } else {
+        let evictable = if evictable {
+            libc::FAN_MARK_EVICTABLE
+        } else {
+            0
+        };
+        if stat_at(dirfd, path)?.st_mode & libc::S_IFMT == libc::S_IFDIR {
+            (
+                evictable,
+                events | libc::FAN_ONDIR | libc::FAN_EVENT_ON_CHILD,
+            )
+        } else {
+            (evictable, events)
+        }
+    };
+
+    let add = |flags: c_uint, mask: u64| {
+        fanotify_mark(
+            notify_fd,
+            libc::FAN_MARK_ADD | mark_type | flags,
+            mask,
+            dirfd,
+            path.as_ptr(),
+        )
+    };
+    // without SURV_MODIFY the first write to an ignored file would quietly
+    // clear its mark, and the events come back
+    match add(libc::FAN_MARK_IGNORE_SURV, mask) {
+        // before 6.0 ignore masks can't say whether they apply to
+        // directories
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => add(
+            libc::FAN_MARK_IGNORED_MASK | libc::FAN_MARK_IGNORED_SURV_MODIFY,
+            mask & !libc::FAN_ONDIR,
+        ),
+        res => res,
+    }?;
+    Ok(())
+}
+
+/// Whether `path` is under one of `ignore`, for what the marks don't
+/// cover.
+pub fn ignored(ignore: &[CString], path: &Path) -> bool {
+    ignore
+        .iter()
+        .any(|p| path.starts_with(OsStr::from_bytes(p.as_bytes())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignore_subtree() {
+        let ignore = [CString::new("/var/log").unwrap()];
+        assert!(ignored(&ignore, Path::new("/var/log")));
+        assert!(ignored(&ignore, Path::new("/var/log/a/b")));
+        assert!(!ignored(&ignore, Path::new("/var/logs")));
+        assert!(!ignored(&ignore, Path::new("/var")));
+    }
+
+    #[test]
+    fn mount_root() {
+        assert!(is_mount_root(libc::AT_FDCWD, &CString::new("/").unwrap()).unwrap());
+        assert!(is_mount_root(libc::AT_FDCWD, &CString::new("/proc").unwrap()).unwrap());
+        assert!(!is_mount_root(libc::AT_FDCWD, &CString::new("/proc/self").unwrap()).unwrap());
+    }
+}
@@ -0,0 +1,183 @@
+use std::ffi::OsString;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// inotifywait's names, which -e takes as they are
+const EVENTS: &[&str] = &[
+    "access",
+    "modify",
+    "attrib",
+    "close_write",
+    "close_nowrite",
+    "close",
+    "open",
+    "moved_to",
+    "moved_from",
+    "move",
+    "create",
+    "delete",
+];
+
+const DEFAULT_FORMAT: &str = "%w %e %f";
+
+/// Whether to take inotifywait's arguments, when run as inotifywait or
+/// with --inotifywait first.
+pub fn wanted(args: &[OsString]) -> bool {
+    args.first()
+        .and_then(|a| Path::new(a).file_name())
+        .is_some_and(|name| name == "inotifywait")
+        || args.get(1).is_some_and(|a| a == "--inotifywait")
+}
+
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("inotifywait {} is not supported", what),
+    )
+}
+
+/// Turn an inotifywait --format into a --format.
+fn format(fmt: &str) -> io::Result<String> {
+    let mut out = String::new();
+    let mut chars = fmt.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '%' => match chars.next() {
+                Some('w') => out.push_str("{dir}"),
+                Some('f') => out.push_str("{name}"),
+                Some('e') => out.push_str("{events}"),
+                Some('T') => out.push_str("{time}"),
+                Some('%') => out.push('%'),
+                Some(c) => return Err(unsupported(&format!("--format %{}", c))),
+                None => out.push('%'),
+            },
+            '{' => out.push_str("{}"),
+            '\\' => out.push_str("\\\\"),
+            c => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+/// Translate inotifywait's arguments into ours.
+pub fn translate(args: &[OsString]) -> io::Result<Vec<OsString>> {
+    let mut out: Vec<OsString> = args.iter().take(1).cloned().collect();
+    let mut rest = args.iter().skip(1).peekable();
+    if rest.peek().is_some_and(|a| *a == "--inotifywait") {
+        rest.next();
+    }
+
+    let mut monitor = false;
+    let mut events = Vec::new();
+    let mut fmt = None;
+    let mut paths = Vec::new();
+    let value = |rest: &mut dyn Iterator<Item = &OsString>, opt: &str| {
+        rest.next()
+            .and_then(|v| v.to_str())
+            .map(String::from)
+            .ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidInput, format!("{} needs a value", opt))
+            })
+    };
+
+    while let Some(arg) = rest.next() {
+        let s = match arg.to_str() {
+            Some(s) if s.starts_with('-') && s.len() > 1 => s,
+            _ => {
+                paths.push(arg.clone());
+                continue;
+            }
+        };
+        // expand -mrq into -m -r -q
+        let opts: Vec<String> = if s.starts_with("--") {
+            vec![s.into()]
+        } else {
+            s[1..].chars().map(|c| format!("-{}", c)).collect()
+        };
+        for opt in opts {
+            match opt.as_str() {
+                "-m" | "--monitor" => monitor = true,
+                "-r" | "--recursive" => out.push("-r".into()),
+                "-q" | "--quiet" => (),
+                "-e" | "--event" => {
+                    for e in value(&mut rest, &opt)?.split(',') {
+                        if !EVENTS.contains(&e) {
+                            return Err(unsupported(&format!("event {}", e)));
+                        }
+                        events.push(e.to_string());
+                    }
+                }
+                "--format" => fmt = Some(format(&value(&mut rest, &opt)?)?),
+                "--timefmt" => {
+                    out.push("--timefmt".into());
+                    out.push(value(&mut rest, &opt)?.into());
+                }
+                "-o" | "--outfile" => {
+                    out.push("--output-file".into());
+                    out.push(value(&mut rest, &opt)?.into());
+                }
+                "-t" | "--timeout" => {
+                    let secs: u64 = value(&mut rest, &opt)?.parse().map_err(|e| {
+                        io::Error::new(ErrorKind::InvalidInput, format!("{}: {}", opt, e))
+                    })?;
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default();
+                    out.push("--stop-at".into());
+                    out.push(format!("@{}", now.as_secs() + secs).into());
+                }
+                "--" => paths.extend(rest.by_ref().cloned()),
+                opt => return Err(unsupported(opt)),
+            }
+        }
+    }
+
+    if !monitor {
+        // inotifywait exits after the first event unless -m
+        out.push("--max-events".into());
+        out.push("1".into());
+    }
+    if !events.is_empty() {
+        // inotify watches on a directory always report its children
+        events.push("ondir".into());
+        events.push("event_on_child".into());
+        out.push("-e".into());
+        out.push(events.join(",").into());
+    }
+    out.push("--format".into());
+    out.push(fmt.unwrap_or(format(DEFAULT_FORMAT)?).into());
+    out.extend(paths);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<OsString> {
+        s.split(' ').map(OsString::from).collect()
+    }
+
+    #[test]
+    fn inotifywait_args() {
+        assert!(wanted(&args("/usr/bin/inotifywait -m /tmp")));
+        assert!(wanted(&args("fanotify-cli --inotifywait -m /tmp")));
+        assert!(!wanted(&args("fanotify-cli -m /tmp")));
+
+        assert_eq!(
+            translate(&args("inotifywait -mrq -e close_write,moved_to --format %T_%w%f /d")).unwrap(),
+            args("inotifywait -r -e close_write,moved_to,ondir,event_on_child --format {time}_{dir}{name} /d")
+        );
+        assert_eq!(
+            translate(&args("fanotify-cli --inotifywait /d")).unwrap(),
+            args("fanotify-cli --max-events 1 --format {dir}_{events}_{name} /d")
+                .into_iter()
+                .map(|a| OsString::from(a.to_str().unwrap().replace('_', " ")))
+                .collect::<Vec<_>>()
+        );
+        assert!(translate(&args("inotifywait -e delete_self /d")).is_err());
+        assert!(translate(&args("inotifywait --exclude x /d")).is_err());
+        assert_eq!(format("%e {%%} \\").unwrap(), "{events} {}%} \\\\");
+    }
+}
@@ -0,0 +1,103 @@
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixDatagram;
+
+use crate::sink::Sink;
+use crate::stats::Stats;
+use crate::{EventEntry, EventFormat};
+
+const SOCKET: &str = "/run/systemd/journal/socket";
+const IDENTIFIER: &str = "fanotify-cli";
+// LOG_INFO
+const PRIORITY: &str = "6";
+
+/// Write one field in the native protocol but for the newline that ends
+/// it, values with a newline in them need the binary form.
+fn write_value(w: &mut dyn Write, name: &str, value: &[u8]) -> io::Result<()> {
+    if value.contains(&b'\n') {
+        w.write_fmt(format_args!("{}\n", name))?;
+        w.write_all(&(value.len() as u64).to_le_bytes())?;
+    } else {
+        w.write_fmt(format_args!("{}=", name))?;
+    }
+    w.write_all(value)
+}
+
+fn write_field(w: &mut dyn Write, name: &str, value: &[u8]) -> io::Result<()> {
+    write_value(w, name, value)?;
+    w.write_all(b"\n")
+}
+
+/// Writes events as journal entries, with a FAN_ field for each column and
+/// the usual line as MESSAGE. The newline after the last field comes from
+/// whoever writes the record.
+pub struct Journald;
+
+impl EventFormat for Journald {
+    fn write_event(&self, entry: &EventEntry, w: &mut dyn Write) -> io::Result<()> {
+        write_field(w, "SYSLOG_IDENTIFIER", IDENTIFIER.as_bytes())?;
+        write_field(w, "PRIORITY", PRIORITY.as_bytes())?;
+        write_field(w, "FAN_MASK", entry.mask_names().as_bytes())?;
+        if let Some(fd) = entry.fd {
+            write_field(w, "FAN_FD", fd.to_string().as_bytes())?;
+        }
+        if let Some(pid) = entry.pid {
+            write_field(w, "FAN_PID", pid.to_string().as_bytes())?;
+        }
+        if let Some(path) = &entry.path {
+            write_field(w, "FAN_PATH", path.as_os_str().as_bytes())?;
+        }
+        for (k, v) in &entry.fields {
+            write_field(w, &format!("FAN_{}", k.to_uppercase()), v.as_bytes())?;
+        }
+
+        let mut message = Vec::new();
+        entry.write_to(&mut message)?;
+        write_value(w, "MESSAGE", &message)
+    }
+}
+
+/// Sends records to journald, one datagram per entry.
+pub struct JournaldSink {
+    socket: UnixDatagram,
+}
+
+impl JournaldSink {
+    pub fn new() -> io::Result<JournaldSink> {
+        Ok(JournaldSink {
+            socket: UnixDatagram::unbound()?,
+        })
+    }
+}
+
+impl Sink for JournaldSink {
+    fn send(&mut self, record: &[u8], _stats: &mut Stats) -> io::Result<()> {
+        self.socket.send_to(record, SOCKET)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journald_fields() {
+        let entry = EventEntry {
+            pid: Some(42),
+            fields: vec![("comm", "cat".into())],
+            ..EventEntry::test(libc::FAN_OPEN, "/a\nb")
+        };
+        let mut buf = Vec::new();
+        Journald.write_event(&entry, &mut buf).unwrap();
+
+        let mut expected = b"SYSLOG_IDENTIFIER=fanotify-cli\nPRIORITY=6\nFAN_MASK=FAN_OPEN\n\
+                             FAN_PID=42\nFAN_PATH\n"
+            .to_vec();
+        expected.extend_from_slice(&4u64.to_le_bytes());
+        // the message has the path escaped, so it's one line
+        expected
+            .extend_from_slice(b"/a\nb\nFAN_COMM=cat\nMESSAGE=FAN_OPEN\t-\t42\t/a\\nb\tcomm=cat");
+        assert_eq!(buf, expected);
+    }
+}
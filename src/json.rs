@@ -0,0 +1,71 @@
+use std::io::{self, Write};
+
+use crate::{EventEntry, EventFormat};
+
+/// Write `s` as a JSON string.
+pub fn write_str(w: &mut dyn Write, s: &str) -> io::Result<()> {
+    w.write_all(b"\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => w.write_all(b"\\\"")?,
+            '\\' => w.write_all(b"\\\\")?,
+            '\n' => w.write_all(b"\\n")?,
+            '\t' => w.write_all(b"\\t")?,
+            '\r' => w.write_all(b"\\r")?,
+            c if (c as u32) < 0x20 => w.write_fmt(format_args!("\\u{:04x}", c as u32))?,
+            c => w.write_fmt(format_args!("{}", c))?,
+        }
+    }
+    w.write_all(b"\"")
+}
+
+/// Writes each event as a JSON object on one line. Paths that aren't
+/// UTF-8 are written lossily, fields the event doesn't have are left out.
+pub struct Json;
+
+impl EventFormat for Json {
+    fn write_event(&self, entry: &EventEntry, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(b"{\"mask\":")?;
+        write_str(w, &entry.mask_names())?;
+        if let Some(fd) = entry.fd {
+            w.write_fmt(format_args!(",\"fd\":{}", fd))?;
+        }
+        if let Some(pid) = entry.pid {
+            w.write_fmt(format_args!(",\"pid\":{}", pid))?;
+        }
+        if let Some(path) = &entry.path {
+            w.write_all(b",\"path\":")?;
+            write_str(w, &path.to_string_lossy())?;
+        }
+        for (k, v) in &entry.fields {
+            w.write_all(b",")?;
+            write_str(w, k)?;
+            w.write_all(b":")?;
+            write_str(w, v)?;
+        }
+        w.write_all(b"}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_event() {
+        let mut buf = Vec::new();
+        Json.write_event(
+            &EventEntry {
+                pid: Some(42),
+                fields: vec![("comm", "c\\t".into())],
+                ..EventEntry::test(libc::FAN_OPEN, "/a \"b\"\n\x01")
+            },
+            &mut buf,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            r#"{"mask":"FAN_OPEN","pid":42,"path":"/a \"b\"\n\u0001","comm":"c\\t"}"#
+        );
+    }
+}
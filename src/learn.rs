@@ -0,0 +1,177 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::policy;
+use crate::EventEntry;
+
+// files in a directory an executable opens before it's allowed all of it
+const COLLAPSE: usize = 4;
+
+/// `path` as a --policy glob matching only it, or close to it: with `?`
+/// for the whitespace a rule can't have, and the bytes that aren't UTF-8.
+pub fn glob(path: &Path) -> String {
+    let mut glob = String::new();
+    for chunk in path.as_os_str().as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '*' | '?' | '[' | '\\' => {
+                    glob.push('\\');
+                    glob.push(c);
+                }
+                c if c.is_whitespace() => glob.push('?'),
+                c => glob.push(c),
+            }
+        }
+        for _ in chunk.invalid() {
+            glob.push('?');
+        }
+    }
+    glob
+}
+
+/// Globs matching `paths`: the paths themselves, or all under a directory
+/// for one that directly has at least COLLAPSE of them, as * matches /
+/// too.
+fn globs(paths: &BTreeSet<PathBuf>) -> BTreeSet<String> {
+    let mut children = HashMap::new();
+    for parent in paths.iter().filter_map(|p| p.parent()) {
+        *children.entry(parent).or_insert(0) += 1;
+    }
+    paths
+        .iter()
+        .map(|path| {
+            // the one nearest the root
+            match path
+                .ancestors()
+                .skip(1)
+                .filter(|a| children.get(a).is_some_and(|n| *n >= COLLAPSE))
+                .last()
+            {
+                Some(dir) if dir == Path::new("/") => "/*".into(),
+                Some(dir) => format!("{}/*", glob(dir)),
+                None => glob(path),
+            }
+        })
+        .collect()
+}
+
+/// Which executables opened what during --learn, to propose a --policy
+/// allowing only that.
+pub struct Learn {
+    started: Instant,
+    until: Instant,
+    paths: BTreeMap<PathBuf, BTreeSet<PathBuf>>,
+    events: u64,
+    /// events by processes gone before we could tell what they ran
+    unknown: u64,
+}
+
+impl Learn {
+    pub fn new(duration: Duration) -> Learn {
+        let started = Instant::now();
+        Learn {
+            started,
+            until: started + duration,
+            paths: BTreeMap::new(),
+            events: 0,
+            unknown: 0,
+        }
+    }
+
+    pub fn record(&mut self, entry: &EventEntry) {
+        let path = match &entry.path {
+            Some(path) => path,
+            None => return,
+        };
+        self.events += 1;
+        match policy::exe(entry) {
+            Some(exe) => {
+                self.paths.entry(exe).or_default().insert(path.clone());
+            }
+            None => self.unknown += 1,
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.until
+    }
+
+    pub fn write_policy(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_fmt(format_args!(
+            "# proposed by --learn from {} events in {}s\n",
+            self.events,
+            self.started.elapsed().as_secs()
+        ))?;
+        if self.unknown != 0 {
+            w.write_fmt(format_args!(
+                "# and {} more by processes that exited too soon to tell what they ran\n",
+                self.unknown
+            ))?;
+        }
+        for (exe, paths) in &self.paths {
+            for path in globs(paths) {
+                w.write_fmt(format_args!("allow exe={} path={}\n", glob(exe), path))?;
+            }
+        }
+        w.write_all(b"default deny\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn learn_policy() -> io::Result<()> {
+        let mut learn = Learn::new(Duration::from_secs(60));
+        let entry = |exe: Option<&str>, path: &str| EventEntry {
+            fd: Some(3),
+            // not a pid, so only the exe field tells
+            pid: Some(0),
+            fields: exe.map(|e| ("exe", e.into())).into_iter().collect(),
+            ..EventEntry::test(libc::FAN_OPEN, path)
+        };
+        for path in &[
+            "/usr/lib/a.so",
+            "/usr/lib/x/b.so",
+            "/usr/lib/c.so",
+            "/usr/lib/d.so",
+            "/usr/lib/e.so",
+            "/etc/ld.so.cache",
+            "/etc/ld.so.cache",
+            "/home/a b/*.txt",
+        ] {
+            learn.record(&entry(Some("/usr/bin/cat"), path));
+        }
+        learn.record(&entry(Some("/usr/bin/vi"), "/usr/lib/a.so"));
+        learn.record(&entry(None, "/etc/passwd"));
+
+        let mut policy = Vec::new();
+        learn.write_policy(&mut policy)?;
+        let policy = String::from_utf8(policy).unwrap();
+        assert_eq!(
+            policy.lines().skip(1).collect::<Vec<_>>(),
+            vec![
+                "# and 1 more by processes that exited too soon to tell what they ran",
+                "allow exe=/usr/bin/cat path=/etc/ld.so.cache",
+                "allow exe=/usr/bin/cat path=/home/a?b/\\*.txt",
+                "allow exe=/usr/bin/cat path=/usr/lib/*",
+                "allow exe=/usr/bin/vi path=/usr/lib/a.so",
+                "default deny",
+            ]
+        );
+
+        let policy = policy::Policy::parse(&policy)?;
+        let mut e = entry(Some("/usr/bin/cat"), "/usr/lib/x/b.so");
+        e.mask = libc::FAN_OPEN_PERM;
+        assert_eq!(policy.decide(&e), Some((policy::Action::Allow, None)));
+        e.path = Some("/home/a b/*.txt".into());
+        assert_eq!(policy.decide(&e), Some((policy::Action::Allow, None)));
+        e.path = Some("/home/a b/x.txt".into());
+        assert_eq!(policy.decide(&e), Some((policy::Action::Deny, None)));
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+use std::time::Instant;
+
+/// Raise RLIMIT_NOFILE to `n`. Every outstanding permission event holds an
+/// open fd, so the default soft limit of 1024 is easy to exhaust.
+pub fn set_nofile(n: u64) -> io::Result<()> {
+    let mut lim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut lim) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    lim.rlim_cur = n as libc::rlim_t;
+    if lim.rlim_max != libc::RLIM_INFINITY && lim.rlim_max < lim.rlim_cur {
+        // needs CAP_SYS_RESOURCE, which we most likely have anyway
+        lim.rlim_max = lim.rlim_cur;
+    }
+
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &lim) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    debug!("RLIMIT_NOFILE = {}/{}", lim.rlim_cur, lim.rlim_max);
+    Ok(())
+}
+
+/// The soft RLIMIT_NOFILE.
+pub fn nofile() -> io::Result<u64> {
+    let mut lim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut lim) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(lim.rlim_cur)
+}
+
+/// Move this process into the cgroup (v2) directory `path`, so its CPU and
+/// memory can be capped with the usual cgroup controls.
+pub fn join_cgroup(path: &Path) -> io::Result<()> {
+    let mut procs = OpenOptions::new()
+        .write(true)
+        .open(path.join("cgroup.procs"))?;
+    procs.write_all(format!("{}", process::id()).as_bytes())
+}
+
+/// A token bucket refilled at `rate` tokens per second, holding at most one
+/// second worth of tokens. A rate of 0 never runs out.
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32) -> TokenBucket {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn take(&mut self) -> bool {
+        self.take_at(Instant::now())
+    }
+
+    fn take_at(&mut self, now: Instant) -> bool {
+        if self.rate == 0.0 {
+            return true;
+        }
+
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn bucket_refill() {
+        let mut b = TokenBucket::new(2);
+        let now = b.last;
+        assert!(b.take_at(now));
+        assert!(b.take_at(now));
+        assert!(!b.take_at(now));
+        assert!(b.take_at(now + Duration::from_millis(500)));
+        assert!(!b.take_at(now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn bucket_unlimited() {
+        let mut b = TokenBucket::new(0);
+        for _ in 0..1000 {
+            assert!(b.take());
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use std::fs::{self, Permissions};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::sink::Sink;
+use crate::stats::Stats;
+
+// how soon to retry clients with unsent events
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+pub fn parse_addr(url: &str) -> io::Result<PathBuf> {
+    match url.strip_prefix("unix:") {
+        Some(path) if !path.is_empty() => Ok(path.into()),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{}: only unix:PATH is supported", url),
+        )),
+    }
+}
+
+struct Client {
+    conn: UnixStream,
+    /// what the client hasn't read yet
+    buf: Vec<u8>,
+}
+
+impl Client {
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.buf.is_empty() {
+            match self.conn.write(&self.buf) {
+                Ok(n) => {
+                    self.buf.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Streams events to every client connected to a unix socket. A client
+/// that falls more than `max_buffer` bytes behind is disconnected, so one
+/// slow reader can't hold up the others or the kernel.
+pub struct ListenSink {
+    path: PathBuf,
+    listener: UnixListener,
+    clients: Vec<Client>,
+    max_buffer: usize,
+}
+
+impl ListenSink {
+    pub fn new(url: &str, max_buffer: usize) -> io::Result<ListenSink> {
+        let path = parse_addr(url)?;
+        let listener = match UnixListener::bind(&path) {
+            // left behind by a previous run, unless that's still going
+            Err(e)
+                if e.kind() == ErrorKind::AddrInUse
+                    && UnixStream::connect(&path)
+                        .is_err_and(|e| e.kind() == ErrorKind::ConnectionRefused) =>
+            {
+                fs::remove_file(&path)?;
+                UnixListener::bind(&path)?
+            }
+            res => res?,
+        };
+        // the events are as private as what we were able to watch
+        fs::set_permissions(&path, Permissions::from_mode(0o600))?;
+        listener.set_nonblocking(true)?;
+        info!("listening on {:?}", path);
+
+        Ok(ListenSink {
+            path,
+            listener,
+            clients: Vec::new(),
+            max_buffer,
+        })
+    }
+
+    fn accept(&mut self) -> io::Result<()> {
+        loop {
+            match self.listener.accept() {
+                Ok((conn, _)) => {
+                    conn.set_nonblocking(true)?;
+                    debug!("client {} connected", conn.as_raw_fd());
+                    self.clients.push(Client {
+                        conn,
+                        buf: Vec::new(),
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Write out what's buffered, dropping clients that are gone or too
+    /// far behind.
+    fn flush(&mut self) {
+        let max_buffer = self.max_buffer;
+        self.clients.retain_mut(|c| {
+            if let Err(e) = c.flush() {
+                debug!("client {}: {}", c.conn.as_raw_fd(), e);
+                return false;
+            }
+            if c.buf.len() > max_buffer {
+                warn!(
+                    "client {} is {} bytes behind, disconnecting",
+                    c.conn.as_raw_fd(),
+                    c.buf.len()
+                );
+                return false;
+            }
+            true
+        });
+    }
+}
+
+impl Sink for ListenSink {
+    fn send(&mut self, record: &[u8], _stats: &mut Stats) -> io::Result<()> {
+        for c in &mut self.clients {
+            c.buf.extend_from_slice(record);
+        }
+        self.flush();
+        Ok(())
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        if self.clients.iter().any(|c| !c.buf.is_empty()) {
+            Some(Instant::now() + RETRY_INTERVAL)
+        } else {
+            None
+        }
+    }
+
+    fn tick(&mut self, _stats: &mut Stats) -> io::Result<()> {
+        self.accept()?;
+        self.flush();
+        Ok(())
+    }
+
+    fn fd(&self) -> Option<RawFd> {
+        Some(self.listener.as_raw_fd())
+    }
+}
+
+impl Drop for ListenSink {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn listen_fanout() -> io::Result<()> {
+        let dir = TempDir::new("listen")?;
+        let path = dir.join("sock");
+        let url = format!("unix:{}", path.display());
+        let mut stats = Stats::default();
+        let mut sink = ListenSink::new(&url, 16)?;
+
+        let a = UnixStream::connect(&path)?;
+        let b = UnixStream::connect(&path)?;
+        sink.tick(&mut stats)?;
+        assert_eq!(sink.clients.len(), 2);
+
+        sink.send(b"{\"mask\":\"FAN_OPEN\"}\n", &mut stats)?;
+        let mut line = String::new();
+        BufReader::new(&a).read_line(&mut line)?;
+        assert_eq!(line, "{\"mask\":\"FAN_OPEN\"}\n");
+        line.clear();
+        BufReader::new(&b).read_line(&mut line)?;
+        assert_eq!(line, "{\"mask\":\"FAN_OPEN\"}\n");
+
+        drop(a);
+        sink.send(b"x\n", &mut stats)?;
+        sink.send(b"x\n", &mut stats)?;
+        assert_eq!(sink.clients.len(), 1);
+
+        drop(sink);
+        assert!(!path.exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use crate::clock;
+use crate::sink::Sink;
+use crate::stats::Stats;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotate {
+    Hourly,
+    Daily,
+}
+
+impl FromStr for Rotate {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hourly" => Ok(Rotate::Hourly),
+            "daily" => Ok(Rotate::Daily),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid value: {}, options: hourly, daily", s),
+            )),
+        }
+    }
+}
+
+impl Rotate {
+    fn suffix_format(self) -> &'static str {
+        match self {
+            Rotate::Hourly => "%Y%m%d-%H",
+            Rotate::Daily => "%Y%m%d",
+        }
+    }
+}
+
+/// Rotating the file once it grows past `size`, logrotate style: the file
+/// becomes .1, .1 becomes .2 and so on, and only `keep` of them are kept.
+#[derive(Debug, Clone, Copy)]
+pub struct BySize {
+    pub size: u64,
+    pub keep: usize,
+    /// gzip rotated files, into .1.gz and so on
+    pub compress: bool,
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut p = OsString::from(path.as_os_str());
+    p.push(suffix);
+    p.into()
+}
+
+fn gzip(from: &Path, to: &Path) -> io::Result<()> {
+    let tmp = with_suffix(to, ".tmp");
+    let mut gz = flate2::write::GzEncoder::new(File::create(&tmp)?, flate2::Compression::fast());
+    io::copy(&mut File::open(from)?, &mut gz)?;
+    gz.finish()?.sync_all()?;
+    fs::rename(&tmp, to)?;
+    fs::remove_file(from)
+}
+
+impl BySize {
+    fn rotated(&self, path: &Path, n: usize) -> PathBuf {
+        let suffix = if self.compress { ".gz" } else { "" };
+        with_suffix(path, &format!(".{}{}", n, suffix))
+    }
+
+    /// Move `path` out of the way, shifting what was rotated before.
+    fn rotate(&self, path: &Path) -> io::Result<()> {
+        if self.keep == 0 {
+            return fs::remove_file(path);
+        }
+        for n in (1..self.keep).rev() {
+            match fs::rename(self.rotated(path, n), self.rotated(path, n + 1)) {
+                Err(e) if e.kind() == ErrorKind::NotFound => (),
+                res => res?,
+            }
+        }
+        if self.compress {
+            // readers only ever see a whole .gz, and the events in the
+            // .1 left behind if this fails aren't lost
+            let plain = with_suffix(path, ".1");
+            fs::rename(path, &plain)?;
+            gzip(&plain, &self.rotated(path, 1))
+        } else {
+            fs::rename(path, self.rotated(path, 1))
+        }
+    }
+}
+
+/// Appends events to a file. With time based rotation, the file name gets the
+/// current hour or day appended and a new file is started when that changes.
+pub struct FileSink {
+    base: PathBuf,
+    rotate: Option<Rotate>,
+    by_size: Option<BySize>,
+    path: PathBuf,
+    file: Option<File>,
+    written: u64,
+}
+
+impl FileSink {
+    pub fn new(
+        base: PathBuf,
+        rotate: Option<Rotate>,
+        by_size: Option<BySize>,
+    ) -> io::Result<FileSink> {
+        let mut sink = FileSink {
+            path: base.clone(),
+            base,
+            rotate,
+            by_size,
+            file: None,
+            written: 0,
+        };
+        sink.open(SystemTime::now())?;
+        Ok(sink)
+    }
+
+    fn current_path(&self, now: SystemTime) -> PathBuf {
+        match self.rotate {
+            Some(r) => {
+                let mut p = OsString::from(self.base.as_os_str());
+                p.push(".");
+                p.push(clock::strftime(now, r.suffix_format()));
+                p.into()
+            }
+            None => self.base.clone(),
+        }
+    }
+
+    fn open(&mut self, now: SystemTime) -> io::Result<()> {
+        let path = self.current_path(now);
+        if self.file.is_none() || path != self.path {
+            debug!("writing to {:?}", path);
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            self.written = file.metadata()?.len();
+            self.file = Some(file);
+            self.path = path;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for FileSink {
+    fn send(&mut self, record: &[u8], _stats: &mut Stats) -> io::Result<()> {
+        self.roll(record.len() as u64)?;
+        self.file.as_mut().unwrap().write_all(record)?;
+        self.written += record.len() as u64;
+        Ok(())
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+
+    fn roll(&mut self, len: u64) -> io::Result<bool> {
+        let now = SystemTime::now();
+        // not open after a rotation that failed
+        let was = self.file.as_ref().map(|_| self.path.clone());
+        self.open(now)?;
+        if let Some(by_size) = self.by_size {
+            if self.written != 0 && self.written + len > by_size.size {
+                debug!("rotating {:?}", self.path);
+                self.file = None;
+                by_size.rotate(&self.path)?;
+                self.open(now)?;
+                return Ok(true);
+            }
+        }
+        Ok(was.as_ref() != Some(&self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+    use std::io::Read;
+
+    #[test]
+    fn rotate_by_size() -> io::Result<()> {
+        let dir = TempDir::new("logfile")?;
+        let path = dir.join("events");
+        let mut stats = Stats::default();
+
+        let by_size = BySize {
+            size: 10,
+            keep: 2,
+            compress: false,
+        };
+        let mut sink = FileSink::new(path.clone(), None, Some(by_size))?;
+        for r in &["aaaaaa\n", "bbbbbb\n", "cccccc\n", "dddddd\n"] {
+            sink.send(r.as_bytes(), &mut stats)?;
+        }
+        assert_eq!(fs::read_to_string(&path)?, "dddddd\n");
+        assert_eq!(fs::read_to_string(dir.join("events.1"))?, "cccccc\n");
+        assert_eq!(fs::read_to_string(dir.join("events.2"))?, "bbbbbb\n");
+        assert!(!dir.join("events.3").exists());
+
+        let by_size = BySize {
+            compress: true,
+            ..by_size
+        };
+        let mut sink = FileSink::new(path.clone(), None, Some(by_size))?;
+        sink.send(b"eeeeee\n", &mut stats)?;
+        let mut gz = flate2::read::GzDecoder::new(File::open(dir.join("events.1.gz"))?);
+        let mut s = String::new();
+        gz.read_to_string(&mut s)?;
+        assert_eq!(s, "dddddd\n");
+        assert!(!dir.join("events.1").exists());
+
+        Ok(())
+    }
+}
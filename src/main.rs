@@ -1,12 +1,15 @@
-use std::ffi::OsStr;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr};
 use std::fmt::{Debug, Display};
-use std::fs::{self, File, OpenOptions};
+use std::fs::{File, OpenOptions};
 use std::io::{self, ErrorKind, Read, Write};
 use std::mem;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::{fs::OpenOptionsExt, io::AsRawFd, io::FromRawFd, io::RawFd};
-use std::path::PathBuf;
+use std::os::unix::{fs::OpenOptionsExt, io::AsRawFd, io::FromRawFd, io::IntoRawFd, io::RawFd};
+use std::path::{Path, PathBuf};
 use std::slice;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 #[macro_use]
 extern crate log;
@@ -17,12 +20,86 @@ use libc::{c_int, c_uint};
 
 #[macro_use]
 mod c_enum;
+mod canary;
 use crate::c_enum::EnumValues;
 mod flags;
-use flags::Opt;
+use decider::OnFailure;
+use flags::{Class, Command, Opt, PolicyCommand};
+mod limits;
+use limits::TokenBucket;
+mod stats;
+use stats::Stats;
+mod sink;
+use sink::{Sink, StdoutSink};
+mod allowlist;
+mod audit;
+mod baseline;
+mod cef;
+mod chain;
+mod clock;
+mod color;
+mod containers;
+mod csv;
+mod decider;
+mod decisions;
+mod dedup;
+mod enrich;
+mod expect;
+mod fid;
+mod filter;
+mod forward;
+mod freeze;
+mod grpc;
+mod hasher;
+mod heatmap;
+mod histogram;
+mod ignore;
+mod inotifywait;
+mod journald;
+mod json;
+mod learn;
+mod listen;
+use heatmap::HeatMap;
+mod logfile;
+mod mountinfo;
+mod notify;
+mod otlp;
+mod plugin;
+mod policy;
+mod prompt;
+mod quarantine;
+mod quiesce;
+mod ratelimit;
+mod report;
+use report::Report;
+mod scan;
+mod script;
+mod selftest;
+mod service;
+mod session;
+mod snapshot;
+mod sqlite;
+use session::Sessions;
+mod spool;
+mod tee;
+mod template;
+mod throttle;
+mod timeout;
+mod tui;
+mod upgrade;
+mod webhook;
 
 // no good reason, but fanotify(7) uses 200 in the example code
 const MAX_FANOTIFY_BUFS: usize = 200;
+// fds left for everything but the events, the spool, sockets, databases
+const RESERVED_FDS: usize = 64;
+
+const PERM_EVENTS: u64 = libc::FAN_OPEN_PERM | libc::FAN_ACCESS_PERM | libc::FAN_OPEN_EXEC_PERM;
+// not events themselves, they only say which objects to report on
+const EVENT_FLAGS: u64 = libc::FAN_ONDIR | libc::FAN_EVENT_ON_CHILD;
+// directory entry events, reported with the directory and name
+const DIRENT_EVENTS: u64 =
+    libc::FAN_CREATE | libc::FAN_DELETE | libc::FAN_MOVED_FROM | libc::FAN_MOVED_TO;
 
 c_enum! {
     enum FanEvents {
@@ -31,9 +108,17 @@ c_enum! {
     FAN_CLOSE_WRITE,
     FAN_CLOSE_NOWRITE,
     FAN_OPEN,
+    FAN_OPEN_EXEC,
+    FAN_ATTRIB,
     FAN_Q_OVERFLOW,
     FAN_ACCESS_PERM,
     FAN_OPEN_PERM,
+    FAN_OPEN_EXEC_PERM,
+    FAN_CREATE,
+    FAN_DELETE,
+    FAN_MOVED_FROM,
+    FAN_MOVED_TO,
+    FAN_RENAME,
     FAN_ONDIR,
     FAN_EVENT_ON_CHILD,
     }
@@ -46,6 +131,18 @@ c_enum! {
     }
 }
 
+c_enum! {
+    enum(i32) OpenFlags {
+    O_RDONLY,
+    O_WRONLY,
+    O_RDWR,
+    O_NOATIME,
+    O_NONBLOCK,
+    O_SYNC,
+    O_DSYNC,
+    }
+}
+
 // copied from https://github.com/kahing/catfs/blob/daa2b85798fa8ca38306242d51cbc39ed122e271/src/catfs/rlibc.rs#L45
 macro_rules! libc_wrap {
     ($( fn $name:ident($($arg:ident : $argtype:ty),*) -> $rettype:ty $body:block )*) => (
@@ -81,14 +178,47 @@ libc_wrap! {
     fn fanotify_init(flags: libc::c_uint, event_f_flags: libc::c_uint) {}
     fn fanotify_mark(fd: c_int, flags: c_uint, mask: u64, dirfd: c_int, path: *const libc::c_char) {}
     fn poll(fds: *mut libc::pollfd, nfds: libc::nfds_t, timeout: c_int) {}
+    fn sigprocmask(how: c_int, set: *const libc::sigset_t, oldset: *mut libc::sigset_t) {}
+    fn signalfd(fd: c_int, mask: *const libc::sigset_t, flags: c_int) {}
+}
+
+/// Block `signals` and return a signalfd to receive them in the poll loop
+/// instead.
+fn open_signalfd(signals: &[c_int]) -> io::Result<c_int> {
+    let mut mask: libc::sigset_t = unsafe { mem::zeroed() };
+    unsafe { libc::sigemptyset(&mut mask) };
+    for s in signals {
+        unsafe { libc::sigaddset(&mut mask, *s) };
+    }
+
+    sigprocmask(libc::SIG_BLOCK, &mask, std::ptr::null_mut())?;
+    signalfd(-1, &mask, libc::SFD_CLOEXEC | libc::SFD_NONBLOCK)
+}
+
+fn read_signal(sigfile: &mut File) -> io::Result<Option<c_int>> {
+    let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+    let res = sigfile.read(unsafe {
+        slice::from_raw_parts_mut(
+            &mut info as *mut libc::signalfd_siginfo as *mut u8,
+            mem::size_of::<libc::signalfd_siginfo>(),
+        )
+    });
+
+    match res {
+        Ok(_) => Ok(Some(info.ssi_signo as c_int)),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e),
+    }
 }
 
 fn open_namespace_root(pid: u32) -> io::Result<c_int> {
     let path = format!("/proc/{}/root", pid);
+    // keep it open, marks are resolved against it
     Ok(OpenOptions::new()
+        .read(true)
         .custom_flags(libc::O_CLOEXEC | libc::O_DIRECTORY)
         .open(path)?
-        .as_raw_fd())
+        .into_raw_fd())
 }
 
 // adapted from https://stackoverflow.com/questions/31046763/does-rust-have-anything-like-scanf
@@ -122,33 +252,214 @@ impl ReadLine for io::Stdin {
     }
 }
 
+/// Read an answer to --interactive from stdin.
+fn answer_prompt(
+    input: &mut dyn ReadLine,
+    buf: &mut String,
+    notify: &mut dyn Write,
+    state: &mut State,
+    opt: &Opt,
+) -> io::Result<()> {
+    buf.clear();
+    if input.read_line(buf)? == 0 {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "stdin closed"));
+    }
+    let answered = match &mut state.prompt {
+        Some(prompt) => prompt.answer(buf)?,
+        None => return Ok(()),
+    };
+    for ((fd, seq), action) in answered {
+        // unless it was answered some other way, and its fd reused
+        if still_pending(&state.pending, &state.perm_seqs, (fd, seq)) {
+            respond(
+                notify,
+                fd,
+                audited(action.response(), opt.audit),
+                None,
+                &mut state.pending,
+            )?;
+        }
+    }
+    if let (Some(prompt), Some(decisions)) = (&mut state.prompt, &mut state.decisions) {
+        for (path, pid, action) in prompt.learned() {
+            if let Err(e) = decisions.remember(&path, pid, action) {
+                error!("--decisions: {}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Rule number and trust levels that FAN_RESPONSE_INFO_AUDIT_RULE logs
+/// with a response.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct AuditRule {
+    rule: u32,
+    subj_trust: u32,
+    obj_trust: u32,
+}
+
+// for the trust levels
+const TRUST_UNKNOWN: u32 = 2;
+
+/// fanotify_response followed by fanotify_response_info_audit_rule
+#[repr(C)]
+struct ResponseWithRule {
+    response: libc::fanotify_response,
+    info_type: u8,
+    pad: u8,
+    len: u16,
+    rule: u32,
+    subj_trust: u32,
+    obj_trust: u32,
+}
+
+/// Have the kernel audit denials too with --audit.
+fn audited(response: u32, audit: bool) -> u32 {
+    if audit && response == libc::FAN_DENY {
+        response | libc::FAN_AUDIT
+    } else {
+        response
+    }
+}
+
+/// Answer a pending permission event and close its fd.
+fn respond(
+    notify: &mut dyn Write,
+    fd: RawFd,
+    response: u32,
+    rule: Option<AuditRule>,
+    pending: &mut HashSet<RawFd>,
+) -> io::Result<()> {
+    if !pending.remove(&fd) {
+        error!("{} is not a pending permission event", fd);
+        return Ok(());
+    }
+
+    let plain = |notify: &mut dyn Write| {
+        let command = libc::fanotify_response { response, fd };
+        notify.write_all(unsafe {
+            slice::from_raw_parts(
+                &command as *const libc::fanotify_response as *const u8,
+                mem::size_of::<libc::fanotify_response>(),
+            )
+        })
+    };
+    let res = match rule {
+        Some(rule) => {
+            let command = ResponseWithRule {
+                response: libc::fanotify_response {
+                    response: response | libc::FAN_AUDIT | libc::FAN_INFO,
+                    fd,
+                },
+                info_type: libc::FAN_RESPONSE_INFO_AUDIT_RULE,
+                pad: 0,
+                len: (mem::size_of::<ResponseWithRule>()
+                    - mem::size_of::<libc::fanotify_response>()) as u16,
+                rule: rule.rule,
+                subj_trust: rule.subj_trust,
+                obj_trust: rule.obj_trust,
+            };
+            match notify.write_all(unsafe {
+                slice::from_raw_parts(
+                    &command as *const ResponseWithRule as *const u8,
+                    mem::size_of::<ResponseWithRule>(),
+                )
+            }) {
+                // FAN_INFO is new in 6.3, answer without the rule
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                    debug!("response with audit rule: {}", e);
+                    plain(notify)
+                }
+                res => res,
+            }
+        }
+        None => plain(notify),
+    };
+
+    // close the file
+    unsafe { File::from_raw_fd(fd) };
+    match res {
+        // killed while it waited, which takes the event with it
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+            debug!("{} was answered by its process going away", fd);
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+/// Where and how the marks were added, to take them out again.
+struct Marks {
+    notify_fd: c_int,
+    dirfd: c_int,
+    /// FAN_MARK_MOUNT, FAN_MARK_FILESYSTEM or 0 for inodes, with
+    /// FAN_MARK_ONLYDIR and FAN_MARK_DONT_FOLLOW
+    flags: c_uint,
+    mask: u64,
+}
+
+impl Marks {
+    /// Stop watching `path`, with -m or -f that's its whole mount or
+    /// filesystem.
+    fn remove(&self, path: &str) -> io::Result<()> {
+        let cpath = CString::new(path).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        fanotify_mark(
+            self.notify_fd,
+            libc::FAN_MARK_REMOVE | self.flags,
+            self.mask,
+            self.dirfd,
+            cpath.as_ptr(),
+        )?;
+        Ok(())
+    }
+
+    /// Remove every mark, --ignore ones included.
+    fn flush(&self) -> io::Result<()> {
+        for mark_type in &[0, libc::FAN_MARK_MOUNT, libc::FAN_MARK_FILESYSTEM] {
+            fanotify_mark(
+                self.notify_fd,
+                libc::FAN_MARK_FLUSH | mark_type,
+                0,
+                libc::AT_FDCWD,
+                std::ptr::null(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Read a line from stdin, which is either RESPONSE FD [RULE [SUBJ_TRUST
+/// OBJ_TRUST]] to answer a permission event or REMOVE PATH to stop
+/// watching a path.
 fn handle_command(
     input: &mut dyn ReadLine,
     buf: &mut String,
     notify: &mut dyn Write,
+    pending: &mut HashSet<RawFd>,
+    marks: &Marks,
+    audit: bool,
 ) -> io::Result<()> {
+    buf.clear();
     if input.read_line(buf)? == 0 {
         Err(io::Error::new(
             ErrorKind::UnexpectedEof,
             io::Error::last_os_error(),
         ))
+    } else if let Some(path) = buf.trim_end_matches('\n').strip_prefix("REMOVE ") {
+        if let Err(e) = marks.remove(path) {
+            error!("remove {}: {}", path, e);
+        }
+        Ok(())
     } else {
-        match scan!(buf, FanResponse, i32) {
-            (Some(resp), Some(fd)) => {
-                let command = libc::fanotify_response {
-                    response: resp as u32,
-                    fd: fd,
-                };
-                let res = notify.write_all(unsafe {
-                    slice::from_raw_parts(
-                        &command as *const libc::fanotify_response as *const u8,
-                        mem::size_of::<libc::fanotify_response>(),
-                    )
+        match scan!(buf.trim_end(), FanResponse, i32, u32, u32, u32) {
+            (Some(resp), Some(fd), rule, subj_trust, obj_trust) if audit || rule.is_none() => {
+                let rule = rule.map(|rule| AuditRule {
+                    rule,
+                    subj_trust: subj_trust.unwrap_or(TRUST_UNKNOWN),
+                    obj_trust: obj_trust.unwrap_or(TRUST_UNKNOWN),
                 });
-
-                // close the file
-                unsafe { File::from_raw_fd(fd) };
-                res
+                respond(notify, fd, audited(resp as u32, audit), rule, pending)
             }
             _ => {
                 error!("invalid input: {}", buf);
@@ -161,31 +472,77 @@ fn handle_command(
     }
 }
 
-struct EventEntry {
-    mask: u64,
-    fd: Option<RawFd>,
-    pid: Option<u32>,
-    path: Option<PathBuf>,
+/// A permission event by its fd and the order it was read in, as the fd
+/// is another's once it's answered.
+pub type PermId = (RawFd, u64);
+
+pub struct EventEntry {
+    pub mask: u64,
+    pub fd: Option<RawFd>,
+    pub pid: Option<u32>,
+    pub path: Option<PathBuf>,
+    /// key=value pairs from --enrich
+    pub fields: Vec<(&'static str, String)>,
 }
 
 impl EventEntry {
-    fn display_field<T: Display>(f: &Option<T>) -> String {
+    pub fn display_field<T: Display>(f: &Option<T>) -> String {
         f.as_ref()
             .map(|f| format!("{}", f))
             .unwrap_or("-".to_string())
     }
 
-    fn write_to(&self, w: &mut dyn Write) -> io::Result<()> {
+    /// The names of the bits in the mask, separated by |.
+    pub fn mask_names(&self) -> String {
         let mut mask_buf = String::new();
+        let mut unknown = self.mask;
 
         for m in FanEvents::values() {
             if (m as u64) & self.mask != 0 {
                 mask_buf += format!("{}|", m.as_ref()).as_ref();
+                unknown &= !(m as u64);
             }
         }
+        if unknown != 0 {
+            // bits from a kernel newer than us
+            mask_buf += format!("{:#x}|", unknown).as_ref();
+        }
         if mask_buf.len() != 0 {
             mask_buf.remove(mask_buf.len() - 1);
         }
+        mask_buf
+    }
+
+    /// Write the path, `raw` or escaped like `write_escaped`, or - if there
+    /// isn't one.
+    pub fn write_path(&self, w: &mut dyn Write, raw: bool) -> io::Result<()> {
+        match &self.path {
+            Some(file) => write_path(w, file, raw),
+            None => w.write_all(b"-"),
+        }
+    }
+
+    /// Write the fields, each after a tab, escaped like the path.
+    pub fn write_fields(&self, w: &mut dyn Write, raw_paths: bool) -> io::Result<()> {
+        for (k, v) in &self.fields {
+            w.write_fmt(format_args!("\t{}=", k))?;
+            match *k {
+                // escaped already, with its spaces too
+                "cmdline" => w.write_all(v.as_bytes())?,
+                // the other path of a rename
+                "to" => write_path(w, Path::new(v), raw_paths)?,
+                _ => write_escaped(w, v.as_bytes())?,
+            }
+        }
+        Ok(())
+    }
+
+    fn write_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.write_with(w, false)
+    }
+
+    fn write_with(&self, w: &mut dyn Write, raw_paths: bool) -> io::Result<()> {
+        let mask_buf = self.mask_names();
 
         w.write_fmt(format_args!(
             "{}\t{}\t{}\t",
@@ -194,13 +551,96 @@ impl EventEntry {
             EventEntry::display_field(&self.pid),
         ))?;
 
-        if let Some(file) = &self.path {
-            w.write(&file.as_os_str().as_bytes())?;
-        } else {
-            w.write_all(b"-")?;
+        self.write_path(w, raw_paths)?;
+        self.write_fields(w, raw_paths)
+    }
+}
+
+/// Write `path`, `raw` or escaped like `write_escaped`.
+pub fn write_path(w: &mut dyn Write, path: &Path, raw: bool) -> io::Result<()> {
+    if raw {
+        w.write_all(path.as_os_str().as_bytes())
+    } else {
+        write_escaped(w, path.as_os_str().as_bytes())
+    }
+}
+
+#[cfg(test)]
+impl EventEntry {
+    /// An event on `path` by pid 1, for tests to change what they need of.
+    pub fn test(mask: u64, path: &str) -> EventEntry {
+        EventEntry {
+            mask,
+            fd: None,
+            pid: Some(1),
+            path: Some(path.into()),
+            fields: Vec::new(),
         }
+    }
+}
 
-        Ok(())
+/// A directory of a test's own, removed with everything in it when it's
+/// done, passed or not.
+#[cfg(test)]
+pub struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(name: &str) -> io::Result<TempDir> {
+        let dir =
+            std::env::temp_dir().join(format!("fanotify-cli-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        Ok(TempDir(dir))
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Write `s` with \\, tabs, newlines and other control characters escaped,
+/// and bytes that aren't UTF-8 as \xNN, so that it stays in its column.
+pub fn write_escaped(w: &mut dyn Write, s: &[u8]) -> io::Result<()> {
+    for chunk in s.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => w.write_all(b"\\\\")?,
+                '\t' => w.write_all(b"\\t")?,
+                '\n' => w.write_all(b"\\n")?,
+                '\r' => w.write_all(b"\\r")?,
+                c if c.is_control() => {
+                    for b in c.encode_utf8(&mut [0; 4]).bytes() {
+                        w.write_fmt(format_args!("\\x{:02x}", b))?;
+                    }
+                }
+                c => w.write_fmt(format_args!("{}", c))?,
+            }
+        }
+        for b in chunk.invalid() {
+            w.write_fmt(format_args!("\\x{:02x}", b))?;
+        }
+    }
+    Ok(())
+}
+
+/// The default format with paths as they are, for --raw-paths.
+pub struct RawPaths;
+
+impl EventFormat for RawPaths {
+    fn write_event(&self, entry: &EventEntry, w: &mut dyn Write) -> io::Result<()> {
+        entry.write_with(w, true)
     }
 }
 
@@ -222,6 +662,7 @@ mod event_entry_tests {
             fd: Some(2),
             pid: Some(1),
             path: Some("/foo/bar".into()),
+            fields: Vec::new(),
         }
         .write_to(&mut buf)?;
 
@@ -230,148 +671,1417 @@ mod event_entry_tests {
             "FAN_ACCESS|FAN_MODIFY\t2\t1\t/foo/bar"
         );
 
+        let mut buf = vec![];
+        EventEntry {
+            mask: FanEvents::FAN_OPEN as u64 | 0x1000_0000_0000,
+            fd: None,
+            pid: None,
+            path: None,
+            fields: Vec::new(),
+        }
+        .write_to(&mut buf)?;
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "FAN_OPEN|0x100000000000\t-\t-\t-"
+        );
+
+        let entry = EventEntry {
+            mask: FanEvents::FAN_CREATE as u64,
+            fd: None,
+            pid: Some(1),
+            path: Some(PathBuf::from(OsStr::from_bytes(
+                b"/a\tb\nc\\d\x1b\xff\xc3\xa9",
+            ))),
+            fields: Vec::new(),
+        };
+        let mut buf = vec![];
+        entry.write_to(&mut buf)?;
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "FAN_CREATE\t-\t1\t/a\\tb\\nc\\\\d\\x1b\\xff\u{e9}"
+        );
+        let mut buf = vec![];
+        RawPaths.write_event(&entry, &mut buf)?;
+        assert!(buf.ends_with(b"\t/a\tb\nc\\d\x1b\xff\xc3\xa9"));
+
+        let entry = EventEntry {
+            fields: vec![
+                ("comm", "a\tb".into()),
+                ("to", "/c\nd".into()),
+                ("cmdline", "sh -c a\\x20b".into()),
+            ],
+            ..entry
+        };
+        let mut buf = vec![];
+        entry.write_fields(&mut buf, false)?;
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "\tcomm=a\\tb\tto=/c\\nd\tcmdline=sh -c a\\x20b"
+        );
+        let mut buf = vec![];
+        RawPaths.write_event(&entry, &mut buf)?;
+        assert!(buf.ends_with(b"\tcomm=a\\tb\tto=/c\nd\tcmdline=sh -c a\\x20b"));
+
         Ok(())
     }
 }
 
-fn handle_fanotify(
-    notify: &mut File,
-    fabuf: &mut Vec<libc::fanotify_event_metadata>,
-    opt: &Opt,
-) -> io::Result<()> {
-    let nread = notify.read(unsafe {
-        slice::from_raw_parts_mut(
-            fabuf.as_mut_ptr() as *mut u8,
-            mem::size_of::<libc::fanotify_event_metadata>() * fabuf.len(),
-        )
-    });
-
-    match nread {
-        Err(errno) => match errno.raw_os_error().unwrap() {
-            libc::EAGAIN | libc::EINTR => return Ok(()),
-            _ => {
-                error!("read: {:?}", errno);
-                return Err(errno);
-            }
-        },
-        Ok(mut nread) => {
-            'next_metadata: for metadata in fabuf {
-                if nread < mem::size_of::<libc::fanotify_event_metadata>() as usize
-                    || metadata.event_len < mem::size_of::<libc::fanotify_event_metadata>() as u32
-                    || metadata.event_len > nread as u32
-                {
-                    break;
-                } else {
-                    if metadata.vers != libc::FANOTIFY_METADATA_VERSION {
-                        return Err(io::Error::from_raw_os_error(libc::EINVAL));
-                    }
+#[cfg(test)]
+mod respond_tests {
+    use super::*;
+    use std::convert::TryInto;
 
-                    nread -= metadata.event_len as usize;
+    #[test]
+    fn respond_audit_rule() -> io::Result<()> {
+        let fd = File::open("/dev/null")?.into_raw_fd();
+        let mut pending = HashSet::new();
+        pending.insert(fd);
 
-                    let file = if metadata.fd >= 0 {
-                        let procfd_path = format!("/proc/self/fd/{}", metadata.fd);
-                        let path = fs::read_link(procfd_path)?;
+        let mut buf = vec![];
+        let rule = AuditRule {
+            rule: 7,
+            subj_trust: 1,
+            obj_trust: TRUST_UNKNOWN,
+        };
+        respond(
+            &mut buf,
+            fd,
+            audited(libc::FAN_DENY, true),
+            Some(rule),
+            &mut pending,
+        )?;
+        assert!(pending.is_empty());
 
-                        if metadata.mask & FanEvents::FAN_OPEN_PERM != 0
-                            || metadata.mask & FanEvents::FAN_ACCESS_PERM != 0
-                        {
-                            // wait for command to close it
-                        } else {
-                            unsafe {
-                                // let this drop and close
-                                File::from_raw_fd(metadata.fd);
-                            };
-                        }
+        let word = |i: usize| u32::from_ne_bytes(buf[i * 4..i * 4 + 4].try_into().unwrap());
+        assert_eq!(buf.len(), 24);
+        assert_eq!(word(0), fd as u32);
+        assert_eq!(word(1), libc::FAN_DENY | libc::FAN_AUDIT | libc::FAN_INFO);
+        assert_eq!(buf[8], libc::FAN_RESPONSE_INFO_AUDIT_RULE);
+        assert_eq!(u16::from_ne_bytes([buf[10], buf[11]]), 16);
+        assert_eq!((word(3), word(4), word(5)), (7, 1, TRUST_UNKNOWN));
+        Ok(())
+    }
+}
 
-                        if opt.recursive {
-                            if opt.namespace.is_none() {
-                                for p in &opt.paths {
-                                    if !path.starts_with(OsStr::from_bytes(&p.as_bytes())) {
-                                        debug!("dropping unwanted notification: {:?}", path);
-                                        continue 'next_metadata;
-                                    }
-                                }
-                            }
-                        }
+/// Another way to print events than the default tab separated one.
+pub trait EventFormat {
+    fn write_event(&self, entry: &EventEntry, w: &mut dyn Write) -> io::Result<()>;
 
-                        Some(path)
-                    } else {
-                        None
-                    };
+    /// What goes between --timestamp, --seq and the event.
+    fn separator(&self) -> u8 {
+        b'\t'
+    }
+}
 
-                    EventEntry {
-                        mask: metadata.mask,
-                        fd: if metadata.fd >= 0 {
-                            Some(metadata.fd)
-                        } else {
-                            None
-                        },
-                        pid: if metadata.pid >= 0 {
-                            Some(metadata.pid as u32)
-                        } else {
-                            None
-                        },
-                        path: file,
-                    }
-                    .write_to(&mut io::stdout())?;
+/// Write `entry` after the `prefix` columns.
+fn write_event(
+    entry: &EventEntry,
+    prefix: &[String],
+    format: Option<&dyn EventFormat>,
+    sink: &mut dyn Sink,
+    stats: &mut Stats,
+) -> io::Result<()> {
+    let mut record = Vec::new();
+    for p in prefix {
+        record.extend_from_slice(p.as_bytes());
+        record.push(format.map_or(b'\t', |f| f.separator()));
+    }
+    match format {
+        Some(format) => format.write_event(entry, &mut record)?,
+        None => entry.write_to(&mut record)?,
+    }
+    record.push(b'\n');
+    send_record(&record, sink, stats)
+}
 
-                    println!();
-                    io::stdout().flush()?;
-                }
-            }
+fn send_record(record: &[u8], sink: &mut dyn Sink, stats: &mut Stats) -> io::Result<()> {
+    if let Err(e) = sink.send(record, stats) {
+        if e.kind() == ErrorKind::BrokenPipe {
+            return Err(e);
         }
-    };
-
-    return Ok(());
+        error!("write: {:?}", e);
+        stats.lost_sink_error += 1;
+    }
+    Ok(())
 }
 
-fn main() -> io::Result<()> {
-    env_logger::init();
-
-    let opt = Opt::from_args_with_default()?;
+/// Whether `perm` is still waiting for an answer, rather than answered and
+/// its fd reused by a later one.
+fn still_pending(pending: &HashSet<RawFd>, seqs: &HashMap<RawFd, u64>, perm: PermId) -> bool {
+    let (fd, seq) = perm;
+    pending.contains(&fd) && seqs.get(&fd).copied().unwrap_or(0) == seq
+}
 
-    let dirfd = match opt.namespace {
-        Some(p) => open_namespace_root(p)?,
-        None => libc::AT_FDCWD,
+/// Answer the permission events --decider, --grpc, --notify or --scan-cmd
+/// decided, and with --overload-response the ones --decider couldn't.
+fn answer_decided(
+    notify: &mut dyn Write,
+    state: &mut State,
+    opt: &Opt,
+    decided: Vec<(PermId, policy::Action)>,
+) -> io::Result<()> {
+    let lost = match &mut state.decider {
+        Some(decider) => {
+            if decider.fd().is_none() && opt.on_decider_failure == Some(OnFailure::Exit) {
+                return give_up(
+                    notify,
+                    state,
+                    opt,
+                    io::Error::new(ErrorKind::NotConnected, "--decider disconnected"),
+                );
+            }
+            decider.take_lost()
+        }
+        None => Vec::new(),
     };
+    let (response, name) = match opt.on_decider_failure {
+        Some(OnFailure::Answer(action)) => (action.response(), action.name()),
+        _ => (opt.overload_response as u32, opt.overload_response.as_ref()),
+    };
+    if !lost.is_empty() {
+        warn!(
+            "answering {} permission events {} without --decider",
+            lost.len(),
+            name
+        );
+    }
+    let decided = decided.into_iter().map(|(perm, a)| (perm, a.response()));
+    let lost = lost.into_iter().map(|perm| (perm, response));
+    for ((fd, seq), response) in decided.chain(lost) {
+        // unless stdin got to it first
+        if still_pending(&state.pending, &state.perm_seqs, (fd, seq)) {
+            respond(
+                notify,
+                fd,
+                audited(response, opt.audit),
+                None,
+                &mut state.pending,
+            )?;
+        }
+    }
+    Ok(())
+}
 
-    let mut mask = 0;
-
-    for m in opt.events.as_ref().unwrap().split(',') {
-        mask = mask
-            | m.parse::<FanEvents>()
-                .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
-
-        debug!(
-            "adding event {} = {:x}",
-            m,
-            m.parse::<FanEvents>().unwrap() as u64
+/// Answer the pending permission events with --perm-default before
+/// exiting with `e`, rather than leave them to whenever the fanotify fd
+/// closes.
+fn give_up(notify: &mut dyn Write, state: &mut State, opt: &Opt, e: io::Error) -> io::Result<()> {
+    if !state.pending.is_empty() {
+        warn!(
+            "{}, answering {} pending permission events {}",
+            e,
+            state.pending.len(),
+            opt.perm_default.name()
         );
     }
+    let pending: Vec<RawFd> = state.pending.iter().cloned().collect();
+    for fd in pending {
+        respond(
+            notify,
+            fd,
+            audited(opt.perm_default.response(), opt.audit),
+            None,
+            &mut state.pending,
+        )?;
+    }
+    Err(e)
+}
 
-    // TODO: fork myself and sleep in the child forever, so this
-    // fd is never closed
-    let notify_fd = fanotify_init(
-        libc::FAN_CLASS_CONTENT | libc::FAN_CLOEXEC | libc::FAN_NONBLOCK,
-        (libc::O_CLOEXEC | libc::O_RDONLY | libc::O_LARGEFILE) as u32,
-    )?;
+/// What events go through once read from the kernel.
+struct State {
+    marks: Marks,
+    filter: filter::Filter,
+    enrich: TokenBucket,
+    procs: enrich::Procs,
+    containers: Option<containers::Containers>,
+    stats: Stats,
+    sink: Box<dyn Sink>,
+    // permission events waiting for an answer
+    pending: HashSet<RawFd>,
+    /// the order the last permission event with each fd was read in, for
+    /// telling the pending one from those answered before it
+    perm_seqs: HashMap<RawFd, u64>,
+    perm_seq: u64,
+    perm_timeouts: Option<timeout::PermTimeouts>,
+    /// how to answer what stdin would have, once it closed with
+    /// --on-decider-failure allow|deny
+    fallback: Option<policy::Action>,
+    prompt: Option<prompt::Prompt>,
+    notifier: Option<notify::Notifier>,
+    report: Option<Report>,
+    tui: Option<tui::Tui>,
+    sessions: Option<Sessions>,
+    learn: Option<learn::Learn>,
+    heatmap: Option<HeatMap>,
+    /// the --baseline scan, until it's done
+    baseline: Option<baseline::Scan>,
+    quiesce: Option<quiesce::Quiesce>,
+    snapshots: Option<snapshot::Snapshots>,
+    quarantine: Option<quarantine::Quarantine>,
+    audit: Option<audit::AuditLog>,
+    host_paths: Option<mountinfo::HostPaths>,
+    expect: Option<expect::Expect>,
+    webhook: Option<webhook::Webhook>,
+    otlp: Option<otlp::Otlp>,
+    tees: tee::Tees,
+    policy: Option<policy::Policy>,
+    canaries: Option<canary::Canaries>,
+    throttle: Option<throttle::Throttle>,
+    dedup: Option<dedup::Dedup>,
+    rate_limit: Option<ratelimit::RateLimit>,
+    hasher: Option<hasher::Hasher>,
+    allowlist: Option<allowlist::Allowlist>,
+    decisions: Option<decisions::Decisions>,
+    plugin: Option<plugin::Plugin>,
+    script: Option<script::Script>,
+    decider: Option<decider::Decider>,
+    grpc: Option<grpc::Grpc>,
+    scanner: Option<scan::Scanner>,
+    store: Option<sqlite::Store>,
+    /// start answering permission events ourselves past this many
+    max_pending: usize,
+    overloaded: bool,
+    fid: Option<fid::FidResolver>,
+    format: Option<Box<dyn EventFormat>>,
+    /// of the last event printed, for --seq
+    seq: u64,
+    /// events to print before exiting, from --max-events
+    remaining: Option<u64>,
+    /// pidfds of the pending permission events
+    pidfds: HashMap<RawFd, File>,
+}
 
-    for path in &opt.paths {
-        fanotify_mark(
-            notify_fd,
-            libc::FAN_MARK_ADD
-                | if opt.filesystem {
-                    libc::FAN_MARK_FILESYSTEM
-                } else if opt.mount {
-                    libc::FAN_MARK_MOUNT
+fn flush_heatmap(state: &mut State, opt: &Opt) -> io::Result<()> {
+    if let Some(heatmap) = &mut state.heatmap {
+        // a record each, as --hash-chain hashes them
+        for heat in heatmap.take() {
+            let mut record = Vec::new();
+            heat.write_to(&mut record, opt.raw_paths)?;
+            record.push(b'\n');
+            send_record(&record, state.sink.as_mut(), &mut state.stats)?;
+        }
+    }
+    Ok(())
+}
+
+/// What would be written out as `state` is dropped on exit, as an exec
+/// drops nothing.
+fn sync(state: &mut State, opt: &Opt) {
+    if let Err(e) = flush_heatmap(state, opt) {
+        error!("heatmap: {}", e);
+    }
+    if let Err(e) = state.sink.sync() {
+        error!("output: {}", e);
+    }
+    if let Err(e) = state.tees.sync() {
+        error!("tee: {}", e);
+    }
+    if let Some(store) = &mut state.store {
+        if let Err(e) = store.commit() {
+            error!("sqlite: {}", e);
+        }
+    }
+    if let Some(webhook) = &mut state.webhook {
+        if let Err(e) = webhook.sync() {
+            warn!("webhook spool: {}", e);
+        }
+    }
+}
+
+fn poll_audit(state: &mut State, opt: &Opt) -> io::Result<()> {
+    if let Some(audit) = &mut state.audit {
+        for audited in audit.poll()? {
+            let mut record = Vec::new();
+            audited.write_to(&mut record, opt.raw_paths)?;
+            record.push(b'\n');
+            send_record(&record, state.sink.as_mut(), &mut state.stats)?;
+        }
+    }
+    Ok(())
+}
+
+/// Decide and record the event for `fd`, as soon as it's read, or once it's
+/// let through by --perm-rate. One the filters left out is only decided.
+fn handle_event(
+    notify: &mut File,
+    state: &mut State,
+    opt: &Opt,
+    held: throttle::Held,
+) -> io::Result<()> {
+    let throttle::Held {
+        fd,
+        mut entry,
+        pidfd,
+        timestamp,
+        mut report,
+    } = held;
+    let perm = (fd, state.perm_seqs.get(&fd).copied().unwrap_or(0));
+    if let Some(canaries) = &mut state.canaries {
+        if entry.path.as_ref().is_some_and(|p| canaries.hit(p)) {
+            canaries.alert(&entry, &mut state.stats)?;
+            if opt.canary_deny && state.pending.contains(&fd) {
+                respond(
+                    notify,
+                    fd,
+                    audited(libc::FAN_DENY, opt.audit),
+                    None,
+                    &mut state.pending,
+                )?;
+                entry.fields.push(("canary", "deny".into()));
+            } else {
+                entry.fields.push(("canary", "alert".into()));
+            }
+        }
+    }
+    if let Some(allowlist) = &mut state.allowlist {
+        if entry.mask & libc::FAN_OPEN_EXEC_PERM != 0 && state.pending.contains(&fd) {
+            // hashed by the workers while it was held back
+            let hash = entry.fields.iter().find(|(k, _)| *k == "sha256");
+            let allowed = match hash {
+                Some((_, hash)) => allowlist.check(fd, hash),
+                None => {
+                    error!("--exec-allowlist: {:?}: couldn't hash it", entry.path);
+                    false
+                }
+            };
+            if !allowed {
+                state.stats.exec_unknown += 1;
+            }
+            if opt.exec_allowlist_report {
+                // change nothing, only say what would have
+                if !allowed {
+                    warn!(
+                        "--exec-allowlist would deny {:?} for pid {}",
+                        entry.path,
+                        EventEntry::display_field(&entry.pid)
+                    );
+                    entry.fields.push(("exec", "unknown".into()));
+                }
+            } else {
+                let action = if allowed {
+                    policy::Action::Allow
                 } else {
-                    0
-                },
-            mask,
-            dirfd,
-            path.as_ptr(),
+                    policy::Action::Deny
+                };
+                respond(
+                    notify,
+                    fd,
+                    audited(action.response(), opt.audit),
+                    None,
+                    &mut state.pending,
+                )?;
+                entry.fields.push(("exec", action.name().into()));
+            }
+        }
+    }
+    if let Some(plugin) = &mut state.plugin {
+        if !plugin.on_event(&mut entry) {
+            // left out like the filters do, so still decided
+            report = false;
+        }
+        if state.pending.contains(&fd) {
+            if let Some(action) = plugin.decide(&mut entry) {
+                respond(
+                    notify,
+                    fd,
+                    audited(action.response(), opt.audit),
+                    None,
+                    &mut state.pending,
+                )?;
+                entry.fields.push(("plugin", action.name().into()));
+            }
+        }
+    }
+    if let Some(script) = &mut state.script {
+        if let Some(action) = script.run(&mut entry) {
+            if state.pending.contains(&fd) {
+                respond(
+                    notify,
+                    fd,
+                    audited(action.response(), opt.audit),
+                    None,
+                    &mut state.pending,
+                )?;
+                entry.fields.push(("script", action.name().into()));
+            }
+        }
+    }
+    if let Some(policy) = &state.policy {
+        if state.pending.contains(&fd) {
+            if let Some((action, contain)) = policy.decide(&entry) {
+                // while it waits for the answer, so it can't
+                // go on to anything else first
+                if let Some(how) = contain {
+                    let pidfd = pidfd.as_ref().map(File::as_raw_fd);
+                    let res = match (how, &state.quarantine) {
+                        (policy::Contain::Quarantine, Some(quarantine)) => {
+                            quarantine.save(fd, &entry)
+                        }
+                        _ => policy::contain(&entry, pidfd, how),
+                    };
+                    if let Err(e) = res {
+                        warn!(
+                            "{} pid {}: {}",
+                            how.name(),
+                            EventEntry::display_field(&entry.pid),
+                            e
+                        );
+                    }
+                }
+                respond(
+                    notify,
+                    fd,
+                    audited(action.response(), opt.audit),
+                    None,
+                    &mut state.pending,
+                )?;
+                entry.fields.push(("policy", action.name().into()));
+            }
+        }
+    }
+    if let Some(decisions) = &mut state.decisions {
+        if state.pending.contains(&fd) {
+            if let Some(action) = decisions.decide(&entry) {
+                respond(
+                    notify,
+                    fd,
+                    audited(action.response(), opt.audit),
+                    None,
+                    &mut state.pending,
+                )?;
+                entry.fields.push(("remembered", action.name().into()));
+            }
+        }
+    }
+    if let Some(scanner) = &mut state.scanner {
+        if state.pending.contains(&fd) {
+            if let Some(action) = scanner.ask(perm, &entry)? {
+                respond(
+                    notify,
+                    fd,
+                    audited(action.response(), opt.audit),
+                    None,
+                    &mut state.pending,
+                )?;
+                entry.fields.push(("scan", action.name().into()));
+            }
+        }
+    }
+    if let Some(decider) = &mut state.decider {
+        if state.pending.contains(&fd) {
+            decider.ask(perm, &entry);
+        }
+    }
+    if let Some(grpc) = &mut state.grpc {
+        if state.pending.contains(&fd) {
+            if let Some(action) = grpc.ask(perm, &entry, &mut state.stats) {
+                respond(
+                    notify,
+                    fd,
+                    audited(action.response(), opt.audit),
+                    None,
+                    &mut state.pending,
+                )?;
+            }
+        }
+    }
+    if let Some(action) = state.fallback {
+        if state.pending.contains(&fd) {
+            respond(
+                notify,
+                fd,
+                audited(action.response(), opt.audit),
+                None,
+                &mut state.pending,
+            )?;
+        }
+    }
+    if let Some(prompt) = &mut state.prompt {
+        if state.pending.contains(&fd) {
+            if let Some(action) = prompt.ask(perm, &entry)? {
+                respond(
+                    notify,
+                    fd,
+                    audited(action.response(), opt.audit),
+                    None,
+                    &mut state.pending,
+                )?;
+                entry.fields.push(("prompt", action.name().into()));
+            }
+        }
+    }
+    if let Some(notifier) = &mut state.notifier {
+        if opt.notify && state.pending.contains(&fd) {
+            notifier.ask(perm, &entry)?;
+        } else if report {
+            notifier.record(&entry)?;
+        }
+    }
+
+    if let Some(pidfd) = pidfd {
+        // keep it usable for as long as the event is
+        if state.pending.contains(&fd) {
+            state.pidfds.insert(fd, pidfd);
+        }
+    }
+    if !report {
+        // nothing else would answer it, stdin is for the events shown
+        let asked = state.decider.is_some()
+            || state.grpc.is_some()
+            || state.prompt.is_some()
+            || (opt.notify && state.notifier.is_some())
+            || (state.scanner.is_some() && entry.mask & libc::FAN_OPEN_PERM != 0);
+        if !asked && state.pending.contains(&fd) {
+            respond(notify, fd, libc::FAN_ALLOW, None, &mut state.pending)?;
+        }
+        return Ok(());
+    }
+
+    if let Some(quiesce) = &mut state.quiesce {
+        quiesce.record(&entry);
+    }
+    if let Some(expect) = &mut state.expect {
+        expect.record(&entry);
+    }
+    if let Some(webhook) = &mut state.webhook {
+        webhook.record(&entry, &mut state.stats);
+    }
+    if let Some(otlp) = &mut state.otlp {
+        let perm = if state.pending.contains(&fd) {
+            Some(entry.mask & PERM_EVENTS)
+        } else {
+            None
+        };
+        otlp.record(&entry, perm, &mut state.stats);
+    }
+    state.tees.record(&entry, &mut state.stats)?;
+    if let Some(audit) = &mut state.audit {
+        if entry.mask & PERM_EVENTS != 0 {
+            audit.record(&entry);
+        }
+    }
+
+    if let Some(tui) = &mut state.tui {
+        let perm = if state.pending.contains(&fd) {
+            Some(perm)
+        } else {
+            None
+        };
+        tui.record(&entry, perm);
+    } else if let Some(learn) = &mut state.learn {
+        learn.record(&entry);
+    } else if let Some(report) = &mut state.report {
+        report.record(&entry);
+    } else if let Some(heatmap) = &mut state.heatmap {
+        if let Some(path) = &entry.path {
+            heatmap.record(path);
+        }
+    } else if let Some(sessions) = &mut state.sessions {
+        if let Some(session) = sessions.record(&entry, Instant::now()) {
+            let mut record = Vec::new();
+            session.write_to(&mut record, opt.raw_paths)?;
+            record.push(b'\n');
+            send_record(&record, state.sink.as_mut(), &mut state.stats)?;
+        }
+    } else if state.remaining != Some(0) {
+        if let Some(store) = &mut state.store {
+            store.record(&entry, SystemTime::now())?;
+        } else {
+            let mut prefix = Vec::new();
+            prefix.extend(timestamp);
+            if opt.seq {
+                state.seq += 1;
+                prefix.push(state.seq.to_string());
+            }
+            write_event(
+                &entry,
+                &prefix,
+                state.format.as_deref(),
+                state.sink.as_mut(),
+                &mut state.stats,
+            )?;
+        }
+        if let Some(n) = &mut state.remaining {
+            *n -= 1;
+        }
+    }
+    Ok(())
+}
+
+/// Whether to report the events on `path`, for what the marks can't tell
+/// apart.
+fn wanted(
+    opt: &Opt,
+    filter: &filter::Filter,
+    canaries: Option<&canary::Canaries>,
+    path: &Path,
+) -> bool {
+    if ignore::ignored(&opt.ignore, path) {
+        return false;
+    }
+    canaries.is_some_and(|c| c.hit(path)) || filter.path(path)
+}
+
+/// Decide and record `held` now, or once --hash is done with `file` and the
+/// events before it.
+fn hash_or_handle(
+    notify: &mut File,
+    state: &mut State,
+    opt: &Opt,
+    held: throttle::Held,
+    file: Option<File>,
+) -> io::Result<()> {
+    if let Some(hasher) = &mut state.hasher {
+        if file.is_some() || hasher.busy() {
+            // the oldest, if it was full
+            for held in hasher.queue(held, file, &mut state.stats) {
+                handle_event(notify, state, opt, held)?;
+            }
+            return Ok(());
+        }
+    }
+    handle_event(notify, state, opt, held)
+}
+
+/// Answer the oldest pending permission events with --overload-response, for
+/// when there's no fd left to read the next one with.
+fn shed(notify: &mut File, state: &mut State, opt: &Opt) -> io::Result<()> {
+    let keep = state.pending.len().min(state.max_pending) / 2;
+    let seqs = &state.perm_seqs;
+    let mut oldest: Vec<RawFd> = state.pending.iter().cloned().collect();
+    oldest.sort_by_key(|fd| seqs.get(fd).cloned().unwrap_or(0));
+    oldest.truncate(oldest.len() - keep);
+    error!(
+        "out of fds with {} permission events pending, answering {} until below {}",
+        state.pending.len(),
+        opt.overload_response.as_ref(),
+        state.max_pending / 2
+    );
+    state.overloaded = true;
+    for fd in oldest {
+        state.stats.perm_overload += 1;
+        respond(
+            notify,
+            fd,
+            audited(opt.overload_response as u32, opt.audit),
+            None,
+            &mut state.pending,
         )?;
     }
+    Ok(())
+}
+
+fn handle_fanotify(
+    notify: &mut File,
+    fabuf: &mut Vec<libc::fanotify_event_metadata>,
+    state: &mut State,
+    opt: &Opt,
+) -> io::Result<()> {
+    let nread = notify.read(unsafe {
+        slice::from_raw_parts_mut(
+            fabuf.as_mut_ptr() as *mut u8,
+            mem::size_of::<libc::fanotify_event_metadata>() * fabuf.len(),
+        )
+    });
+
+    match nread {
+        Err(errno) => match errno.raw_os_error().unwrap() {
+            libc::EAGAIN | libc::EINTR => return Ok(()),
+            // no fd left to give the next event, free some up
+            libc::EMFILE if !state.pending.is_empty() => return shed(notify, state, opt),
+            _ => {
+                error!("read: {:?}", errno);
+                return Err(errno);
+            }
+        },
+        Ok(nread) => {
+            let buf = unsafe { slice::from_raw_parts(fabuf.as_ptr() as *const u8, nread) };
+            let mut off = 0;
+            // they were all read just now
+            let timestamp = opt.timestamp.map(|t| t.now());
+
+            // events are variable length once info records follow them
+            'next_metadata: while off < nread {
+                let metadata: libc::fanotify_event_metadata =
+                    unsafe { std::ptr::read_unaligned(buf[off..].as_ptr() as *const _) };
+                if nread - off < mem::size_of::<libc::fanotify_event_metadata>() as usize
+                    || metadata.event_len < mem::size_of::<libc::fanotify_event_metadata>() as u32
+                    || metadata.event_len > (nread - off) as u32
+                {
+                    break;
+                } else {
+                    if metadata.vers != libc::FANOTIFY_METADATA_VERSION {
+                        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+                    }
+
+                    let info = &buf
+                        [off + metadata.metadata_len as usize..off + metadata.event_len as usize];
+                    off += metadata.event_len as usize;
+                    state.stats.events += 1;
+                    let now = Instant::now();
+                    for m in FanEvents::values() {
+                        if m as u64 & metadata.mask != 0 && m as u64 & EVENT_FLAGS == 0 {
+                            state.stats.time_event(m.as_ref(), now);
+                            if m as u64 & PERM_EVENTS != 0 && metadata.fd >= 0 {
+                                state.stats.time_perm(metadata.fd, m.as_ref(), now);
+                            }
+                        }
+                    }
+
+                    let mut fields = Vec::new();
+                    if metadata.mask & FanEvents::FAN_Q_OVERFLOW != 0 {
+                        state.stats.lost_kernel_overflow += 1;
+                        // how many so far, for telling one gap from another
+                        fields.push(("overflows", state.stats.lost_kernel_overflow.to_string()));
+                    }
+
+                    // let this drop and close unless the event is held
+                    let pidfd = if opt.pidfd {
+                        fid::pidfd(info).map(|fd| unsafe { File::from_raw_fd(fd) })
+                    } else {
+                        None
+                    };
+
+                    let ours = metadata.pid >= 0 && state.filter.ours(metadata.pid as u32);
+                    // permission events the filters leave out are still
+                    // decided, only not reported
+                    let mut report = !ours
+                        && (metadata.pid < 0
+                            || state.filter.pid(metadata.pid as u32, &mut state.procs))
+                        && state.filter.file(metadata.fd, metadata.mask);
+                    if !report && (ours || metadata.mask & PERM_EVENTS == 0 || metadata.fd < 0) {
+                        if metadata.mask & PERM_EVENTS != 0 && metadata.fd >= 0 {
+                            // our own, which would wait on us to decide
+                            state.pending.insert(metadata.fd);
+                            respond(
+                                notify,
+                                metadata.fd,
+                                libc::FAN_ALLOW,
+                                None,
+                                &mut state.pending,
+                            )?;
+                        } else if metadata.fd >= 0 {
+                            // close it
+                            unsafe { File::from_raw_fd(metadata.fd) };
+                        }
+                        continue 'next_metadata;
+                    }
+                    // opened before the fd of a notification closes
+                    let mut hash_file = None;
+                    // whether the path was found unwanted, before the rest
+                    // of the enrichment
+                    let mut dropped = None;
+                    let file = if metadata.fd >= 0 {
+                        let pid = if metadata.pid >= 0 {
+                            Some(metadata.pid as u32)
+                        } else {
+                            None
+                        };
+                        // the path first, everything's filtered on it and
+                        // over budget it's all that's found
+                        let fd = metadata.fd;
+                        let enriched = opt.enrich.path(fd, &mut state.stats);
+                        let unwanted = enriched.path.as_deref().is_some_and(|p| {
+                            !wanted(opt, &state.filter, state.canaries.as_ref(), p)
+                        });
+                        dropped = Some(unwanted);
+                        let enriched = if unwanted {
+                            enriched
+                        } else if state.enrich.take() {
+                            let pidfd = pidfd.as_ref().map(File::as_raw_fd);
+                            opt.enrich.rest(
+                                fd,
+                                pid,
+                                pidfd,
+                                &mut state.procs,
+                                &mut state.stats,
+                                enriched,
+                            )
+                        } else {
+                            state.stats.enrich_skipped += 1;
+                            enriched
+                        };
+                        fields = enriched.fields;
+                        if let Some(containers) = &mut state.containers {
+                            let id = fields.iter().find(|(k, _)| *k == "container_id");
+                            if let Some(c) = id.and_then(|(_, id)| containers.lookup(id)) {
+                                fields.push(("container_name", c.name.clone()));
+                                fields.push(("container_image", c.image.clone()));
+                            }
+                        }
+                        let path = enriched.path;
+                        // --exec-allowlist needs the hash of any executable
+                        let exec = metadata.mask & libc::FAN_OPEN_EXEC_PERM != 0
+                            && state.allowlist.is_some();
+                        let hashed = fields.iter().any(|(k, _)| *k == "sha256");
+                        let known = match &state.allowlist {
+                            Some(allowlist) if exec && !hashed => allowlist.hashed(fd),
+                            _ => None,
+                        };
+                        if let Some(hash) = known {
+                            fields.push(("sha256", hash));
+                        } else if let Some(hasher) = &state.hasher {
+                            if !hashed && (exec || (dropped == Some(false) && report)) {
+                                hash_file = hasher.open(fd, metadata.mask, &mut state.stats);
+                            }
+                        }
+
+                        if metadata.mask & PERM_EVENTS != 0 {
+                            // wait for command to close it
+                            state.pending.insert(metadata.fd);
+                            state.perm_seq += 1;
+                            state.perm_seqs.insert(metadata.fd, state.perm_seq);
+                            if let Some(timeouts) = &mut state.perm_timeouts {
+                                timeouts.start(metadata.fd, now);
+                            }
+
+                            if state.overloaded && state.pending.len() < state.max_pending / 2 {
+                                info!("{} permission events pending", state.pending.len());
+                                state.overloaded = false;
+                            }
+                            if state.pending.len() > state.max_pending && !state.overloaded {
+                                error!(
+                                    "{} permission events pending, answering {} until below {}",
+                                    state.pending.len(),
+                                    opt.overload_response.as_ref(),
+                                    state.max_pending / 2
+                                );
+                                state.overloaded = true;
+                            }
+                            if state.overloaded {
+                                // answer now rather than run out of fds, and
+                                // with it the ability to read any event
+                                state.stats.perm_overload += 1;
+                                respond(
+                                    notify,
+                                    metadata.fd,
+                                    audited(opt.overload_response as u32, opt.audit),
+                                    None,
+                                    &mut state.pending,
+                                )?;
+                            }
+                        } else {
+                            // let this drop and close, or the snapshot worker
+                            // once it's copied
+                            let f = unsafe { File::from_raw_fd(metadata.fd) };
+
+                            if let (Some(snapshots), Some(path), false) =
+                                (&state.snapshots, &path, unwanted)
+                            {
+                                if metadata.mask & libc::FAN_CLOSE_WRITE != 0 {
+                                    snapshots.save(f, path);
+                                }
+                            }
+                        }
+
+                        path
+                    } else if let Some(fid) = &state.fid {
+                        let path = fid.resolve(info).map(|path| {
+                            let (path, deleted) = enrich::strip_deleted(path);
+                            if deleted {
+                                fields.push(("deleted", "true".into()));
+                            }
+                            path
+                        });
+                        let unwanted = path.as_deref().is_some_and(|p| {
+                            !wanted(opt, &state.filter, state.canaries.as_ref(), p)
+                        });
+                        dropped = Some(unwanted);
+                        if !unwanted && state.enrich.take() {
+                            if metadata.mask & libc::FAN_RENAME != 0 {
+                                if let Some(to) = fid.resolve_target(info) {
+                                    fields.push(("to", to.to_string_lossy().into_owned()));
+                                }
+                            }
+                            if opt.enrich.contains(enrich::Step::Inode) {
+                                if let Some((dev, ino)) = fid.inode(info) {
+                                    fields.extend(enrich::inode_fields(dev, ino));
+                                }
+                            }
+                            if opt.enrich.contains(enrich::Step::Mount) {
+                                // no mount id without an fd, but the
+                                // filesystem's enough across bind mounts
+                                if let Some((dev, _)) = fid.inode(info) {
+                                    fields.extend(enrich::dev_mount_fields(dev));
+                                }
+                            }
+                        } else if !unwanted {
+                            state.stats.enrich_skipped += 1;
+                        }
+                        path
+                    } else {
+                        None
+                    };
+
+                    if let Some(pidfd) = &pidfd {
+                        fields.push(("pidfd", pidfd.as_raw_fd().to_string()));
+                    }
+
+                    if let Some(path) = &file {
+                        let dropped = dropped.unwrap_or_else(|| {
+                            !wanted(opt, &state.filter, state.canaries.as_ref(), path)
+                        });
+                        if dropped && state.pending.contains(&metadata.fd) {
+                            report = false;
+                        } else if dropped {
+                            debug!("dropping unwanted notification: {:?}", path);
+                            continue 'next_metadata;
+                        }
+                    }
+
+                    let mut entry = EventEntry {
+                        mask: metadata.mask,
+                        fd: if metadata.fd >= 0 {
+                            Some(metadata.fd)
+                        } else {
+                            None
+                        },
+                        pid: if metadata.pid >= 0 {
+                            Some(metadata.pid as u32)
+                        } else {
+                            None
+                        },
+                        path: match &state.host_paths {
+                            Some(hp) => file.map(|p| hp.translate(&p)),
+                            None => file,
+                        },
+                        fields,
+                    };
+
+                    if let Some(dedup) = &mut state.dedup {
+                        // permission events are each answered
+                        if !state.pending.contains(&metadata.fd) {
+                            match dedup.record(&entry, now) {
+                                None => {
+                                    state.stats.deduped += 1;
+                                    continue 'next_metadata;
+                                }
+                                Some(0) => (),
+                                Some(n) => entry.fields.push(("repeated", n.to_string())),
+                            }
+                        }
+                    }
+                    if let Some(limit) = &mut state.rate_limit {
+                        if !state.pending.contains(&metadata.fd) {
+                            match limit.admit(&entry, now) {
+                                Some(fields) => entry.fields.extend(fields),
+                                None => {
+                                    state.stats.rate_limited += 1;
+                                    continue 'next_metadata;
+                                }
+                            }
+                        }
+                    }
+
+                    if let Some(throttle) = &mut state.throttle {
+                        if state.pending.contains(&metadata.fd)
+                            && !throttle.admit(metadata.fd, &entry)
+                        {
+                            state.stats.perm_throttled += 1;
+                            match opt.perm_rate_action {
+                                throttle::OverRate::Delay => {
+                                    throttle.hold(throttle::Held {
+                                        fd: metadata.fd,
+                                        entry,
+                                        pidfd,
+                                        timestamp: timestamp.clone(),
+                                        report,
+                                    });
+                                    continue 'next_metadata;
+                                }
+                                throttle::OverRate::Deny => {
+                                    respond(
+                                        notify,
+                                        metadata.fd,
+                                        audited(libc::FAN_DENY, opt.audit),
+                                        None,
+                                        &mut state.pending,
+                                    )?;
+                                    entry.fields.push(("throttled", "deny".into()));
+                                }
+                            }
+                        }
+                    }
+                    let held = throttle::Held {
+                        fd: metadata.fd,
+                        entry,
+                        pidfd,
+                        timestamp: timestamp.clone(),
+                        report,
+                    };
+                    hash_or_handle(notify, state, opt, held, hash_file)?;
+                }
+            }
+        }
+    };
+
+    return Ok(());
+}
+
+fn poll_timeout(deadline: Option<Instant>) -> c_int {
+    match deadline {
+        // round up so we don't wake up just before the deadline
+        // and don't wrap around into waiting forever, or not at all
+        Some(d) => d
+            .saturating_duration_since(Instant::now())
+            .as_micros()
+            .div_ceil(1000)
+            .min(c_int::MAX as u128) as c_int,
+        None => -1,
+    }
+}
+
+#[cfg(test)]
+mod poll_timeout_tests {
+    use super::*;
+
+    #[test]
+    fn poll_timeout_clamped() {
+        assert_eq!(poll_timeout(None), -1);
+        assert_eq!(poll_timeout(Some(Instant::now())), 0);
+        let far = Instant::now() + Duration::from_secs(100 * 86400);
+        assert_eq!(poll_timeout(Some(far)), c_int::MAX);
+    }
+}
+
+fn finish(mut state: State, opt: &Opt) -> io::Result<()> {
+    // no new events while we wrap up
+    if let Err(e) = state.marks.flush() {
+        warn!("flush marks: {}", e);
+    }
+    // restore the terminal first
+    state.tui = None;
+    flush_heatmap(&mut state, opt)?;
+    if let Some(report) = &state.report {
+        report.write_to(&mut io::stdout(), opt.raw_paths)?;
+    }
+    if let Some(learn) = &state.learn {
+        learn.write_policy(&mut io::stdout())?;
+    }
+    if let Some(prompt) = &state.prompt {
+        prompt.write_rules(&mut io::stderr())?;
+    }
+    if opt.stats || state.stats.lost() != 0 {
+        state.stats.write_to(&mut io::stderr())?;
+    }
+    if let Some(expect) = &state.expect {
+        if !expect.write_report(&mut io::stderr())? {
+            return Err(io::Error::other("expectations not met"));
+        }
+    }
+    if opt.canary_exit && state.stats.canary_hits != 0 {
+        return Err(io::Error::other("a --canary was touched"));
+    }
+    Ok(())
+}
+
+fn parse_mask(events: &str) -> io::Result<u64> {
+    let mut mask = 0;
+
+    for m in events.split(',') {
+        // raw bits for events we don't have a name for
+        if let Some(hex) = m.strip_prefix("0x") {
+            mask |= u64::from_str_radix(hex, 16)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("{}: {}", m, e)))?;
+            continue;
+        }
+        mask = mask
+            | m.parse::<FanEvents>()
+                .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+
+        debug!(
+            "adding event {} = {:x}",
+            m,
+            m.parse::<FanEvents>().unwrap() as u64
+        );
+    }
+    Ok(mask)
+}
+
+/// The flags to open event fds with, O_CLOEXEC and O_LARGEFILE are always
+/// added.
+fn parse_fflags(fflags: &str) -> io::Result<c_int> {
+    let mut flags = libc::O_CLOEXEC | libc::O_LARGEFILE;
+    for f in fflags.split(',') {
+        flags |= f
+            .parse::<OpenFlags>()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))? as c_int;
+    }
+    Ok(flags)
+}
+
+fn main() -> io::Result<()> {
+    env_logger::init();
+
+    let opt = Opt::from_args_with_default()?;
+
+    if let Some(Command::InstallService {
+        name,
+        unit_dir,
+        config_dir,
+        enable,
+        args,
+    }) = &opt.cmd
+    {
+        return service::install(name, unit_dir, config_dir, *enable, args);
+    }
+
+    if let Some(Command::Selftest) = &opt.cmd {
+        return selftest::run(parse_mask(opt.events.as_ref().unwrap())?);
+    }
+
+    if let Some(Command::Query {
+        db,
+        path,
+        pid,
+        comm,
+        events,
+        since,
+        until,
+        limit,
+    }) = &opt.cmd
+    {
+        let q = sqlite::Query {
+            path: path.clone(),
+            pid: *pid,
+            comm: comm.clone(),
+            mask: events.as_deref().map(parse_mask).transpose()?,
+            since: *since,
+            until: *until,
+            limit: *limit,
+        };
+        sqlite::query(db, &q, &mut io::stdout().lock())?;
+        return Ok(());
+    }
+
+    if let Some(Command::Unfreeze { cgroups }) = &opt.cmd {
+        for cgroup in cgroups {
+            freeze::set_frozen(cgroup, false)?;
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Policy {
+        cmd:
+            PolicyCommand::Test {
+                path,
+                pid,
+                uid,
+                comm,
+                exe,
+                event,
+            },
+    }) = &opt.cmd
+    {
+        let file = opt
+            .policy
+            .as_ref()
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "policy test needs --policy"))?;
+        let policy = policy::Policy::load(file)?;
+        let mut fields = Vec::new();
+        if let Some(comm) = comm {
+            fields.push(("comm", comm.clone()));
+        }
+        if let Some(exe) = exe {
+            fields.push(("exe", exe.to_string_lossy().into_owned()));
+        }
+        let entry = EventEntry {
+            mask: parse_mask(event)?,
+            fd: None,
+            pid: *pid,
+            path: path.clone(),
+            fields,
+        };
+        match policy.explain(&entry, *uid) {
+            (Some(at), Some(verdict)) => println!("{}: {}: {}", file.display(), at, verdict),
+            (None, Some(verdict)) => println!("{}: default: {}", file.display(), verdict),
+            _ => println!("{}: no rule matches, left to stdin", file.display()),
+        }
+        return Ok(());
+    }
+
+    if let Some(log) = &opt.verify_chain {
+        let n = chain::verify(log, opt.verify_checkpoint_cmd.as_deref())?;
+        println!("{} records ok", n);
+        return Ok(());
+    }
+
+    if let Some(n) = opt.nofile {
+        limits::set_nofile(n)?;
+    }
+    if let Some(cgroup) = &opt.cgroup {
+        limits::join_cgroup(cgroup)?;
+    }
+
+    if let Some(start) = opt.start_at {
+        if let Ok(wait) = start.duration_since(SystemTime::now()) {
+            info!("waiting {:?} to start", wait);
+            thread::sleep(wait);
+        }
+    }
+
+    let dirfd = match opt.namespace {
+        Some(p) => open_namespace_root(p)?,
+        None => libc::AT_FDCWD,
+    };
+
+    let mask = parse_mask(opt.events.as_ref().unwrap())?;
+    if opt.exec_allowlist.is_some() && mask & libc::FAN_OPEN_EXEC_PERM == 0 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "--exec-allowlist needs FAN_OPEN_EXEC_PERM in --events",
+        ));
+    }
+    let mut fflags = parse_fflags(&opt.event_fflags)?;
+    if opt.noatime {
+        fflags |= libc::O_NOATIME;
+    }
+
+    let mut path_flags = 0;
+    if opt.onlydir {
+        path_flags |= libc::FAN_MARK_ONLYDIR;
+    }
+    if opt.dont_follow {
+        path_flags |= libc::FAN_MARK_DONT_FOLLOW;
+    }
+    let mark_type = if opt.filesystem {
+        libc::FAN_MARK_FILESYSTEM
+    } else if opt.mount {
+        libc::FAN_MARK_MOUNT
+    } else {
+        0
+    };
+
+    let canaries = if opt.canary.is_empty() {
+        None
+    } else {
+        Some(canary::Canaries::new(
+            opt.canary.clone(),
+            opt.canary_webhook.as_deref(),
+        )?)
+    };
+    let (notify_fd, pending) = match upgrade::take_inherited()? {
+        // marks are already in place from before the exec
+        Some(inherited) => inherited,
+        None => {
+            // TODO: fork myself and sleep in the child forever, so this
+            // fd is never closed
+            let class = if opt.fid {
+                // events come with file handles instead of fds, which only
+                // notification groups can do
+                Class::Notif.flag()
+                    | libc::FAN_REPORT_FID
+                    | if mask & libc::FAN_RENAME != 0 {
+                        // both the old and new directory and name
+                        libc::FAN_REPORT_DFID_NAME_TARGET
+                    } else if mask & DIRENT_EVENTS != 0 || opt.unprivileged {
+                        // without open_by_handle_at, children can only be
+                        // found through the marked directory and a name
+                        libc::FAN_REPORT_DFID_NAME
+                    } else {
+                        0
+                    }
+            } else {
+                opt.class.unwrap_or(Class::Content).flag()
+            };
+            let report_tid = if opt.tid { libc::FAN_REPORT_TID } else { 0 };
+            let report_pidfd = if opt.pidfd { libc::FAN_REPORT_PIDFD } else { 0 };
+            let audit = if opt.audit { libc::FAN_ENABLE_AUDIT } else { 0 };
+            let notify_fd = fanotify_init(
+                class | report_tid | report_pidfd | audit | libc::FAN_CLOEXEC | libc::FAN_NONBLOCK,
+                fflags as u32,
+            )?;
+
+            let evictable = if opt.evictable && mark_type == 0 {
+                libc::FAN_MARK_EVICTABLE
+            } else {
+                0
+            };
+            for path in &opt.paths {
+                fanotify_mark(
+                    notify_fd,
+                    libc::FAN_MARK_ADD | mark_type | path_flags | evictable,
+                    mask,
+                    dirfd,
+                    path.as_ptr(),
+                )?;
+            }
+            for path in &opt.ignore {
+                ignore::mark(notify_fd, dirfd, path, mask, opt.evictable)?;
+            }
+            if let Some(canaries) = &canaries {
+                canaries.mark(notify_fd, dirfd, mask)?;
+            }
+            (notify_fd, HashSet::new())
+        }
+    };
+
+    // start it now that the marks are in, and before we block signals
+    let expect = match (&opt.expect, &opt.run) {
+        (Some(file), Some(cmd)) => Some(expect::Expect::new(file, cmd)?),
+        _ => None,
+    };
+
+    let mut signals = vec![libc::SIGINT, libc::SIGTERM, libc::SIGUSR1];
+    if opt.upgrade_exec.is_some() {
+        signals.push(libc::SIGUSR2);
+    }
+    if opt.policy.is_some() || opt.exec_allowlist.is_some() {
+        signals.push(libc::SIGHUP);
+    }
+    let signal_fd = open_signalfd(&signals)?;
+    let mut sigfile = unsafe { File::from_raw_fd(signal_fd) };
+
+    // after blocking signals, so their threads do too
+    let webhook = match &opt.webhook {
+        Some(url) => {
+            let rules = opt
+                .webhook_match
+                .iter()
+                .map(|r| expect::Rule::parse(r))
+                .collect::<io::Result<_>>()?;
+            // apart from --forward's
+            let spool = match &opt.spill_dir {
+                Some(dir) => Some(spool::Spool::open(&dir.join("webhook"), opt.spill_max)?),
+                None => None,
+            };
+            Some(webhook::Webhook::new(
+                url,
+                rules,
+                opt.webhook_batch,
+                opt.webhook_rate,
+                spool,
+            )?)
+        }
+        None => None,
+    };
+    let otlp = match &opt.otlp {
+        Some(endpoint) => Some(otlp::Otlp::new(endpoint)?),
+        None => None,
+    };
+    let notifier = if opt.notify || !opt.notify_match.is_empty() {
+        let rules = opt
+            .notify_match
+            .iter()
+            .map(|r| expect::Rule::parse(r))
+            .collect::<io::Result<_>>()?;
+        Some(notify::Notifier::new(rules)?)
+    } else {
+        None
+    };
+    let grpc = match &opt.grpc {
+        Some(url) => Some(grpc::Grpc::new(
+            url,
+            opt.grpc_connections,
+            Duration::from_millis(opt.grpc_timeout_ms),
+            if opt.grpc_fail_closed {
+                policy::Action::Deny
+            } else {
+                policy::Action::Allow
+            },
+        )?),
+        None => None,
+    };
 
     let mut events = vec![
         libc::pollfd {
@@ -384,6 +2094,11 @@ fn main() -> io::Result<()> {
             events: libc::POLLIN,
             revents: 0,
         },
+        libc::pollfd {
+            fd: signal_fd,
+            events: libc::POLLIN,
+            revents: 0,
+        },
     ];
 
     let mut fabuf = Vec::new();
@@ -392,20 +2107,713 @@ fn main() -> io::Result<()> {
 
     let mut notify = unsafe { File::from_raw_fd(notify_fd) };
     let mut command_buf = String::new();
+    let mut sink: Box<dyn Sink> = match (&opt.forward, &opt.listen) {
+        (None, Some(url)) => Box::new(listen::ListenSink::new(url, opt.listen_buffer)?),
+        (Some(url), _) => {
+            let spool = match &opt.spill_dir {
+                Some(dir) => Some(spool::Spool::open(dir, opt.spill_max)?),
+                None => None,
+            };
+            Box::new(forward::TcpSink::new(
+                url,
+                opt.forward_ca.as_deref(),
+                spool,
+            )?)
+        }
+        (None, None) => match (&opt.output_file, &opt.output) {
+            (Some(path), _) => {
+                let by_size = opt.rotate_size.map(|size| logfile::BySize {
+                    size,
+                    keep: opt.rotate_keep,
+                    compress: opt.rotate_compress,
+                });
+                Box::new(logfile::FileSink::new(path.clone(), opt.rotate, by_size)?)
+            }
+            (None, Some(sink::Output::Journald)) => Box::new(journald::JournaldSink::new()?),
+            (None, Some(sink::Output::Sqlite(_))) | (None, None) => Box::new(StdoutSink),
+        },
+    };
+    if opt.hash_chain {
+        sink = Box::new(chain::ChainSink::new(
+            sink,
+            opt.checkpoint_cmd.clone(),
+            opt.checkpoint_every,
+        ));
+    }
+    if let Some(fd) = sink.fd() {
+        events.push(libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        });
+    }
+    if let Some(notifier) = &notifier {
+        events.push(libc::pollfd {
+            fd: notifier.fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        });
+    }
+    if let Some(grpc) = &grpc {
+        events.push(libc::pollfd {
+            fd: grpc.fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        });
+    }
+    let scanner = match &opt.scan_cmd {
+        Some(cmd) => Some(scan::Scanner::new(cmd, opt.perm_default)?),
+        None => None,
+    };
+    if let Some(scanner) = &scanner {
+        events.push(libc::pollfd {
+            fd: scanner.fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        });
+    }
+    let hasher = if opt.hash.is_some() || opt.exec_allowlist.is_some() {
+        Some(hasher::Hasher::new(opt.hash, opt.exec_allowlist.is_some())?)
+    } else {
+        None
+    };
+    if let Some(hasher) = &hasher {
+        events.push(libc::pollfd {
+            fd: hasher.fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        });
+    }
+    // filled in on each poll, it changes when --decider reconnects
+    let decider_slot = events.len();
+    events.push(libc::pollfd {
+        fd: -1,
+        events: libc::POLLIN,
+        revents: 0,
+    });
+    // filled in on each poll, until --baseline is done
+    let baseline_slot = events.len();
+    events.push(libc::pollfd {
+        fd: -1,
+        events: libc::POLLIN,
+        revents: 0,
+    });
+
+    let mut state = State {
+        marks: Marks {
+            notify_fd,
+            dirfd,
+            flags: mark_type | path_flags,
+            mask,
+        },
+        filter: filter::Filter::new(&opt)?,
+        enrich: TokenBucket::new(opt.enrich_rate),
+        containers: match &opt.container_api {
+            Some(socket) => Some(containers::Containers::new(socket)?),
+            None => None,
+        },
+        procs: enrich::Procs::new(opt.cmdline.map(|max| max.unwrap_or(enrich::CMDLINE_LEN))),
+        stats: Stats::default(),
+        sink,
+        pending,
+        perm_seqs: HashMap::new(),
+        perm_seq: 0,
+        fallback: None,
+        prompt: if opt.interactive {
+            Some(prompt::Prompt::new(Box::new(io::stderr())))
+        } else {
+            None
+        },
+        notifier,
+        perm_timeouts: opt
+            .perm_timeout
+            .map(|secs| timeout::PermTimeouts::new(Duration::from_secs(secs))),
+        max_pending: match opt.max_pending {
+            Some(n) => n,
+            // leave room for a whole read of events on top, and everything
+            // else we have open
+            None => (limits::nofile()? as usize)
+                .saturating_sub(MAX_FANOTIFY_BUFS + RESERVED_FDS)
+                .max(1),
+        },
+        overloaded: false,
+        fid: if opt.fid {
+            Some(fid::FidResolver::new(&opt.paths, dirfd)?)
+        } else {
+            None
+        },
+        pidfds: HashMap::new(),
+        format: None,
+        seq: 0,
+        remaining: opt.max_events,
+        report: if opt.report {
+            Some(Report::default())
+        } else {
+            None
+        },
+        expect,
+        webhook,
+        otlp,
+        tees: tee::Tees::default(),
+        policy: match &opt.policy {
+            Some(file) => {
+                let policy = policy::Policy::load(file)?;
+                if policy.quarantines() && opt.quarantine.is_none() {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("{}: quarantine rules need --quarantine", file.display()),
+                    ));
+                }
+                Some(policy)
+            }
+            None => None,
+        },
+        canaries,
+        throttle: opt
+            .perm_rate
+            .map(|rate| throttle::Throttle::new(rate, opt.perm_rate_by)),
+        dedup: opt
+            .dedup
+            .map(|ms| dedup::Dedup::new(Duration::from_millis(ms))),
+        rate_limit: if opt.max_events_per_sec.is_some() || !opt.rate_limit.is_empty() {
+            Some(ratelimit::RateLimit::new(
+                opt.max_events_per_sec,
+                &opt.rate_limit,
+                opt.rate_limit_action,
+            )?)
+        } else {
+            None
+        },
+        hasher,
+        allowlist: match &opt.exec_allowlist {
+            Some(path) => Some(allowlist::Allowlist::load(
+                path,
+                opt.exec_allowlist_key.as_deref(),
+            )?),
+            None => None,
+        },
+        decisions: match &opt.decisions {
+            Some(file) => Some(decisions::Decisions::open(file)?),
+            None => None,
+        },
+        plugin: match &opt.plugin {
+            Some(file) => Some(plugin::Plugin::load(file)?),
+            None => None,
+        },
+        script: match &opt.script {
+            Some(file) => Some(script::Script::load(file, opt.perm_default)?),
+            None => None,
+        },
+        decider: match &opt.decider {
+            Some(url) => Some(decider::Decider::new(url)?),
+            None => None,
+        },
+        grpc,
+        scanner,
+        store: match &opt.output {
+            Some(sink::Output::Sqlite(db)) => Some(sqlite::Store::open(db)?),
+            _ => None,
+        },
+        host_paths: match opt.namespace {
+            Some(pid) if opt.host_paths => Some(mountinfo::HostPaths::new(pid)?),
+            _ => None,
+        },
+        audit: match &opt.audit_log {
+            Some(path) => Some(audit::AuditLog::open(path)?),
+            None => None,
+        },
+        snapshots: match &opt.snapshot_dir {
+            Some(dir) => Some(snapshot::Snapshots::open(dir)?),
+            None => None,
+        },
+        quarantine: match &opt.quarantine {
+            Some(dir) => Some(quarantine::Quarantine::open(dir)?),
+            None => None,
+        },
+        quiesce: opt
+            .on_quiesce
+            .clone()
+            .map(|cmd| quiesce::Quiesce::new(cmd, Duration::from_secs(opt.quiet_secs))),
+        heatmap: opt
+            .heatmap
+            .map(|depth| HeatMap::new(depth, opt.heatmap_top, opt.heatmap_interval())),
+        baseline: None,
+        sessions: if opt.sessions {
+            Some(Sessions::default())
+        } else {
+            None
+        },
+        learn: opt.learn.map(learn::Learn::new),
+        tui: if opt.tui {
+            if unsafe { libc::isatty(libc::STDIN_FILENO) } != 1 {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "--tui needs a terminal on stdin",
+                ));
+            }
+            Some(tui::Tui::new(if opt.tid { "tid" } else { "pid" })?)
+        } else {
+            None
+        },
+    };
+
+    let mut fields = vec!["mask", "fd", "pid", "path"];
+    fields.extend(opt.enrich.fields());
+    if opt.cmdline.is_some() {
+        fields.push("cmdline");
+    }
+    if opt.container_api.is_some() {
+        fields.extend(["container_name", "container_image"]);
+    }
+    fields.push("overflows");
+    if opt.fid {
+        fields.push("to");
+    }
+    if opt.pidfd {
+        fields.push("pidfd");
+    }
+    if opt.hash.is_some() {
+        fields.push("sha256");
+    }
+    if opt.exec_allowlist.is_some() {
+        if !fields.contains(&"sha256") {
+            fields.push("sha256");
+        }
+        fields.push("exec");
+    }
+    if opt.plugin.is_some() {
+        fields.push("plugin");
+    }
+    if opt.script.is_some() {
+        fields.push("script");
+    }
+    if opt.policy.is_some() {
+        fields.push("policy");
+    }
+    if opt.decisions.is_some() {
+        fields.push("remembered");
+    }
+    if opt.interactive {
+        fields.push("prompt");
+    }
+    state.tees = tee::Tees::open(&opt.tee, &fields[4..], opt.csv_header, &mut state.stats)?;
+    if opt.output == Some(sink::Output::Journald) {
+        state.format = Some(Box::new(journald::Journald));
+    } else if let Some(format) = &opt.format {
+        fields.extend(&["time", "dir", "name", "events"]);
+        state.format = Some(Box::new(template::Template::parse(
+            format,
+            &fields,
+            &opt.timefmt,
+        )?));
+    } else if opt.output_format == csv::Format::Csv {
+        let csv = csv::Csv::new(fields[4..].to_vec());
+        if opt.csv_header {
+            let mut record = Vec::new();
+            if opt.timestamp.is_some() {
+                record.extend_from_slice(b"time,");
+            }
+            if opt.seq {
+                record.extend_from_slice(b"seq,");
+            }
+            csv.write_header(&mut record)?;
+            record.push(b'\n');
+            send_record(&record, state.sink.as_mut(), &mut state.stats)?;
+        }
+        state.format = Some(Box::new(csv));
+    } else if opt.output_format != csv::Format::Tab {
+        state.format = opt.output_format.event_format(&fields[4..]);
+    } else if opt
+        .color
+        .enabled(opt.forward.is_none() && opt.output_file.is_none() && opt.output.is_none())
+    {
+        state.format = Some(Box::new(color::Colored {
+            raw_paths: opt.raw_paths,
+        }));
+    } else if opt.raw_paths {
+        state.format = Some(Box::new(RawPaths));
+    }
+
+    if opt.baseline {
+        // the marks are in, so nothing falls between this and the events,
+        // and it's on a thread so they're still answered meanwhile
+        let baseline = baseline::Baseline {
+            recursive: opt.recursive,
+            one_dev: opt.mount || opt.filesystem,
+            hash: opt.baseline_hash,
+            raw_paths: opt.raw_paths,
+        };
+        let roots = opt
+            .paths
+            .iter()
+            .map(|path| {
+                let path = Path::new(OsStr::from_bytes(path.as_bytes()));
+                match opt.namespace {
+                    Some(pid) => Path::new(&format!("/proc/{}/root", pid))
+                        .join(path.strip_prefix("/").unwrap_or(path)),
+                    None => path.into(),
+                }
+            })
+            .collect();
+        state.baseline = Some(baseline.spawn(roots)?);
+    }
+
+    let stop_at = opt
+        .stop_at
+        .map(|t| Instant::now() + t.duration_since(SystemTime::now()).unwrap_or_default());
 
     loop {
-        let ready = poll(events.as_mut_ptr(), events.len() as libc::nfds_t, -1)?;
-        if ready > 0 {
+        if let Some(prompt) = &mut state.prompt {
+            let (pending, seqs) = (&state.pending, &state.perm_seqs);
+            prompt.answered(&|perm| still_pending(pending, seqs, perm))?;
+        }
+        let deadline = [
+            state.sink.deadline(),
+            state.tees.deadline(),
+            state.tui.as_ref().and_then(|t| t.deadline()),
+            state.heatmap.as_ref().map(|h| h.deadline()),
+            state.quiesce.as_ref().and_then(|q| q.deadline()),
+            state.audit.as_ref().map(|a| a.deadline()),
+            state.expect.as_ref().map(|e| e.deadline()),
+            state.store.as_ref().and_then(|s| s.deadline()),
+            state.decider.as_ref().and_then(|d| d.deadline()),
+            state.perm_timeouts.as_ref().and_then(|t| t.deadline()),
+            state.throttle.as_ref().and_then(|t| t.deadline()),
+            state.dedup.as_ref().and_then(|d| d.deadline()),
+            state.learn.as_ref().map(|l| l.deadline()),
+            state.webhook.as_ref().and_then(|w| w.deadline()),
+            stop_at,
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .cloned();
+        events[decider_slot].fd = state.decider.as_ref().and_then(|d| d.fd()).unwrap_or(-1);
+        events[baseline_slot].fd = state.baseline.as_ref().map_or(-1, |b| b.fd());
+        let ready = poll(
+            events.as_mut_ptr(),
+            events.len() as libc::nfds_t,
+            poll_timeout(deadline),
+        )?;
+        if let Some(t) = stop_at {
+            if Instant::now() >= t {
+                info!("reached --stop-at");
+                return finish(state, &opt);
+            }
+        }
+        if state
+            .learn
+            .as_ref()
+            .is_some_and(|l| Instant::now() >= l.deadline())
+        {
+            info!("done learning");
+            return finish(state, &opt);
+        }
+
+        if let Some(tui) = &mut state.tui {
+            if tui.deadline().is_some_and(|t| Instant::now() >= t) {
+                let (pending, seqs) = (&state.pending, &state.perm_seqs);
+                tui.draw(&state.stats, &|perm| still_pending(pending, seqs, perm))?;
+            }
+        }
+
+        if state
+            .heatmap
+            .as_ref()
+            .is_some_and(|h| Instant::now() >= h.deadline())
+        {
+            flush_heatmap(&mut state, &opt)?;
+        }
+
+        if let Some(quiesce) = &mut state.quiesce {
+            quiesce.tick();
+        }
+
+        if let Some(store) = &mut state.store {
+            if store.deadline().is_some_and(|t| Instant::now() >= t) {
+                store.commit()?;
+            }
+        }
+
+        if let Some(decider) = &mut state.decider {
+            decider.tick();
+        }
+
+        if let Some(webhook) = &mut state.webhook {
+            webhook.tick();
+        }
+
+        if let Some(timeouts) = &mut state.perm_timeouts {
+            for fd in timeouts.expired(Instant::now(), &state.pending) {
+                debug!(
+                    "answering {} {} after --perm-timeout",
+                    fd,
+                    opt.perm_default.name()
+                );
+                state.stats.perm_timed_out += 1;
+                respond(
+                    &mut notify,
+                    fd,
+                    audited(opt.perm_default.response(), opt.audit),
+                    None,
+                    &mut state.pending,
+                )?;
+            }
+        }
+
+        let released = match &mut state.throttle {
+            Some(throttle) => throttle.release(&state.pending),
+            None => Vec::new(),
+        };
+        for mut held in released {
+            held.entry.fields.push(("throttled", "delay".into()));
+            // still pending, so the fd is still open
+            let file = match &state.hasher {
+                Some(hasher) => hasher.open(held.fd, held.entry.mask, &mut state.stats),
+                None => None,
+            };
+            hash_or_handle(&mut notify, &mut state, &opt, held, file)?;
+        }
+
+        let repeats = match &mut state.dedup {
+            Some(dedup) => dedup.expire(Instant::now()),
+            None => Vec::new(),
+        };
+        for entry in repeats {
+            // not seen again after repeating, so the count goes on its own
+            let held = throttle::Held {
+                fd: -1,
+                entry,
+                pidfd: None,
+                timestamp: opt.timestamp.map(|t| t.now()),
+                report: true,
+            };
+            hash_or_handle(&mut notify, &mut state, &opt, held, None)?;
+        }
+
+        if state
+            .audit
+            .as_ref()
+            .is_some_and(|a| Instant::now() >= a.deadline())
+        {
+            poll_audit(&mut state, &opt)?;
+        }
+
+        if let Some(expect) = &mut state.expect {
+            if Instant::now() >= expect.deadline() && expect.check_exit()? {
+                // its events are all queued by now
+                loop {
+                    let before = state.stats.events;
+                    handle_fanotify(&mut notify, &mut fabuf, &mut state, &opt)?;
+                    if state.stats.events == before {
+                        break;
+                    }
+                }
+                return finish(state, &opt);
+            }
+        }
+
+        if ready == 0 {
+            state.sink.tick(&mut state.stats)?;
+            state.tees.tick(&mut state.stats)?;
+        } else {
+            let mut stdin_closed = false;
             for e in &events {
                 if e.revents > 0 {
                     match e.fd {
+                        libc::STDIN_FILENO if state.tui.is_some() => {
+                            let (pending, seqs) = (&state.pending, &state.perm_seqs);
+                            let input = state
+                                .tui
+                                .as_mut()
+                                .unwrap()
+                                .handle_input(&|perm| still_pending(pending, seqs, perm))?;
+                            match input {
+                                Some(tui::Action::Quit) => return finish(state, &opt),
+                                Some(tui::Action::Respond((fd, _), resp)) => respond(
+                                    &mut notify,
+                                    fd,
+                                    audited(resp, opt.audit),
+                                    None,
+                                    &mut state.pending,
+                                )?,
+                                None => (),
+                            }
+                        }
                         libc::STDIN_FILENO => {
-                            handle_command(&mut io::stdin(), &mut command_buf, &mut notify)?
+                            let res = match state.prompt {
+                                Some(_) => answer_prompt(
+                                    &mut io::stdin(),
+                                    &mut command_buf,
+                                    &mut notify,
+                                    &mut state,
+                                    &opt,
+                                ),
+                                None => handle_command(
+                                    &mut io::stdin(),
+                                    &mut command_buf,
+                                    &mut notify,
+                                    &mut state.pending,
+                                    &state.marks,
+                                    opt.audit,
+                                ),
+                            };
+                            match res {
+                                // nothing to answer, so keep going without stdin,
+                                // which is the case when running as a service
+                                Err(ref err)
+                                    if err.kind() == ErrorKind::UnexpectedEof
+                                        && mask & PERM_EVENTS == 0 =>
+                                {
+                                    debug!("stdin closed");
+                                    stdin_closed = true;
+                                }
+                                Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                                    match opt.on_decider_failure {
+                                        // they answer for themselves
+                                        Some(_)
+                                            if state.decider.is_some()
+                                                || state.grpc.is_some()
+                                                || state.scanner.is_some() =>
+                                        {
+                                            debug!("stdin closed");
+                                            stdin_closed = true;
+                                        }
+                                        Some(OnFailure::Answer(action)) => {
+                                            warn!(
+                                                "stdin closed, answering permission events {}",
+                                                action.name()
+                                            );
+                                            stdin_closed = true;
+                                            state.fallback = Some(action);
+                                            let pending: Vec<RawFd> =
+                                                state.pending.iter().cloned().collect();
+                                            for fd in pending {
+                                                respond(
+                                                    &mut notify,
+                                                    fd,
+                                                    audited(action.response(), opt.audit),
+                                                    None,
+                                                    &mut state.pending,
+                                                )?;
+                                            }
+                                        }
+                                        _ => return give_up(&mut notify, &mut state, &opt, err),
+                                    }
+                                }
+                                res => res?,
+                            }
+                        }
+                        fd if fd == signal_fd => match read_signal(&mut sigfile)? {
+                            Some(libc::SIGUSR1) => {
+                                state.stats.write_to(&mut io::stderr())?;
+                                if let Some(report) = &state.report {
+                                    report.write_to(&mut io::stdout(), opt.raw_paths)?;
+                                }
+                            }
+                            Some(libc::SIGUSR2) => {
+                                let exe = opt.upgrade_exec.as_ref().unwrap();
+                                sync(&mut state, &opt);
+                                let e = upgrade::reexec(exe, notify_fd, &state.pending);
+                                error!("upgrade to {:?}: {}", exe, e);
+                            }
+                            Some(libc::SIGHUP) => {
+                                // the pending events stay for stdin to answer
+                                if let Some(file) = &opt.policy {
+                                    match policy::Policy::load(file) {
+                                        Ok(policy) => {
+                                            info!("reloaded {:?}", file);
+                                            state.policy = Some(policy);
+                                        }
+                                        Err(e) => error!("keeping the old policy: {}", e),
+                                    }
+                                }
+                                if let Some(path) = &opt.exec_allowlist {
+                                    match allowlist::Allowlist::load(
+                                        path,
+                                        opt.exec_allowlist_key.as_deref(),
+                                    ) {
+                                        Ok(allowlist) => {
+                                            info!("reloaded {:?}", path);
+                                            state.allowlist = Some(allowlist);
+                                        }
+                                        Err(e) => error!("keeping the old allowlist: {}", e),
+                                    }
+                                }
+                            }
+                            Some(_) => return finish(state, &opt),
+                            None => (),
+                        },
+                        fd if Some(fd) == state.sink.fd() => state.sink.tick(&mut state.stats)?,
+                        fd if Some(fd) == state.decider.as_ref().and_then(|d| d.fd()) => {
+                            let decided = state.decider.as_mut().unwrap().read();
+                            answer_decided(&mut notify, &mut state, &opt, decided)?;
+                        }
+                        fd if Some(fd) == state.notifier.as_ref().map(|n| n.fd()) => {
+                            let decided = state.notifier.as_mut().unwrap().read();
+                            answer_decided(&mut notify, &mut state, &opt, decided)?;
+                        }
+                        fd if Some(fd) == state.grpc.as_ref().map(|g| g.fd()) => {
+                            let decided = state.grpc.as_mut().unwrap().read(&mut state.stats);
+                            answer_decided(&mut notify, &mut state, &opt, decided)?;
+                        }
+                        fd if Some(fd) == state.scanner.as_ref().map(|s| s.fd()) => {
+                            let decided = state.scanner.as_mut().unwrap().read(&mut state.stats);
+                            answer_decided(&mut notify, &mut state, &opt, decided)?;
                         }
-                        _ => handle_fanotify(&mut notify, &mut fabuf, &opt)?,
+                        fd if Some(fd) == state.hasher.as_ref().map(|h| h.fd()) => {
+                            let released = state.hasher.as_mut().unwrap().read(&mut state.stats);
+                            for held in released {
+                                handle_event(&mut notify, &mut state, &opt, held)?;
+                            }
+                        }
+                        fd if Some(fd) == state.baseline.as_ref().map(|b| b.fd()) => {
+                            let (records, done) = state.baseline.as_mut().unwrap().read();
+                            for record in records {
+                                send_record(&record, state.sink.as_mut(), &mut state.stats)?;
+                            }
+                            if done {
+                                state.baseline = None;
+                            }
+                        }
+                        _ => handle_fanotify(&mut notify, &mut fabuf, &mut state, &opt)?,
                     }
                 }
             }
+            answer_decided(&mut notify, &mut state, &opt, Vec::new())?;
+            state.stats.time_answered(&state.pending, Instant::now());
+            if let Some(otlp) = &mut state.otlp {
+                otlp.answered(&state.pending, &mut state.stats);
+            }
+            let (pending, seqs) = (&state.pending, &state.perm_seqs);
+            let still = |perm| still_pending(pending, seqs, perm);
+            if let Some(notifier) = &mut state.notifier {
+                notifier.answered(&still);
+            }
+            if let Some(decider) = &mut state.decider {
+                decider.answered(&still);
+            }
+            if let Some(grpc) = &mut state.grpc {
+                grpc.answered(&still);
+            }
+            if let Some(scanner) = &mut state.scanner {
+                scanner.answered(&still);
+            }
+            if !state.pidfds.is_empty() {
+                let pending = &state.pending;
+                state.pidfds.retain(|fd, _| pending.contains(fd));
+            }
+            if state.remaining == Some(0) {
+                info!("reached --max-events");
+                return finish(state, &opt);
+            }
+            if opt.canary_exit && state.stats.canary_hits != 0 {
+                return finish(state, &opt);
+            }
+            if stdin_closed {
+                // poll ignores negative fds
+                events[0].fd = -1;
+            }
         }
     }
 }
@@ -0,0 +1,189 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, PartialEq)]
+struct Mount {
+    id: u32,
+    dev: String,
+    /// the directory of the filesystem that's mounted
+    root: PathBuf,
+    point: PathBuf,
+    fstype: String,
+}
+
+/// Undo the octal escapes of space, tab, newline and backslash.
+fn unescape(s: &str) -> PathBuf {
+    let b = s.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        if b[i] == b'\\' && i + 3 < b.len() && b[i + 1..i + 4].iter().all(u8::is_ascii_digit) {
+            if let Ok(c) = u8::from_str_radix(&s[i + 1..i + 4], 8) {
+                out.push(c);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(b[i]);
+        i += 1;
+    }
+    OsString::from_vec(out).into()
+}
+
+fn parse(mountinfo: &str) -> Vec<Mount> {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let id = fields.next()?.parse().ok()?;
+            fields.next()?;
+            let dev = fields.next()?.into();
+            let root = unescape(fields.next()?);
+            let point = unescape(fields.next()?);
+            // past the optional fields
+            fields.find(|f| *f == "-")?;
+            Some(Mount {
+                id,
+                dev,
+                root,
+                point,
+                fstype: fields.next()?.into(),
+            })
+        })
+        .collect()
+}
+
+/// Where the mount with this id is mounted, as seen from here, and the
+/// type of its filesystem.
+pub fn mount_point(id: u32) -> io::Result<Option<(PathBuf, String)>> {
+    Ok(parse(&fs::read_to_string("/proc/self/mountinfo")?)
+        .into_iter()
+        .find(|m| m.id == id)
+        .map(|m| (m.point, m.fstype)))
+}
+
+/// The mount of the filesystem on device `dev`, the whole of it over a
+/// bind mount of some directory in it.
+fn dev_mount(mounts: Vec<Mount>, dev: u64) -> Option<Mount> {
+    let dev = format!("{}:{}", libc::major(dev), libc::minor(dev));
+    mounts
+        .into_iter()
+        .filter(|m| m.dev == dev)
+        .min_by_key(|m| m.root.components().count())
+}
+
+/// Where the filesystem on device `dev` is mounted, as seen from here, and
+/// its type, for when there's no mount id to go by.
+pub fn dev_mount_point(dev: u64) -> io::Result<Option<(PathBuf, String)>> {
+    let mounts = parse(&fs::read_to_string("/proc/self/mountinfo")?);
+    Ok(dev_mount(mounts, dev).map(|m| (m.point, m.fstype)))
+}
+
+/// Where the first filesystem of this type is mounted, like cgroup2.
+pub fn find_fstype(fstype: &str) -> io::Result<Option<PathBuf>> {
+    Ok(parse(&fs::read_to_string("/proc/self/mountinfo")?)
+        .into_iter()
+        .find(|m| m.fstype == fstype)
+        .map(|m| m.point))
+}
+
+/// Maps paths in the mount namespace of another process to paths we can
+/// open, by way of the filesystem they're on.
+pub struct HostPaths {
+    pid: u32,
+    theirs: Vec<Mount>,
+    ours: Vec<Mount>,
+}
+
+impl HostPaths {
+    pub fn new(pid: u32) -> io::Result<HostPaths> {
+        Ok(HostPaths {
+            pid,
+            theirs: parse(&fs::read_to_string(format!("/proc/{}/mountinfo", pid))?),
+            ours: parse(&fs::read_to_string("/proc/self/mountinfo")?),
+        })
+    }
+
+    fn lookup(&self, path: &Path) -> Option<PathBuf> {
+        // the last one mounted on the longest prefix is the one visible
+        let theirs = self
+            .theirs
+            .iter()
+            .filter(|m| path.starts_with(&m.point))
+            .max_by_key(|m| m.point.components().count())?;
+        let in_fs = theirs.root.join(path.strip_prefix(&theirs.point).ok()?);
+
+        let ours = self
+            .ours
+            .iter()
+            .rev()
+            .filter(|m| m.dev == theirs.dev && in_fs.starts_with(&m.root))
+            .max_by_key(|m| m.root.components().count())?;
+        Some(ours.point.join(in_fs.strip_prefix(&ours.root).ok()?))
+    }
+
+    /// Where `path` in their namespace is in ours, going through
+    /// /proc/<pid>/root if the filesystem isn't mounted here.
+    pub fn translate(&self, path: &Path) -> PathBuf {
+        self.lookup(path).unwrap_or_else(|| {
+            Path::new(&format!("/proc/{}/root", self.pid))
+                .join(path.strip_prefix("/").unwrap_or(path))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mountinfo_translate() {
+        let hp = HostPaths {
+            pid: 42,
+            theirs: parse(
+                "1 0 8:1 /var/lib/c1 / rw - ext4 /dev/sda1 rw\n\
+                 2 1 8:2 /vol /data rw - ext4 /dev/sdb1 rw\n\
+                 3 1 0:50 / /tmp rw - tmpfs tmpfs rw\n\
+                 4 2 8:2 /other /data rw - ext4 /dev/sdb1 rw\n",
+            ),
+            ours: parse(
+                "1 0 8:1 / / rw - ext4 /dev/sda1 rw\n\
+                 2 1 8:2 / /mnt/my\\040disk rw - ext4 /dev/sdb1 rw\n",
+            ),
+        };
+        assert_eq!(
+            hp.translate(Path::new("/etc/passwd")),
+            Path::new("/var/lib/c1/etc/passwd")
+        );
+        // overmounted
+        assert_eq!(
+            hp.translate(Path::new("/data/x")),
+            Path::new("/mnt/my disk/other/x")
+        );
+        assert_eq!(
+            hp.translate(Path::new("/tmp/x")),
+            Path::new("/proc/42/root/tmp/x")
+        );
+        assert_eq!(hp.theirs[2].fstype, "tmpfs");
+    }
+
+    #[test]
+    fn mount_by_dev() {
+        let mounts = || {
+            parse(
+                "1 0 8:1 / / rw - ext4 /dev/sda1 rw\n\
+                 2 1 8:2 /vol /data rw - xfs /dev/sdb1 rw\n\
+                 3 1 8:2 / /mnt rw - xfs /dev/sdb1 rw\n",
+            )
+        };
+        let found = dev_mount(mounts(), libc::makedev(8, 2)).unwrap();
+        assert_eq!(
+            (found.point, found.fstype),
+            (PathBuf::from("/mnt"), "xfs".into())
+        );
+        assert_eq!(dev_mount(mounts(), libc::makedev(8, 3)), None);
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+
+use zbus::blocking::proxy::SignalIterator;
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::Value;
+
+use crate::expect::Rule;
+use crate::policy::{self, Action};
+use crate::{prompt, write_escaped, EventEntry, PermId};
+
+const NOTIFICATIONS: &str = "org.freedesktop.Notifications";
+const PATH: &str = "/org/freedesktop/Notifications";
+// notifications waiting to be shown, any more are dropped
+const QUEUE_LEN: usize = 100;
+
+enum Call {
+    /// show a notification, with allow and deny buttons if it's for an ask
+    Notify {
+        ask: Option<u64>,
+        summary: String,
+        body: String,
+    },
+    Close(u32),
+}
+
+enum Reply {
+    /// the notification for an ask was shown with this id
+    Shown(u64, u32),
+    /// a button of a notification was clicked
+    Action(u32, String),
+    Closed(u32),
+}
+
+fn dbus_error(e: zbus::Error) -> io::Error {
+    io::Error::new(
+        ErrorKind::NotConnected,
+        format!("desktop notifications: {}", e),
+    )
+}
+
+/// `s` with what the notification server would take for markup escaped.
+fn escape_markup(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Makes the D-Bus calls, so a slow notification server doesn't hold up
+/// the main loop.
+fn run(proxy: Proxy<'static>, calls: Receiver<Call>, replies: Sender<Reply>, mut wake: UnixStream) {
+    // whether the last call failed, to log only when that changes
+    let mut failing = false;
+    for call in calls {
+        let result = match call {
+            Call::Notify { ask, summary, body } => {
+                let (actions, urgency, expire) = match ask {
+                    // critical, and shown until it's answered
+                    Some(_) => (vec!["allow", "Allow", "deny", "Deny"], 2u8, 0),
+                    None => (Vec::new(), 1u8, -1),
+                };
+                let mut hints = HashMap::new();
+                hints.insert("urgency", Value::U8(urgency));
+                proxy
+                    .call(
+                        "Notify",
+                        &(
+                            "fanotify-cli",
+                            0u32,
+                            "",
+                            summary.as_str(),
+                            body.as_str(),
+                            actions,
+                            hints,
+                            expire,
+                        ),
+                    )
+                    .map(|id: u32| {
+                        if let Some(ask) = ask {
+                            if replies.send(Reply::Shown(ask, id)).is_ok() {
+                                // it's nonblocking, and a full buffer already wakes it
+                                let _ = wake.write(&[0]);
+                            }
+                        }
+                    })
+            }
+            Call::Close(id) => proxy.call("CloseNotification", &(id,)),
+        };
+        match result {
+            Ok(()) if failing => {
+                info!("desktop notifications: working again");
+                failing = false;
+            }
+            Ok(()) => (),
+            Err(e) if !failing => {
+                warn!("desktop notifications: {}", e);
+                failing = true;
+            }
+            Err(e) => debug!("desktop notifications: {}", e),
+        }
+    }
+}
+
+/// Passes on the buttons clicked and the notifications closed, until we
+/// exit.
+fn listen(
+    signals: SignalIterator<'static>,
+    replies: Sender<Reply>,
+    mut wake: UnixStream,
+) -> zbus::Result<()> {
+    for msg in signals {
+        let reply = match msg.header().member().map(|m| m.as_str()) {
+            Some("ActionInvoked") => {
+                let (id, key): (u32, String) = msg.body().deserialize()?;
+                Reply::Action(id, key)
+            }
+            Some("NotificationClosed") => {
+                let (id, _reason): (u32, u32) = msg.body().deserialize()?;
+                Reply::Closed(id)
+            }
+            _ => continue,
+        };
+        if replies.send(reply).is_err() {
+            break;
+        }
+        let _ = wake.write(&[0]);
+    }
+    Ok(())
+}
+
+/// Shows desktop notifications over D-Bus, asking about permission events
+/// with allow and deny buttons, and alerting about the events matching
+/// --notify-match.
+pub struct Notifier {
+    rules: Vec<Rule>,
+    tx: Option<SyncSender<Call>>,
+    replies: Receiver<Reply>,
+    wake: UnixStream,
+    worker: Option<JoinHandle<()>>,
+    next_ask: u64,
+    /// the permission events asked about, and the id of their
+    /// notification once it's shown, by ask
+    asked: HashMap<u64, (PermId, Option<u32>)>,
+}
+
+impl Notifier {
+    pub fn new(rules: Vec<Rule>) -> io::Result<Notifier> {
+        let conn = Connection::session().map_err(dbus_error)?;
+        let proxy = Proxy::new(&conn, NOTIFICATIONS, PATH, NOTIFICATIONS).map_err(dbus_error)?;
+        let (name, vendor, version, _spec): (String, String, String, String) = proxy
+            .call("GetServerInformation", &())
+            .map_err(dbus_error)?;
+        info!("desktop notifications: {} {} {}", vendor, name, version);
+        // before anything is shown, so no click is missed
+        let signals = proxy.receive_all_signals().map_err(dbus_error)?;
+
+        let (tx, calls) = mpsc::sync_channel(QUEUE_LEN);
+        let (replies_tx, replies) = mpsc::channel();
+        let (wake, wake_tx) = UnixStream::pair()?;
+        wake.set_nonblocking(true)?;
+        wake_tx.set_nonblocking(true)?;
+        let (listen_replies, listen_wake) = (replies_tx.clone(), wake_tx.try_clone()?);
+        let worker = thread::Builder::new()
+            .name("notify".into())
+            .spawn(move || run(proxy, calls, replies_tx, wake_tx))?;
+        // it waits on the bus, so it's left to exit with us
+        thread::Builder::new()
+            .name("notify-signals".into())
+            .spawn(move || {
+                if let Err(e) = listen(signals, listen_replies, listen_wake) {
+                    error!("desktop notifications: {}", e);
+                }
+            })?;
+
+        Ok(Notifier {
+            rules,
+            tx: Some(tx),
+            replies,
+            wake,
+            worker: Some(worker),
+            next_ask: 0,
+            asked: HashMap::new(),
+        })
+    }
+
+    fn send(&self, call: Call) {
+        if let Err(TrySendError::Full(_)) = self.tx.as_ref().unwrap().try_send(call) {
+            warn!("desktop notifications: too many waiting to be shown, dropping one");
+        }
+    }
+
+    /// Close the notifications of the events `gone` says were answered.
+    fn forget(&mut self, gone: impl Fn(PermId) -> bool) {
+        let mut closed = Vec::new();
+        self.asked.retain(|_, (perm, id)| {
+            if gone(*perm) {
+                closed.extend(*id);
+                false
+            } else {
+                true
+            }
+        });
+        for id in closed {
+            self.send(Call::Close(id));
+        }
+    }
+
+    /// Ask about the permission event `perm`.
+    pub fn ask(&mut self, perm: PermId, entry: &EventEntry) -> io::Result<()> {
+        // the fd was reused, so the last event with it was answered
+        self.forget(|(fd, _)| fd == perm.0);
+
+        let body = String::from_utf8_lossy(&prompt::describe(entry)?).into_owned();
+        self.asked.insert(self.next_ask, (perm, None));
+        self.send(Call::Notify {
+            ask: Some(self.next_ask),
+            summary: "Permission request".into(),
+            body: escape_markup(&body),
+        });
+        self.next_ask += 1;
+        Ok(())
+    }
+
+    /// Alert about `entry` if it matches --notify-match.
+    pub fn record(&mut self, entry: &EventEntry) -> io::Result<()> {
+        if !self.rules.iter().any(|r| r.matches(entry)) {
+            return Ok(());
+        }
+        let mut body = Vec::new();
+        if let Some(path) = &entry.path {
+            write_escaped(&mut body, path.as_os_str().as_bytes())?;
+        }
+        let body = format!(
+            "{}\nby {} (pid {})",
+            String::from_utf8_lossy(&body),
+            policy::comm(entry).as_deref().unwrap_or("?"),
+            EventEntry::display_field(&entry.pid)
+        );
+        self.send(Call::Notify {
+            ask: None,
+            summary: entry.mask_names(),
+            body: escape_markup(&body),
+        });
+        Ok(())
+    }
+
+    /// The events answered with a button since the last time.
+    pub fn read(&mut self) -> Vec<(PermId, Action)> {
+        let mut buf = [0; 4096];
+        while let Ok(n) = (&self.wake).read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+
+        let mut decided = Vec::new();
+        while let Ok(reply) = self.replies.try_recv() {
+            let (id, action) = match reply {
+                Reply::Shown(ask, id) => {
+                    match self.asked.get_mut(&ask) {
+                        Some((_, shown)) => *shown = Some(id),
+                        // answered before it was shown
+                        None => self.send(Call::Close(id)),
+                    }
+                    continue;
+                }
+                Reply::Action(id, key) => match key.parse() {
+                    Ok(action) => (id, Some(action)),
+                    // the default action, of clicking the notification
+                    // itself, doesn't answer it
+                    Err(_) => continue,
+                },
+                // dismissed, it's left to stdin or --perm-timeout
+                Reply::Closed(id) => (id, None),
+            };
+            let ask = self
+                .asked
+                .iter()
+                .find(|(_, (_, shown))| *shown == Some(id))
+                .map(|(ask, _)| *ask);
+            if let Some((perm, _)) = ask.and_then(|ask| self.asked.remove(&ask)) {
+                decided.extend(action.map(|action| (perm, action)));
+            }
+        }
+        decided
+    }
+
+    /// Close the notifications of the events that are no longer `pending`.
+    pub fn answered(&mut self, pending: &dyn Fn(PermId) -> bool) {
+        if !self.asked.is_empty() {
+            self.forget(|perm| !pending(perm));
+        }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.wake.as_raw_fd()
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        self.tx = None;
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notify_answers() -> io::Result<()> {
+        let (tx, calls) = mpsc::sync_channel(QUEUE_LEN);
+        let (replies_tx, replies) = mpsc::channel();
+        let (wake, _wake_tx) = UnixStream::pair()?;
+        wake.set_nonblocking(true)?;
+        let mut notifier = Notifier {
+            rules: vec![Rule::parse("/etc/* FAN_MODIFY")?],
+            tx: Some(tx),
+            replies,
+            wake,
+            worker: None,
+            next_ask: 0,
+            asked: HashMap::new(),
+        };
+        let entry = |mask, path: &str| EventEntry {
+            fd: Some(3),
+            pid: Some(42),
+            fields: vec![("comm", "cat".into())],
+            ..EventEntry::test(mask, path)
+        };
+
+        for fd in 3..6 {
+            notifier.ask(
+                (fd, fd as u64),
+                &entry(libc::FAN_OPEN_PERM, "/etc/<shadow>"),
+            )?;
+        }
+        match calls.try_recv() {
+            Ok(Call::Notify {
+                ask: Some(0), body, ..
+            }) => assert_eq!(body, "cat (pid 42) wants to open /etc/&lt;shadow&gt;"),
+            _ => panic!("no notification"),
+        }
+        for (ask, id) in [(0, 10), (1, 11), (2, 12)] {
+            replies_tx.send(Reply::Shown(ask, id)).unwrap();
+        }
+        replies_tx
+            .send(Reply::Action(10, "default".into()))
+            .unwrap();
+        replies_tx.send(Reply::Action(10, "deny".into())).unwrap();
+        replies_tx.send(Reply::Closed(11)).unwrap();
+        replies_tx.send(Reply::Action(11, "allow".into())).unwrap();
+        // someone else's
+        replies_tx.send(Reply::Action(99, "allow".into())).unwrap();
+        assert_eq!(notifier.read(), vec![((3, 3), Action::Deny)]);
+        assert_eq!(notifier.asked.len(), 1);
+
+        // 5 was answered some other way, and 6 before it was shown
+        notifier.ask((6, 6), &entry(libc::FAN_OPEN_PERM, "/etc/passwd"))?;
+        notifier.answered(&|_| false);
+        replies_tx.send(Reply::Shown(3, 13)).unwrap();
+        assert!(notifier.read().is_empty());
+        let closed: Vec<u32> = calls
+            .try_iter()
+            .filter_map(|c| match c {
+                Call::Close(id) => Some(id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(closed, vec![12, 13]);
+
+        notifier.record(&entry(libc::FAN_MODIFY, "/etc/passwd"))?;
+        notifier.record(&entry(libc::FAN_MODIFY, "/tmp/passwd"))?;
+        notifier.record(&entry(libc::FAN_ACCESS, "/etc/passwd"))?;
+        match calls.try_recv() {
+            Ok(Call::Notify {
+                ask: None,
+                summary,
+                body,
+            }) => {
+                assert_eq!(summary, "FAN_MODIFY");
+                assert_eq!(body, "/etc/passwd\nby cat (pid 42)");
+            }
+            _ => panic!("no alert"),
+        }
+        assert!(calls.try_recv().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::io::RawFd;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::color::{self, Class};
+use crate::json::write_str;
+use crate::stats::Stats;
+use crate::webhook::Poster;
+use crate::EventEntry;
+
+// most log records or spans per POST
+const BATCH: usize = 512;
+const SPAN_KIND_INTERNAL: u8 = 1;
+
+/// The semantic convention names of the --enrich fields that have one,
+/// the rest are fanotify.NAME.
+const ATTRIBUTES: &[(&str, &str)] = &[
+    ("comm", "process.command"),
+    ("exe", "process.executable.path"),
+    ("size", "file.size"),
+    ("ino", "file.inode"),
+];
+
+fn nanos(t: SystemTime) -> u128 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
+
+fn random_hex(n: usize) -> String {
+    let mut buf = vec![0u8; n];
+    unsafe { libc::getrandom(buf.as_mut_ptr() as *mut libc::c_void, n, 0) };
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn write_attr(w: &mut dyn Write, first: &mut bool, key: &str, value: &str) -> io::Result<()> {
+    if !*first {
+        w.write_all(b",")?;
+    }
+    *first = false;
+    w.write_all(b"{\"key\":")?;
+    write_str(w, key)?;
+    w.write_all(b",\"value\":{\"stringValue\":")?;
+    write_str(w, value)?;
+    w.write_all(b"}}")
+}
+
+/// The "attributes" of an event, without the brackets.
+fn write_attrs(w: &mut dyn Write, entry: &EventEntry) -> io::Result<()> {
+    let mut first = true;
+    write_attr(w, &mut first, "fanotify.mask", &entry.mask_names())?;
+    if let Some(pid) = entry.pid {
+        w.write_fmt(format_args!(
+            ",{{\"key\":\"process.pid\",\"value\":{{\"intValue\":\"{}\"}}}}",
+            pid
+        ))?;
+    }
+    if let Some(path) = &entry.path {
+        write_attr(w, &mut first, "file.path", &path.to_string_lossy())?;
+    }
+    for (k, v) in &entry.fields {
+        match ATTRIBUTES.iter().find(|(name, _)| name == k) {
+            Some((_, key)) => write_attr(w, &mut first, key, v)?,
+            None => write_attr(w, &mut first, &format!("fanotify.{}", k), v)?,
+        }
+    }
+    Ok(())
+}
+
+/// An event as an OTLP/JSON LogRecord, the body being the line we'd
+/// otherwise print.
+fn log_record(entry: &EventEntry, now: SystemTime) -> io::Result<Vec<u8>> {
+    let (number, text) = match color::class(entry.mask) {
+        Some(Class::Perm) => (13, "WARN"),
+        _ => (9, "INFO"),
+    };
+    let mut line = Vec::new();
+    entry.write_to(&mut line)?;
+
+    let mut w = Vec::new();
+    w.write_fmt(format_args!(
+        "{{\"timeUnixNano\":\"{}\",\"severityNumber\":{},\"severityText\":\"{}\",\
+         \"body\":{{\"stringValue\":",
+        nanos(now),
+        number,
+        text
+    ))?;
+    write_str(&mut w, &String::from_utf8_lossy(&line))?;
+    w.write_all(b"},\"attributes\":[")?;
+    write_attrs(&mut w, entry)?;
+    w.write_all(b"]}")?;
+    Ok(w)
+}
+
+/// A permission event waiting for its answer.
+struct Decision {
+    name: String,
+    start: SystemTime,
+    attrs: Vec<u8>,
+}
+
+impl Decision {
+    /// As an OTLP/JSON Span, in a trace of its own.
+    fn span(&self, end: SystemTime) -> Vec<u8> {
+        let mut w = Vec::new();
+        w.extend_from_slice(
+            format!(
+                "{{\"traceId\":\"{}\",\"spanId\":\"{}\",\"name\":\"{}\",\"kind\":{},\
+                 \"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\"attributes\":[",
+                random_hex(16),
+                random_hex(8),
+                self.name,
+                SPAN_KIND_INTERNAL,
+                nanos(self.start),
+                nanos(end)
+            )
+            .as_bytes(),
+        );
+        w.extend_from_slice(&self.attrs);
+        w.extend_from_slice(b"]}");
+        w
+    }
+}
+
+fn resource() -> Vec<u8> {
+    let host = fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+    let mut w = Vec::new();
+    let mut first = true;
+    let _ = w.write_all(b"{\"attributes\":[");
+    let _ = write_attr(&mut w, &mut first, "service.name", "fanotify-cli");
+    let _ = write_attr(
+        &mut w,
+        &mut first,
+        "service.version",
+        env!("CARGO_PKG_VERSION"),
+    );
+    if !host.trim().is_empty() {
+        let _ = write_attr(&mut w, &mut first, "host.name", host.trim());
+    }
+    let _ = w.write_all(b"]}");
+    w
+}
+
+/// An ExportLogsServiceRequest or ExportTraceServiceRequest, `kind` being
+/// "Logs" or "Spans" and `items` the records or spans.
+fn request(kind: &str, resource: &[u8], items: &[Vec<u8>]) -> Vec<u8> {
+    let mut w = format!("{{\"resource{}\":[{{\"resource\":", kind).into_bytes();
+    w.extend_from_slice(resource);
+    w.extend_from_slice(
+        format!(
+            ",\"scope{}\":[{{\"scope\":{{\"name\":\"fanotify-cli\"}},\"{}\":[",
+            kind,
+            if kind == "Logs" {
+                "logRecords"
+            } else {
+                "spans"
+            }
+        )
+        .as_bytes(),
+    );
+    for (i, item) in items.iter().enumerate() {
+        if i != 0 {
+            w.push(b',');
+        }
+        w.extend_from_slice(item);
+    }
+    w.extend_from_slice(b"]}]}]}");
+    w
+}
+
+/// Exports events as OpenTelemetry log records, and the time it took to
+/// answer each permission event as a span, over OTLP/HTTP with JSON.
+pub struct Otlp {
+    logs: Poster,
+    traces: Poster,
+    /// the unanswered permission events by fd
+    decisions: HashMap<RawFd, Decision>,
+}
+
+impl Otlp {
+    /// `endpoint` is the collector's base URL, like http://localhost:4318
+    pub fn new(endpoint: &str) -> io::Result<Otlp> {
+        let endpoint = endpoint.trim_end_matches('/');
+        let res = resource();
+        let logs_res = res.clone();
+        Ok(Otlp {
+            logs: Poster::new(
+                "otlp logs",
+                &format!("{}/v1/logs", endpoint),
+                BATCH,
+                0,
+                Box::new(move |items| request("Logs", &logs_res, items)),
+            )?,
+            traces: Poster::new(
+                "otlp traces",
+                &format!("{}/v1/traces", endpoint),
+                BATCH,
+                0,
+                Box::new(move |items| request("Spans", &res, items)),
+            )?,
+            decisions: HashMap::new(),
+        })
+    }
+
+    fn send_span(&self, d: &Decision, end: SystemTime, stats: &mut Stats) {
+        if !self.traces.send(d.span(end)) {
+            stats.otlp_dropped += 1;
+        }
+    }
+
+    /// `perm` is the permission event `entry` is, if it's waiting for an
+    /// answer.
+    pub fn record(&mut self, entry: &EventEntry, perm: Option<u64>, stats: &mut Stats) {
+        let now = SystemTime::now();
+        match log_record(entry, now) {
+            Ok(record) => {
+                if !self.logs.send(record) {
+                    stats.otlp_dropped += 1;
+                }
+            }
+            Err(e) => debug!("otlp: {}", e),
+        }
+
+        if let (Some(mask), Some(fd)) = (perm, entry.fd) {
+            let mut attrs = Vec::new();
+            if write_attrs(&mut attrs, entry).is_err() {
+                return;
+            }
+            let name = EventEntry {
+                mask,
+                fd: None,
+                pid: None,
+                path: None,
+                fields: Vec::new(),
+            }
+            .mask_names();
+            let d = Decision {
+                name,
+                start: now,
+                attrs,
+            };
+            // the fd was reused, so the last one with it was answered
+            if let Some(old) = self.decisions.insert(fd, d) {
+                self.send_span(&old, now, stats);
+            }
+        }
+    }
+
+    /// Send the spans of the permission events that are no longer
+    /// `pending`.
+    pub fn answered(&mut self, pending: &HashSet<RawFd>, stats: &mut Stats) {
+        if self.decisions.len() == pending.len() {
+            return;
+        }
+        let now = SystemTime::now();
+        let answered: Vec<RawFd> = self
+            .decisions
+            .keys()
+            .filter(|fd| !pending.contains(fd))
+            .cloned()
+            .collect();
+        for fd in answered {
+            let d = self.decisions.remove(&fd).unwrap();
+            self.send_span(&d, now, stats);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn otlp_json() -> io::Result<()> {
+        let entry = EventEntry {
+            fd: Some(3),
+            pid: Some(42),
+            fields: vec![("comm", "cat".into()), ("uid", "0".into())],
+            ..EventEntry::test(libc::FAN_OPEN_PERM, "/etc/\"x\"")
+        };
+        let t = UNIX_EPOCH + Duration::from_secs(1);
+        let record = log_record(&entry, t)?;
+        assert_eq!(
+            String::from_utf8(request("Logs", b"{}", &[record])).unwrap(),
+            "{\"resourceLogs\":[{\"resource\":{},\"scopeLogs\":[{\"scope\":\
+             {\"name\":\"fanotify-cli\"},\"logRecords\":[{\"timeUnixNano\":\"1000000000\",\
+             \"severityNumber\":13,\"severityText\":\"WARN\",\"body\":{\"stringValue\":\
+             \"FAN_OPEN_PERM\\t3\\t42\\t/etc/\\\"x\\\"\\tcomm=cat\\tuid=0\"},\"attributes\":[\
+             {\"key\":\"fanotify.mask\",\"value\":{\"stringValue\":\"FAN_OPEN_PERM\"}},\
+             {\"key\":\"process.pid\",\"value\":{\"intValue\":\"42\"}},\
+             {\"key\":\"file.path\",\"value\":{\"stringValue\":\"/etc/\\\"x\\\"\"}},\
+             {\"key\":\"process.command\",\"value\":{\"stringValue\":\"cat\"}},\
+             {\"key\":\"fanotify.uid\",\"value\":{\"stringValue\":\"0\"}}]}]}]}]}"
+        );
+
+        let d = Decision {
+            name: "FAN_OPEN_PERM".into(),
+            start: t,
+            attrs: Vec::new(),
+        };
+        let span = String::from_utf8(d.span(t + Duration::from_micros(250))).unwrap();
+        assert!(span.contains(
+            "\"name\":\"FAN_OPEN_PERM\",\"kind\":1,\"startTimeUnixNano\":\"1000000000\",\
+             \"endTimeUnixNano\":\"1000250000\""
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use wasmi::{Caller, Config, Engine, Extern, Linker, Memory, Module, Store, TypedFunc};
+
+use crate::json::Json;
+use crate::policy::Action;
+use crate::{EventEntry, EventFormat};
+
+// instructions or so each call may run before it's stopped
+const FUEL: u64 = 10_000_000;
+// distinct field names scripts and plugins may add, past which the
+// fields with new ones are dropped
+const FIELD_NAMES: usize = 256;
+
+/// What the module's calls back into us leave behind.
+#[derive(Default)]
+struct Host {
+    fields: Vec<(String, String)>,
+}
+
+fn read_str(caller: &Caller<'_, Host>, ptr: i32, len: i32) -> Result<String, wasmi::Error> {
+    let memory = match caller.get_export("memory") {
+        Some(Extern::Memory(memory)) => memory,
+        _ => return Err(wasmi::Error::new("no memory")),
+    };
+    let mut buf = vec![0; len as u32 as usize];
+    memory
+        .read(caller, ptr as u32 as usize, &mut buf)
+        .map_err(|e| wasmi::Error::new(e.to_string()))?;
+    String::from_utf8(buf).map_err(|e| wasmi::Error::new(e.to_string()))
+}
+
+/// The names of the fields scripts and plugins add, which have to live as
+/// long as we do, so only so many of them.
+#[derive(Default)]
+pub struct FieldNames {
+    names: HashSet<&'static str>,
+    /// whether it's said it's full
+    warned: bool,
+}
+
+impl FieldNames {
+    /// `name` to add a field with, None past the first FIELD_NAMES.
+    pub fn get(&mut self, name: String) -> Option<&'static str> {
+        if let Some(name) = self.names.get(name.as_str()) {
+            return Some(name);
+        }
+        if self.names.len() >= FIELD_NAMES {
+            if !self.warned {
+                warn!(
+                    "{} field names already, dropping {:?} and the other new ones",
+                    FIELD_NAMES, name
+                );
+                self.warned = true;
+            }
+            return None;
+        }
+        let name: &'static str = Box::leak(name.into_boxed_str());
+        self.names.insert(name);
+        Some(name)
+    }
+}
+
+fn invalid(path: &Path, e: impl ToString) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), e.to_string()),
+    )
+}
+
+/// A WebAssembly module that sees each event and can drop it, add fields
+/// to it, or answer it if it's a permission event. It exports
+///
+/// - `memory`, and `alloc(len) -> ptr` for us to put the event in, as JSON
+/// - `on_event(ptr, len) -> i32`, 0 to drop the event, 1 to keep it, and
+///   a permission event it drops is still answered by the rest
+/// - `decide(ptr, len) -> i32` for permission events, 0 to leave it to
+///   the rest, 1 to allow it and 2 to deny it
+///
+/// both of the last ones optional, and can import
+/// `env.add_field(key_ptr, key_len, value_ptr, value_len)` and
+/// `env.log(ptr, len)`. It has no access to anything else.
+pub struct Plugin {
+    path: PathBuf,
+    store: Store<Host>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    on_event: Option<TypedFunc<(i32, i32), i32>>,
+    decide: Option<TypedFunc<(i32, i32), i32>>,
+    names: FieldNames,
+}
+
+impl Plugin {
+    pub fn load(path: &Path) -> io::Result<Plugin> {
+        let wasm = fs::read(path)?;
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, &wasm).map_err(|e| invalid(path, e))?;
+
+        let mut store = Store::new(&engine, Host::default());
+        let mut linker = <Linker<Host>>::new(&engine);
+        let name = path.display().to_string();
+        linker
+            .func_wrap(
+                "env",
+                "add_field",
+                |mut caller: Caller<'_, Host>, k: i32, k_len: i32, v: i32, v_len: i32| {
+                    let field = (read_str(&caller, k, k_len)?, read_str(&caller, v, v_len)?);
+                    caller.data_mut().fields.push(field);
+                    Ok(())
+                },
+            )
+            .and_then(|l| {
+                l.func_wrap(
+                    "env",
+                    "log",
+                    move |caller: Caller<'_, Host>, ptr: i32, len: i32| {
+                        info!("{}: {}", name, read_str(&caller, ptr, len)?);
+                        Ok(())
+                    },
+                )
+            })
+            .map_err(|e| invalid(path, e))?;
+        store.set_fuel(FUEL).map_err(|e| invalid(path, e))?;
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .and_then(|i| i.start(&mut store))
+            .map_err(|e| invalid(path, e))?;
+
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| invalid(path, "no memory export"))?;
+        let alloc = instance
+            .get_typed_func(&store, "alloc")
+            .map_err(|e| invalid(path, format!("alloc: {}", e)))?;
+        let func = |name| match instance.get_export(&store, name) {
+            Some(_) => instance
+                .get_typed_func(&store, name)
+                .map(Some)
+                .map_err(|e| invalid(path, format!("{}: {}", name, e))),
+            None => Ok(None),
+        };
+        let on_event = func("on_event")?;
+        let decide = func("decide")?;
+        if on_event.is_none() && decide.is_none() {
+            return Err(invalid(path, "exports neither on_event nor decide"));
+        }
+
+        Ok(Plugin {
+            path: path.into(),
+            store,
+            memory,
+            alloc,
+            on_event,
+            decide,
+            names: FieldNames::default(),
+        })
+    }
+
+    /// Call `func` with `entry`, adding the fields it adds.
+    fn call(
+        &mut self,
+        func: TypedFunc<(i32, i32), i32>,
+        entry: &mut EventEntry,
+    ) -> Result<i32, wasmi::Error> {
+        let mut event = Vec::new();
+        Json.write_event(entry, &mut event)
+            .map_err(|e| wasmi::Error::new(e.to_string()))?;
+        self.store.set_fuel(FUEL)?;
+        let len = event.len() as i32;
+        let ptr = self.alloc.call(&mut self.store, len)?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, &event)
+            .map_err(|e| wasmi::Error::new(e.to_string()))?;
+        let ret = func.call(&mut self.store, (ptr, len));
+
+        for (k, v) in self.store.data_mut().fields.drain(..) {
+            if let Some(k) = self.names.get(k) {
+                entry.fields.push((k, v));
+            }
+        }
+        ret
+    }
+
+    /// Whether to keep `entry`, after adding any fields to it. Events the
+    /// module fails on are kept.
+    pub fn on_event(&mut self, entry: &mut EventEntry) -> bool {
+        let func = match self.on_event {
+            Some(func) => func,
+            None => return true,
+        };
+        match self.call(func, entry) {
+            Ok(keep) => keep != 0,
+            Err(e) => {
+                error!("{} on_event: {}", self.path.display(), e);
+                true
+            }
+        }
+    }
+
+    /// How to answer the permission event `entry`, if the module knows.
+    pub fn decide(&mut self, entry: &mut EventEntry) -> Option<Action> {
+        let func = self.decide?;
+        match self.call(func, entry) {
+            Ok(0) => None,
+            Ok(1) => Some(Action::Allow),
+            Ok(2) => Some(Action::Deny),
+            Ok(n) => {
+                warn!("{} decide: unknown answer {}", self.path.display(), n);
+                None
+            }
+            Err(e) => {
+                error!("{} decide: {}", self.path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+
+    // denies what has "shadow" in it, drops what has "tmp", and tags
+    // the rest
+    const WAT: &str = r#"
+        (module
+          (import "env" "add_field" (func $add_field (param i32 i32 i32 i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "tagyes")
+          (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+          (func $has (param $ptr i32) (param $len i32) (param $word i32) (param $n i32)
+                (result i32)
+            (local $i i32) (local $j i32)
+            (block $done
+              (loop $next
+                (br_if $done (i32.gt_s (local.get $i) (i32.sub (local.get $len) (local.get $n))))
+                (local.set $j (i32.const 0))
+                (block $differ
+                  (loop $cmp
+                    (br_if $differ
+                      (i32.ne
+                        (i32.load8_u (i32.add (i32.add (local.get $ptr) (local.get $i))
+                                              (local.get $j)))
+                        (i32.load8_u (i32.add (local.get $word) (local.get $j)))))
+                    (local.set $j (i32.add (local.get $j) (i32.const 1)))
+                    (if (i32.eq (local.get $j) (local.get $n)) (then (return (i32.const 1))))
+                    (br $cmp)))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br $next)))
+            (i32.const 0))
+          (data (i32.const 16) "shadowtmp")
+          (func (export "on_event") (param i32 i32) (result i32)
+            (if (call $has (local.get 0) (local.get 1) (i32.const 22) (i32.const 3))
+              (then (return (i32.const 0))))
+            (call $add_field (i32.const 0) (i32.const 3) (i32.const 3) (i32.const 3))
+            (i32.const 1))
+          (func (export "decide") (param i32 i32) (result i32)
+            (if (result i32) (call $has (local.get 0) (local.get 1) (i32.const 16) (i32.const 6))
+              (then (i32.const 2)) (else (i32.const 0))))
+        )
+    "#;
+
+    #[test]
+    fn field_names_cap() {
+        let mut names = FieldNames::default();
+        for i in 0..FIELD_NAMES {
+            assert_eq!(names.get(format!("f{}", i)), Some(&*format!("f{}", i)));
+        }
+        assert_eq!(names.get("new".into()), None);
+        // the ones it has still are
+        assert_eq!(names.get("f0".into()), Some("f0"));
+    }
+
+    #[test]
+    fn plugin_calls() -> io::Result<()> {
+        let dir = TempDir::new("plugin")?;
+        let path = dir.join("plugin.wasm");
+        fs::write(&path, wat::parse_str(WAT).unwrap())?;
+        let mut plugin = Plugin::load(&path)?;
+
+        let entry = |path: &str| EventEntry {
+            fd: Some(3),
+            ..EventEntry::test(libc::FAN_OPEN_PERM, path)
+        };
+        let mut e = entry("/etc/shadow");
+        assert!(plugin.on_event(&mut e));
+        assert_eq!(e.fields, vec![("tag", "yes".to_string())]);
+        assert_eq!(plugin.decide(&mut e), Some(Action::Deny));
+        let mut e = entry("/etc/passwd");
+        assert_eq!(plugin.decide(&mut e), None);
+        assert!(!plugin.on_event(&mut entry("/tmp/x")));
+        assert_eq!(plugin.names.0.len(), 1);
+
+        let bad = dir.join("plugin.bad");
+        fs::write(&bad, "not wasm")?;
+        assert!(Plugin::load(&bad).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,648 @@
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use libc::c_int;
+use serde::Deserialize;
+
+use crate::{freeze, parse_mask, EventEntry};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+impl Action {
+    pub fn response(self) -> u32 {
+        match self {
+            Action::Allow => libc::FAN_ALLOW,
+            Action::Deny => libc::FAN_DENY,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Allow => "allow",
+            Action::Deny => "deny",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Action> {
+        match s {
+            "allow" => Some(Action::Allow),
+            "deny" => Some(Action::Deny),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Action::parse(s).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid value: {}, options: allow, deny", s),
+            )
+        })
+    }
+}
+
+/// What a kill, stop, freeze or quarantine rule does, besides deny the
+/// event.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Contain {
+    Kill,
+    Stop,
+    /// its whole cgroup, like the service or container it's in
+    Freeze,
+    /// the file, copied into --quarantine
+    Quarantine,
+}
+
+impl Contain {
+    pub fn name(self) -> &'static str {
+        match self {
+            Contain::Kill => "kill",
+            Contain::Stop => "stop",
+            Contain::Freeze => "freeze",
+            Contain::Quarantine => "quarantine",
+        }
+    }
+}
+
+/// A line of the --policy file: `allow|deny|kill|stop|freeze|quarantine
+/// [path=GLOB] [pid=PID] [uid=UID] [comm=COMM] [exe=GLOB] [event=MASK]
+/// [log]`, matching events that match all of what it has.
+#[derive(Debug)]
+struct Rule {
+    /// where it is in the file, for the logs
+    at: String,
+    action: Action,
+    /// for kill, stop, freeze and quarantine, which deny
+    contain: Option<Contain>,
+    glob: Option<CString>,
+    pid: Option<u32>,
+    uid: Option<u32>,
+    comm: Option<String>,
+    exe: Option<CString>,
+    /// 0 for any event
+    mask: u64,
+    /// log the events it answers
+    log: bool,
+}
+
+fn invalid(at: &str, msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, format!("{}: {}", at, msg))
+}
+
+fn glob(at: &str, s: &str) -> io::Result<CString> {
+    CString::new(s).map_err(|e| invalid(at, e.to_string()))
+}
+
+fn mask(s: &str) -> io::Result<u64> {
+    parse_mask(&s.replace('|', ","))
+}
+
+/// What a rule does: allow, deny, or deny and contain the process.
+fn rule_action(s: &str) -> Option<(Action, Option<Contain>)> {
+    match s {
+        "kill" => Some((Action::Deny, Some(Contain::Kill))),
+        "stop" => Some((Action::Deny, Some(Contain::Stop))),
+        "freeze" => Some((Action::Deny, Some(Contain::Freeze))),
+        "quarantine" => Some((Action::Deny, Some(Contain::Quarantine))),
+        _ => Action::parse(s).map(|action| (action, None)),
+    }
+}
+
+/// Send `signal` to the process behind `entry`, through `pidfd` if there
+/// is one so it can't be another that got its pid.
+fn signal(entry: &EventEntry, pidfd: Option<RawFd>, signal: c_int) -> io::Result<()> {
+    let ret = match (pidfd, entry.pid) {
+        (Some(pidfd), _) => unsafe {
+            let null = std::ptr::null::<libc::siginfo_t>();
+            libc::syscall(libc::SYS_pidfd_send_signal, pidfd, signal, null, 0) as c_int
+        },
+        (None, Some(pid)) => unsafe { libc::kill(pid as libc::pid_t, signal) },
+        (None, None) => {
+            return Err(io::Error::new(ErrorKind::NotFound, "no pid"));
+        }
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Do what `how` says to the process behind `entry`.
+pub fn contain(entry: &EventEntry, pidfd: Option<RawFd>, how: Contain) -> io::Result<()> {
+    match how {
+        Contain::Kill => signal(entry, pidfd, libc::SIGKILL),
+        Contain::Stop => signal(entry, pidfd, libc::SIGSTOP),
+        Contain::Freeze => {
+            let pid = entry
+                .pid
+                .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "no pid"))?;
+            let cgroup = freeze::freeze_pid(pid)?;
+            warn!(
+                "froze {} for pid {}, fanotify-cli unfreeze {} to thaw it",
+                cgroup.display(),
+                pid,
+                cgroup.display()
+            );
+            Ok(())
+        }
+        // by the caller, which has the event's fd
+        Contain::Quarantine => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "no --quarantine directory",
+        )),
+    }
+}
+
+impl Rule {
+    fn new(at: String, (action, contain): (Action, Option<Contain>)) -> Rule {
+        Rule {
+            at,
+            action,
+            contain,
+            glob: None,
+            pid: None,
+            uid: None,
+            comm: None,
+            exe: None,
+            mask: 0,
+            log: false,
+        }
+    }
+
+    fn parse(
+        at: String,
+        action: (Action, Option<Contain>),
+        words: std::str::SplitWhitespace,
+    ) -> io::Result<Rule> {
+        let mut rule = Rule::new(at, action);
+        for w in words {
+            let at = &rule.at;
+            let number = |v: &str| v.parse().map_err(|e| invalid(at, format!("{}: {}", w, e)));
+            match w.split_once('=') {
+                Some(("path", v)) => rule.glob = Some(glob(at, v)?),
+                Some(("pid", v)) => rule.pid = Some(number(v)?),
+                Some(("uid", v)) => rule.uid = Some(number(v)?),
+                Some(("comm", v)) => rule.comm = Some(v.into()),
+                Some(("exe", v)) => rule.exe = Some(glob(at, v)?),
+                Some(("event", v)) => rule.mask = mask(v)?,
+                None if w == "log" => rule.log = true,
+                _ => {
+                    return Err(invalid(
+                        at,
+                        format!("{}: expected path, pid, uid, comm, exe, event= or log", w),
+                    ))
+                }
+            }
+        }
+        Ok(rule)
+    }
+
+    fn name(&self) -> &'static str {
+        match self.contain {
+            Some(contain) => contain.name(),
+            None => self.action.name(),
+        }
+    }
+
+    fn matches(&self, entry: &EventEntry, process: &mut Process) -> bool {
+        if self.mask != 0 && entry.mask & self.mask == 0 {
+            return false;
+        }
+        if self.pid.is_some() && self.pid != entry.pid {
+            return false;
+        }
+        if let Some(glob) = &self.glob {
+            if !fnmatch(glob, entry.path.as_deref()) {
+                return false;
+            }
+        }
+        if self.comm.is_some() && self.comm.as_deref() != process.comm(entry) {
+            return false;
+        }
+        if let Some(glob) = &self.exe {
+            if !fnmatch(glob, process.exe(entry)) {
+                return false;
+            }
+        }
+        if self.uid.is_some() && self.uid != process.uid(entry) {
+            return false;
+        }
+        true
+    }
+}
+
+fn fnmatch(glob: &CStr, path: Option<&Path>) -> bool {
+    match path.and_then(|p| CString::new(p.as_os_str().as_bytes()).ok()) {
+        Some(path) => unsafe { libc::fnmatch(glob.as_ptr(), path.as_ptr(), 0) == 0 },
+        None => false,
+    }
+}
+
+/// What the rules need to know about the process behind an event, read
+/// from /proc only if they do.
+#[derive(Default)]
+struct Process {
+    comm: Option<Option<String>>,
+    exe: Option<Option<PathBuf>>,
+    uid: Option<Option<u32>>,
+}
+
+/// The command name of the process behind `entry`, from --enrich proc if
+/// it's there.
+pub fn comm(entry: &EventEntry) -> Option<String> {
+    match entry.fields.iter().find(|(k, _)| *k == "comm") {
+        Some((_, comm)) => Some(comm.clone()),
+        None => entry.pid.and_then(|pid| {
+            fs::read_to_string(format!("/proc/{}/comm", pid))
+                .ok()
+                .map(|c| c.trim_end().into())
+        }),
+    }
+}
+
+/// The executable of the process behind `entry`, from --enrich proc if
+/// it's there.
+pub fn exe(entry: &EventEntry) -> Option<PathBuf> {
+    match entry.fields.iter().find(|(k, _)| *k == "exe") {
+        Some((_, exe)) => Some(exe.into()),
+        None => fs::read_link(format!("/proc/{}/exe", entry.pid?)).ok(),
+    }
+}
+
+impl Process {
+    fn comm(&mut self, entry: &EventEntry) -> Option<&str> {
+        self.comm.get_or_insert_with(|| comm(entry)).as_deref()
+    }
+
+    fn exe(&mut self, entry: &EventEntry) -> Option<&Path> {
+        self.exe.get_or_insert_with(|| exe(entry)).as_deref()
+    }
+
+    /// The effective uid.
+    fn uid(&mut self, entry: &EventEntry) -> Option<u32> {
+        *self.uid.get_or_insert_with(|| {
+            let status = fs::read_to_string(format!("/proc/{}/status", entry.pid?)).ok()?;
+            status
+                .lines()
+                .find_map(|l| l.strip_prefix("Uid:"))?
+                .split_whitespace()
+                .nth(1)?
+                .parse()
+                .ok()
+        })
+    }
+}
+
+/// A --policy file in TOML, like
+///
+/// ```toml
+/// default = "allow"
+///
+/// [[rule]]
+/// action = "deny"
+/// path = "/etc/shadow"
+/// comm = "cat"
+/// log = true
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlPolicy {
+    default: Option<Action>,
+    #[serde(default)]
+    rule: Vec<TomlRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlRule {
+    action: String,
+    path: Option<String>,
+    pid: Option<u32>,
+    uid: Option<u32>,
+    comm: Option<String>,
+    exe: Option<String>,
+    event: Option<String>,
+    #[serde(default)]
+    log: bool,
+}
+
+/// Answers permission events by the first rule they match, or the default
+/// action if there is one.
+pub struct Policy {
+    rules: Vec<Rule>,
+    default: Option<Action>,
+}
+
+impl Policy {
+    /// TOML if the file ends in .toml, lines of rules otherwise.
+    pub fn load(path: &Path) -> io::Result<Policy> {
+        let s = fs::read_to_string(path)?;
+        if path.extension().is_some_and(|e| e == "toml") {
+            Policy::parse_toml(&s)
+        } else {
+            Policy::parse(&s)
+        }
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    /// A rule per line, and `default allow|deny` for the rest.
+    pub fn parse(s: &str) -> io::Result<Policy> {
+        let mut policy = Policy {
+            rules: Vec::new(),
+            default: None,
+        };
+        for (i, line) in s.lines().enumerate() {
+            let at = format!("line {}", i + 1);
+            let mut words = line.split_whitespace();
+            let action = match words.next() {
+                None => continue,
+                Some(w) if w.starts_with('#') => continue,
+                Some("default") => {
+                    policy.default = match (words.next().and_then(Action::parse), words.next()) {
+                        (Some(action), None) => Some(action),
+                        _ => return Err(invalid(&at, "expected default allow|deny".into())),
+                    };
+                    continue;
+                }
+                Some(w) => rule_action(w).ok_or_else(|| {
+                    invalid(
+                        &at,
+                        format!(
+                            "expected allow, deny, kill, stop, freeze, quarantine or default, not {}",
+                            w
+                        ),
+                    )
+                })?,
+            };
+            policy.rules.push(Rule::parse(at, action, words)?);
+        }
+        Ok(policy)
+    }
+
+    fn parse_toml(s: &str) -> io::Result<Policy> {
+        let toml: TomlPolicy =
+            toml::from_str(s).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        let mut rules = Vec::new();
+        for (i, r) in toml.rule.into_iter().enumerate() {
+            let at = format!("rule {}", i + 1);
+            let action = rule_action(&r.action).ok_or_else(|| {
+                invalid(
+                    &at,
+                    format!(
+                        "expected action allow, deny, kill, stop, freeze or quarantine, not {}",
+                        r.action
+                    ),
+                )
+            })?;
+            let mut rule = Rule::new(at, action);
+            if let Some(path) = &r.path {
+                rule.glob = Some(glob(&rule.at, path)?);
+            }
+            if let Some(exe) = &r.exe {
+                rule.exe = Some(glob(&rule.at, exe)?);
+            }
+            if let Some(event) = &r.event {
+                rule.mask = mask(event)?;
+            }
+            rule.pid = r.pid;
+            rule.uid = r.uid;
+            rule.comm = r.comm;
+            rule.log = r.log;
+            rules.push(rule);
+        }
+        Ok(Policy {
+            rules,
+            default: toml.default,
+        })
+    }
+
+    /// Whether any rule is a quarantine one.
+    pub fn quarantines(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|r| r.contain == Some(Contain::Quarantine))
+    }
+
+    /// How to answer `entry`, and what else to do for a kill, stop, freeze
+    /// or quarantine rule.
+    pub fn decide(&self, entry: &EventEntry) -> Option<(Action, Option<Contain>)> {
+        let mut process = Process::default();
+        match self.rules.iter().find(|r| r.matches(entry, &mut process)) {
+            Some(rule) => {
+                if rule.log || rule.contain.is_some() {
+                    info!(
+                        "{} {:?} for pid {} by {}",
+                        rule.name(),
+                        entry.path,
+                        EventEntry::display_field(&entry.pid),
+                        rule.at
+                    );
+                } else {
+                    debug!("{} {:?} by {}", rule.name(), entry.path, rule.at);
+                }
+                Some((rule.action, rule.contain))
+            }
+            None => self.default.map(|action| (action, None)),
+        }
+    }
+
+    /// Which rule `entry` matches, as if by a process of `uid` with the
+    /// comm and exe in its fields, without looking for it in /proc, and
+    /// the verdict, if any.
+    pub fn explain(&self, entry: &EventEntry, uid: Option<u32>) -> (Option<&str>, Option<&str>) {
+        let field = |k| {
+            entry
+                .fields
+                .iter()
+                .find(|(f, _)| *f == k)
+                .map(|(_, v)| v.clone())
+        };
+        let mut process = Process {
+            comm: Some(field("comm")),
+            exe: Some(field("exe").map(PathBuf::from)),
+            uid: Some(uid),
+        };
+        match self.rules.iter().find(|r| r.matches(entry, &mut process)) {
+            Some(rule) => (Some(&rule.at), Some(rule.name())),
+            None => (None, self.default.map(Action::name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn policy_rules() -> io::Result<()> {
+        let policy = Policy::parse(&format!(
+            "# comment\n\
+             deny path=/etc/shadow event=FAN_OPEN_PERM\n\
+             allow comm=sshd\n\
+             allow exe=/usr/sbin/* path=/var/*\n\
+             deny uid={} path=/home/*\n\
+             allow pid=1\n\
+             kill path=/srv/decoy/*\n",
+            unsafe { libc::geteuid() }
+        ))?;
+        let entry = |path: &str, pid, comm: Option<&str>| EventEntry {
+            fd: Some(3),
+            pid: Some(pid),
+            fields: comm
+                .map(|c| vec![("comm", c.into()), ("exe", format!("/usr/sbin/{}", c))])
+                .unwrap_or_default(),
+            ..EventEntry::test(libc::FAN_OPEN_PERM, path)
+        };
+        let me = process::id();
+
+        assert_eq!(
+            policy.decide(&entry("/etc/shadow", 1, Some("sshd"))),
+            Some((Action::Deny, None))
+        );
+        assert_eq!(
+            policy.decide(&entry("/etc/passwd", me, Some("sshd"))),
+            Some((Action::Allow, None))
+        );
+        assert_eq!(
+            policy.decide(&entry("/home/a/b", me, None)),
+            Some((Action::Deny, None))
+        );
+        assert_eq!(
+            policy.decide(&entry("/tmp/x", 1, None)),
+            Some((Action::Allow, None))
+        );
+        assert_eq!(policy.decide(&entry("/tmp/x", me, None)), None);
+        assert_eq!(
+            policy.decide(&entry("/var/log/x", me, Some("cron"))),
+            Some((Action::Allow, None))
+        );
+        // what we run isn't in /usr/sbin
+        assert_eq!(policy.decide(&entry("/var/log/x", me, None)), None);
+        assert_eq!(
+            policy.decide(&entry("/srv/decoy/x", me, None)),
+            Some((Action::Deny, Some(Contain::Kill)))
+        );
+
+        let e = Policy::parse("allow\nblock path=/x\n").err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            "line 2: expected allow, deny, kill, stop, freeze, quarantine or default, not block"
+        );
+        assert!(Policy::parse("allow pid=x").is_err());
+        assert!(Policy::parse("deny glob=/x").is_err());
+        assert!(Policy::parse("default maybe").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn policy_explain() -> io::Result<()> {
+        let policy = Policy::parse(
+            "allow comm=sshd path=/etc/shadow\n\
+             quarantine uid=1000 exe=/tmp/*\n\
+             deny path=/etc/shadow event=FAN_OPEN_PERM\n",
+        )?;
+        let entry = |mask, fields: Vec<(&'static str, String)>| EventEntry {
+            pid: None,
+            fields,
+            ..EventEntry::test(mask, "/etc/shadow")
+        };
+        let vim = vec![("comm", "vim".into())];
+        assert_eq!(
+            policy.explain(&entry(libc::FAN_OPEN_PERM, vim.clone()), Some(1000)),
+            (Some("line 3"), Some("deny"))
+        );
+        assert_eq!(
+            policy.explain(&entry(libc::FAN_ACCESS_PERM, vim), Some(1000)),
+            (None, None)
+        );
+        let sshd = vec![("comm", "sshd".into())];
+        assert_eq!(
+            policy.explain(&entry(libc::FAN_OPEN_PERM, sshd), None),
+            (Some("line 1"), Some("allow"))
+        );
+        let tmp = vec![("exe", "/tmp/x".into())];
+        assert_eq!(
+            policy.explain(&entry(libc::FAN_OPEN_PERM, tmp.clone()), Some(1000)),
+            (Some("line 2"), Some("quarantine"))
+        );
+        let policy = Policy::parse("default allow\n")?;
+        assert_eq!(
+            policy.explain(&entry(libc::FAN_OPEN_PERM, tmp), None),
+            (None, Some("allow"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn policy_toml() -> io::Result<()> {
+        let toml = Policy::parse_toml(
+            r#"
+            default = "deny"
+
+            [[rule]]
+            action = "allow"
+            path = "/usr/*"
+            event = "FAN_OPEN_PERM|FAN_ACCESS_PERM"
+            log = true
+
+            [[rule]]
+            action = "allow"
+            pid = 1
+
+            [[rule]]
+            action = "stop"
+            pid = 3
+            "#,
+        )?;
+        let lines = Policy::parse(
+            "default deny\n\
+             allow path=/usr/* event=FAN_OPEN_PERM|FAN_ACCESS_PERM log\n\
+             allow pid=1\n\
+             stop pid=3\n",
+        )?;
+        for policy in &[toml, lines] {
+            let entry = |mask, pid| EventEntry {
+                fd: Some(3),
+                pid: Some(pid),
+                ..EventEntry::test(mask, "/usr/bin/ls")
+            };
+            assert_eq!(
+                policy.decide(&entry(libc::FAN_ACCESS_PERM, 2)),
+                Some((Action::Allow, None))
+            );
+            assert_eq!(
+                policy.decide(&entry(libc::FAN_OPEN_EXEC_PERM, 1)),
+                Some((Action::Allow, None))
+            );
+            // the default
+            assert_eq!(
+                policy.decide(&entry(libc::FAN_OPEN_EXEC_PERM, 2)),
+                Some((Action::Deny, None))
+            );
+            assert_eq!(
+                policy.decide(&entry(libc::FAN_OPEN_EXEC_PERM, 3)),
+                Some((Action::Deny, Some(Contain::Stop)))
+            );
+            assert!(policy.rules[0].log && !policy.rules[1].log);
+        }
+
+        assert!(Policy::parse_toml("[[rule]]\naction = \"maybe\"").is_err());
+        assert!(Policy::parse_toml("[[rule]]\naction = \"deny\"\nglob = \"/x\"").is_err());
+        Ok(())
+    }
+}
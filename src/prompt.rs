@@ -0,0 +1,269 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+use crate::learn;
+use crate::policy::{self, Action};
+use crate::{write_escaped, EventEntry, PermId};
+
+/// A permission event waiting for the user.
+struct Question {
+    perm: PermId,
+    pid: Option<u32>,
+    path: Option<PathBuf>,
+    text: Vec<u8>,
+}
+
+/// Asks about each permission event on the terminal, one at a time, and
+/// remembers the paths answered always for the rest of the session.
+pub struct Prompt {
+    out: Box<dyn Write>,
+    queue: VecDeque<Question>,
+    always: HashMap<PathBuf, Action>,
+    /// the paths in `always` in the order they were answered
+    rules: Vec<PathBuf>,
+    /// the always answers not yet taken by `learned`, and the pid that
+    /// asked
+    learned: Vec<(PathBuf, Option<u32>, Action)>,
+}
+
+fn verb(mask: u64) -> &'static str {
+    if mask & libc::FAN_OPEN_EXEC_PERM != 0 {
+        "execute"
+    } else if mask & libc::FAN_ACCESS_PERM != 0 {
+        "read"
+    } else {
+        "open"
+    }
+}
+
+/// What the permission event `entry` asks for, like "cat (pid 42) wants
+/// to open /etc/shadow".
+pub fn describe(entry: &EventEntry) -> io::Result<Vec<u8>> {
+    let mut text = Vec::new();
+    text.extend_from_slice(
+        format!(
+            "{} (pid {}) wants to {} ",
+            policy::comm(entry).as_deref().unwrap_or("?"),
+            EventEntry::display_field(&entry.pid),
+            verb(entry.mask)
+        )
+        .as_bytes(),
+    );
+    match &entry.path {
+        Some(path) => write_escaped(&mut text, path.as_os_str().as_bytes())?,
+        None => text.extend_from_slice(b"a file"),
+    }
+    Ok(text)
+}
+
+impl Prompt {
+    pub fn new(out: Box<dyn Write>) -> Prompt {
+        Prompt {
+            out,
+            queue: VecDeque::new(),
+            always: HashMap::new(),
+            rules: Vec::new(),
+            learned: Vec::new(),
+        }
+    }
+
+    fn show(&mut self) -> io::Result<()> {
+        if let Some(q) = self.queue.front() {
+            self.out.write_all(&q.text)?;
+            self.out
+                .write_all(b"? [y]es/[n]o/[a]lways/[d]eny always: ")?;
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Ask about the permission event `perm`, unless an always answer
+    /// says what to do with it.
+    pub fn ask(&mut self, perm: PermId, entry: &EventEntry) -> io::Result<Option<Action>> {
+        if let Some(action) = entry.path.as_ref().and_then(|p| self.always.get(p)) {
+            return Ok(Some(*action));
+        }
+
+        self.queue.push_back(Question {
+            perm,
+            pid: entry.pid,
+            path: entry.path.clone(),
+            text: describe(entry)?,
+        });
+        if self.queue.len() == 1 {
+            self.show()?;
+        }
+        Ok(None)
+    }
+
+    /// Take a line of input as the answer to the event being asked about,
+    /// and ask about the next, returning the events it answered: that one,
+    /// and for always, the others for the same path.
+    pub fn answer(&mut self, line: &str) -> io::Result<Vec<(PermId, Action)>> {
+        let (action, always) = match line.trim() {
+            "y" => (Action::Allow, false),
+            "n" => (Action::Deny, false),
+            "a" => (Action::Allow, true),
+            "d" => (Action::Deny, true),
+            _ => {
+                // ask again
+                self.show()?;
+                return Ok(Vec::new());
+            }
+        };
+        let q = match self.queue.pop_front() {
+            Some(q) => q,
+            None => return Ok(Vec::new()),
+        };
+        let mut answered = vec![(q.perm, action)];
+        if let (true, Some(path)) = (always, q.path) {
+            self.queue.retain(|o| {
+                if o.path.as_ref() == Some(&path) {
+                    answered.push((o.perm, action));
+                    false
+                } else {
+                    true
+                }
+            });
+            if self.always.insert(path.clone(), action).is_none() {
+                self.rules.push(path.clone());
+            }
+            self.learned.push((path, q.pid, action));
+        }
+        self.show()?;
+        Ok(answered)
+    }
+
+    /// Stop asking about the events that are no longer `pending`.
+    pub fn answered(&mut self, pending: &dyn Fn(PermId) -> bool) -> io::Result<()> {
+        let head = self.queue.front().map(|q| q.perm);
+        self.queue.retain(|q| pending(q.perm));
+        if self.queue.front().map(|q| q.perm) != head {
+            self.out.write_all(b"answered\n")?;
+            self.show()?;
+        }
+        Ok(())
+    }
+
+    /// The always answers since the last time, for --decisions.
+    pub fn learned(&mut self) -> Vec<(PathBuf, Option<u32>, Action)> {
+        self.learned.split_off(0)
+    }
+
+    /// The always answers, as --policy lines matching only their paths.
+    pub fn write_rules(&self, w: &mut dyn Write) -> io::Result<()> {
+        if !self.rules.is_empty() {
+            w.write_all(b"# always answers from --interactive\n")?;
+        }
+        for path in &self.rules {
+            w.write_all(self.always[path].name().as_bytes())?;
+            w.write_all(b" path=")?;
+            w.write_all(learn::glob(path).as_bytes())?;
+            w.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn prompt_answers() -> io::Result<()> {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut prompt = Prompt::new(Box::new(Shared(out.clone())));
+        let entry = |mask, path: &str| EventEntry {
+            fd: Some(3),
+            pid: Some(42),
+            fields: vec![("comm", "cat".into())],
+            ..EventEntry::test(mask, path)
+        };
+
+        assert_eq!(
+            prompt.ask((3, 1), &entry(libc::FAN_OPEN_PERM, "/etc/shadow"))?,
+            None
+        );
+        assert_eq!(
+            prompt.ask((4, 1), &entry(libc::FAN_OPEN_PERM, "/etc/passwd"))?,
+            None
+        );
+        assert_eq!(
+            prompt.ask((5, 1), &entry(libc::FAN_ACCESS_PERM, "/etc/shadow"))?,
+            None
+        );
+        // only the first is asked about until it's answered
+        assert_eq!(
+            String::from_utf8(out.borrow_mut().split_off(0)).unwrap(),
+            "cat (pid 42) wants to open /etc/shadow? [y]es/[n]o/[a]lways/[d]eny always: "
+        );
+
+        assert!(prompt.answer("maybe\n")?.is_empty());
+        assert_eq!(
+            prompt.answer("d\n")?,
+            vec![((3, 1), Action::Deny), ((5, 1), Action::Deny)]
+        );
+        assert_eq!(
+            prompt.learned(),
+            vec![("/etc/shadow".into(), Some(42), Action::Deny)]
+        );
+        assert!(prompt.learned().is_empty());
+        assert!(String::from_utf8(out.borrow_mut().split_off(0))
+            .unwrap()
+            .ends_with(
+                "cat (pid 42) wants to open /etc/passwd? [y]es/[n]o/[a]lways/[d]eny always: "
+            ));
+        assert_eq!(
+            prompt.ask((6, 1), &entry(libc::FAN_OPEN_PERM, "/etc/shadow"))?,
+            Some(Action::Deny)
+        );
+
+        // answered some other way, and its fd reused by another
+        assert_eq!(
+            prompt.ask((4, 2), &entry(libc::FAN_OPEN_PERM, "/a b*"))?,
+            None
+        );
+        prompt.answered(&|perm| perm == (4, 2))?;
+        assert_eq!(
+            String::from_utf8(out.borrow_mut().split_off(0)).unwrap(),
+            "answered\ncat (pid 42) wants to open /a b*? [y]es/[n]o/[a]lways/[d]eny always: "
+        );
+        assert_eq!(prompt.answer("a\n")?, vec![((4, 2), Action::Allow)]);
+        assert!(prompt.answer("y\n")?.is_empty());
+
+        let mut rules = Vec::new();
+        prompt.write_rules(&mut rules)?;
+        let rules = String::from_utf8(rules).unwrap();
+        assert_eq!(
+            rules,
+            "# always answers from --interactive\n\
+             deny path=/etc/shadow\n\
+             allow path=/a?b\\*\n"
+        );
+        // which match only what was answered
+        let policy = policy::Policy::parse(&rules)?;
+        let open = |path| entry(libc::FAN_OPEN_PERM, path);
+        assert_eq!(
+            policy.decide(&open("/a b*")).map(|d| d.0),
+            Some(Action::Allow)
+        );
+        assert_eq!(policy.decide(&open("/a bc")), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,259 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::mem::ManuallyDrop;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use crate::chain::to_hex;
+use crate::clock::{self, Timestamp};
+use crate::json::Json;
+use crate::{policy, EventEntry, EventFormat};
+
+// files waiting to be copied, past which the next aren't rather than have
+// their fds held open
+const QUEUED: usize = 64;
+
+/// A file to copy aside, with the event that tripped it at `at`.
+struct Job {
+    file: File,
+    event: EventEntry,
+    at: SystemTime,
+}
+
+/// Where a quarantine rule of --policy copies the files it denies, each
+/// by its sha256, with the event that tripped it next to it as JSON. The
+/// copying is done by a worker, so a big file doesn't hold up the rest.
+pub struct Quarantine {
+    tx: SyncSender<Job>,
+}
+
+/// `name(0)` in `dir`, or `name(1)` and so on if that's taken, created
+/// with `mode` for writing.
+fn create_new(dir: &Path, mode: u32, name: impl Fn(u32) -> String) -> io::Result<(File, PathBuf)> {
+    let mut n = 0;
+    loop {
+        let path = dir.join(name(n));
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&path)
+        {
+            Ok(f) => return Ok((f, path)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Copy `from` to `to`, returning the sha256 of what was copied.
+fn copy_hashed(from: &File, to: &mut File) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 65536];
+    let mut off = 0;
+    loop {
+        let n = from.read_at(&mut buf, off)?;
+        if n == 0 {
+            return Ok(to_hex(&hasher.finalize()));
+        }
+        hasher.update(&buf[..n]);
+        to.write_all(&buf[..n])?;
+        off += n as u64;
+    }
+}
+
+/// Copy `file` into `dir` as its sha256, returning where.
+fn copy(dir: &Path, file: &File) -> io::Result<PathBuf> {
+    // nothing to run, only to look at, and named once it's all there
+    let (mut tmp, tmp_path) = create_new(dir, 0o400, |n| format!(".{}.tmp", n))?;
+    let hash = match copy_hashed(file, &mut tmp) {
+        Ok(hash) => hash,
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    };
+    let target = dir.join(hash);
+    // the same content is only kept once
+    if target.exists() {
+        fs::remove_file(&tmp_path)?;
+    } else {
+        fs::rename(&tmp_path, &target)?;
+    }
+    Ok(target)
+}
+
+/// The sidecar for the file `hash` quarantined at `t`, like
+/// dir/HASH@YYYYmmddTHHMMSS.mmm.json, or with -1, -2 and so on after the
+/// millis if there's one already.
+fn sidecar(dir: &Path, hash: &str, t: SystemTime) -> io::Result<(File, PathBuf)> {
+    let millis = t
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_millis();
+    let stem = format!(
+        "{}@{}.{:03}",
+        hash,
+        clock::strftime(t, "%Y%m%dT%H%M%S"),
+        millis
+    );
+    create_new(dir, 0o644, |n| match n {
+        0 => format!("{}.json", stem),
+        n => format!("{}-{}.json", stem, n),
+    })
+}
+
+fn quarantine(dir: &Path, mut job: Job) -> io::Result<PathBuf> {
+    let target = copy(dir, &job.file)?;
+    let hash = target.file_name().unwrap().to_string_lossy().into_owned();
+    job.event.fields.push(("sha256", hash.clone()));
+    job.event
+        .fields
+        .push(("time", Timestamp::Iso8601.format(job.at, Duration::ZERO)));
+
+    let mut buf = Vec::new();
+    Json.write_event(&job.event, &mut buf)?;
+    buf.push(b'\n');
+    sidecar(dir, &hash, job.at)?.0.write_all(&buf)?;
+    Ok(target)
+}
+
+impl Quarantine {
+    pub fn open(dir: &Path) -> io::Result<Quarantine> {
+        fs::create_dir_all(dir)?;
+        let dir = dir.canonicalize()?;
+        let (tx, rx) = mpsc::sync_channel::<Job>(QUEUED);
+        thread::Builder::new()
+            .name("quarantine".into())
+            .spawn(move || {
+                for job in rx {
+                    let path = job.event.path.clone();
+                    match quarantine(&dir, job) {
+                        Ok(at) => warn!("quarantined {:?} as {}", path, at.display()),
+                        Err(e) => warn!("quarantine {:?}: {}", path, e),
+                    }
+                }
+            })?;
+        Ok(Quarantine { tx })
+    }
+
+    /// Have the file of the event for `fd` copied aside, with a file of
+    /// its own so the event can be answered meanwhile.
+    pub fn save(&self, fd: RawFd, entry: &EventEntry) -> io::Result<()> {
+        // borrowed, the caller closes it
+        let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+        let mut fields = entry.fields.clone();
+        if !fields.iter().any(|(k, _)| *k == "comm") {
+            if let Some(comm) = policy::comm(entry) {
+                fields.push(("comm", comm));
+            }
+        }
+        let job = Job {
+            file: file.try_clone()?,
+            event: EventEntry {
+                mask: entry.mask,
+                fd: None,
+                pid: entry.pid,
+                path: entry.path.clone(),
+                fields,
+            },
+            at: SystemTime::now(),
+        };
+        match self.tx.try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(io::Error::other(format!(
+                "{} files waiting to be copied",
+                QUEUED
+            ))),
+            Err(TrySendError::Disconnected(_)) => Err(io::Error::other("no worker")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::io::AsRawFd;
+    use std::time::Instant;
+
+    #[test]
+    fn quarantine_copy() -> io::Result<()> {
+        let dir = TempDir::new("quarantine")?;
+        fs::write(dir.join("bad"), "EICAR\n")?;
+        let bad = File::open(dir.join("bad"))?;
+        let quarantine = Quarantine::open(&dir.join("q"))?;
+        let entry = EventEntry {
+            fd: Some(bad.as_raw_fd()),
+            pid: Some(42),
+            path: Some(dir.join("bad")),
+            fields: vec![("comm", "cat".into())],
+            ..EventEntry::test(libc::FAN_OPEN_PERM, "")
+        };
+
+        quarantine.save(bad.as_raw_fd(), &entry)?;
+        // again, as the same copy
+        quarantine.save(bad.as_raw_fd(), &entry)?;
+        drop(bad);
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let (copies, sidecars) = loop {
+            let mut copies = Vec::new();
+            let mut sidecars = Vec::new();
+            for e in fs::read_dir(dir.join("q"))? {
+                let path = e?.path();
+                if path.extension().is_some_and(|e| e == "json") {
+                    sidecars.push(fs::read_to_string(path)?);
+                } else {
+                    copies.push(path);
+                }
+            }
+            // each written after it's created
+            if sidecars.len() == 2 && sidecars.iter().all(|s| s.ends_with('\n')) {
+                break (copies, sidecars);
+            }
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        };
+        // no .tmp left behind either
+        assert_eq!(copies.len(), 1);
+        let at = &copies[0];
+        assert_eq!(fs::read_to_string(at)?, "EICAR\n");
+        assert_eq!(fs::metadata(at)?.permissions().mode() & 0o777, 0o400);
+
+        let hash = at.file_name().unwrap().to_str().unwrap();
+        assert!(sidecars[0].starts_with(&format!(
+            "{{\"mask\":\"FAN_OPEN_PERM\",\"pid\":42,\"path\":\"{}\",\"comm\":\"cat\",\"sha256\":\"{}\",\"time\":",
+            dir.join("bad").display(),
+            hash
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn quarantine_sidecar_names() -> io::Result<()> {
+        let dir = TempDir::new("quarantine-sidecar")?;
+        let t = clock::parse_time("2020-04-01T13:05:06").unwrap() + Duration::from_millis(7);
+        let (mut first, path) = sidecar(&dir, "ab", t)?;
+        first.write_all(b"first\n")?;
+        assert_eq!(path, dir.join("ab@20200401T130506.007.json"));
+        // in the same millisecond, without losing the first
+        assert_eq!(
+            sidecar(&dir, "ab", t)?.1,
+            dir.join("ab@20200401T130506.007-1.json")
+        );
+        assert_eq!(
+            sidecar(&dir, "ab", t)?.1,
+            dir.join("ab@20200401T130506.007-2.json")
+        );
+        assert_eq!(fs::read_to_string(&path)?, "first\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+
+use crate::EventEntry;
+
+/// How often to check whether the command is done, to reap it.
+const REAP_EVERY: Duration = Duration::from_secs(1);
+
+/// Events that mean something in the tree changed.
+const CHANGE_EVENTS: u64 = libc::FAN_MODIFY
+    | libc::FAN_CLOSE_WRITE
+    | libc::FAN_ATTRIB
+    | libc::FAN_CREATE
+    | libc::FAN_DELETE
+    | libc::FAN_MOVED_FROM
+    | libc::FAN_MOVED_TO
+    | libc::FAN_RENAME;
+
+/// Runs a command once the watched tree has seen no changes for a while
+/// after the last change, like a debounced build or sync trigger.
+pub struct Quiesce {
+    cmd: String,
+    quiet: Duration,
+    due: Option<Instant>,
+    child: Option<Child>,
+    /// when to next check on the child
+    reap: Option<Instant>,
+}
+
+impl Quiesce {
+    pub fn new(cmd: String, quiet: Duration) -> Quiesce {
+        Quiesce {
+            cmd,
+            quiet,
+            due: None,
+            child: None,
+            reap: None,
+        }
+    }
+
+    pub fn record(&mut self, entry: &EventEntry) {
+        if entry.mask & CHANGE_EVENTS == 0 {
+            return;
+        }
+        // don't trigger ourselves with what the command, or anything it
+        // started, writes, all in the process group of its own
+        if let (Some(child), Some(pid)) = (&self.child, entry.pid) {
+            let pgid = unsafe { libc::getpgid(pid as libc::pid_t) };
+            if child.id() == pid || pgid == child.id() as libc::pid_t {
+                return;
+            }
+        }
+        self.due = Some(Instant::now() + self.quiet);
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        match (self.due, self.reap) {
+            (Some(due), Some(reap)) => Some(due.min(reap)),
+            (due, reap) => due.or(reap),
+        }
+    }
+
+    /// Reap the command and run it again once it's due, logging what
+    /// fails rather than stopping everything else over it.
+    pub fn tick(&mut self) {
+        let now = Instant::now();
+        if let Some(child) = &mut self.child {
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    if !status.success() {
+                        warn!("{}: {}", self.cmd, status);
+                    }
+                    self.child = None;
+                    self.reap = None;
+                }
+                Ok(None) => self.reap = Some(now + REAP_EVERY),
+                Err(e) => {
+                    warn!("{}: {}", self.cmd, e);
+                    self.child = None;
+                    self.reap = None;
+                }
+            }
+        }
+
+        match self.due {
+            Some(due) if now >= due => (),
+            _ => return,
+        }
+        if self.child.is_some() {
+            // still busy with the last round, check again later
+            self.due = Some(now + self.quiet);
+            return;
+        }
+
+        debug!("quiet for {:?}, running {}", self.quiet, self.cmd);
+        // the next change tries again
+        self.due = None;
+        match Command::new("sh")
+            .arg("-c")
+            .arg(&self.cmd)
+            .process_group(0)
+            .spawn()
+        {
+            Ok(child) => {
+                self.child = Some(child);
+                self.reap = Some(now + REAP_EVERY);
+            }
+            Err(e) => error!("{}: {}", self.cmd, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+    use std::fs;
+    use std::io;
+    use std::thread;
+
+    #[test]
+    fn quiesce_tick() -> io::Result<()> {
+        let dir = TempDir::new("quiesce")?;
+        let ran = dir.join("ran");
+        let quiet = Duration::from_millis(50);
+        let mut quiesce = Quiesce::new(format!("echo >> '{}'; sleep 0.2", ran.display()), quiet);
+
+        // reading doesn't change anything
+        quiesce.record(&EventEntry::test(libc::FAN_OPEN, "/a"));
+        assert_eq!(quiesce.deadline(), None);
+        quiesce.record(&EventEntry::test(libc::FAN_MODIFY, "/a"));
+        assert!(quiesce.deadline().is_some());
+        quiesce.tick();
+        assert!(quiesce.child.is_none());
+
+        thread::sleep(quiet);
+        quiesce.tick();
+        assert_eq!(quiesce.due, None);
+        assert!(quiesce.reap.is_some());
+        let pid = quiesce.child.as_ref().unwrap().id();
+
+        // what the command does doesn't count
+        quiesce.record(&EventEntry {
+            pid: Some(pid),
+            ..EventEntry::test(libc::FAN_CLOSE_WRITE, "/b")
+        });
+        assert_eq!(quiesce.due, None);
+        quiesce.record(&EventEntry::test(libc::FAN_CREATE, "/c"));
+        assert!(quiesce.due.is_some());
+
+        // due while it's still running, so it's put off
+        thread::sleep(quiet);
+        quiesce.tick();
+        assert_eq!(quiesce.child.as_ref().map(Child::id), Some(pid));
+        assert!(quiesce.due.unwrap() > Instant::now());
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while quiesce.deadline().is_some() {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+            quiesce.tick();
+        }
+        // once for each quiet spell
+        assert_eq!(fs::read_to_string(&ran)?, "\n\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::expect::Rule;
+use crate::EventEntry;
+
+/// What --max-events-per-sec and --rate-limit do with the events past the
+/// limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverLimit {
+    /// the rest of each second, counted as dropped=N on the next one
+    Drop,
+    /// every Nth over the second, N from how many there were the second
+    /// before, as sample_rate=1/N
+    Sample,
+}
+
+impl FromStr for OverLimit {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(OverLimit::Drop),
+            "sample" => Ok(OverLimit::Sample),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid value: {}, options: drop, sample", s),
+            )),
+        }
+    }
+}
+
+/// At most `rate` events a second, counted over each second rather than by
+/// a token bucket so sampling can spread them over it.
+struct Window {
+    rate: u64,
+    start: Option<Instant>,
+    /// the events so far this second, and of them those let through
+    seen: u64,
+    passed: u64,
+    /// let through every this many
+    every: u64,
+    dropped: u64,
+}
+
+impl Window {
+    fn new(rate: u32) -> Window {
+        Window {
+            rate: rate as u64,
+            start: None,
+            seen: 0,
+            passed: 0,
+            every: 1,
+            dropped: 0,
+        }
+    }
+
+    /// None to drop the event, or the fields to add to it.
+    fn admit(&mut self, over: OverLimit, now: Instant) -> Option<Vec<(&'static str, String)>> {
+        let elapsed = self.start.map(|s| now.saturating_duration_since(s));
+        if elapsed.is_none_or(|e| e >= Duration::from_secs(1)) {
+            self.every = match (over, elapsed) {
+                // a second without any counts for none
+                (OverLimit::Sample, Some(e)) if e < Duration::from_secs(2) => {
+                    self.seen.div_ceil(self.rate).max(1)
+                }
+                _ => 1,
+            };
+            self.start = Some(now);
+            self.seen = 0;
+            self.passed = 0;
+        }
+        self.seen += 1;
+        if self.passed >= self.rate || (self.seen - 1) % self.every != 0 {
+            self.dropped += 1;
+            return None;
+        }
+        self.passed += 1;
+
+        let mut fields = Vec::new();
+        match over {
+            OverLimit::Drop if self.dropped != 0 => {
+                fields.push(("dropped", mem::take(&mut self.dropped).to_string()))
+            }
+            OverLimit::Sample if self.every != 1 => {
+                fields.push(("sample_rate", format!("1/{}", self.every)))
+            }
+            _ => (),
+        }
+        Some(fields)
+    }
+}
+
+/// --max-events-per-sec for all the events, and --rate-limit for those
+/// matching each rule, the first one an event matches.
+pub struct RateLimit {
+    over: OverLimit,
+    global: Option<Window>,
+    rules: Vec<(Rule, Window)>,
+}
+
+/// Parse N GLOB [MASK].
+fn parse(spec: &str) -> io::Result<(u32, Rule)> {
+    let invalid = |msg: &str| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{}: {}, expected N GLOB [MASK]", spec, msg),
+        )
+    };
+    let (rate, rule) = spec
+        .trim()
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| invalid("no GLOB"))?;
+    let rate = rate.parse().map_err(|_| invalid("invalid N"))?;
+    if rate == 0 {
+        return Err(invalid("N must be more than 0"));
+    }
+    Ok((rate, Rule::parse(rule.trim())?))
+}
+
+impl RateLimit {
+    pub fn new(global: Option<u32>, specs: &[String], over: OverLimit) -> io::Result<RateLimit> {
+        let mut rules = Vec::new();
+        for spec in specs {
+            let (rate, rule) = parse(spec)?;
+            rules.push((rule, Window::new(rate)));
+        }
+        Ok(RateLimit {
+            over,
+            global: global.filter(|&rate| rate != 0).map(Window::new),
+            rules,
+        })
+    }
+
+    /// None to drop `entry`, or the fields to add to it.
+    pub fn admit(
+        &mut self,
+        entry: &EventEntry,
+        now: Instant,
+    ) -> Option<Vec<(&'static str, String)>> {
+        let mut fields = Vec::new();
+        if let Some((_, window)) = self.rules.iter_mut().find(|(r, _)| r.matches(entry)) {
+            fields.extend(window.admit(self.over, now)?);
+        }
+        if let Some(window) = &mut self.global {
+            fields.extend(window.admit(self.over, now)?);
+        }
+        Some(fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str) -> EventEntry {
+        EventEntry::test(libc::FAN_MODIFY, path)
+    }
+
+    #[test]
+    fn drop_over() {
+        let mut w = Window::new(2);
+        let t = Instant::now();
+        assert_eq!(w.admit(OverLimit::Drop, t), Some(vec![]));
+        assert_eq!(w.admit(OverLimit::Drop, t), Some(vec![]));
+        assert_eq!(w.admit(OverLimit::Drop, t), None);
+        assert_eq!(w.admit(OverLimit::Drop, t), None);
+        assert_eq!(
+            w.admit(OverLimit::Drop, t + Duration::from_secs(1)),
+            Some(vec![("dropped", "2".into())])
+        );
+    }
+
+    #[test]
+    fn sample_over() {
+        let mut w = Window::new(2);
+        let t = Instant::now();
+        for _ in 0..6 {
+            w.admit(OverLimit::Sample, t);
+        }
+        // 6 the second before, so 1 in 3 of them
+        let t = t + Duration::from_secs(1);
+        let passed: Vec<_> = (0..6).map(|_| w.admit(OverLimit::Sample, t)).collect();
+        let sampled = Some(vec![("sample_rate", "1/3".into())]);
+        assert_eq!(
+            passed,
+            vec![sampled.clone(), None, None, sampled, None, None]
+        );
+        // none the second before
+        assert_eq!(
+            w.admit(OverLimit::Sample, t + Duration::from_secs(3)),
+            Some(vec![])
+        );
+    }
+
+    #[test]
+    fn rules() -> io::Result<()> {
+        assert!(RateLimit::new(None, &["0 /tmp/*".into()], OverLimit::Drop).is_err());
+        assert!(RateLimit::new(None, &["/tmp/*".into()], OverLimit::Drop).is_err());
+
+        let mut limit = RateLimit::new(Some(3), &["1 /tmp/*".into()], OverLimit::Drop)?;
+        let t = Instant::now();
+        assert!(limit.admit(&entry("/tmp/a"), t).is_some());
+        assert!(limit.admit(&entry("/tmp/a"), t).is_none());
+        assert!(limit.admit(&entry("/etc/a"), t).is_some());
+        assert!(limit.admit(&entry("/etc/a"), t).is_some());
+        // past the global limit
+        assert!(limit.admit(&entry("/etc/a"), t).is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{write_path, EventEntry};
+
+/// How a file differs from when the session started.
+#[derive(Debug, Clone, PartialEq)]
+enum Change {
+    Modified,
+    Created,
+    Deleted,
+    /// moved here from the path it had
+    Renamed(PathBuf),
+}
+
+impl Change {
+    fn letter(&self) -> &'static str {
+        match self {
+            Change::Modified => "M",
+            Change::Created => "A",
+            Change::Deleted => "D",
+            Change::Renamed(_) => "R",
+        }
+    }
+
+    /// What it is after `next`, None if it's as it was.
+    fn then(self, next: Change) -> Option<Change> {
+        match (self, next) {
+            // came and went
+            (Change::Created, Change::Deleted) => None,
+            (Change::Created, _) => Some(Change::Created),
+            // deleted and made again
+            (Change::Deleted, Change::Created) => Some(Change::Modified),
+            (Change::Deleted, _) => Some(Change::Deleted),
+            (Change::Renamed(from), Change::Modified) => Some(Change::Renamed(from)),
+            (_, next) => Some(next),
+        }
+    }
+}
+
+/// Summary of what changed during the session, one entry per file.
+#[derive(Default)]
+pub struct Report {
+    changes: BTreeMap<PathBuf, Change>,
+}
+
+impl Report {
+    pub fn record(&mut self, entry: &EventEntry) {
+        let path = match &entry.path {
+            Some(path) => path,
+            None => return,
+        };
+        if entry.mask & libc::FAN_RENAME != 0 {
+            if let Some((_, to)) = entry.fields.iter().find(|(k, _)| *k == "to") {
+                self.rename(path, Path::new(to));
+                return;
+            }
+        }
+        // without FAN_RENAME a move can't be paired, so it's a delete here
+        // and a create there
+        let change = if entry.mask & (libc::FAN_DELETE | libc::FAN_MOVED_FROM | libc::FAN_RENAME)
+            != 0
+        {
+            Change::Deleted
+        } else if entry.mask & (libc::FAN_CREATE | libc::FAN_MOVED_TO) != 0 {
+            Change::Created
+        } else if entry.mask & (libc::FAN_MODIFY | libc::FAN_CLOSE_WRITE | libc::FAN_ATTRIB) != 0 {
+            Change::Modified
+        } else {
+            return;
+        };
+
+        // unlinked while still open
+        let change = if entry.fields.iter().any(|(k, _)| *k == "deleted") {
+            Change::Deleted
+        } else {
+            change
+        };
+
+        self.change(path, change);
+    }
+
+    fn change(&mut self, path: &Path, change: Change) {
+        let next = match self.changes.remove(path) {
+            // the file it was is gone from where it was
+            Some(Change::Renamed(from)) if change == Change::Deleted => {
+                self.gone(&from);
+                None
+            }
+            Some(c) => c.then(change),
+            None => Some(change),
+        };
+        if let Some(next) = next {
+            self.changes.insert(path.to_path_buf(), next);
+        }
+    }
+
+    /// The file that was at `path` when the session started is no more.
+    fn gone(&mut self, path: &Path) {
+        let c = match self.changes.remove(path) {
+            // another took its place
+            Some(Change::Created) | Some(Change::Modified) => Change::Modified,
+            _ => Change::Deleted,
+        };
+        self.changes.insert(path.to_path_buf(), c);
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) {
+        let moved = match self.changes.remove(from) {
+            // only ever had the new name
+            Some(Change::Created) => Change::Created,
+            // back where it started
+            Some(Change::Renamed(first)) if first == to => Change::Modified,
+            Some(Change::Renamed(first)) => Change::Renamed(first),
+            _ => Change::Renamed(from.to_path_buf()),
+        };
+        // over whatever was there
+        self.changes.remove(to);
+        self.changes.insert(to.to_path_buf(), moved);
+    }
+
+    /// Write the changes by directory, with the paths `raw` or escaped like
+    /// the events'.
+    pub fn write_to(&self, w: &mut dyn Write, raw: bool) -> io::Result<()> {
+        let mut dir = None;
+        for (path, change) in &self.changes {
+            let parent = path.parent().unwrap_or_else(|| Path::new("/"));
+            if dir != Some(parent) {
+                write_path(w, parent, raw)?;
+                w.write_all(b"/\n")?;
+                dir = Some(parent);
+            }
+
+            w.write_fmt(format_args!("  {} ", change.letter()))?;
+            if let Change::Renamed(from) = change {
+                // in full if it came from elsewhere
+                match from.parent() {
+                    Some(p) if p == parent => {
+                        write_path(w, Path::new(from.file_name().unwrap_or_default()), raw)?
+                    }
+                    _ => write_path(w, from, raw)?,
+                }
+                w.write_all(b" -> ")?;
+            }
+            write_path(w, Path::new(path.file_name().unwrap_or_default()), raw)?;
+            w.write_all(b"\n")?;
+        }
+        w.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(mask: u64, path: &str) -> EventEntry {
+        EventEntry {
+            pid: None,
+            ..EventEntry::test(mask, path)
+        }
+    }
+
+    #[test]
+    fn report_grouped() -> io::Result<()> {
+        let mut r = Report::default();
+        r.record(&entry(libc::FAN_CLOSE_WRITE, "/a/y"));
+        r.record(&entry(libc::FAN_MODIFY, "/a/x"));
+        r.record(&entry(libc::FAN_MODIFY, "/a/x"));
+        r.record(&entry(libc::FAN_OPEN, "/a/w"));
+        r.record(&entry(libc::FAN_ATTRIB, "/a/v"));
+        let mut deleted = entry(libc::FAN_CLOSE_WRITE, "/b/z");
+        deleted.fields.push(("deleted", "true".into()));
+        r.record(&deleted);
+        r.record(&entry(libc::FAN_MODIFY, "/b/z"));
+
+        let mut buf = vec![];
+        r.write_to(&mut buf, false)?;
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "/a/\n  M v\n  M x\n  M y\n/b/\n  D z\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn report_dirents() -> io::Result<()> {
+        let mut r = Report::default();
+        r.record(&entry(libc::FAN_CREATE, "/a/new"));
+        r.record(&entry(libc::FAN_CLOSE_WRITE, "/a/new"));
+        r.record(&entry(libc::FAN_MOVED_FROM, "/a/old"));
+        r.record(&entry(libc::FAN_MOVED_TO, "/b/old"));
+        r.record(&entry(libc::FAN_CREATE, "/b/gone"));
+        r.record(&entry(libc::FAN_DELETE, "/b/gone"));
+        r.record(&entry(libc::FAN_DELETE, "/b/again"));
+        r.record(&entry(libc::FAN_CREATE, "/b/again"));
+        let rename = |from: &str, to: &str| {
+            let mut rename = entry(libc::FAN_RENAME, from);
+            rename.fields.push(("to", to.into()));
+            rename
+        };
+        r.record(&rename("/c/from", "/c/to"));
+        r.record(&entry(libc::FAN_MODIFY, "/c/to"));
+        r.record(&rename("/c/x", "/d/x"));
+        r.record(&entry(libc::FAN_CREATE, "/c/tmp"));
+        r.record(&rename("/c/tmp", "/c/kept"));
+        r.record(&rename("/c/y", "/c/y2"));
+        r.record(&entry(libc::FAN_DELETE, "/c/y2"));
+
+        let mut buf = vec![];
+        r.write_to(&mut buf, false)?;
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "/a/\n  A new\n  D old\n/b/\n  M again\n  A old\n\
+             /c/\n  A kept\n  R from -> to\n  D y\n/d/\n  R /c/x -> x\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn report_escaped() -> io::Result<()> {
+        let mut r = Report::default();
+        r.record(&entry(libc::FAN_CREATE, "/a\nb/c\nd"));
+        let mut rename = entry(libc::FAN_RENAME, "/e/f\tg");
+        rename.fields.push(("to", "/e/h".into()));
+        r.record(&rename);
+
+        let mut buf = vec![];
+        r.write_to(&mut buf, false)?;
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "/a\\nb/\n  A c\\nd\n/e/\n  R f\\tg -> h\n"
+        );
+        let mut buf = vec![];
+        r.write_to(&mut buf, true)?;
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "/a\nb/\n  A c\nd\n/e/\n  R f\tg -> h\n"
+        );
+        Ok(())
+    }
+}
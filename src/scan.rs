@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::ManuallyDrop;
+use std::os::fd::AsFd;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::policy::Action;
+use crate::stats::Stats;
+use crate::{EventEntry, PermId};
+
+// verdicts to keep, they're forgotten all at once past this
+const VERDICTS_LEN: usize = 10000;
+
+/// A file by dev, ino and mtime, which changes with any write.
+type Key = (u64, u64, i64, i64);
+
+struct Job {
+    key: Key,
+    file: File,
+    path: Option<PathBuf>,
+}
+
+/// Run `cmd` with the file open as its stdin: exiting 0 allows it, 1
+/// denies it, like clamscan, anything else fails.
+fn scan(cmd: &str, mut job: Job) -> Option<Action> {
+    // it shares the offset with the event's fd, which may have been
+    // scanned before, and reopening it would be an event of its own
+    if let Err(e) = job.file.seek(SeekFrom::Start(0)) {
+        error!("--scan-cmd: {:?}: {}", job.path, e);
+        return None;
+    }
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(cmd).stdin(job.file);
+    if let Some(path) = &job.path {
+        command.env("FANOTIFY_PATH", path);
+    }
+    // what it says goes with our logs, not the events
+    match io::stderr().as_fd().try_clone_to_owned() {
+        Ok(stderr) => command.stdout(stderr),
+        Err(_) => command.stdout(Stdio::null()),
+    };
+    match command.status() {
+        Ok(status) => match status.code() {
+            Some(0) => Some(Action::Allow),
+            Some(1) => Some(Action::Deny),
+            _ => {
+                warn!("--scan-cmd: {:?}: {}", job.path, status);
+                None
+            }
+        },
+        Err(e) => {
+            error!("--scan-cmd: {}", e);
+            None
+        }
+    }
+}
+
+fn run(
+    cmd: Arc<String>,
+    jobs: Arc<Mutex<Receiver<Job>>>,
+    replies: Sender<(Key, Option<Action>)>,
+    mut wake: UnixStream,
+) {
+    loop {
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        let key = job.key;
+        let verdict = scan(&cmd, job);
+        if replies.send((key, verdict)).is_err() {
+            return;
+        }
+        // it's nonblocking, and a full buffer already wakes it
+        let _ = wake.write(&[0]);
+    }
+}
+
+/// Answers FAN_OPEN_PERM events by what a content scanner like clamscan
+/// says about the file, run on worker threads so a slow scan doesn't hold
+/// up the main loop, remembering the verdicts until the file changes.
+pub struct Scanner {
+    tx: Sender<Job>,
+    replies: Receiver<(Key, Option<Action>)>,
+    wake: UnixStream,
+    /// for the scans that fail
+    on_error: Action,
+    verdicts: HashMap<Key, Action>,
+    /// the events waiting for each scan
+    scanning: HashMap<Key, Vec<PermId>>,
+}
+
+impl Scanner {
+    pub fn new(cmd: &str, on_error: Action) -> io::Result<Scanner> {
+        let (tx, jobs) = mpsc::channel();
+        let (replies_tx, replies) = mpsc::channel();
+        let (wake, wake_tx) = UnixStream::pair()?;
+        wake.set_nonblocking(true)?;
+        wake_tx.set_nonblocking(true)?;
+        let cmd = Arc::new(cmd.to_string());
+        let jobs = Arc::new(Mutex::new(jobs));
+        let workers = thread::available_parallelism().map_or(1, |n| n.get());
+        // a hung scan is left to --perm-timeout, and its thread to exit
+        // with us
+        for i in 0..workers {
+            let (cmd, jobs, replies_tx, wake_tx) = (
+                cmd.clone(),
+                jobs.clone(),
+                replies_tx.clone(),
+                wake_tx.try_clone()?,
+            );
+            thread::Builder::new()
+                .name(format!("scan-{}", i))
+                .spawn(move || run(cmd, jobs, replies_tx, wake_tx))?;
+        }
+        Ok(Scanner {
+            tx,
+            replies,
+            wake,
+            on_error,
+            verdicts: HashMap::new(),
+            scanning: HashMap::new(),
+        })
+    }
+
+    /// Scan the file of the FAN_OPEN_PERM event `perm`, unless its
+    /// verdict is known.
+    pub fn ask(&mut self, perm: PermId, entry: &EventEntry) -> io::Result<Option<Action>> {
+        let fd = perm.0;
+        // the fd was reused, so the last event with it was answered
+        for perms in self.scanning.values_mut() {
+            perms.retain(|(f, _)| *f != fd);
+        }
+        if entry.mask & libc::FAN_OPEN_PERM == 0 {
+            return Ok(None);
+        }
+
+        // borrowed, the caller closes it
+        let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+        let m = file.metadata()?;
+        let key = (m.dev(), m.ino(), m.mtime(), m.mtime_nsec());
+        if let Some(action) = self.verdicts.get(&key) {
+            return Ok(Some(*action));
+        }
+        if let Some(perms) = self.scanning.get_mut(&key) {
+            perms.push(perm);
+            return Ok(None);
+        }
+        let job = Job {
+            key,
+            file: file.try_clone()?,
+            path: entry.path.clone(),
+        };
+        // the workers only stop when we do
+        let _ = self.tx.send(job);
+        self.scanning.insert(key, vec![perm]);
+        Ok(None)
+    }
+
+    /// The events decided by the scans finished since the last time.
+    pub fn read(&mut self, stats: &mut Stats) -> Vec<(PermId, Action)> {
+        let mut buf = [0; 4096];
+        while let Ok(n) = (&self.wake).read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+
+        let mut decided = Vec::new();
+        while let Ok((key, verdict)) = self.replies.try_recv() {
+            let action = match verdict {
+                Some(action) => {
+                    if self.verdicts.len() >= VERDICTS_LEN {
+                        self.verdicts.clear();
+                    }
+                    self.verdicts.insert(key, action);
+                    action
+                }
+                None => {
+                    stats.scan_failed += 1;
+                    self.on_error
+                }
+            };
+            for perm in self.scanning.remove(&key).unwrap_or_default() {
+                decided.push((perm, action));
+            }
+        }
+        decided
+    }
+
+    /// Forget the events that were answered some other way, the scans go
+    /// on for the verdicts.
+    pub fn answered(&mut self, pending: &dyn Fn(PermId) -> bool) {
+        for perms in self.scanning.values_mut() {
+            perms.retain(|perm| pending(*perm));
+        }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.wake.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+    use std::fs;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn scan_verdicts() -> io::Result<()> {
+        let dir = TempDir::new("scan")?;
+        let mut scanner = Scanner::new(
+            "read -r l; case $l in EICAR) exit 1;; ERR) exit 2;; esac",
+            Action::Allow,
+        )?;
+        let entry = |mask| EventEntry {
+            pid: Some(42),
+            path: None,
+            ..EventEntry::test(mask, "")
+        };
+        let wait = |scanner: &mut Scanner, n| {
+            let mut decided = Vec::new();
+            let mut stats = Stats::default();
+            while decided.len() < n {
+                let mut pfd = libc::pollfd {
+                    fd: scanner.fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                assert_eq!(unsafe { libc::poll(&mut pfd, 1, 10000) }, 1);
+                decided.extend(scanner.read(&mut stats));
+            }
+            decided.sort_by_key(|(perm, _)| *perm);
+            (decided, stats.scan_failed)
+        };
+
+        fs::write(dir.join("clean"), "hello\n")?;
+        fs::write(dir.join("bad"), "EICAR\n")?;
+        fs::write(dir.join("err"), "ERR\n")?;
+        let clean = File::open(dir.join("clean"))?;
+        let clean2 = File::open(dir.join("clean"))?;
+        let bad = File::open(dir.join("bad"))?;
+        let err = File::open(dir.join("err"))?;
+        let open = libc::FAN_OPEN_PERM;
+        let (c, c2, b, e) = (
+            (clean.as_raw_fd(), 1),
+            (clean2.as_raw_fd(), 2),
+            (bad.as_raw_fd(), 3),
+            (err.as_raw_fd(), 4),
+        );
+
+        assert_eq!(scanner.ask(c, &entry(open))?, None);
+        // the same file only gets scanned once
+        assert_eq!(scanner.ask(c2, &entry(open))?, None);
+        assert_eq!(scanner.ask(b, &entry(open))?, None);
+        assert_eq!(scanner.ask(e, &entry(libc::FAN_ACCESS_PERM))?, None);
+        assert_eq!(
+            wait(&mut scanner, 3),
+            (
+                vec![(c, Action::Allow), (c2, Action::Allow), (b, Action::Deny)],
+                0
+            )
+        );
+        assert_eq!(scanner.ask(c, &entry(open))?, Some(Action::Allow));
+        assert_eq!(scanner.ask(b, &entry(open))?, Some(Action::Deny));
+
+        // failures aren't remembered
+        assert_eq!(scanner.ask(e, &entry(open))?, None);
+        assert_eq!(wait(&mut scanner, 1), (vec![(e, Action::Allow)], 1));
+        assert_eq!(scanner.ask(e, &entry(open))?, None);
+        assert_eq!(wait(&mut scanner, 1), (vec![(e, Action::Allow)], 1));
+
+        // nor are verdicts for what the file was
+        fs::write(dir.join("clean"), "EICAR\n")?;
+        let clean = File::open(dir.join("clean"))?;
+        // in case it was written within the same tick
+        clean.set_modified(UNIX_EPOCH)?;
+        let c = (clean.as_raw_fd(), 5);
+        assert_eq!(scanner.ask(c, &entry(open))?, None);
+        assert_eq!(wait(&mut scanner, 1), (vec![(c, Action::Deny)], 0));
+
+        // answered some other way while it was scanned
+        fs::write(dir.join("clean"), "hello again\n")?;
+        let again = File::open(dir.join("clean"))?;
+        let c = (again.as_raw_fd(), 6);
+        assert_eq!(scanner.ask(c, &entry(open))?, None);
+        scanner.answered(&|_| false);
+        assert!(scanner.scanning.values().all(|perms| perms.is_empty()));
+        Ok(())
+    }
+}
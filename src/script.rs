@@ -0,0 +1,232 @@
+use std::cell::Cell;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use mlua::{Function, HookTriggers, Lua, Table, Value};
+
+use crate::plugin::FieldNames;
+use crate::policy::Action;
+use crate::EventEntry;
+
+// instructions between checks of the budget
+const HOOK_EVERY: u32 = 1000;
+// checks each call may go through before it's stopped, so 10M
+// instructions or so like a plugin's fuel
+const BUDGET: u32 = 10_000;
+
+/// A Lua script with an `on_event(event)` function, which gets each event
+/// as a table of mask, fd, pid, path and the fields, and returns
+/// "allow", "deny" or "log" (or nothing) and optionally a table of fields
+/// to add, like
+///
+/// ```lua
+/// function on_event(e)
+///   if e.path == "/etc/shadow" then
+///     return "deny", {reason = "secrets"}
+///   end
+///   return "log"
+/// end
+/// ```
+///
+/// Allow and deny answer permission events, everything else is only
+/// logged. A call that runs too long is stopped, and its event answered
+/// with the fallback.
+pub struct Script {
+    path: PathBuf,
+    lua: Lua,
+    names: FieldNames,
+    budget: Rc<Cell<u32>>,
+    fallback: Action,
+}
+
+fn invalid(path: &Path, e: mlua::Error) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("{}: {}", path.display(), e),
+    )
+}
+
+impl Script {
+    pub fn load(path: &Path, fallback: Action) -> io::Result<Script> {
+        let src = fs::read_to_string(path)?;
+        let lua = Lua::new();
+        let budget = Rc::new(Cell::new(BUDGET));
+        let left = budget.clone();
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(HOOK_EVERY),
+            move |_, _| match left.get() {
+                0 => Err(mlua::Error::runtime("ran too long")),
+                n => {
+                    left.set(n - 1);
+                    Ok(())
+                }
+            },
+        );
+        lua.load(&src)
+            .set_name(path.display().to_string())
+            .exec()
+            .map_err(|e| invalid(path, e))?;
+        if lua.globals().get::<_, Function>("on_event").is_err() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{}: no on_event function", path.display()),
+            ));
+        }
+        Ok(Script {
+            path: path.into(),
+            lua,
+            names: FieldNames::default(),
+            budget,
+            fallback,
+        })
+    }
+
+    fn call(&mut self, entry: &mut EventEntry) -> mlua::Result<Option<Action>> {
+        let event = self.lua.create_table()?;
+        event.set("mask", entry.mask_names())?;
+        event.set("fd", entry.fd)?;
+        event.set("pid", entry.pid)?;
+        event.set(
+            "path",
+            entry
+                .path
+                .as_ref()
+                .map(|p| p.to_string_lossy().into_owned()),
+        )?;
+        for (k, v) in &entry.fields {
+            event.set(*k, v.as_str())?;
+        }
+
+        let on_event: Function = self.lua.globals().get("on_event")?;
+        let (action, fields): (Option<String>, Option<Table>) = on_event.call(event)?;
+        if let Some(fields) = fields {
+            for pair in fields.pairs::<String, Value>() {
+                let (k, v) = pair?;
+                if let Some(k) = self.names.get(k) {
+                    entry.fields.push((k, v.to_string()?));
+                }
+            }
+        }
+        match action.as_deref() {
+            None | Some("log") => Ok(None),
+            Some("allow") => Ok(Some(Action::Allow)),
+            Some("deny") => Ok(Some(Action::Deny)),
+            Some(a) => Err(mlua::Error::runtime(format!(
+                "expected allow, deny or log, not {}",
+                a
+            ))),
+        }
+    }
+
+    /// Run the script on `entry`, adding the fields it returns, and
+    /// returning how to answer it.
+    pub fn run(&mut self, entry: &mut EventEntry) -> Option<Action> {
+        self.budget.set(BUDGET);
+        match self.call(entry) {
+            Ok(action) => action,
+            Err(e) if self.budget.get() == 0 => {
+                error!(
+                    "{}: {}, answering {}",
+                    self.path.display(),
+                    e,
+                    self.fallback.name()
+                );
+                Some(self.fallback)
+            }
+            Err(e) => {
+                error!("{}: {}", self.path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+
+    #[test]
+    fn script_on_event() -> io::Result<()> {
+        let dir = TempDir::new("script")?;
+        let path = dir.join("script.lua");
+        fs::write(
+            &path,
+            r#"
+            function on_event(e)
+              if e.path == "/etc/shadow" then
+                return "deny", {reason = "secrets", by = e.comm}
+              elseif e.pid == 1 then
+                return "allow"
+              elseif e.path == nil then
+                return "maybe"
+              end
+              return "log", {size = 10}
+            end
+            "#,
+        )?;
+        let mut script = Script::load(&path, Action::Allow)?;
+
+        let entry = |path: Option<&str>, pid| EventEntry {
+            fd: Some(3),
+            pid: Some(pid),
+            path: path.map(|p| p.into()),
+            fields: vec![("comm", "cat".into())],
+            ..EventEntry::test(libc::FAN_OPEN_PERM, "")
+        };
+        let mut e = entry(Some("/etc/shadow"), 2);
+        assert_eq!(script.run(&mut e), Some(Action::Deny));
+        e.fields.sort();
+        assert_eq!(
+            e.fields,
+            vec![
+                ("by", "cat".into()),
+                ("comm", "cat".into()),
+                ("reason", "secrets".into())
+            ]
+        );
+        assert_eq!(
+            script.run(&mut entry(Some("/etc/passwd"), 1)),
+            Some(Action::Allow)
+        );
+        let mut e = entry(Some("/etc/passwd"), 2);
+        assert_eq!(script.run(&mut e), None);
+        assert_eq!(e.fields[1], ("size", "10".into()));
+        assert_eq!(script.run(&mut entry(None, 2)), None);
+
+        fs::write(&path, "function on_event(e")?;
+        assert!(Script::load(&path, Action::Allow).is_err());
+        fs::write(&path, "x = 1")?;
+        assert!(Script::load(&path, Action::Allow).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn script_budget() -> io::Result<()> {
+        let dir = TempDir::new("script-budget")?;
+        let path = dir.join("script.lua");
+        fs::write(
+            &path,
+            r#"
+            function on_event(e)
+              if e.path == "/loop" then
+                while true do end
+              end
+              return "allow"
+            end
+            "#,
+        )?;
+        let mut script = Script::load(&path, Action::Deny)?;
+        let entry = |path| EventEntry::test(libc::FAN_OPEN_PERM, path);
+        assert_eq!(script.run(&mut entry("/loop")), Some(Action::Deny));
+        // with all of its budget again
+        assert_eq!(script.run(&mut entry("/a")), Some(Action::Allow));
+        assert_eq!(script.run(&mut entry("/loop")), Some(Action::Deny));
+
+        fs::write(&path, "while true do end")?;
+        assert!(Script::load(&path, Action::Deny).is_err());
+        Ok(())
+    }
+}
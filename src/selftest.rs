@@ -0,0 +1,153 @@
+use std::collections::HashSet;
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::process::{self, Command};
+use std::time::{Duration, Instant};
+
+use crate::c_enum::EnumValues;
+use crate::{fanotify_init, fanotify_mark, poll, respond, FanEvents, PERM_EVENTS};
+
+// one write and one read of a file, and a listing of a subdirectory
+const SCRIPT: &str = "echo hello > file && cat file > /dev/null && mkdir sub && ls sub";
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The events in `mask` that SCRIPT should produce.
+fn expected(mask: u64) -> Vec<FanEvents> {
+    FanEvents::values()
+        .into_iter()
+        .filter(|e| {
+            // the rest only change what's reported, and without FID
+            // reporting FAN_ONDIR isn't set on events
+            (*e as u64) & mask != 0
+                && *e != FanEvents::FAN_Q_OVERFLOW
+                && *e != FanEvents::FAN_EVENT_ON_CHILD
+                && *e != FanEvents::FAN_ONDIR
+        })
+        .collect()
+}
+
+/// Read what's queued on `notify`, allowing permission events, returns the
+/// union of the event masks.
+fn drain(notify: &mut File) -> io::Result<u64> {
+    let mut seen = 0;
+    let mut buf = vec![0u8; 4096 * mem::size_of::<libc::fanotify_event_metadata>()];
+    let n = match notify.read(&mut buf) {
+        Ok(n) => n,
+        Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut off = 0;
+    while off + mem::size_of::<libc::fanotify_event_metadata>() <= n {
+        let metadata: libc::fanotify_event_metadata =
+            unsafe { std::ptr::read_unaligned(buf[off..].as_ptr() as *const _) };
+        if metadata.event_len == 0 {
+            break;
+        }
+        off += metadata.event_len as usize;
+
+        seen |= metadata.mask;
+        if metadata.fd >= 0 {
+            if metadata.mask & PERM_EVENTS != 0 {
+                let mut pending = HashSet::new();
+                pending.insert(metadata.fd);
+                respond(notify, metadata.fd, libc::FAN_ALLOW, None, &mut pending)?;
+            } else {
+                unsafe { File::from_raw_fd(metadata.fd) };
+            }
+        }
+    }
+    Ok(seen)
+}
+
+fn check(dir: &Path, mask: u64) -> io::Result<()> {
+    let notify_fd = fanotify_init(
+        libc::FAN_CLASS_CONTENT | libc::FAN_CLOEXEC | libc::FAN_NONBLOCK,
+        (libc::O_CLOEXEC | libc::O_RDONLY | libc::O_LARGEFILE) as u32,
+    )?;
+    let mut notify = unsafe { File::from_raw_fd(notify_fd) };
+    let cdir = CString::new(dir.as_os_str().as_bytes()).unwrap();
+    fanotify_mark(
+        notify_fd,
+        libc::FAN_MARK_ADD,
+        mask | libc::FAN_EVENT_ON_CHILD,
+        libc::AT_FDCWD,
+        cdir.as_ptr(),
+    )?;
+
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(SCRIPT)
+        .current_dir(dir)
+        .spawn()?;
+
+    let deadline = Instant::now() + TIMEOUT;
+    let mut seen = 0;
+    loop {
+        // anything still queued once the child is gone is read below
+        let exited = child.try_wait()?.is_some();
+        let mut fds = [libc::pollfd {
+            fd: notify_fd,
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        poll(fds.as_mut_ptr(), 1, 100)?;
+        seen |= drain(&mut notify)?;
+        if exited || Instant::now() >= deadline {
+            break;
+        }
+    }
+    if child.try_wait()?.is_none() {
+        let _ = child.kill();
+    }
+
+    let mut missing = 0;
+    for e in expected(mask) {
+        if seen & (e as u64) != 0 {
+            println!("ok\t{}", e.as_ref());
+        } else {
+            println!("FAIL\t{}", e.as_ref());
+            missing += 1;
+        }
+    }
+    if missing != 0 {
+        return Err(io::Error::other(format!(
+            "{} expected events not seen",
+            missing
+        )));
+    }
+    Ok(())
+}
+
+/// Watch a scratch directory while a child process works on it, and check
+/// that every event in `mask` comes through.
+pub fn run(mask: u64) -> io::Result<()> {
+    let dir = env::temp_dir().join(format!("fanotify-cli-selftest-{}", process::id()));
+    fs::create_dir_all(&dir)?;
+    let res = check(&dir, mask);
+    fs::remove_dir_all(&dir)?;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selftest_expected() {
+        assert_eq!(
+            expected(
+                FanEvents::FAN_OPEN as u64
+                    | FanEvents::FAN_ONDIR as u64
+                    | FanEvents::FAN_EVENT_ON_CHILD as u64
+            ),
+            vec![FanEvents::FAN_OPEN]
+        );
+    }
+}
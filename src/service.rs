@@ -0,0 +1,304 @@
+use std::env;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+use std::process::Command;
+
+use structopt::StructOpt;
+
+use crate::flags::{self, Opt};
+use crate::sink::Output;
+
+/// Capabilities the service may need: fanotify itself, following other
+/// processes' /proc links, open_by_handle_at, raising RLIMIT_NOFILE and
+/// signalling other users' processes for kill and stop rules.
+const CAPABILITIES: &str =
+    "CAP_SYS_ADMIN CAP_SYS_PTRACE CAP_DAC_READ_SEARCH CAP_SYS_RESOURCE CAP_KILL";
+
+// the short flags that take a value, the rest are switches
+const SHORT_VALUES: &[u8] = b"ep";
+
+/// The flags whose value is a file, which the service would look for
+/// relative to / instead of where it was installed from.
+const PATH_FLAGS: &[&str] = &[
+    "plugin",
+    "script",
+    "policy",
+    "exec-allowlist",
+    "exec-allowlist-key",
+    "decisions",
+    "container-api",
+    "forward-ca",
+    "spill-dir",
+    "output-file",
+    "snapshot-dir",
+    "quarantine",
+    "audit-log",
+    "upgrade-exec",
+    "expect",
+];
+
+/// `s` quoted for a systemd command line, with its specifiers and
+/// variables escaped too.
+fn quote(s: &OsStr) -> io::Result<String> {
+    let s = s.to_str().ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{:?}: systemd needs UTF-8", s),
+        )
+    })?;
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            '%' => quoted.push_str("%%"),
+            '$' => quoted.push_str("$$"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    Ok(quoted)
+}
+
+fn unit_file(exe: &Path, config: &Path) -> io::Result<String> {
+    Ok(format!(
+        "[Unit]
+Description=fanotify-cli filesystem monitor
+After=local-fs.target
+
+[Service]
+ExecStart={} --config {}
+Restart=on-failure
+CapabilityBoundingSet={}
+NoNewPrivileges=yes
+LockPersonality=yes
+RestrictRealtime=yes
+RestrictNamespaces=yes
+RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6
+SystemCallArchitectures=native
+# no ProtectSystem= and friends, a private mount namespace would make
+# FAN_MARK_MOUNT watch a copy of the mount instead of the real one
+
+[Install]
+WantedBy=multi-user.target
+",
+        quote(exe.as_os_str())?,
+        quote(config.as_os_str())?,
+        CAPABILITIES
+    ))
+}
+
+/// `args` with clusters of short flags split up, and the values of
+/// --flag=VALUE and -eVALUE in args of their own, so clap counts each
+/// where it is.
+fn split_args(args: &[OsString]) -> Vec<OsString> {
+    let mut split = Vec::new();
+    let mut rest = args.iter();
+    while let Some(a) = rest.next() {
+        let b = a.as_bytes();
+        if b == b"--" {
+            split.push(a.clone());
+            split.extend(rest.cloned());
+            break;
+        } else if b.starts_with(b"--") {
+            match b.iter().position(|&c| c == b'=') {
+                Some(i) => {
+                    split.push(OsStr::from_bytes(&b[..i]).into());
+                    split.push(OsStr::from_bytes(&b[i + 1..]).into());
+                }
+                None => split.push(a.clone()),
+            }
+        } else if b.len() > 1 && b[0] == b'-' {
+            for (i, &c) in b.iter().enumerate().skip(1) {
+                split.push(OsString::from_vec(vec![b'-', c]));
+                if SHORT_VALUES.contains(&c) {
+                    if i + 1 < b.len() {
+                        split.push(OsStr::from_bytes(&b[i + 1..]).into());
+                    }
+                    break;
+                }
+            }
+        } else {
+            split.push(a.clone());
+        }
+    }
+    split
+}
+
+/// `args` as they'd be from anywhere: with the paths resolved the way
+/// they would be here, as the service runs in /.
+fn config_args(args: &[OsString]) -> io::Result<Vec<OsString>> {
+    let mut argv = vec![OsString::from("fanotify-cli")];
+    argv.extend(split_args(args));
+    let matches = Opt::clap()
+        .get_matches_from_safe(&argv)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e.message))?;
+    // catch bad flags now instead of when the service starts
+    let opt = Opt::from_clap(&matches).with_default()?;
+
+    let cwd = env::current_dir()?;
+    let mut resolved: Vec<(&str, Vec<OsString>)> = PATH_FLAGS
+        .iter()
+        .map(|&name| {
+            let values = matches.values_of_os(name).into_iter().flatten();
+            (name, values.map(|v| cwd.join(v).into_os_string()).collect())
+        })
+        .collect();
+    if opt.namespace.is_none() {
+        let abs = |paths: &[CString]| -> Vec<OsString> {
+            paths
+                .iter()
+                .map(|p| OsStr::from_bytes(p.as_bytes()).to_os_string())
+                .collect()
+        };
+        resolved.push(("paths", abs(&opt.paths)));
+        resolved.push(("ignore", abs(&opt.ignore)));
+        resolved.push(("canary", abs(&opt.canary)));
+    }
+    if let Some(Output::Sqlite(db)) = &opt.output {
+        let mut output = OsString::from("sqlite:");
+        output.push(cwd.join(db));
+        resolved.push(("output", vec![output]));
+    }
+
+    for (name, values) in resolved {
+        let at = matches.indices_of(name).into_iter().flatten();
+        let given = matches.values_of_os(name).into_iter().flatten();
+        for ((i, given), value) in at.zip(given).zip(values) {
+            // where clap found it, unless it counted differently
+            match argv.get_mut(i) {
+                Some(arg) if arg.as_os_str() == given => *arg = value,
+                _ => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("--{}: can't tell which arg {:?} is", name, given),
+                    ))
+                }
+            }
+        }
+    }
+    argv.remove(0);
+    Ok(argv)
+}
+
+fn systemctl(args: &[&str]) -> io::Result<()> {
+    let status = Command::new("systemctl").args(args).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "systemctl {}: {}",
+            args.join(" "),
+            status
+        )))
+    }
+}
+
+pub fn install(
+    name: &str,
+    unit_dir: &Path,
+    config_dir: &Path,
+    enable: bool,
+    args: &[OsString],
+) -> io::Result<()> {
+    let args = config_args(args)?;
+
+    let unit = format!("{}.service", name);
+    let config = config_dir.join(format!("{}.conf", name));
+
+    fs::create_dir_all(config_dir)?;
+    flags::write_config(&config, &args)?;
+    fs::write(
+        unit_dir.join(&unit),
+        unit_file(&env::current_exe()?, &config)?,
+    )?;
+    println!("wrote {:?} and {:?}", unit_dir.join(&unit), config);
+
+    if enable {
+        systemctl(&["daemon-reload"])?;
+        systemctl(&["enable", "--now", &unit])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+
+    #[test]
+    fn unit_quoting() -> io::Result<()> {
+        let unit = unit_file(
+            Path::new("/opt/my bin/fanotify-cli"),
+            Path::new("/etc/fanotify-cli/\"50%\"$x.conf"),
+        )?;
+        assert!(unit.contains(
+            "\nExecStart=\"/opt/my bin/fanotify-cli\" \
+             --config \"/etc/fanotify-cli/\\\"50%%\\\"$$x.conf\"\n"
+        ));
+        assert!(unit.contains("\nCapabilityBoundingSet=CAP_SYS_ADMIN "));
+        assert!(unit.contains(" CAP_KILL\n"));
+        assert!(quote(OsStr::from_bytes(b"/a\xff")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn config_paths() -> io::Result<()> {
+        let args = |a: &[&str]| a.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(
+            split_args(&args(&[
+                "-rme",
+                "open",
+                "-eopen",
+                "--policy=a=b",
+                "--",
+                "-rm"
+            ])),
+            args(&["-r", "-m", "-e", "open", "-e", "open", "--policy", "a=b", "--", "-rm"])
+        );
+
+        let dir = TempDir::new("service")?;
+        let cwd = env::current_dir()?;
+        let here = fs::canonicalize(&cwd)?;
+        let watched = dir.to_str().unwrap();
+        let config = config_args(&args(&[
+            "--policy=rules",
+            "-re",
+            "open",
+            "--output",
+            "sqlite:events.db",
+            watched,
+            ".",
+            "--exec-allowlist",
+            "/etc/allowed",
+        ]))?;
+        assert_eq!(
+            config,
+            vec![
+                "--policy".into(),
+                cwd.join("rules").into_os_string(),
+                "-r".into(),
+                "-e".into(),
+                "open".into(),
+                "--output".into(),
+                format!("sqlite:{}", cwd.join("events.db").display()).into(),
+                fs::canonicalize(watched)?.into_os_string(),
+                here.into_os_string(),
+                "--exec-allowlist".into(),
+                "/etc/allowed".into(),
+            ]
+        );
+
+        let conf = dir.join("a.conf");
+        flags::write_config(&conf, &config)?;
+        assert_eq!(flags::read_config(&conf)?, config);
+        assert!(config_args(&args(&["--bogus"])).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::{write_path, EventEntry};
+
+/// One open() to close() of a file by a process.
+#[derive(Debug, PartialEq)]
+pub struct Session {
+    pub pid: Option<u32>,
+    pub path: PathBuf,
+    /// None if the open happened before we started watching
+    pub duration: Option<Duration>,
+    pub written: bool,
+}
+
+impl Session {
+    pub fn write_to(&self, w: &mut dyn Write, raw_paths: bool) -> io::Result<()> {
+        fn field<T: Display>(f: Option<T>) -> String {
+            f.map(|f| f.to_string()).unwrap_or_else(|| "-".into())
+        }
+
+        w.write_fmt(format_args!(
+            "SESSION\t{}\t{}\t{}\t",
+            field(self.pid),
+            field(self.duration.map(|d| format!("{:.3}", d.as_secs_f64()))),
+            if self.written { "write" } else { "read" },
+        ))?;
+        write_path(w, &self.path, raw_paths)
+    }
+}
+
+struct Open {
+    at: Instant,
+    written: bool,
+}
+
+/// What a file is known by, its inode from --enrich inode, which stays the
+/// same across renames, or else its path.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum File {
+    Inode(String, String),
+    Path(PathBuf),
+}
+
+impl File {
+    fn of(entry: &EventEntry, path: &Path) -> File {
+        let field = |name| {
+            entry
+                .fields
+                .iter()
+                .find(|(k, _)| *k == name)
+                .map(|(_, v)| v)
+        };
+        match (field("dev"), field("ino")) {
+            (Some(dev), Some(ino)) => File::Inode(dev.clone(), ino.clone()),
+            _ => File::Path(path.to_path_buf()),
+        }
+    }
+}
+
+/// How many files may be open before those of processes that have exited
+/// since, whose close we missed, are forgotten.
+const SWEEP_AT: usize = 1024;
+
+/// Pairs FAN_OPEN with the FAN_CLOSE_* of the same file by the same pid.
+#[derive(Default)]
+pub struct Sessions {
+    open: HashMap<(Option<u32>, File), Vec<Open>>,
+    /// twice as many as were left the last time, to not go over them all
+    /// with every open
+    sweep_at: usize,
+}
+
+impl Sessions {
+    /// Feed an event, returns the session it completed if any.
+    pub fn record(&mut self, entry: &EventEntry, now: Instant) -> Option<Session> {
+        let path = entry.path.as_ref()?;
+        let key = (entry.pid, File::of(entry, path));
+
+        if entry.mask & libc::FAN_OPEN != 0 {
+            self.open.entry(key.clone()).or_default().push(Open {
+                at: now,
+                written: false,
+            });
+            if self.open.len() >= self.sweep_at.max(SWEEP_AT) {
+                self.sweep();
+            }
+        }
+        if entry.mask & libc::FAN_MODIFY != 0 {
+            if let Some(open) = self.open.get_mut(&key).and_then(|o| o.last_mut()) {
+                open.written = true;
+            }
+        }
+        if entry.mask & libc::FAN_CLOSE == 0 {
+            return None;
+        }
+
+        let open = match self.open.get_mut(&key) {
+            Some(opens) => {
+                let open = opens.pop();
+                if opens.is_empty() {
+                    self.open.remove(&key);
+                }
+                open
+            }
+            None => None,
+        };
+
+        Some(Session {
+            pid: entry.pid,
+            path: path.clone(),
+            duration: open.as_ref().map(|o| now.saturating_duration_since(o.at)),
+            written: entry.mask & libc::FAN_CLOSE_WRITE != 0
+                || open.map(|o| o.written).unwrap_or(false),
+        })
+    }
+
+    /// Forget the files of processes that are gone.
+    fn sweep(&mut self) {
+        let before = self.open.len();
+        self.open.retain(|(pid, _), _| {
+            pid.is_none_or(|pid| Path::new(&format!("/proc/{}", pid)).exists())
+        });
+        debug!(
+            "--sessions: forgot {} files of exited processes",
+            before - self.open.len()
+        );
+        self.sweep_at = self.open.len() * 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(mask: u64, pid: u32) -> EventEntry {
+        EventEntry {
+            pid: Some(pid),
+            ..EventEntry::test(mask, "/a")
+        }
+    }
+
+    #[test]
+    fn session_by_inode() {
+        let mut s = Sessions::default();
+        let t = Instant::now();
+        let inode = |mut e: EventEntry, path: &str| {
+            e.path = Some(path.into());
+            e.fields = vec![("dev", "8:1".into()), ("ino", "12".into())];
+            e
+        };
+
+        assert_eq!(
+            s.record(&inode(entry(libc::FAN_OPEN, 1), "/a.tmp"), t),
+            None
+        );
+        // renamed while open
+        let session = s
+            .record(
+                &inode(entry(libc::FAN_CLOSE_WRITE, 1), "/a"),
+                t + Duration::from_secs(1),
+            )
+            .unwrap();
+        assert_eq!(session.path, PathBuf::from("/a"));
+        assert_eq!(session.duration, Some(Duration::from_secs(1)));
+        assert!(s.open.is_empty());
+    }
+
+    #[test]
+    fn session_pairing() {
+        let mut s = Sessions::default();
+        let t = Instant::now();
+
+        assert_eq!(s.record(&entry(libc::FAN_OPEN, 1), t), None);
+        assert_eq!(s.record(&entry(libc::FAN_OPEN, 2), t), None);
+        assert_eq!(s.record(&entry(libc::FAN_MODIFY, 1), t), None);
+        assert_eq!(
+            s.record(
+                &entry(libc::FAN_CLOSE_NOWRITE, 1),
+                t + Duration::from_secs(2)
+            ),
+            Some(Session {
+                pid: Some(1),
+                path: "/a".into(),
+                duration: Some(Duration::from_secs(2)),
+                written: true,
+            })
+        );
+        assert!(
+            !s.record(
+                &entry(libc::FAN_CLOSE_NOWRITE, 2),
+                t + Duration::from_secs(1)
+            )
+            .unwrap()
+            .written
+        );
+        // opened before we started
+        assert_eq!(
+            s.record(&entry(libc::FAN_CLOSE_WRITE, 3), t)
+                .unwrap()
+                .duration,
+            None
+        );
+        // the kernel merged open and close into one event
+        assert_eq!(
+            s.record(&entry(libc::FAN_OPEN | libc::FAN_CLOSE_NOWRITE, 4), t)
+                .unwrap()
+                .duration,
+            Some(Duration::from_secs(0))
+        );
+        assert!(s.open.is_empty());
+    }
+
+    #[test]
+    fn session_sweep() {
+        let mut s = Sessions::default();
+        let t = Instant::now();
+        let open = |pid, path: String| EventEntry {
+            path: Some(path.into()),
+            ..entry(libc::FAN_OPEN, pid)
+        };
+
+        s.record(&open(std::process::id(), "/ours".into()), t);
+        // past pid_max, so long gone
+        for i in 1..SWEEP_AT {
+            s.record(&open(1 << 23, format!("/{}", i)), t);
+        }
+        assert_eq!(s.open.len(), 1);
+        assert_eq!(s.sweep_at, 2);
+    }
+}
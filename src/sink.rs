@@ -0,0 +1,92 @@
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+
+use crate::stats::Stats;
+
+/// Somewhere formatted events go.
+pub trait Sink {
+    /// Write one record, including its trailing newline.
+    fn send(&mut self, record: &[u8], stats: &mut Stats) -> io::Result<()>;
+
+    /// When the sink next needs `tick` to be called, if ever.
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    fn tick(&mut self, _stats: &mut Stats) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// An fd to poll, `tick` is called when it's readable.
+    fn fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// The file records are appended to, if that's where they go.
+    fn path(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Move on to another file now if the next `len` bytes would go in
+    /// one, returning whether it did.
+    fn roll(&mut self, _len: u64) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Write out what's buffered, or set it aside for the next run, as is
+    /// done on exit, staying usable after.
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Where --output sends events, other than stdout, --output-file and
+/// --forward.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Output {
+    Journald,
+    Sqlite(PathBuf),
+}
+
+impl FromStr for Output {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "journald" {
+            return Ok(Output::Journald);
+        }
+        match crate::sqlite::parse_addr(s) {
+            Some(db) => Ok(Output::Sqlite(db)),
+            None => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid value: {}, options: journald, sqlite:PATH", s),
+            )),
+        }
+    }
+}
+
+/// Open where a --tee sends events: - for stdout, journald, tcp:// or
+/// tls:// to forward to, or else a file to append to.
+pub fn open(dest: &str) -> io::Result<Box<dyn Sink>> {
+    Ok(match dest {
+        "-" => Box::new(StdoutSink),
+        "journald" => Box::new(crate::journald::JournaldSink::new()?),
+        _ if dest.starts_with("tcp://") || dest.starts_with("tls://") => {
+            Box::new(crate::forward::TcpSink::new(dest, None, None)?)
+        }
+        _ => Box::new(crate::logfile::FileSink::new(dest.into(), None, None)?),
+    })
+}
+
+pub struct StdoutSink;
+
+impl Sink for StdoutSink {
+    fn send(&mut self, record: &[u8], _stats: &mut Stats) -> io::Result<()> {
+        let mut out = io::stdout();
+        out.write_all(record)?;
+        out.flush()
+    }
+}
@@ -0,0 +1,159 @@
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::clock;
+
+// files waiting to be copied, past which the next are skipped rather than
+// have their fds held open
+const QUEUED: usize = 256;
+
+/// A file to copy, opened by fanotify for `path` when it was closed at
+/// `at`.
+struct Job {
+    file: File,
+    path: PathBuf,
+    at: SystemTime,
+}
+
+/// Keeps a timestamped copy of every file that's closed after writing,
+/// mirroring the original layout under `dir`, copied by a worker so a big
+/// one doesn't hold up the main loop.
+pub struct Snapshots {
+    dir: PathBuf,
+    tx: SyncSender<Job>,
+}
+
+/// /a/b written at `t` is saved as dir/a/b@YYYYmmddTHHMMSS.mmm
+fn target(dir: &Path, path: &Path, t: SystemTime) -> Option<PathBuf> {
+    let millis = t
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_millis();
+    let mut name = OsString::from(path.file_name()?);
+    name.push(format!(
+        "@{}.{:03}",
+        clock::strftime(t, "%Y%m%dT%H%M%S"),
+        millis
+    ));
+
+    let rel = path.strip_prefix("/").unwrap_or(path);
+    Some(dir.join(rel).with_file_name(name))
+}
+
+fn copy(dir: &Path, mut job: Job) -> io::Result<()> {
+    let target = match target(dir, &job.path, job.at) {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    job.file.seek(SeekFrom::Start(0))?;
+    io::copy(&mut job.file, &mut File::create(&target)?)?;
+    debug!("saved {:?} as {:?}", job.path, target);
+    Ok(())
+}
+
+impl Snapshots {
+    pub fn open(dir: &Path) -> io::Result<Snapshots> {
+        fs::create_dir_all(dir)?;
+        let dir = dir.canonicalize()?;
+        let (tx, rx) = mpsc::sync_channel::<Job>(QUEUED);
+        let to = dir.clone();
+        thread::Builder::new()
+            .name("snapshot".into())
+            .spawn(move || {
+                for job in rx {
+                    let path = job.path.clone();
+                    if let Err(e) = copy(&to, job) {
+                        warn!("snapshot {:?}: {}", path, e);
+                    }
+                }
+            })?;
+        Ok(Snapshots { dir, tx })
+    }
+
+    /// Copy the content of `file`, which was opened by fanotify for `path`,
+    /// closing it once it's done.
+    pub fn save(&self, file: File, path: &Path) {
+        if path.starts_with(&self.dir) {
+            // our own copies
+            return;
+        }
+        let job = Job {
+            file,
+            path: path.into(),
+            at: SystemTime::now(),
+        };
+        match self.tx.try_send(job) {
+            Ok(()) => (),
+            Err(TrySendError::Full(job)) => {
+                warn!(
+                    "snapshot {:?}: {} copies waiting, skipping it",
+                    job.path, QUEUED
+                )
+            }
+            Err(TrySendError::Disconnected(job)) => warn!("snapshot {:?}: no worker", job.path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn snapshot_target() {
+        let t = clock::parse_time("2020-04-01T13:05:06").unwrap() + Duration::from_millis(7);
+        assert_eq!(
+            target(Path::new("/snap"), Path::new("/a/b"), t).unwrap(),
+            Path::new("/snap/a/b@20200401T130506.007")
+        );
+        assert_eq!(target(Path::new("/snap"), Path::new("/"), t), None);
+    }
+
+    #[test]
+    fn snapshot_worker() -> io::Result<()> {
+        let dir = TempDir::new("snapshot")?;
+        let snapshots = Snapshots::open(&dir.join("snap"))?;
+        let path = fs::canonicalize(&*dir)?.join("a");
+        fs::write(&path, "hello\n")?;
+        snapshots.save(File::open(&path)?, &path);
+        // our own copies aren't copied again
+        snapshots.save(File::open(&path)?, &snapshots.dir.join("x"));
+
+        let copies = snapshots
+            .dir
+            .join(path.strip_prefix("/").unwrap().parent().unwrap());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let saved = loop {
+            let saved: Vec<_> = fs::read_dir(&copies)
+                .into_iter()
+                .flatten()
+                .collect::<io::Result<_>>()?;
+            if !saved.is_empty() {
+                break saved;
+            }
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(saved.len(), 1);
+        let name = saved[0].file_name();
+        assert!(name.to_str().unwrap().starts_with("a@"));
+        // written before it's named, so it may not be all there yet
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while fs::read(saved[0].path())? != b"hello\n" {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// A bounded on-disk queue of newline terminated records. Records left over
+/// from a previous run are kept and replayed as well.
+pub struct Spool {
+    path: PathBuf,
+    max: u64,
+    /// the size of the file, and how much of it has been replayed
+    len: u64,
+    head: u64,
+}
+
+impl Spool {
+    pub fn open(dir: &Path, max: u64) -> io::Result<Spool> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join("spool");
+        let len = match fs::metadata(&path) {
+            Ok(m) => m.len(),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+
+        Ok(Spool {
+            path,
+            max,
+            len,
+            head: 0,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head == self.len
+    }
+
+    /// Append `record`, returns false if the spool is full and the record
+    /// was dropped.
+    pub fn push(&mut self, record: &[u8]) -> io::Result<bool> {
+        if self.len - self.head + record.len() as u64 > self.max {
+            return Ok(false);
+        }
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(record)?;
+        self.len += record.len() as u64;
+        Ok(true)
+    }
+
+    /// Feed spooled records to `take` in order, until it returns false for
+    /// one it has no room for. The file is read a record at a time and what
+    /// was taken isn't fed again.
+    pub fn replay(&mut self, take: &mut dyn FnMut(&[u8]) -> bool) -> io::Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        let mut file = BufReader::new(File::open(&self.path)?);
+        file.seek(SeekFrom::Start(self.head))?;
+        let mut record = Vec::new();
+        while self.head < self.len {
+            record.clear();
+            if file.read_until(b'\n', &mut record)? == 0 {
+                // shorter than we thought, nothing more to replay
+                break;
+            }
+            if !take(&record) {
+                // so the file doesn't keep growing while we're behind
+                if self.head * 2 >= self.len {
+                    self.compact()?;
+                }
+                return Ok(());
+            }
+            self.head += record.len() as u64;
+        }
+
+        fs::remove_file(&self.path)?;
+        self.len = 0;
+        self.head = 0;
+        Ok(())
+    }
+
+    /// Drop what's been replayed from the file now, so the next run
+    /// doesn't replay them again.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.head != 0 {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Drop what's been replayed from the file.
+    fn compact(&mut self) -> io::Result<()> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.head))?;
+        let tmp = self.path.with_extension("tmp");
+        io::copy(&mut file, &mut File::create(&tmp)?)?;
+        fs::rename(&tmp, &self.path)?;
+        self.len -= self.head;
+        self.head = 0;
+        Ok(())
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            warn!("{:?}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+
+    #[test]
+    fn spool_replay() -> io::Result<()> {
+        let dir = TempDir::new("spool")?;
+        let mut spool = Spool::open(&dir, 10)?;
+
+        assert!(spool.push(b"aaa\n")?);
+        assert!(spool.push(b"bbb\n")?);
+        assert!(!spool.push(b"ccc\n")?);
+
+        let mut got = vec![];
+        spool.replay(&mut |r| {
+            if !got.is_empty() {
+                return false;
+            }
+            got.push(r.to_vec());
+            true
+        })?;
+        assert_eq!(got, vec![b"aaa\n".to_vec()]);
+        assert!(!spool.is_empty());
+        // room again for what was taken
+        assert!(spool.push(b"ccc\n")?);
+
+        // survives reopening, without what was taken, as by a process
+        // exec'd before this one is dropped
+        spool.sync()?;
+        let mut reopened = Spool::open(&dir, 10)?;
+        reopened.replay(&mut |r| {
+            got.push(r.to_vec());
+            true
+        })?;
+        assert_eq!(
+            got,
+            vec![b"aaa\n".to_vec(), b"bbb\n".to_vec(), b"ccc\n".to_vec()]
+        );
+        assert!(reopened.is_empty());
+        drop(spool);
+        assert!(!dir.join("spool").exists());
+
+        Ok(())
+    }
+}
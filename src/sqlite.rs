@@ -0,0 +1,293 @@
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rusqlite::types::ToSql;
+use rusqlite::{params, Connection, OpenFlags};
+
+use crate::clock::Timestamp;
+use crate::EventEntry;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS events (
+        id INTEGER PRIMARY KEY,
+        -- milliseconds since the epoch
+        time INTEGER NOT NULL,
+        mask INTEGER NOT NULL,
+        pid INTEGER,
+        comm TEXT,
+        path TEXT,
+        dev TEXT,
+        ino INTEGER,
+        -- the other --enrich fields, as tab separated NAME=VALUE
+        fields TEXT
+    );
+    CREATE INDEX IF NOT EXISTS events_time ON events (time);
+    CREATE INDEX IF NOT EXISTS events_path ON events (path, time);
+    CREATE INDEX IF NOT EXISTS events_pid ON events (pid, time);
+";
+
+// commit after this many events or this long, whichever comes first
+const BATCH_SIZE: usize = 1000;
+const BATCH_DELAY: Duration = Duration::from_secs(1);
+
+fn db_err(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+fn millis(t: SystemTime) -> i64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+}
+
+/// The database in a `sqlite:PATH` --output.
+pub fn parse_addr(s: &str) -> Option<PathBuf> {
+    s.strip_prefix("sqlite:")
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Inserts events into a SQLite database, a transaction per batch.
+pub struct Store {
+    conn: Connection,
+    batch: usize,
+    /// when the open transaction started
+    since: Option<Instant>,
+}
+
+impl Store {
+    pub fn open(path: &Path) -> io::Result<Store> {
+        let conn = Connection::open(path).map_err(db_err)?;
+        // so queries can run while we write
+        conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))
+            .map_err(db_err)?;
+        conn.execute_batch(SCHEMA).map_err(db_err)?;
+        Ok(Store {
+            conn,
+            batch: 0,
+            since: None,
+        })
+    }
+
+    pub fn record(&mut self, entry: &EventEntry, now: SystemTime) -> io::Result<()> {
+        if self.since.is_none() {
+            self.conn.execute_batch("BEGIN").map_err(db_err)?;
+            self.since = Some(Instant::now());
+        }
+
+        let (mut comm, mut dev, mut ino) = (None, None, None);
+        let mut rest = Vec::new();
+        for (k, v) in &entry.fields {
+            match *k {
+                "comm" => comm = Some(v),
+                "dev" => dev = Some(v),
+                "ino" => ino = v.parse::<i64>().ok(),
+                _ => rest.push(format!("{}={}", k, v)),
+            }
+        }
+        self.conn
+            .prepare_cached(
+                "INSERT INTO events (time, mask, pid, comm, path, dev, ino, fields)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )
+            .and_then(|mut insert| {
+                insert.execute(params![
+                    millis(now),
+                    entry.mask as i64,
+                    entry.pid,
+                    comm,
+                    entry.path.as_ref().map(|p| p.to_string_lossy()),
+                    dev,
+                    ino,
+                    if rest.is_empty() {
+                        None
+                    } else {
+                        Some(rest.join("\t"))
+                    },
+                ])
+            })
+            .map_err(db_err)?;
+
+        self.batch += 1;
+        if self.batch >= BATCH_SIZE {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.since.map(|t| t + BATCH_DELAY)
+    }
+
+    pub fn commit(&mut self) -> io::Result<()> {
+        if self.since.take().is_some() {
+            self.batch = 0;
+            self.conn.execute_batch("COMMIT").map_err(db_err)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        if let Err(e) = self.commit() {
+            error!("sqlite: {}", e);
+        }
+    }
+}
+
+/// What `query` looks for, everything if empty.
+#[derive(Default)]
+pub struct Query {
+    /// a GLOB pattern
+    pub path: Option<String>,
+    pub pid: Option<u32>,
+    pub comm: Option<String>,
+    /// events with any of these bits
+    pub mask: Option<u64>,
+    pub since: Option<SystemTime>,
+    pub until: Option<SystemTime>,
+    /// only the latest this many
+    pub limit: Option<u64>,
+}
+
+/// Print the events in `db` matching `q`, oldest first, as the time and
+/// then the event like we would've printed it. Returns how many there were.
+pub fn query(db: &Path, q: &Query, w: &mut dyn Write) -> io::Result<u64> {
+    let conn = Connection::open_with_flags(db, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(db_err)?;
+
+    let mut cond = vec!["1"];
+    let mut args: Vec<Box<dyn ToSql>> = Vec::new();
+    if let Some(path) = &q.path {
+        cond.push("path GLOB ?");
+        args.push(Box::new(path.clone()));
+    }
+    if let Some(pid) = q.pid {
+        cond.push("pid = ?");
+        args.push(Box::new(pid));
+    }
+    if let Some(comm) = &q.comm {
+        cond.push("comm = ?");
+        args.push(Box::new(comm.clone()));
+    }
+    if let Some(mask) = q.mask {
+        cond.push("mask & ? != 0");
+        args.push(Box::new(mask as i64));
+    }
+    if let Some(since) = q.since {
+        cond.push("time >= ?");
+        args.push(Box::new(millis(since)));
+    }
+    if let Some(until) = q.until {
+        cond.push("time < ?");
+        args.push(Box::new(millis(until)));
+    }
+    let sql = format!(
+        "SELECT * FROM (
+             SELECT id, time, mask, pid, comm, path, dev, ino, fields FROM events
+             WHERE {} ORDER BY time DESC, id DESC LIMIT {}
+         ) ORDER BY time, id",
+        cond.join(" AND "),
+        q.limit.map_or(-1, |n| n as i64)
+    );
+
+    let mut stmt = conn.prepare(&sql).map_err(db_err)?;
+    let mut rows = stmt
+        .query(rusqlite::params_from_iter(args.iter()))
+        .map_err(db_err)?;
+    let mut n = 0;
+    while let Some(row) = rows.next().map_err(db_err)? {
+        let get = |row: &rusqlite::Row| -> rusqlite::Result<_> {
+            let mut fields = Vec::new();
+            if let Some(comm) = row.get::<_, Option<String>>(4)? {
+                fields.push(("comm", comm));
+            }
+            if let Some(dev) = row.get::<_, Option<String>>(6)? {
+                fields.push(("dev", dev));
+            }
+            if let Some(ino) = row.get::<_, Option<i64>>(7)? {
+                fields.push(("ino", ino.to_string()));
+            }
+            let entry = EventEntry {
+                mask: row.get::<_, i64>(2)? as u64,
+                fd: None,
+                pid: row.get(3)?,
+                path: row.get::<_, Option<String>>(5)?.map(PathBuf::from),
+                fields,
+            };
+            let time = UNIX_EPOCH + Duration::from_millis(row.get::<_, i64>(1)? as u64);
+            Ok((time, entry, row.get::<_, Option<String>>(8)?))
+        };
+        let (time, entry, rest) = get(row).map_err(db_err)?;
+
+        w.write_fmt(format_args!(
+            "{}\t",
+            Timestamp::Iso8601.format(time, Duration::default())
+        ))?;
+        entry.write_to(w)?;
+        if let Some(rest) = rest {
+            w.write_fmt(format_args!("\t{}", rest))?;
+        }
+        w.write_all(b"\n")?;
+        n += 1;
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+
+    #[test]
+    fn sqlite_store_query() -> io::Result<()> {
+        let dir = TempDir::new("sqlite")?;
+        let db = dir.join("events.db");
+        let t = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let entry = |mask, pid, path: &str, comm: &str| EventEntry {
+            fd: Some(3),
+            pid: Some(pid),
+            fields: vec![("comm", comm.into()), ("exe", format!("/bin/{}", comm))],
+            ..EventEntry::test(mask, path)
+        };
+
+        let mut store = Store::open(&db)?;
+        store.record(&entry(libc::FAN_OPEN, 1, "/etc/passwd", "cat"), t)?;
+        store.record(
+            &entry(libc::FAN_MODIFY, 2, "/etc/passwd", "vi"),
+            t + Duration::from_secs(60),
+        )?;
+        store.record(
+            &entry(libc::FAN_MODIFY, 2, "/tmp/x", "vi"),
+            t + Duration::from_secs(120),
+        )?;
+        drop(store);
+
+        let run = |q: &Query| -> io::Result<String> {
+            let mut out = Vec::new();
+            query(&db, q, &mut out)?;
+            Ok(String::from_utf8(out).unwrap())
+        };
+        let out = run(&Query {
+            path: Some("/etc/*".into()),
+            mask: Some(libc::FAN_MODIFY),
+            ..Default::default()
+        })?;
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.ends_with("\tFAN_MODIFY\t-\t2\t/etc/passwd\tcomm=vi\texe=/bin/vi\n"));
+
+        let out = run(&Query {
+            comm: Some("vi".into()),
+            limit: Some(1),
+            ..Default::default()
+        })?;
+        assert!(out.ends_with("\t/tmp/x\tcomm=vi\texe=/bin/vi\n"));
+
+        let out = run(&Query {
+            until: Some(t + Duration::from_secs(60)),
+            ..Default::default()
+        })?;
+        assert!(out.contains("FAN_OPEN\t-\t1\t/etc/passwd"));
+        assert_eq!(out.lines().count(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Write};
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+use crate::histogram::Histogram;
+
+/// Counters for everything we see and every place an event can get lost, so
+/// that a quiet stream can be told apart from a lossy one.
+#[derive(Default, Debug)]
+pub struct Stats {
+    /// events read from the kernel, including ones dropped later
+    pub events: u64,
+    /// FAN_Q_OVERFLOW received, the kernel queue was full and dropped events
+    pub lost_kernel_overflow: u64,
+    /// events that could not be written out
+    pub lost_sink_error: u64,
+    /// events dropped because the spill queue was full
+    pub lost_spool_full: u64,
+    /// events spilled to disk while the sink was down
+    pub spilled: u64,
+    /// events reported without a path because --enrich-rate ran out
+    pub enrich_skipped: u64,
+    /// permission events answered with --overload-response
+    pub perm_overload: u64,
+    /// permission events answered with --perm-default
+    pub perm_timed_out: u64,
+    /// events not sent to --webhook because it fell behind
+    pub webhook_dropped: u64,
+    /// log records and spans not sent to --otlp because it fell behind
+    pub otlp_dropped: u64,
+    /// permission events --grpc failed to decide
+    pub grpc_failed: u64,
+    /// executables not in --exec-allowlist
+    pub exec_unknown: u64,
+    /// files --scan-cmd failed to scan
+    pub scan_failed: u64,
+    /// events for --canary paths
+    pub canary_hits: u64,
+    /// permission events over --perm-rate
+    pub perm_throttled: u64,
+    /// repeats of the event before dropped by --dedup
+    pub deduped: u64,
+    /// events over --max-events-per-sec or --rate-limit
+    pub rate_limited: u64,
+    /// files --hash left out for being too big or failing to read
+    pub hash_skipped: u64,
+    /// time spent in each --enrich step
+    pub enrich_time: Vec<(&'static str, Duration)>,
+    /// microseconds between events of each type
+    intervals: BTreeMap<String, (Instant, Histogram)>,
+    /// microseconds from reading a permission event to answering it
+    decisions: BTreeMap<String, Histogram>,
+    undecided: HashMap<RawFd, (String, Instant)>,
+}
+
+impl Stats {
+    /// Events that never made it out, to wherever they were going.
+    pub fn lost(&self) -> u64 {
+        self.lost_kernel_overflow
+            + self.lost_sink_error
+            + self.lost_spool_full
+            + self.webhook_dropped
+            + self.otlp_dropped
+            + self.rate_limited
+    }
+
+    pub fn time_enrich(&mut self, step: &'static str, d: Duration) {
+        match self.enrich_time.iter_mut().find(|(s, _)| *s == step) {
+            Some((_, total)) => *total += d,
+            None => self.enrich_time.push((step, d)),
+        }
+    }
+
+    pub fn time_event(&mut self, kind: &str, now: Instant) {
+        match self.intervals.get_mut(kind) {
+            Some((last, hist)) => {
+                hist.record(now.saturating_duration_since(*last).as_micros() as u64);
+                *last = now;
+            }
+            None => {
+                self.intervals
+                    .insert(kind.into(), (now, Histogram::default()));
+            }
+        }
+    }
+
+    /// Start timing the decision on the permission event for `fd`.
+    pub fn time_perm(&mut self, fd: RawFd, kind: &str, now: Instant) {
+        // the fd was reused, so the last one with it was answered
+        if let Some((kind, since)) = self.undecided.remove(&fd) {
+            self.decided(kind, since, now);
+        }
+        self.undecided.insert(fd, (kind.into(), now));
+    }
+
+    /// Stop timing the permission events that are no longer `pending`.
+    pub fn time_answered(&mut self, pending: &HashSet<RawFd>, now: Instant) {
+        let answered: Vec<RawFd> = self
+            .undecided
+            .keys()
+            .filter(|fd| !pending.contains(fd))
+            .cloned()
+            .collect();
+        for fd in answered {
+            let (kind, since) = self.undecided.remove(&fd).unwrap();
+            self.decided(kind, since, now);
+        }
+    }
+
+    fn decided(&mut self, kind: String, since: Instant, now: Instant) {
+        self.decisions
+            .entry(kind)
+            .or_default()
+            .record(now.saturating_duration_since(since).as_micros() as u64);
+    }
+
+    pub fn write_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_fmt(format_args!(
+            "events\t{}\n\
+             lost\t{}\n\
+             lost_kernel_overflow\t{}\n\
+             lost_sink_error\t{}\n\
+             lost_spool_full\t{}\n\
+             spilled\t{}\n\
+             enrich_skipped\t{}\n\
+             perm_overload\t{}\n",
+            self.events,
+            self.lost(),
+            self.lost_kernel_overflow,
+            self.lost_sink_error,
+            self.lost_spool_full,
+            self.spilled,
+            self.enrich_skipped,
+            self.perm_overload,
+        ))?;
+        if self.webhook_dropped != 0 {
+            w.write_fmt(format_args!("webhook_dropped\t{}\n", self.webhook_dropped))?;
+        }
+        if self.otlp_dropped != 0 {
+            w.write_fmt(format_args!("otlp_dropped\t{}\n", self.otlp_dropped))?;
+        }
+        if self.perm_timed_out != 0 {
+            w.write_fmt(format_args!("perm_timed_out\t{}\n", self.perm_timed_out))?;
+        }
+        if self.grpc_failed != 0 {
+            w.write_fmt(format_args!("grpc_failed\t{}\n", self.grpc_failed))?;
+        }
+        if self.exec_unknown != 0 {
+            w.write_fmt(format_args!("exec_unknown\t{}\n", self.exec_unknown))?;
+        }
+        if self.scan_failed != 0 {
+            w.write_fmt(format_args!("scan_failed\t{}\n", self.scan_failed))?;
+        }
+        if self.canary_hits != 0 {
+            w.write_fmt(format_args!("canary_hits\t{}\n", self.canary_hits))?;
+        }
+        if self.perm_throttled != 0 {
+            w.write_fmt(format_args!("perm_throttled\t{}\n", self.perm_throttled))?;
+        }
+        if self.deduped != 0 {
+            w.write_fmt(format_args!("deduped\t{}\n", self.deduped))?;
+        }
+        if self.rate_limited != 0 {
+            w.write_fmt(format_args!("rate_limited\t{}\n", self.rate_limited))?;
+        }
+        if self.hash_skipped != 0 {
+            w.write_fmt(format_args!("hash_skipped\t{}\n", self.hash_skipped))?;
+        }
+        for (step, d) in &self.enrich_time {
+            w.write_fmt(format_args!("enrich_{}_us\t{}\n", step, d.as_micros()))?;
+        }
+        // an interval needs two events
+        for (kind, (_, hist)) in self.intervals.iter().filter(|(_, (_, h))| !h.is_empty()) {
+            w.write_fmt(format_args!("interval_{}_us\t{}\n", kind, hist))?;
+        }
+        for (kind, hist) in &self.decisions {
+            w.write_fmt(format_args!("decision_{}_us\t{}\n", kind, hist))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_display() -> io::Result<()> {
+        let mut buf = vec![];
+        Stats {
+            events: 10,
+            lost_kernel_overflow: 1,
+            lost_sink_error: 2,
+            lost_spool_full: 3,
+            webhook_dropped: 4,
+            otlp_dropped: 5,
+            rate_limited: 6,
+            ..Default::default()
+        }
+        .write_to(&mut buf)?;
+
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "events\t10\nlost\t21\nlost_kernel_overflow\t1\nlost_sink_error\t2\n\
+             lost_spool_full\t3\nspilled\t0\nenrich_skipped\t0\nperm_overload\t0\n\
+             webhook_dropped\t4\notlp_dropped\t5\nrate_limited\t6\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stats_decisions() {
+        let mut stats = Stats::default();
+        let t = Instant::now();
+        let mut pending = HashSet::new();
+
+        stats.time_perm(5, "FAN_OPEN_PERM", t);
+        pending.insert(5);
+        stats.time_answered(&pending, t + Duration::from_micros(10));
+        assert!(stats.decisions.is_empty());
+
+        pending.remove(&5);
+        stats.time_answered(&pending, t + Duration::from_micros(20));
+        // answered and reused without a chance to notice
+        stats.time_perm(6, "FAN_OPEN_PERM", t);
+        stats.time_perm(6, "FAN_ACCESS_PERM", t + Duration::from_micros(3));
+
+        assert_eq!(
+            stats.decisions["FAN_OPEN_PERM"].to_string(),
+            "n=2 p50=3 p90=20 p99=20 max=20"
+        );
+        assert_eq!(stats.undecided.len(), 1);
+    }
+}
@@ -0,0 +1,159 @@
+use std::io::{self, ErrorKind};
+use std::time::Instant;
+
+use crate::csv::{Csv, Format};
+use crate::expect::Rule;
+use crate::journald::Journald;
+use crate::sink::{self, Sink};
+use crate::stats::Stats;
+use crate::{send_record, write_event, EventEntry, EventFormat};
+
+/// An output from --tee, with a format and filter of its own.
+struct Tee {
+    sink: Box<dyn Sink>,
+    /// none for the default one
+    format: Option<Box<dyn EventFormat>>,
+    /// only the events this matches, all of them without it
+    rule: Option<Rule>,
+}
+
+/// Parse FORMAT:DEST [GLOB [MASK]].
+fn parse(spec: &str) -> io::Result<(Format, &str, Option<Rule>)> {
+    let (format, rest) = spec.split_once(':').ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{}: expected FORMAT:DEST [GLOB [MASK]]", spec),
+        )
+    })?;
+    let (dest, rule) = match rest.split_once(char::is_whitespace) {
+        Some((dest, rule)) if !rule.trim().is_empty() => (dest, Some(Rule::parse(rule.trim())?)),
+        _ => (rest.trim_end(), None),
+    };
+    if dest.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{}: no DEST", spec),
+        ));
+    }
+    Ok((format.parse()?, dest, rule))
+}
+
+/// The outputs events go to besides the main one, each getting the events
+/// before --timestamp, --seq and --max-events.
+#[derive(Default)]
+pub struct Tees(Vec<Tee>);
+
+impl Tees {
+    /// `fields` are the ones from --enrich and such, for csv.
+    pub fn open(
+        specs: &[String],
+        fields: &[&'static str],
+        csv_header: bool,
+        stats: &mut Stats,
+    ) -> io::Result<Tees> {
+        let mut tees = Vec::new();
+        for spec in specs {
+            let (format, dest, rule) = parse(spec)?;
+            let mut sink = sink::open(dest)?;
+            let format: Option<Box<dyn EventFormat>> = if dest == "journald" {
+                Some(Box::new(Journald))
+            } else if format == Format::Csv && csv_header {
+                let csv = Csv::new(fields.to_vec());
+                let mut record = Vec::new();
+                csv.write_header(&mut record)?;
+                record.push(b'\n');
+                send_record(&record, sink.as_mut(), stats)?;
+                Some(Box::new(csv))
+            } else {
+                format.event_format(fields)
+            };
+            tees.push(Tee { sink, format, rule });
+        }
+        Ok(Tees(tees))
+    }
+
+    pub fn record(&mut self, entry: &EventEntry, stats: &mut Stats) -> io::Result<()> {
+        for tee in &mut self.0 {
+            if tee.rule.as_ref().is_none_or(|r| r.matches(entry)) {
+                write_event(entry, &[], tee.format.as_deref(), tee.sink.as_mut(), stats)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.0.iter().filter_map(|t| t.sink.deadline()).min()
+    }
+
+    pub fn tick(&mut self, stats: &mut Stats) -> io::Result<()> {
+        let now = Instant::now();
+        for tee in &mut self.0 {
+            if tee.sink.deadline().is_some_and(|t| now >= t) {
+                tee.sink.tick(stats)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        for tee in &mut self.0 {
+            tee.sink.sync()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+    use std::fs;
+
+    #[test]
+    fn tee_spec() -> io::Result<()> {
+        let (format, dest, rule) = parse("json:tcp://[::1]:514 /etc/* FAN_MODIFY")?;
+        assert_eq!((format, dest), (Format::Json, "tcp://[::1]:514"));
+        let rule = rule.unwrap();
+        let entry = |mask, path: &str| EventEntry {
+            pid: None,
+            ..EventEntry::test(mask, path)
+        };
+        assert!(rule.matches(&entry(libc::FAN_MODIFY, "/etc/passwd")));
+        assert!(!rule.matches(&entry(libc::FAN_OPEN, "/etc/passwd")));
+        let (format, dest, rule) = parse("tab:-")?;
+        assert_eq!((format, dest, rule.is_none()), (Format::Tab, "-", true));
+        assert!(parse("json").is_err());
+        assert!(parse("xml:-").is_err());
+        assert!(parse("json: /etc/*").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn tee_filter() -> io::Result<()> {
+        let dir = TempDir::new("tee")?;
+        let (all, etc) = (dir.join("all.csv"), dir.join("etc.json"));
+        let specs = [
+            format!("csv:{}", all.display()),
+            format!("json:{} /etc/*", etc.display()),
+        ];
+        let mut stats = Stats::default();
+        let mut tees = Tees::open(&specs, &["comm"], true, &mut stats)?;
+        let entry = |path: &str| EventEntry {
+            fields: vec![("comm", "cat".into())],
+            ..EventEntry::test(libc::FAN_OPEN, path)
+        };
+        tees.record(&entry("/etc/passwd"), &mut stats)?;
+        tees.record(&entry("/tmp/x"), &mut stats)?;
+        drop(tees);
+
+        assert_eq!(
+            fs::read_to_string(&all)?,
+            "mask,fd,pid,path,comm\nFAN_OPEN,,1,/etc/passwd,cat\nFAN_OPEN,,1,/tmp/x,cat\n"
+        );
+        assert_eq!(
+            fs::read_to_string(&etc)?,
+            "{\"mask\":\"FAN_OPEN\",\"pid\":1,\"path\":\"/etc/passwd\",\"comm\":\"cat\"}\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::time::SystemTime;
+
+use crate::clock;
+use crate::{EventEntry, EventFormat};
+
+#[derive(Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Field(&'static str),
+}
+
+/// A --format like "{time} {comm} {mask} {path}".
+#[derive(Debug, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+    time_format: String,
+}
+
+/// The mask like inotifywait has it, CLOSE_WRITE,ISDIR and so on.
+fn inotify_events(entry: &EventEntry) -> String {
+    entry
+        .mask_names()
+        .split('|')
+        .filter(|m| *m != "FAN_EVENT_ON_CHILD")
+        .map(|m| match m.strip_prefix("FAN_") {
+            Some("ONDIR") => "ISDIR",
+            Some(m) => m,
+            None => m,
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+impl Template {
+    /// `fields` are the placeholders events can fill in, {time} is
+    /// formatted with strftime `time_format`.
+    pub fn parse(s: &str, fields: &[&'static str], time_format: &str) -> io::Result<Template> {
+        let invalid = |what: String| io::Error::new(ErrorKind::InvalidInput, what);
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' => {
+                    let name: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                    if name.is_empty() {
+                        // {} is a literal {
+                        text.push('{');
+                        continue;
+                    }
+                    let field = fields.iter().find(|f| **f == name).ok_or_else(|| {
+                        invalid(format!(
+                            "--format: unknown field {{{}}}, options: {}",
+                            name,
+                            fields.join(", ")
+                        ))
+                    })?;
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Field(field));
+                }
+                '\\' => match chars.next() {
+                    Some('t') => text.push('\t'),
+                    Some('n') => text.push('\n'),
+                    Some(c) => text.push(c),
+                    None => text.push('\\'),
+                },
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Template {
+            parts,
+            time_format: time_format.into(),
+        })
+    }
+}
+
+impl EventFormat for Template {
+    fn write_event(&self, entry: &EventEntry, w: &mut dyn Write) -> io::Result<()> {
+        for part in &self.parts {
+            match part {
+                Part::Text(s) => w.write_all(s.as_bytes())?,
+                Part::Field("mask") => w.write_all(entry.mask_names().as_bytes())?,
+                Part::Field("fd") => {
+                    w.write_all(EventEntry::display_field(&entry.fd).as_bytes())?
+                }
+                Part::Field("pid") => {
+                    w.write_all(EventEntry::display_field(&entry.pid).as_bytes())?
+                }
+                Part::Field("path") => match &entry.path {
+                    Some(path) => w.write_all(path.as_os_str().as_bytes())?,
+                    None => w.write_all(b"-")?,
+                },
+                Part::Field("time") => {
+                    w.write_all(clock::strftime(SystemTime::now(), &self.time_format).as_bytes())?
+                }
+                Part::Field("dir") => {
+                    // with the trailing / like inotifywait's %w
+                    if let Some(dir) = entry.path.as_ref().and_then(|p| p.parent()) {
+                        w.write_all(dir.as_os_str().as_bytes())?;
+                        if dir.parent().is_some() {
+                            w.write_all(b"/")?;
+                        }
+                    }
+                }
+                Part::Field("name") => {
+                    if let Some(name) = entry.path.as_ref().and_then(|p| p.file_name()) {
+                        w.write_all(name.as_bytes())?;
+                    }
+                }
+                Part::Field("events") => w.write_all(inotify_events(entry).as_bytes())?,
+                Part::Field(name) => match entry.fields.iter().find(|(k, _)| k == name) {
+                    Some((_, v)) => w.write_all(v.as_bytes())?,
+                    None => w.write_all(b"-")?,
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIELDS: &[&str] = &[
+        "mask", "fd", "pid", "path", "time", "comm", "dir", "name", "events",
+    ];
+
+    #[test]
+    fn template_parse() {
+        assert_eq!(
+            Template::parse("{comm}\\t{path} {}x", FIELDS, "")
+                .unwrap()
+                .parts,
+            vec![
+                Part::Field("comm"),
+                Part::Text("\t".into()),
+                Part::Field("path"),
+                Part::Text(" {x".into()),
+            ]
+        );
+        assert!(Template::parse("{exe}", FIELDS, "").is_err());
+    }
+
+    #[test]
+    fn template_write() -> io::Result<()> {
+        let t = Template::parse("{mask} {pid} {comm}: {path} {dir}|{name}", FIELDS, "")?;
+        let mut buf = vec![];
+        t.write_event(
+            &EventEntry {
+                fd: Some(5),
+                pid: Some(7),
+                ..EventEntry::test(libc::FAN_OPEN | libc::FAN_ONDIR, "/a/b c")
+            },
+            &mut buf,
+        )?;
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "FAN_OPEN|FAN_ONDIR 7 -: /a/b c /a/|b c"
+        );
+
+        let t = Template::parse("{dir} {events} {name}", FIELDS, "")?;
+        let mut buf = vec![];
+        t.write_event(
+            &EventEntry {
+                pid: None,
+                ..EventEntry::test(
+                    libc::FAN_CLOSE_WRITE | libc::FAN_ONDIR | libc::FAN_EVENT_ON_CHILD,
+                    "/b",
+                )
+            },
+            &mut buf,
+        )?;
+        assert_eq!(String::from_utf8(buf).unwrap(), "/ CLOSE_WRITE,ISDIR b");
+        Ok(())
+    }
+}
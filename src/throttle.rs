@@ -0,0 +1,216 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::os::unix::io::RawFd;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::limits::TokenBucket;
+use crate::{policy, EventEntry};
+
+// processes to keep budgets for, they're forgotten all at once past this
+const BUCKETS_LEN: usize = 10000;
+
+/// What --perm-rate counts permission events by.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateBy {
+    Pid,
+    Comm,
+}
+
+impl FromStr for RateBy {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pid" => Ok(RateBy::Pid),
+            "comm" => Ok(RateBy::Comm),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid value: {}, options: pid, comm", s),
+            )),
+        }
+    }
+}
+
+/// What --perm-rate does with the permission events past the budget.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverRate {
+    /// hold them until there's budget again
+    Delay,
+    Deny,
+}
+
+impl FromStr for OverRate {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "delay" => Ok(OverRate::Delay),
+            "deny" => Ok(OverRate::Deny),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid value: {}, options: delay, deny", s),
+            )),
+        }
+    }
+}
+
+/// A permission event held back, to be decided as if it was just read.
+pub struct Held {
+    pub fd: RawFd,
+    pub entry: EventEntry,
+    pub pidfd: Option<File>,
+    pub timestamp: Option<String>,
+    /// or only decided, for one the filters left out
+    pub report: bool,
+}
+
+/// A token bucket for each process, or each command, so one opening
+/// thousands of files a second gets only so many of them through.
+pub struct Throttle {
+    rate: u32,
+    by: RateBy,
+    buckets: HashMap<String, TokenBucket>,
+    /// the ones over budget, oldest first
+    held: VecDeque<(String, Held)>,
+    /// the processes over budget, to warn once each time they get there
+    over: HashSet<String>,
+}
+
+impl Throttle {
+    pub fn new(rate: u32, by: RateBy) -> Throttle {
+        Throttle {
+            rate,
+            by,
+            buckets: HashMap::new(),
+            held: VecDeque::new(),
+            over: HashSet::new(),
+        }
+    }
+
+    fn key(&self, entry: &EventEntry) -> String {
+        match self.by {
+            RateBy::Pid => EventEntry::display_field(&entry.pid),
+            RateBy::Comm => policy::comm(entry).unwrap_or_else(|| "?".into()),
+        }
+    }
+
+    fn take(&mut self, key: &str) -> bool {
+        if self.buckets.len() >= BUCKETS_LEN && !self.buckets.contains_key(key) {
+            self.buckets.clear();
+        }
+        let rate = self.rate;
+        let ok = self
+            .buckets
+            .entry(key.into())
+            .or_insert_with(|| TokenBucket::new(rate))
+            .take();
+        if ok {
+            self.over.remove(key);
+        } else if self.over.insert(key.into()) {
+            warn!("--perm-rate: {} {} is over budget", self.by_name(), key);
+        }
+        ok
+    }
+
+    fn by_name(&self) -> &'static str {
+        match self.by {
+            RateBy::Pid => "pid",
+            RateBy::Comm => "comm",
+        }
+    }
+
+    /// Whether the permission event for `fd` is within budget, using it up
+    /// if so.
+    pub fn admit(&mut self, fd: RawFd, entry: &EventEntry) -> bool {
+        // the fd was reused, so the last event with it was answered
+        self.held.retain(|(_, h)| h.fd != fd);
+        let key = self.key(entry);
+        // behind the ones held already, in order
+        if self.held.iter().any(|(k, _)| *k == key) {
+            return false;
+        }
+        self.take(&key)
+    }
+
+    pub fn hold(&mut self, held: Held) {
+        let key = self.key(&held.entry);
+        self.held.push_back((key, held));
+    }
+
+    /// The held events within budget now, dropping those `pending` no
+    /// longer has, answered by --perm-timeout or the like.
+    pub fn release(&mut self, pending: &HashSet<RawFd>) -> Vec<Held> {
+        let mut released = Vec::new();
+        let mut still = VecDeque::new();
+        let mut blocked = HashSet::new();
+        for (key, held) in self.held.split_off(0) {
+            if !pending.contains(&held.fd) {
+                debug!("--perm-rate: dropping answered {:?}", held.entry.path);
+            } else if blocked.contains(&key) || !self.take(&key) {
+                blocked.insert(key.clone());
+                still.push_back((key, held));
+            } else {
+                released.push(held);
+            }
+        }
+        self.held = still;
+        released
+    }
+
+    /// When a held event may be within budget, about when one token more
+    /// is.
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.held.is_empty() {
+            None
+        } else {
+            Some(Instant::now() + Duration::from_secs(1) / self.rate.max(1))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn throttle_budget() {
+        let mut throttle = Throttle::new(10, RateBy::Pid);
+        let entry = |pid| EventEntry {
+            pid: Some(pid),
+            path: None,
+            ..EventEntry::test(libc::FAN_OPEN_PERM, "")
+        };
+        let held = |fd| Held {
+            fd,
+            entry: entry(42),
+            pidfd: None,
+            timestamp: None,
+            report: true,
+        };
+        let mut fd = 100;
+        while throttle.admit(fd, &entry(42)) {
+            fd += 1;
+        }
+        assert_eq!(fd, 110);
+        // someone else's budget is their own
+        assert!(throttle.admit(200, &entry(43)));
+
+        throttle.hold(held(110));
+        throttle.hold(held(111));
+        throttle.hold(held(112));
+        assert!(throttle.deadline().is_some());
+        // and the held ones go first
+        thread::sleep(Duration::from_millis(120));
+        assert!(!throttle.admit(113, &entry(42)));
+        let pending: HashSet<RawFd> = [110, 112].iter().cloned().collect();
+        let released = throttle.release(&pending);
+        assert_eq!(released.iter().map(|h| h.fd).collect::<Vec<_>>(), vec![110]);
+        assert_eq!(throttle.held.len(), 1);
+        // the fd was reused, so it was answered
+        throttle.admit(112, &entry(43));
+        assert!(throttle.deadline().is_none());
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+/// When each pending permission event has waited long enough for
+/// --perm-timeout.
+pub struct PermTimeouts {
+    timeout: Duration,
+    /// in the order they're due, which is the order they came in
+    queue: VecDeque<(Instant, RawFd)>,
+    /// when the current event with each fd is due, so a reused fd isn't
+    /// answered by the deadline of the event it last had
+    due: HashMap<RawFd, Instant>,
+}
+
+impl PermTimeouts {
+    pub fn new(timeout: Duration) -> PermTimeouts {
+        PermTimeouts {
+            timeout,
+            queue: VecDeque::new(),
+            due: HashMap::new(),
+        }
+    }
+
+    pub fn start(&mut self, fd: RawFd, now: Instant) {
+        let t = now + self.timeout;
+        self.queue.push_back((t, fd));
+        self.due.insert(fd, t);
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.queue.front().map(|(t, _)| *t)
+    }
+
+    /// The events in `pending` that are due by `now`.
+    pub fn expired(&mut self, now: Instant, pending: &HashSet<RawFd>) -> Vec<RawFd> {
+        let mut expired = Vec::new();
+        while let Some(&(t, fd)) = self.queue.front() {
+            if t > now {
+                break;
+            }
+            self.queue.pop_front();
+            if self.due.get(&fd) == Some(&t) {
+                self.due.remove(&fd);
+                if pending.contains(&fd) {
+                    expired.push(fd);
+                }
+            }
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn perm_timeouts() {
+        let t = Instant::now();
+        let mut timeouts = PermTimeouts::new(Duration::from_secs(5));
+        let mut pending = HashSet::new();
+        assert_eq!(timeouts.deadline(), None);
+
+        for (fd, after) in &[(3, 0), (4, 1), (5, 2)] {
+            timeouts.start(*fd, t + Duration::from_secs(*after));
+            pending.insert(*fd);
+        }
+        // 4 was answered and its fd reused
+        timeouts.start(4, t + Duration::from_secs(3));
+        // 5 was answered
+        pending.remove(&5);
+
+        assert_eq!(timeouts.deadline(), Some(t + Duration::from_secs(5)));
+        assert!(timeouts
+            .expired(t + Duration::from_secs(4), &pending)
+            .is_empty());
+        assert_eq!(
+            timeouts.expired(t + Duration::from_secs(7), &pending),
+            vec![3]
+        );
+        assert_eq!(timeouts.deadline(), Some(t + Duration::from_secs(8)));
+        assert_eq!(
+            timeouts.expired(t + Duration::from_secs(8), &pending),
+            vec![4]
+        );
+        assert_eq!(timeouts.deadline(), None);
+    }
+}
@@ -0,0 +1,429 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::io::{self, Stdout};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::crossterm::ExecutableCommand;
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, Paragraph, Row, Table};
+use ratatui::Terminal;
+
+use crate::stats::Stats;
+use crate::{EventEntry, PermId};
+
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+// rates are averaged over about this long
+const RATE_WINDOW: f64 = 5.0;
+const MAX_RECENT: usize = 1000;
+// files and processes are forgotten once their rate decays below this
+const MIN_RATE: f64 = 0.01;
+
+/// An exponentially decaying event rate.
+struct Rate {
+    value: f64,
+    last: Instant,
+}
+
+impl Rate {
+    fn decayed(&self, now: Instant) -> f64 {
+        let dt = now.saturating_duration_since(self.last).as_secs_f64();
+        self.value * (-dt / RATE_WINDOW).exp()
+    }
+
+    fn hit(&mut self, now: Instant) {
+        self.value = self.decayed(now) + 1.0;
+        self.last = now;
+    }
+
+    fn per_sec(&self, now: Instant) -> f64 {
+        self.decayed(now) / RATE_WINDOW
+    }
+}
+
+fn hit<K: Hash + Eq>(rates: &mut HashMap<K, Rate>, key: K, now: Instant) {
+    rates
+        .entry(key)
+        .or_insert(Rate {
+            value: 0.0,
+            last: now,
+        })
+        .hit(now);
+}
+
+fn top<K: Clone>(rates: &HashMap<K, Rate>, n: usize, now: Instant) -> Vec<(K, f64)> {
+    let mut v: Vec<(K, f64)> = rates
+        .iter()
+        .map(|(k, r)| (k.clone(), r.per_sec(now)))
+        .collect();
+    v.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    v.truncate(n);
+    v
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Quit,
+    Respond(PermId, u32),
+}
+
+/// What's shown, apart from the terminal it's drawn on.
+#[derive(Default)]
+struct View {
+    recent: VecDeque<String>,
+    files: HashMap<PathBuf, Rate>,
+    procs: HashMap<u32, Rate>,
+    total: Option<Rate>,
+    prompts: VecDeque<(PermId, String)>,
+    paused: bool,
+    filter: String,
+    editing_filter: bool,
+}
+
+/// Live view of the busiest files and processes plus the latest events,
+/// permission events can be answered from here too.
+pub struct Tui {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+    view: View,
+    next_draw: Instant,
+    /// what the pid column holds, pid or tid
+    pid_label: &'static str,
+}
+
+impl View {
+    fn record(&mut self, entry: &EventEntry, perm: Option<PermId>, now: Instant) {
+        let mut line = Vec::new();
+        let _ = entry.write_to(&mut line);
+        let line = String::from_utf8_lossy(&line).into_owned();
+
+        match &mut self.total {
+            Some(t) => t.hit(now),
+            None => {
+                self.total = Some(Rate {
+                    value: 1.0,
+                    last: now,
+                })
+            }
+        }
+        if let Some(path) = &entry.path {
+            hit(&mut self.files, path.clone(), now);
+        }
+        if let Some(pid) = entry.pid {
+            hit(&mut self.procs, pid, now);
+        }
+
+        if let Some(perm) = perm {
+            self.prompts.push_back((perm, line.clone()));
+        }
+        if !self.paused {
+            self.recent.push_front(line);
+            self.recent.truncate(MAX_RECENT);
+        }
+    }
+
+    /// Stop asking about the events that aren't `pending` anymore, which
+    /// were answered some other way.
+    fn answered(&mut self, pending: &dyn Fn(PermId) -> bool) {
+        self.prompts.retain(|(perm, _)| pending(*perm));
+    }
+
+    fn key(&mut self, code: KeyCode) -> Option<Action> {
+        if self.editing_filter {
+            match code {
+                KeyCode::Enter => self.editing_filter = false,
+                KeyCode::Esc => {
+                    self.filter.clear();
+                    self.editing_filter = false;
+                }
+                KeyCode::Backspace => {
+                    self.filter.pop();
+                }
+                KeyCode::Char(c) => self.filter.push(c),
+                _ => (),
+            }
+            return None;
+        }
+
+        match code {
+            KeyCode::Char('q') => return Some(Action::Quit),
+            KeyCode::Char('p') => self.paused = !self.paused,
+            KeyCode::Char('/') => self.editing_filter = true,
+            KeyCode::Char('a') | KeyCode::Char('d') => {
+                if let Some((perm, _)) = self.prompts.pop_front() {
+                    let resp = if code == KeyCode::Char('a') {
+                        libc::FAN_ALLOW
+                    } else {
+                        libc::FAN_DENY
+                    };
+                    return Some(Action::Respond(perm, resp));
+                }
+            }
+            _ => (),
+        }
+        None
+    }
+
+    /// The recent events that match the filter, latest first.
+    fn shown(&self) -> Vec<&str> {
+        let filter = self.filter.as_str();
+        self.recent
+            .iter()
+            .filter(|l| l.contains(filter))
+            .map(|l| l.as_str())
+            .collect()
+    }
+}
+
+impl Tui {
+    pub fn new(pid_label: &'static str) -> io::Result<Tui> {
+        terminal::enable_raw_mode()?;
+        io::stdout().execute(EnterAlternateScreen)?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+        terminal.clear()?;
+
+        Ok(Tui {
+            terminal,
+            view: View::default(),
+            next_draw: Instant::now(),
+            pid_label,
+        })
+    }
+
+    /// Show `entry`, and ask about it if it's the pending permission event
+    /// `perm`.
+    pub fn record(&mut self, entry: &EventEntry, perm: Option<PermId>) {
+        self.view.record(entry, perm, Instant::now());
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        Some(self.next_draw)
+    }
+
+    /// Handle pending key presses, call when stdin is readable.
+    pub fn handle_input(&mut self, pending: &dyn Fn(PermId) -> bool) -> io::Result<Option<Action>> {
+        self.view.answered(pending);
+        while event::poll(Duration::from_secs(0))? {
+            match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => {
+                    if let Some(action) = self.view.key(key.code) {
+                        return Ok(Some(action));
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn draw(&mut self, stats: &Stats, pending: &dyn Fn(PermId) -> bool) -> io::Result<()> {
+        let now = Instant::now();
+        self.next_draw = now + REDRAW_INTERVAL;
+        let view = &mut self.view;
+        view.answered(pending);
+        // or a mount mark has them pile up
+        view.files.retain(|_, r| r.decayed(now) >= MIN_RATE);
+        view.procs.retain(|_, r| r.decayed(now) >= MIN_RATE);
+
+        let rate = view.total.as_ref().map(|t| t.per_sec(now)).unwrap_or(0.0);
+        let files = top(&view.files, 20, now);
+        let procs = top(&view.procs, 20, now);
+        let pid_label = self.pid_label;
+        let recent = view.shown();
+
+        let mut status = vec![Span::raw(format!(
+            "events {} ({:.1}/s)  ",
+            stats.events, rate
+        ))];
+        if stats.lost() != 0 {
+            status.push(Span::styled(
+                format!(
+                    "LOST {} (overflow {})  ",
+                    stats.lost(),
+                    stats.lost_kernel_overflow
+                ),
+                Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+            ));
+        }
+        if view.paused {
+            status.push(Span::styled("PAUSED  ", Style::default().fg(Color::Yellow)));
+        }
+        if view.editing_filter || !view.filter.is_empty() {
+            status.push(Span::raw(format!("filter: {}  ", view.filter)));
+        }
+        status.push(Span::styled(
+            "q:quit p:pause /:filter a/d:allow/deny",
+            Style::default().add_modifier(Modifier::DIM),
+        ));
+
+        let prompt = view.prompts.front().map(|(_, line)| {
+            Line::from(vec![
+                Span::styled(
+                    format!("{} pending: ", view.prompts.len()),
+                    Style::default()
+                        .fg(Color::Yellow)
+                        .add_modifier(Modifier::BOLD),
+                ),
+                Span::raw(line.clone()),
+            ])
+        });
+
+        self.terminal.draw(|f| {
+            let [status_area, prompt_area, top_area, recent_area] = Layout::vertical([
+                Constraint::Length(1),
+                Constraint::Length(if prompt.is_some() { 1 } else { 0 }),
+                Constraint::Percentage(50),
+                Constraint::Min(3),
+            ])
+            .areas(f.area());
+            let [files_area, procs_area] =
+                Layout::horizontal([Constraint::Percentage(70), Constraint::Percentage(30)])
+                    .areas(top_area);
+
+            f.render_widget(Paragraph::new(Line::from(status)), status_area);
+            if let Some(prompt) = prompt {
+                f.render_widget(Paragraph::new(prompt), prompt_area);
+            }
+
+            let rows = files
+                .iter()
+                .map(|(path, r)| Row::new(vec![format!("{:.1}", r), path.display().to_string()]));
+            f.render_widget(
+                Table::new(rows, [Constraint::Length(8), Constraint::Min(10)])
+                    .header(Row::new(vec!["ev/s", "file"]))
+                    .block(Block::bordered().title("top files")),
+                files_area,
+            );
+
+            let rows = procs
+                .iter()
+                .map(|(pid, r)| Row::new(vec![format!("{:.1}", r), pid.to_string()]));
+            f.render_widget(
+                Table::new(rows, [Constraint::Length(8), Constraint::Min(6)])
+                    .header(Row::new(vec!["ev/s", pid_label]))
+                    .block(Block::bordered().title("top processes")),
+                procs_area,
+            );
+
+            f.render_widget(
+                List::new(recent).block(Block::bordered().title("recent events")),
+                recent_area,
+            );
+        })?;
+        Ok(())
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+        let _ = io::stdout().execute(LeaveAlternateScreen);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn tui_rates() {
+        let t0 = Instant::now();
+        let mut rates = HashMap::new();
+        for _ in 0..3 {
+            hit(&mut rates, "busy", t0);
+        }
+        hit(&mut rates, "quiet", t0);
+        let top2 = top(&rates, 2, t0);
+        assert_eq!(top2[0].0, "busy");
+        assert!((top2[0].1 - 3.0 / RATE_WINDOW).abs() < 1e-9);
+        assert_eq!(top2[1].0, "quiet");
+        assert_eq!(top(&rates, 1, t0).len(), 1);
+
+        // decays by e every RATE_WINDOW
+        let later = t0 + Duration::from_secs_f64(RATE_WINDOW);
+        let decayed = rates["busy"].decayed(later);
+        assert!((decayed - 3.0 / std::f64::consts::E).abs() < 1e-9);
+    }
+
+    #[test]
+    fn tui_view() {
+        let now = Instant::now();
+        let mut view = View::default();
+        let mut entry = EventEntry::test(libc::FAN_OPEN, "/a");
+        view.record(&entry, None, now);
+        entry.path = Some("/b".into());
+        entry.pid = Some(2);
+        view.record(&entry, None, now);
+        view.record(&entry, None, now);
+
+        assert!((view.total.as_ref().unwrap().per_sec(now) - 3.0 / RATE_WINDOW).abs() < 1e-9);
+        assert_eq!(top(&view.files, 1, now)[0].0, Path::new("/b"));
+        assert_eq!(top(&view.procs, 1, now)[0].0, 2);
+        assert_eq!(view.shown().len(), 3);
+        assert!(view.shown()[0].contains("/b"));
+
+        // paused keeps the rates going but not the recent events
+        assert_eq!(view.key(KeyCode::Char('p')), None);
+        view.record(&entry, None, now);
+        assert_eq!(view.recent.len(), 3);
+        assert_eq!(view.files.len(), 2);
+        view.key(KeyCode::Char('p'));
+        view.record(&entry, None, now);
+        assert_eq!(view.recent.len(), 4);
+
+        // q only quits when not typing a filter
+        view.key(KeyCode::Char('/'));
+        for c in "/ax".chars() {
+            assert_eq!(view.key(KeyCode::Char(c)), None);
+        }
+        view.key(KeyCode::Backspace);
+        assert_eq!(view.key(KeyCode::Char('q')), None);
+        view.key(KeyCode::Backspace);
+        view.key(KeyCode::Enter);
+        assert_eq!(view.filter, "/a");
+        assert_eq!(view.shown().len(), 1);
+        assert_eq!(view.key(KeyCode::Char('q')), Some(Action::Quit));
+
+        view.key(KeyCode::Char('/'));
+        view.key(KeyCode::Char('x'));
+        view.key(KeyCode::Esc);
+        assert!(view.filter.is_empty() && !view.editing_filter);
+        assert_eq!(view.shown().len(), 4);
+    }
+
+    #[test]
+    fn tui_answer() {
+        let now = Instant::now();
+        let mut view = View::default();
+        let entry = EventEntry::test(libc::FAN_OPEN_PERM, "/a");
+        // nothing to answer yet
+        assert_eq!(view.key(KeyCode::Char('a')), None);
+
+        for perm in [(5, 1), (6, 2), (7, 3)] {
+            view.record(&entry, Some(perm), now);
+        }
+        // the first was answered some other way
+        view.answered(&|perm| perm != (5, 1));
+        assert_eq!(
+            view.key(KeyCode::Char('d')),
+            Some(Action::Respond((6, 2), libc::FAN_DENY))
+        );
+        assert_eq!(
+            view.key(KeyCode::Char('a')),
+            Some(Action::Respond((7, 3), libc::FAN_ALLOW))
+        );
+        assert_eq!(view.key(KeyCode::Char('a')), None);
+        // asked about while paused all the same
+        view.key(KeyCode::Char('p'));
+        view.record(&entry, Some((8, 4)), now);
+        assert_eq!(
+            view.key(KeyCode::Char('a')),
+            Some(Action::Respond((8, 4), libc::FAN_ALLOW))
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::HashSet;
+use std::env;
+use std::io::{self, ErrorKind};
+use std::os::unix::io::RawFd;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+
+/// Tells the re-executed process which fds it inherited: the fanotify fd
+/// first, then the fds of permission events still waiting for an answer.
+const INHERIT_ENV: &str = "FANOTIFY_CLI_INHERIT_FDS";
+
+fn set_cloexec(fd: RawFd, cloexec: bool) -> io::Result<()> {
+    let flags = if cloexec { libc::FD_CLOEXEC } else { 0 };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn parse_fds(s: &str) -> io::Result<Vec<RawFd>> {
+    s.split(',')
+        .map(|fd| {
+            fd.parse::<RawFd>().map_err(|_| {
+                io::Error::new(ErrorKind::InvalidInput, format!("{}={}", INHERIT_ENV, s))
+            })
+        })
+        .collect()
+}
+
+/// The fanotify fd and pending permission fds handed over by `reexec`, if
+/// this process was started that way.
+pub fn take_inherited() -> io::Result<Option<(RawFd, HashSet<RawFd>)>> {
+    let fds = match env::var(INHERIT_ENV) {
+        Ok(fds) => parse_fds(&fds)?,
+        Err(_) => return Ok(None),
+    };
+    env::remove_var(INHERIT_ENV);
+
+    for fd in &fds {
+        set_cloexec(*fd, true)?;
+    }
+    info!("inherited fds {:?}", fds);
+    Ok(Some((fds[0], fds[1..].iter().cloned().collect())))
+}
+
+/// Replace this process with `exe`, run with the same arguments, keeping the
+/// fanotify group and pending permission events alive across the exec. Only
+/// returns if the exec failed, in which case we carry on as before.
+pub fn reexec(exe: &Path, notify: RawFd, pending: &HashSet<RawFd>) -> io::Error {
+    let fds: Vec<RawFd> = Some(notify)
+        .into_iter()
+        .chain(pending.iter().cloned())
+        .collect();
+
+    for fd in &fds {
+        if let Err(e) = set_cloexec(*fd, false) {
+            return e;
+        }
+    }
+
+    info!("re-executing {:?}", exe);
+    let err = Command::new(exe)
+        .args(env::args_os().skip(1))
+        .env(
+            INHERIT_ENV,
+            fds.iter()
+                .map(|fd| fd.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        )
+        .exec();
+
+    for fd in &fds {
+        let _ = set_cloexec(*fd, true);
+    }
+    err
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inherit_fds_parse() {
+        assert_eq!(parse_fds("3").unwrap(), vec![3]);
+        assert_eq!(parse_fds("3,7,8").unwrap(), vec![3, 7, 8]);
+        assert!(parse_fds("3,x").is_err());
+    }
+}
@@ -0,0 +1,456 @@
+use std::cmp;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rustls::ClientConfig;
+
+use crate::expect::Rule;
+use crate::forward::{self, Conn};
+use crate::json::Json;
+use crate::spool::Spool;
+use crate::stats::Stats;
+use crate::{EventEntry, EventFormat};
+
+// how long the first event of a batch waits for others
+const BATCH_DELAY: Duration = Duration::from_secs(1);
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// events waiting to be posted, any more are dropped or spilled
+const QUEUE_LEN: usize = 10000;
+// how often to move spilled events back to the queue when there are no
+// new ones to do it with
+const REPLAY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, PartialEq)]
+struct Url {
+    tls: bool,
+    /// host:port to connect to
+    addr: String,
+    /// for the Host header
+    host: String,
+    path: String,
+}
+
+fn parse_url(url: &str) -> io::Result<Url> {
+    let (tls, rest) = if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{}: only http:// and https:// are supported", url),
+        ));
+    };
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    // a : after any ] of an IPv6 address
+    let has_port = host.rsplit_once(']').map_or(host, |(_, p)| p).contains(':');
+    let addr = if has_port {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, if tls { 443 } else { 80 })
+    };
+    Ok(Url {
+        tls,
+        addr,
+        host: host.into(),
+        path: path.into(),
+    })
+}
+
+/// A JSON object with the events, and a text that chat webhooks show.
+fn body(events: &[Vec<u8>]) -> Vec<u8> {
+    let mut body = format!(
+        "{{\"text\":\"{} fanotify-cli event{}\",\"events\":[",
+        events.len(),
+        if events.len() == 1 { "" } else { "s" }
+    )
+    .into_bytes();
+    for (i, e) in events.iter().enumerate() {
+        if i != 0 {
+            body.push(b',');
+        }
+        body.extend_from_slice(e);
+    }
+    body.extend_from_slice(b"]}");
+    body
+}
+
+/// POST `body`, returning the status code.
+fn post(url: &Url, tls: Option<&Arc<ClientConfig>>, body: &[u8]) -> io::Result<u16> {
+    let sock = forward::connect(&url.addr)?;
+    sock.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut conn = match tls {
+        Some(config) => forward::handshake(sock, config, &url.addr)?,
+        None => Conn::Tcp(sock),
+    };
+
+    conn.write_fmt(format_args!(
+        "POST {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         User-Agent: fanotify-cli\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        url.path,
+        url.host,
+        body.len()
+    ))?;
+    conn.write_all(body)?;
+    conn.flush()?;
+
+    let mut status = String::new();
+    BufReader::new(conn).read_line(&mut status)?;
+    status
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("bad response: {:?}", status.trim_end()),
+            )
+        })
+}
+
+/// Turns a batch of items into a request body.
+pub type Body = Box<dyn Fn(&[Vec<u8>]) -> Vec<u8> + Send>;
+
+struct Worker {
+    /// for the logs
+    name: &'static str,
+    url: Url,
+    tls: Option<Arc<ClientConfig>>,
+    batch: usize,
+    /// between the starts of posts
+    interval: Duration,
+    body: Body,
+}
+
+impl Worker {
+    /// Post `items`, retrying with backoff unless this is the last batch.
+    fn send(&self, items: &[Vec<u8>], last: bool) {
+        let body = (self.body)(items);
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            match post(&self.url, self.tls.as_ref(), &body) {
+                Ok(code) if (200..300).contains(&code) => return,
+                // the rest won't get any better by trying again
+                Ok(code) if code != 429 && code < 500 => {
+                    error!(
+                        "{}: HTTP {}, dropping {} events",
+                        self.name,
+                        code,
+                        items.len()
+                    );
+                    return;
+                }
+                Ok(code) => warn!("{}: HTTP {}, retrying in {:?}", self.name, code, backoff),
+                Err(e) => warn!("{}: {}, retrying in {:?}", self.name, e, backoff),
+            }
+            if last {
+                error!("{}: dropping {} events on exit", self.name, items.len());
+                return;
+            }
+            thread::sleep(backoff);
+            backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+        }
+    }
+
+    fn run(self, rx: Receiver<Vec<u8>>) {
+        let mut next_post = Instant::now();
+        while let Ok(first) = rx.recv() {
+            let mut items = vec![first];
+            let mut last = false;
+            let deadline = Instant::now() + BATCH_DELAY;
+            while items.len() < self.batch {
+                match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(item) => items.push(item),
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        last = true;
+                        break;
+                    }
+                }
+            }
+
+            if !last {
+                thread::sleep(next_post.saturating_duration_since(Instant::now()));
+            }
+            next_post = Instant::now() + self.interval;
+            self.send(&items, last);
+        }
+    }
+}
+
+/// POSTs items in batches from a thread of its own, so a slow or
+/// unreachable endpoint only ever costs the items it drops.
+pub struct Poster {
+    tx: Option<SyncSender<Vec<u8>>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Poster {
+    /// At most `batch` items per POST and `rate` POSTs per minute, 0 is
+    /// unlimited.
+    pub fn new(
+        name: &'static str,
+        url: &str,
+        batch: usize,
+        rate: u32,
+        body: Body,
+    ) -> io::Result<Poster> {
+        let url = parse_url(url)?;
+        let worker = Worker {
+            name,
+            tls: if url.tls {
+                Some(forward::tls_config(None)?)
+            } else {
+                None
+            },
+            url,
+            batch: cmp::max(batch, 1),
+            interval: if rate == 0 {
+                Duration::default()
+            } else {
+                Duration::from_secs(60) / rate
+            },
+            body,
+        };
+        let (tx, rx) = mpsc::sync_channel(QUEUE_LEN);
+        let worker = thread::Builder::new()
+            .name(name.into())
+            .spawn(move || worker.run(rx))?;
+
+        Ok(Poster {
+            tx: Some(tx),
+            worker: Some(worker),
+        })
+    }
+
+    /// Queue `item`, false if it had to be dropped.
+    pub fn send(&self, item: Vec<u8>) -> bool {
+        self.try_send(item).is_ok()
+    }
+
+    /// Queue `item`, or give it back if there's no room.
+    fn try_send(&self, item: Vec<u8>) -> Result<(), Vec<u8>> {
+        match self.tx.as_ref().unwrap().try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(item)) | Err(TrySendError::Disconnected(item)) => Err(item),
+        }
+    }
+}
+
+impl Drop for Poster {
+    /// Post what's left, once.
+    fn drop(&mut self) {
+        self.tx = None;
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// POSTs the events matching any of `rules`, or all of them without rules,
+/// in batches of JSON. Those it falls too far behind on go to the spool if
+/// there is one, a line of JSON each, and back in the queue once there's
+/// room.
+pub struct Webhook {
+    rules: Vec<Rule>,
+    poster: Poster,
+    spool: Option<Spool>,
+}
+
+impl Webhook {
+    pub fn new(
+        url: &str,
+        rules: Vec<Rule>,
+        batch: usize,
+        rate: u32,
+        spool: Option<Spool>,
+    ) -> io::Result<Webhook> {
+        Ok(Webhook {
+            rules,
+            poster: Poster::new("webhook", url, batch, rate, Box::new(body))?,
+            spool,
+        })
+    }
+
+    fn spilling(&self) -> bool {
+        self.spool.as_ref().is_some_and(|s| !s.is_empty())
+    }
+
+    /// Move spilled events back to the queue, as many as there's room for.
+    pub fn tick(&mut self) {
+        if let Some(spool) = &mut self.spool {
+            let poster = &self.poster;
+            let res = spool.replay(&mut |r| {
+                let event = r.strip_suffix(b"\n").unwrap_or(r);
+                poster.try_send(event.to_vec()).is_ok()
+            });
+            if let Err(e) = res {
+                warn!("webhook spool: {}", e);
+            }
+        }
+    }
+
+    /// Compact the spool, for the next run to carry on from.
+    pub fn sync(&mut self) -> io::Result<()> {
+        match &mut self.spool {
+            Some(spool) => spool.sync(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.spilling() {
+            Some(Instant::now() + REPLAY_INTERVAL)
+        } else {
+            None
+        }
+    }
+
+    pub fn record(&mut self, entry: &EventEntry, stats: &mut Stats) {
+        if !self.rules.is_empty() && !self.rules.iter().any(|r| r.matches(entry)) {
+            return;
+        }
+
+        let mut event = Vec::new();
+        if Json.write_event(entry, &mut event).is_err() {
+            return;
+        }
+        self.tick();
+        // behind the spilled ones if there are any, to keep them in order
+        let mut event = if self.spilling() {
+            event
+        } else {
+            match self.poster.try_send(event) {
+                Ok(()) => return,
+                Err(event) => event,
+            }
+        };
+        match &mut self.spool {
+            Some(spool) => {
+                event.push(b'\n');
+                match spool.push(&event) {
+                    Ok(true) => stats.spilled += 1,
+                    Ok(false) => stats.webhook_dropped += 1,
+                    Err(e) => {
+                        warn!("webhook spool: {}", e);
+                        stats.webhook_dropped += 1;
+                    }
+                }
+            }
+            None => stats.webhook_dropped += 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TempDir;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn webhook_url() -> io::Result<()> {
+        assert_eq!(
+            parse_url("https://hooks.example.com/services/T0/B0")?,
+            Url {
+                tls: true,
+                addr: "hooks.example.com:443".into(),
+                host: "hooks.example.com".into(),
+                path: "/services/T0/B0".into(),
+            }
+        );
+        let url = parse_url("http://[::1]:8080")?;
+        assert_eq!((url.addr.as_str(), url.path.as_str()), ("[::1]:8080", "/"));
+        assert!(parse_url("ftp://x/").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn webhook_post() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/hook", listener.local_addr()?);
+        let server = thread::spawn(move || -> io::Result<String> {
+            let (mut conn, _) = listener.accept()?;
+            let mut req = Vec::new();
+            let mut buf = [0; 4096];
+            // the body ends with the closing brace
+            while !req.ends_with(b"]}") {
+                let n = conn.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                req.extend_from_slice(&buf[..n]);
+            }
+            conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")?;
+            Ok(String::from_utf8(req).unwrap())
+        });
+
+        let mut stats = Stats::default();
+        let mut hook = Webhook::new(&url, vec![Rule::parse("/etc/* FAN_MODIFY")?], 10, 60, None)?;
+        let entry = EventEntry::test;
+        hook.record(&entry(libc::FAN_MODIFY, "/etc/passwd"), &mut stats);
+        hook.record(&entry(libc::FAN_ACCESS, "/etc/passwd"), &mut stats);
+        hook.record(&entry(libc::FAN_MODIFY, "/tmp/x"), &mut stats);
+        drop(hook);
+
+        let req = server.join().unwrap()?;
+        assert!(req.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(req.ends_with(
+            "\r\n\r\n{\"text\":\"1 fanotify-cli event\",\"events\":\
+             [{\"mask\":\"FAN_MODIFY\",\"pid\":1,\"path\":\"/etc/passwd\"}]}"
+        ));
+        assert_eq!(stats.webhook_dropped, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn webhook_spill() -> io::Result<()> {
+        let dir = TempDir::new("webhook-spill")?;
+        // a queue of one that nothing takes from but us
+        let (tx, rx) = mpsc::sync_channel(1);
+        let mut hook = Webhook {
+            rules: Vec::new(),
+            poster: Poster {
+                tx: Some(tx),
+                worker: None,
+            },
+            spool: Some(Spool::open(&dir, 1 << 20)?),
+        };
+        let mut stats = Stats::default();
+        let entry = EventEntry::test;
+        for path in &["/a", "/b", "/c"] {
+            hook.record(&entry(libc::FAN_MODIFY, path), &mut stats);
+        }
+        assert_eq!(stats.spilled, 2);
+        assert!(hook.deadline().is_some());
+
+        let mut posted = Vec::new();
+        for _ in 0..3 {
+            posted.push(String::from_utf8(rx.try_recv().unwrap()).unwrap());
+            hook.tick();
+        }
+        assert_eq!(
+            posted,
+            ["/a", "/b", "/c"]
+                .iter()
+                .map(|p| format!("{{\"mask\":\"FAN_MODIFY\",\"pid\":1,\"path\":\"{}\"}}", p))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(hook.deadline(), None);
+        assert_eq!(stats.webhook_dropped, 0);
+        Ok(())
+    }
+}